@@ -1,3 +1,48 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 fn main() {
     println!("cargo:rerun-if-changed=migrations");
+    watch_git_head();
+
+    // 构建信息，供运行时查询部署的版本。不在git仓库中构建时记为unknown。
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let build_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=WECOM_GPT_GIT_SHA={git_sha}");
+    println!("cargo:rustc-env=WECOM_GPT_BUILD_TIMESTAMP={build_timestamp}");
+}
+
+// 新的提交只改动HEAD所指向的分支引用，HEAD本身不变。因此同时关注该引用与打包的引用。
+// 不存在的文件会使构建脚本每次都重新运行，故只关注存在的文件。
+fn watch_git_head() {
+    let git_dir = Command::new("git")
+        .args(["rev-parse", "--git-dir"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| PathBuf::from(s.trim()))
+        .unwrap_or_else(|| PathBuf::from(".git"));
+    let head = git_dir.join("HEAD");
+    let mut watched = vec![head.clone(), git_dir.join("packed-refs")];
+    if let Ok(content) = fs::read_to_string(&head) {
+        if let Some(reference) = content.trim().strip_prefix("ref: ") {
+            watched.push(git_dir.join(reference));
+        }
+    }
+    for path in watched.iter().filter(|p| Path::exists(p)) {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
 }
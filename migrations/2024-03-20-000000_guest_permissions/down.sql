@@ -0,0 +1,2 @@
+-- 移除用户管理权限位
+ALTER TABLE guests DROP COLUMN permissions;
@@ -0,0 +1,2 @@
+-- 为用户添加管理权限位。完整管理员（admin）不受此字段限制。
+ALTER TABLE guests ADD COLUMN permissions INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- 移除用户回复语言偏好
+ALTER TABLE guests DROP COLUMN language;
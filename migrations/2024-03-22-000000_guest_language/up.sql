@@ -0,0 +1,2 @@
+-- 用户偏好的AI回复语言。为空时不做限定。
+ALTER TABLE guests ADD COLUMN language TEXT;
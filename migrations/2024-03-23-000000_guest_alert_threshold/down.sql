@@ -0,0 +1,2 @@
+-- 移除用户余额预警阈值
+ALTER TABLE guests DROP COLUMN alert_threshold;
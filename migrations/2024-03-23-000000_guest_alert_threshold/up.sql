@@ -0,0 +1,2 @@
+-- 用户自定义的余额预警阈值。为空时使用全局配置。
+ALTER TABLE guests ADD COLUMN alert_threshold DOUBLE;
@@ -0,0 +1,2 @@
+-- 移除消息的向量表示
+ALTER TABLE messages DROP COLUMN embedding;
@@ -0,0 +1,2 @@
+-- 消息的向量表示，用于语义检索。以小端序f32数组存储。
+ALTER TABLE messages ADD COLUMN embedding BLOB;
@@ -0,0 +1,2 @@
+-- 移除会话的个性化设置
+ALTER TABLE conversations DROP COLUMN settings;
@@ -0,0 +1,2 @@
+-- 会话的个性化设置，如采样温度。以JSON格式存储，NULL表示沿用助手默认值。
+ALTER TABLE conversations ADD COLUMN settings TEXT;
@@ -0,0 +1,2 @@
+-- 移除已处理消息表
+DROP TABLE processed_messages;
@@ -0,0 +1,5 @@
+-- 已处理的企业微信消息ID，用于跨重启的消息去重
+CREATE TABLE processed_messages (
+    msg_id TEXT NOT NULL PRIMARY KEY,
+    processed_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+-- 移除会话的置顶标记
+ALTER TABLE conversations DROP COLUMN pinned;
@@ -0,0 +1,2 @@
+-- 置顶的会话不会被清理，也不会因闲置而被新会话替换
+ALTER TABLE conversations ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT 0;
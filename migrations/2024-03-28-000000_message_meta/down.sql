@@ -0,0 +1,2 @@
+-- 移除消息的附加信息
+ALTER TABLE messages DROP COLUMN meta;
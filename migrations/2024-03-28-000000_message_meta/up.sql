@@ -0,0 +1,2 @@
+-- 消息的附加信息，如供应商返回的请求ID。以JSON格式存储。
+ALTER TABLE messages ADD COLUMN meta TEXT;
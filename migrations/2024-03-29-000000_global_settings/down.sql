@@ -0,0 +1,2 @@
+-- 移除全局设置
+DROP TABLE global_settings;
@@ -0,0 +1,6 @@
+-- 全局设置，仅有一行
+CREATE TABLE global_settings (
+    id INTEGER PRIMARY KEY NOT NULL CHECK (id = 1),
+    default_prompt TEXT,
+    updated_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+-- 移除余额流水表
+DROP TABLE credit_ledger;
@@ -0,0 +1,9 @@
+-- 余额流水，记录每次充值及其撤销
+CREATE TABLE credit_ledger (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    guest_id INTEGER NOT NULL REFERENCES guests(id),
+    amount DOUBLE NOT NULL,
+    kind TEXT NOT NULL,
+    operator TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
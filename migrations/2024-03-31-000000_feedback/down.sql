@@ -0,0 +1,2 @@
+-- 移除反馈表
+DROP TABLE feedback;
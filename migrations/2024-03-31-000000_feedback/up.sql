@@ -0,0 +1,9 @@
+-- 用户对AI回复的反馈
+CREATE TABLE feedback (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    guest_id INTEGER NOT NULL REFERENCES guests(id),
+    assistant_id INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    reply TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+-- 移除会话标题
+ALTER TABLE conversations DROP COLUMN title;
@@ -0,0 +1,2 @@
+-- 会话标题，由首条用户消息自动生成
+ALTER TABLE conversations ADD COLUMN title TEXT;
@@ -0,0 +1,3 @@
+-- 移除会话摘要
+ALTER TABLE conversations DROP COLUMN summarized_messages;
+ALTER TABLE conversations DROP COLUMN summary;
@@ -0,0 +1,3 @@
+-- 超出长度限制的早期消息的摘要，以及摘要涵盖的消息数
+ALTER TABLE conversations ADD COLUMN summary TEXT;
+ALTER TABLE conversations ADD COLUMN summarized_messages INTEGER NOT NULL DEFAULT 0;
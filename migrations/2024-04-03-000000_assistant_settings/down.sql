@@ -0,0 +1,2 @@
+-- 移除助手设置
+DROP TABLE assistant_settings;
@@ -0,0 +1,6 @@
+-- 助手的运行时设置，每个助手一行
+CREATE TABLE assistant_settings (
+    assistant_id INTEGER PRIMARY KEY NOT NULL,
+    persona TEXT,
+    updated_at TIMESTAMP NOT NULL
+);
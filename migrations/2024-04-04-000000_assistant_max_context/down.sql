@@ -0,0 +1,2 @@
+-- 移除上下文长度设置
+ALTER TABLE assistant_settings DROP COLUMN max_context;
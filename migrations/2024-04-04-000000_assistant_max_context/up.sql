@@ -0,0 +1,2 @@
+-- 管理员调整后的上下文长度，为空时使用供应商上限
+ALTER TABLE assistant_settings ADD COLUMN max_context INTEGER;
@@ -0,0 +1,2 @@
+-- 移除模拟费用表
+DROP TABLE impersonation_costs;
@@ -0,0 +1,9 @@
+-- 管理员模拟用户产生的费用。模拟不写入会话记录，故单独记录，计入助手的费用上限
+CREATE TABLE impersonation_costs (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    assistant_id INTEGER NOT NULL,
+    operator TEXT NOT NULL,
+    guest TEXT NOT NULL,
+    cost DOUBLE NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
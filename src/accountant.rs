@@ -1,8 +1,12 @@
 //! Accountant专职用户账户管理
 use crate::core::Guest;
-use crate::storage::Agent as StorageAgent;
-use crate::wecom_api::{CallbackParams, CallbackRequestBody, ContactEventContent, UrlVerifyParams};
+use crate::storage::{
+    Agent as StorageAgent, ConversationSummary, Error as StorageError, FeedbackEntry, Usage,
+    UserOrder,
+};
+use crate::wecom_api::{CallbackParams, CallbackRequestBody, ContactEventContent};
 use axum::extract::Query;
+use chrono::NaiveDateTime;
 use serde::Deserialize;
 use serde_xml_rs::from_str;
 use std::fmt;
@@ -32,6 +36,10 @@ pub struct Config {
     pub agent_id: u64,
     pub token: String,
     pub key: String,
+    #[serde(default)]
+    pub onboarding_credit: f64, // 经通讯录新增的用户获得的初始额度
+    #[serde(default)]
+    pub grace_credit: f64, // 允许透支至的余额下限，如-1.0。余额不高于此值时暂停服务
 }
 
 // 账户信息的数据库读取与更新。
@@ -39,6 +47,8 @@ pub struct Accountant {
     agent_id: u64,
     storage: Arc<StorageAgent>,
     crypto_agent: CryptoAgent,
+    onboarding_credit: f64,
+    grace_credit: f64,
 }
 
 impl Accountant {
@@ -48,6 +58,8 @@ impl Accountant {
             agent_id: config.agent_id,
             storage,
             crypto_agent,
+            onboarding_credit: config.onboarding_credit,
+            grace_credit: config.grace_credit,
         }
     }
 
@@ -56,21 +68,14 @@ impl Accountant {
         self.agent_id
     }
 
-    /// 通讯录API服务有效性验证
-    pub fn verify_url(&self, params: &UrlVerifyParams) -> Result<String, Error> {
-        if self.crypto_agent.generate_signature(vec![
-            &params.timestamp,
-            &params.nonce,
-            &params.echostr,
-        ]) != params.msg_signature
-        {
-            return Err(Error::Internal("签名校验失败".to_string()));
-        }
-        Ok(self
-            .crypto_agent
-            .decrypt(&params.echostr)
-            .map_err(|e| Error::Internal(format!("解密消息失败。{e}")))?
-            .text)
+    /// 允许透支至的余额下限
+    pub fn grace_credit(&self) -> f64 {
+        self.grace_credit
+    }
+
+    /// 通讯录应用使用的加解密对象。用于服务有效性验证。
+    pub fn crypto_agent(&self) -> &CryptoAgent {
+        &self.crypto_agent
     }
 
     /// 处理企业微信发来的新增用户事件
@@ -104,31 +109,54 @@ impl Accountant {
             .map_err(|e| Error::Internal(format!("解析xml失败。{e}")))?;
         tracing::debug!("Callback parsed");
 
-        // 注册该用户
+        self.register_contact(&callback_content.user_id)
+    }
+
+    /// 注册通讯录中新增的用户，并授予初始额度。用户已存在时无需处理。
+    pub fn register_contact(&self, user_id: &str) -> Result<(), Error> {
+        let exists = self
+            .storage
+            .guest_exists(user_id)
+            .map_err(|e| Error::Internal(format!("查询用户失败。{e}")))?;
+        if exists {
+            tracing::info!("用户{user_id}已存在，无需注册。");
+            return Ok(());
+        }
         let guest = Guest {
-            name: callback_content.user_id,
-            credit: 0.0,
+            name: user_id.to_owned(),
+            credit: self.onboarding_credit,
             admin: false,
+            permissions: 0,
         };
-        self.register(&guest)
+        self.register_if_absent(&guest)
+            .map(|_| ())
             .map_err(|e| Error::Internal(format!("新增用户失败。{e}")))
     }
 
-    /// 开户
-    pub fn register(&self, guest: &Guest) -> Result<(), Error> {
-        self.storage
-            .create_user(guest)
-            .map_err(|e| Error::Internal(format!("新建用户失败。用户名：{}， {e}", guest.name)))
+    /// 开户，返回是否实际新建。通讯录事件与用户消息可能同时触发注册，
+    /// 账户已被另一方创建时视为成功，不重复创建。
+    pub fn register_if_absent(&self, guest: &Guest) -> Result<bool, Error> {
+        match self.storage.create_user(guest) {
+            Ok(_) => Ok(true),
+            Err(StorageError::AlreadyExists) => {
+                tracing::info!("用户{}已由其他请求注册。", guest.name);
+                Ok(false)
+            }
+            Err(e) => Err(Error::Internal(format!(
+                "新建用户失败。用户名：{}， {e}",
+                guest.name
+            ))),
+        }
     }
 
-    /// 检查账户的有效性
+    /// 检查账户的有效性。余额高于透支下限时视为有效。
     pub fn verify_guest(&self, guest_name: &str) -> Result<(), Error> {
         let user = self
             .storage
             .get_user(guest_name)
             .map_err(|_| Error::NotFound)?;
 
-        if user.credit <= 0.0 {
+        if user.credit <= self.grace_credit {
             Err(Error::Overdue(user.credit))
         } else {
             Ok(())
@@ -142,9 +170,24 @@ impl Accountant {
             .map_err(|_| Error::NotFound)
     }
 
-    /// 获取全部账户
+    /// 获取全部账户，按用户名排序。
     pub fn get_guests(&self) -> Result<Vec<Guest>, Error> {
-        self.storage.get_users().map_err(|_| Error::NotFound)
+        self.storage
+            .get_users(UserOrder::Name, i64::MAX, 0)
+            .map(|(guests, _)| guests)
+            .map_err(|_| Error::NotFound)
+    }
+
+    /// 分页获取账户，并返回账户总数。
+    pub fn get_guests_page(
+        &self,
+        order: UserOrder,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<Guest>, i64), Error> {
+        self.storage
+            .get_users(order, limit, offset)
+            .map_err(|_| Error::NotFound)
     }
 
     /// 更新账户
@@ -154,10 +197,142 @@ impl Accountant {
             .map_err(|e| Error::Internal(format!("更新用户失败。{e}")))
     }
 
+    /// 为账户充值并记入余额流水，返回充值后的账户
+    pub fn recharge(&self, guest_name: &str, amount: f64, operator: &str) -> Result<Guest, Error> {
+        self.storage
+            .recharge(guest_name, amount, operator)
+            .map_err(|e| Error::Internal(format!("充值失败。{e}")))
+    }
+
+    /// 撤销账户最近一笔充值，返回撤销后的账户与扣回的金额
+    pub fn reverse_last_recharge(
+        &self,
+        guest_name: &str,
+        operator: &str,
+    ) -> Result<(Guest, f64), Error> {
+        self.storage
+            .reverse_last_recharge(guest_name, operator)
+            .map_err(|e| Error::Internal(format!("撤销充值失败。{e}")))
+    }
+
     /// 删除账户
     pub fn remove_guest(&self, guest: &Guest) -> Result<u64, Error> {
         self.storage
             .remove_user(guest)
             .map_err(|e| Error::Internal(format!("删除用户失败。{e}")))
     }
+
+    /// 合并账户。旧账户的会话记录与余额并入新账户，旧账户随后删除。
+    pub fn merge_guests(&self, old_name: &str, new_name: &str) -> Result<Guest, Error> {
+        self.storage
+            .merge_guests(old_name, new_name)
+            .map_err(|e| Error::Internal(format!("合并用户失败。{e}")))
+    }
+
+    /// 设置账户偏好的回复语言。None表示清除偏好。
+    pub fn set_language(&self, guest: &Guest, language: Option<&str>) -> Result<(), Error> {
+        self.storage
+            .set_language(guest, language)
+            .map_err(|e| Error::Internal(format!("设置语言失败。{e}")))
+    }
+
+    /// 设置账户的余额预警阈值。None表示使用全局配置。
+    pub fn set_alert_threshold(&self, guest: &Guest, threshold: Option<f64>) -> Result<(), Error> {
+        self.storage
+            .set_alert_threshold(guest, threshold)
+            .map_err(|e| Error::Internal(format!("设置预警阈值失败。{e}")))
+    }
+
+    /// 查询账户自定义的余额预警阈值
+    pub fn alert_threshold(&self, guest: &Guest) -> Result<Option<f64>, Error> {
+        self.storage
+            .get_alert_threshold(guest)
+            .map_err(|e| Error::Internal(format!("获取预警阈值失败。{e}")))
+    }
+
+    /// 删除早于指定时间的非活跃会话
+    pub fn prune_conversations(&self, before: NaiveDateTime) -> Result<usize, Error> {
+        self.storage
+            .prune_conversations(before)
+            .map_err(|e| Error::Internal(format!("清理会话失败。{e}")))
+    }
+
+    /// 结束全部账户与指定助手的当前会话，返回结束的会话数量
+    pub fn reset_all_conversations(&self, assistant_id: u64) -> Result<usize, Error> {
+        self.storage
+            .reset_all_conversations(assistant_id)
+            .map_err(|e| Error::Internal(format!("重置会话失败。{e}")))
+    }
+
+    /// 账户总数
+    pub fn guest_count(&self) -> Result<i64, Error> {
+        self.storage
+            .get_guest_count()
+            .map_err(|e| Error::Internal(format!("统计用户数量失败。{e}")))
+    }
+
+    /// 全局默认提示词
+    pub fn default_prompt(&self) -> Result<Option<String>, Error> {
+        self.storage
+            .get_default_prompt()
+            .map_err(|e| Error::Internal(format!("获取全局提示词失败。{e}")))
+    }
+
+    /// 设置全局默认提示词。None表示清除。
+    pub fn set_default_prompt(&self, prompt: Option<&str>) -> Result<(), Error> {
+        self.storage
+            .set_default_prompt(prompt)
+            .map_err(|e| Error::Internal(format!("设置全局提示词失败。{e}")))
+    }
+
+    /// 已应用的数据库迁移版本
+    pub fn migration_status(&self) -> Result<Vec<String>, Error> {
+        self.storage
+            .migration_status()
+            .map_err(|e| Error::Internal(format!("查询迁移状态失败。{e}")))
+    }
+
+    /// 分页列出账户与指定助手的会话，同时返回会话总数
+    pub fn list_conversations(
+        &self,
+        guest: &Guest,
+        assistant_id: u64,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<ConversationSummary>, i64), Error> {
+        self.storage
+            .list_conversations(guest, assistant_id, limit, offset)
+            .map_err(|e| Error::Internal(format!("获取会话列表失败。{e}")))
+    }
+
+    /// 记录用户对AI回复的反馈
+    pub fn add_feedback(
+        &self,
+        guest: &Guest,
+        assistant_id: u64,
+        content: &str,
+        reply: &str,
+    ) -> Result<(), Error> {
+        self.storage
+            .add_feedback(guest, assistant_id, content, reply)
+            .map_err(|e| Error::Internal(format!("记录反馈失败。{e}")))
+    }
+
+    /// 按时间由新到旧分页列出用户反馈，同时返回反馈总数
+    pub fn recent_feedback(
+        &self,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<FeedbackEntry>, i64), Error> {
+        self.storage
+            .recent_feedback(limit, offset)
+            .map_err(|e| Error::Internal(format!("获取反馈列表失败。{e}")))
+    }
+
+    /// 查询账户在全部会话中的累计用量
+    pub fn usage(&self, guest: &Guest) -> Result<Usage, Error> {
+        self.storage
+            .lifetime_usage(guest)
+            .map_err(|e| Error::Internal(format!("统计用量失败。{e}")))
+    }
 }
@@ -1,27 +1,43 @@
 /// Assistant负责处理与用户的会话
 /// - 纳管用户与自己的会话记录。
 /// - 协调会话记录与后端AI供应商的兼容性。
-pub use crate::provider::openai::Config as ProviderCfg;
+pub use crate::provider::Config as ProviderCfg;
 
-use crate::core;
-use crate::provider::openai::{Agent as AIAgent, Conversation, Message, Role};
-use crate::storage::Agent as StorageAgent;
-use serde::Deserialize;
+use crate::core::{self, Chat, ContentType};
+use crate::provider::health::Health;
+use crate::provider::limit::RateLimiter;
+use crate::provider::openai::{Conversation, Message, Role};
+use crate::provider::{self, Completion, ModelFamily, Provider};
+use crate::storage::model::Message as DbMessage;
+use crate::storage::{
+    Agent as StorageAgent, ContextSummary, ConversationSettings, Error as StorageError, MessageMeta,
+};
+use chrono::{Datelike, Duration, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt;
-use std::sync::Arc;
-use tiktoken_rs::{cl100k_base, CoreBPE};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Semaphore;
 
 // Custom Error
 #[derive(Debug, Clone)]
 pub enum Error {
     StorageError(String),
     ProviderError(String),
+    CapExceeded(String),
+    InvalidArgument(String),
+    ContentFiltered(String), // 被供应商内容过滤拦截，附带触发的类别
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let err = match self {
             Self::StorageError(e) => format!("数据库错误。{e}"),
             Self::ProviderError(e) => format!("供应商错误。{e}"),
+            Self::CapExceeded(e) => e.to_owned(),
+            Self::InvalidArgument(e) => e.to_owned(),
+            Self::ContentFiltered(category) => format!("内容被供应商过滤。类别：{category}"),
         };
         write!(f, "{}", err)
     }
@@ -29,22 +45,127 @@ impl fmt::Display for Error {
 impl std::error::Error for Error {}
 
 /// 智能助手初始化所需要的参数
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Clone, Default)]
 pub struct Config {
     pub agent_id: u64,
     pub name: String,
     pub token: String,
     pub key: String,
     pub secret: String,
-    pub prompt: String,
+    #[serde(default)]
+    pub prompt: String, // 为空时使用全局默认提示词
     pub provider_id: u64,
     pub context_tokens_reservation: u64,
+    #[serde(default)]
+    pub reply_disclaimer: Option<String>, // 附加在每条AI回复末尾的免责声明
+    #[serde(default)]
+    pub auto_new_conversation_after: Option<u64>, // 会话闲置超过此秒数后自动开启新会话，缺省不启用
+    #[serde(default)]
+    pub requests_per_minute: Option<u32>, // 本助手每分钟最多请求AI的次数，由全部用户共享
+    #[serde(default)]
+    pub tokens_per_minute: Option<u64>, // 本助手每分钟最多发送的prompt token数
+    #[serde(default = "default_rate_limit_timeout_secs")]
+    pub rate_limit_timeout_secs: u64, // 超出速率限制时最长等待时间
+    #[serde(default)]
+    pub max_concurrency: Option<usize>, // 本助手同时进行的供应商请求数上限，缺省不限制
+    #[serde(default)]
+    pub context_strategy: ContextStrategy, // 会话超出长度限制时的处理方式
+    #[serde(default = "default_context_window_messages")]
+    pub context_window_messages: usize, // 滑动窗口策略下最多保留的历史消息数
+    #[serde(default)]
+    pub menu: HashMap<String, MenuAction>, // 应用菜单按钮的key与点击后的操作
+    #[serde(default)]
+    pub mention_sender: bool, // 在回复开头@提问的用户，适用于群聊
+    #[serde(default)]
+    pub assistant_daily_cap: Option<f64>, // 本助手每日（UTC）全部用户的费用上限
+    #[serde(default)]
+    pub assistant_monthly_cap: Option<f64>, // 本助手每月（UTC）全部用户的费用上限
+    #[serde(default)]
+    pub quote_question: bool, // 在回复开头引用用户的问题，适用于消息较多的会话
+    #[serde(default)]
+    pub max_completion_tokens: Option<u64>, // 单条回复的最大token数，缺省使用供应商默认值
+    #[serde(default)]
+    pub allowed_content_types: Vec<ContentType>, // 本助手接受的消息类型，为空时全部接受
+    #[serde(default)]
+    pub conversation_cost_ceiling: Option<f64>, // 单个会话的累计费用上限，超出后自动开启新会话
+    #[serde(default)]
+    pub personas: HashMap<String, String>, // 预设的人设名称与提示词，管理员可在运行时切换
+    #[serde(default)]
+    pub archive_webhook: Option<String>, // 归档会话时，将会话记录以JSON格式POST至此地址
+    #[serde(default)]
+    pub prompt_token_price: Option<f64>, // 本助手的prompt价格，设置后取代供应商价格
+    #[serde(default)]
+    pub completion_token_price: Option<f64>, // 本助手的completion价格，设置后取代供应商价格
+    #[serde(default)]
+    pub auto_title: bool, // 根据首条用户消息自动生成会话标题，便于在会话列表中区分
 }
 
+fn default_rate_limit_timeout_secs() -> u64 {
+    30
+}
+
+fn default_context_window_messages() -> usize {
+    20
+}
+
+/// 会话上下文的取舍策略
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextStrategy {
+    #[default]
+    DropOldest, // 舍弃超出长度限制的早期消息
+    SlidingWindow,   // 仅保留最近若干条消息，同时受长度限制
+    SummarizeOldest, // 超出长度限制的早期消息由AI概括为摘要
+}
+
+/// 点击应用菜单按钮后的操作
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MenuAction {
+    Reply(String),  // 直接回复预设内容
+    Prompt(String), // 作为用户消息交由助手处理。以#开头时视为用户指令
+}
+
+// 助手与全局均未设置提示词时使用的系统消息
+const DEFAULT_PROMPT: &str = "You are a helpful assistant.";
+
+// 引用用户问题时最多保留的字符数
+const QUOTE_MAX_CHARS: usize = 30;
+
+// 发送归档会话记录的超时时间
+const ARCHIVE_WEBHOOK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+// 概括早期消息时使用的系统消息
+const SUMMARY_PROMPT: &str =
+    "Summarize the following conversation in a few sentences. Keep the facts and requests the user may refer to later.";
+
+// 概括早期消息时回复的最大token数
+const SUMMARY_MAX_TOKENS: u64 = 256;
+
+// 推理模型概括早期消息时回复的最大token数，同样需留出推理的余量
+const REASONING_SUMMARY_MAX_TOKENS: u64 = 2048;
+
+// 生成会话标题时使用的系统消息
+const TITLE_PROMPT: &str =
+    "Write a short title for a conversation that starts with the following message. Reply with the title only, in the language of the message.";
+
+// 会话标题的最大字符数
+const TITLE_MAX_CHARS: usize = 20;
+
+// 生成会话标题时回复的最大token数
+const TITLE_MAX_TOKENS: u64 = 32;
+
+// 推理模型的推理过程同样计入回复的token数，生成标题时需留出推理的余量
+const REASONING_TITLE_MAX_TOKENS: u64 = 1024;
+
 /// 助手的回复
 pub struct Response {
     content: String,
     cost: f64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    request_id: Option<String>, // 供应商返回的请求ID
+    notice: Option<String>,     // 需要告知用户的附加提示
 }
 
 impl core::ChatResponse for Response {
@@ -54,188 +175,1131 @@ impl core::ChatResponse for Response {
     fn cost(&self) -> f64 {
         self.cost
     }
+    fn notice(&self) -> Option<&str> {
+        self.notice.as_deref()
+    }
+    fn prompt_tokens(&self) -> u64 {
+        self.prompt_tokens
+    }
+    fn completion_tokens(&self) -> u64 {
+        self.completion_tokens
+    }
 }
 
 /// Assistant根据当前用户与用户消息来生成合适的回复
 pub struct Assistant {
-    provider: AIAgent,
+    provider: Box<dyn Provider>,
+    provider_id: u64,
+    provider_name: String,
+    model_family: ModelFamily,
+    health: Arc<Mutex<Health>>,
     storage: Arc<StorageAgent>,
     id: u64,
-    prompt: String,
+    prompt: Mutex<String>,
+    configured_prompt: String, // 配置文件中的提示词，恢复默认人设时使用
+    personas: HashMap<String, String>,
     context_tokens_reservation: u64,
-    token_counter: CoreBPE,
+    max_context: AtomicU64, // 实际使用的上下文长度，不超过供应商上限
+    reply_disclaimer: Option<String>,
+    auto_new_conversation_after: Option<Duration>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    max_concurrency: Option<usize>,
+    concurrency: Option<Arc<Semaphore>>,
+    context_strategy: ContextStrategy,
+    context_window_messages: usize,
+    menu: HashMap<String, MenuAction>,
+    daily_cap: Option<f64>,
+    monthly_cap: Option<f64>,
+    mention_sender: bool,
+    quote_question: bool,
+    max_completion_tokens: Option<u64>,
+    allowed_content_types: Vec<ContentType>,
+    conversation_cost_ceiling: Option<f64>,
+    archive_webhook: Option<String>,
+    prompt_token_price: Option<f64>,
+    completion_token_price: Option<f64>,
+    auto_title: bool,
 }
 
 impl Assistant {
     pub fn new(config: &Config, provider_cfg: &ProviderCfg, storage: Arc<StorageAgent>) -> Self {
-        let provider = AIAgent::new(provider_cfg);
+        Self::with_provider(config, provider_cfg, provider::build(provider_cfg), storage)
+    }
+
+    /// 使用指定的供应商实现创建助手。provider_cfg仅用于标识供应商。
+    pub fn with_provider(
+        config: &Config,
+        provider_cfg: &ProviderCfg,
+        provider: Box<dyn Provider>,
+        storage: Arc<StorageAgent>,
+    ) -> Self {
         Self {
+            prompt: Mutex::new(restored_prompt(config, &storage)),
+            max_context: AtomicU64::new(restored_max_context(
+                config,
+                provider.max_tokens(),
+                &storage,
+            )),
             provider,
+            provider_id: provider_cfg.id,
+            provider_name: provider_cfg.name.clone(),
+            model_family: provider_cfg.model_family,
+            health: Arc::new(Mutex::new(Health::default())),
             storage,
             id: config.agent_id,
-            prompt: config.prompt.clone(),
+            configured_prompt: config.prompt.clone(),
+            personas: config.personas.clone(),
             context_tokens_reservation: config.context_tokens_reservation,
-            token_counter: cl100k_base().unwrap(),
+            reply_disclaimer: config.reply_disclaimer.clone(),
+            auto_new_conversation_after: config
+                .auto_new_conversation_after
+                .map(|secs| Duration::seconds(secs as i64)),
+            rate_limiter: RateLimiter::per_minute(
+                config.requests_per_minute,
+                config.tokens_per_minute,
+                std::time::Duration::from_secs(config.rate_limit_timeout_secs),
+            )
+            .map(Arc::new),
+            max_concurrency: config.max_concurrency,
+            concurrency: config
+                .max_concurrency
+                .map(|permits| Arc::new(Semaphore::new(permits))),
+            context_strategy: config.context_strategy,
+            context_window_messages: config.context_window_messages,
+            menu: config.menu.clone(),
+            daily_cap: config.assistant_daily_cap,
+            monthly_cap: config.assistant_monthly_cap,
+            mention_sender: config.mention_sender,
+            quote_question: config.quote_question,
+            max_completion_tokens: config.max_completion_tokens,
+            allowed_content_types: config.allowed_content_types.clone(),
+            conversation_cost_ceiling: config.conversation_cost_ceiling,
+            archive_webhook: config.archive_webhook.clone(),
+            prompt_token_price: config.prompt_token_price,
+            completion_token_price: config.completion_token_price,
+            auto_title: config.auto_title,
         }
     }
-}
 
-impl core::Chat for Assistant {
-    /// 根据用户消息，返回合适的回复
-    async fn chat(
-        &self,
-        guest: &core::Guest,
-        message: &str,
-    ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
-        // 获取用户会话记录。若会话记录不存在，则创建新记录。
-        if let Err(e) = self.storage.get_conversation(guest, self.id) {
-            tracing::warn!(
-                "获取用户{}会话记录失败：{}。将为此用户创建新记录。",
-                guest.name,
-                e
-            );
-            self.storage
-                .create_conversation(guest, self.id)
-                .map_err(|e| Error::StorageError(format!("创建会话记录失败。{e}")))?;
-            tracing::info!("已为用户{}创建会话记录。", guest.name);
+    /// 重载配置时，沿用原助手的运行时状态，以免重建助手时将其清空：
+    /// - 供应商ID不变时共用健康与熔断状态；
+    /// - 速率限制不变时共用限流记录；
+    /// - 并发上限不变时共用并发额度，重载前仍在处理的请求继续计入上限。
+    ///
+    /// 相应配置改变时使用新的状态。人设与上下文长度保存在数据库中，创建助手时已恢复。
+    pub fn inherit_state(&mut self, previous: &Assistant) {
+        if self.provider_id == previous.provider_id {
+            self.health = previous.health.clone();
+        }
+        if let (Some(current), Some(old)) = (&self.rate_limiter, &previous.rate_limiter) {
+            if current.same_limits(old) {
+                self.rate_limiter = previous.rate_limiter.clone();
+            }
+        }
+        if self.max_concurrency == previous.max_concurrency {
+            self.concurrency = previous.concurrency.clone();
+        }
+    }
+
+    /// 本助手的prompt价格。未单独设置时使用供应商价格。
+    fn prompt_token_price(&self) -> f64 {
+        self.prompt_token_price
+            .unwrap_or_else(|| self.provider.prompt_token_price())
+    }
+
+    /// 本助手的completion价格。未单独设置时使用供应商价格。
+    fn completion_token_price(&self) -> f64 {
+        self.completion_token_price
+            .unwrap_or_else(|| self.provider.completion_token_price())
+    }
+
+    // 按本助手的价格计算一次回复的费用
+    fn cost(&self, completion: &Completion) -> f64 {
+        (self.prompt_token_price() * completion.prompt_tokens as f64
+            + self.completion_token_price() * completion.completion_tokens as f64)
+            / 1000.0
+    }
+
+    /// 按照本地计算的token数量，估算一条用户消息作为prompt的费用。
+    pub fn estimate_cost(&self, message: &str) -> f64 {
+        let tokens = self.provider.count_tokens(&Conversation {
+            messages: vec![Message {
+                role: Role::User.to_string(),
+                content: message.to_owned(),
+            }],
+            temperature: None,
+            max_tokens: None,
+        });
+        self.prompt_token_price() * tokens as f64 / 1000.0
+    }
+
+    /// 本助手使用的提示词。依次取助手配置、全局默认提示词与内置提示词中首个非空者。
+    pub fn prompt(&self) -> String {
+        let prompt = self
+            .prompt
+            .lock()
+            .expect("Prompt lock should not be poisoned")
+            .clone();
+        if !prompt.is_empty() {
+            return prompt;
+        }
+        match self.storage.get_default_prompt() {
+            Ok(Some(p)) if !p.is_empty() => p,
+            Ok(_) => DEFAULT_PROMPT.to_string(),
+            Err(e) => {
+                tracing::warn!("获取全局提示词失败：{e}");
+                DEFAULT_PROMPT.to_string()
+            }
+        }
+    }
+
+    /// 切换至指定人设，此后以该人设的提示词作为本助手的提示词。None表示恢复配置的提示词。
+    /// 所选人设保存在数据库中，重启或重新加载配置后依然有效。人设未配置时返回Ok(false)。
+    pub fn switch_persona(&self, name: Option<&str>) -> Result<bool, Error> {
+        let prompt = match name {
+            None => self.configured_prompt.clone(),
+            Some(name) => match self.personas.get(name) {
+                Some(prompt) => prompt.clone(),
+                None => return Ok(false),
+            },
         };
-        let db_conv = match self.storage.get_conversation(guest, self.id) {
+        self.storage
+            .set_persona(self.id, name)
+            .map_err(|e| Error::StorageError(format!("保存人设失败。{e}")))?;
+        *self
+            .prompt
+            .lock()
+            .expect("Prompt lock should not be poisoned") = prompt;
+        Ok(true)
+    }
+
+    /// 本助手实际使用的上下文长度（token）
+    pub fn max_context(&self) -> u64 {
+        self.max_context.load(Ordering::Relaxed)
+    }
+
+    /// 调整本助手实际使用的上下文长度。不可超过供应商上限，且须大于为回复预留的长度。
+    /// None表示恢复为供应商上限。调整结果保存在数据库中，重启或重新加载配置后依然有效。
+    pub fn set_max_context(&self, tokens: Option<u64>) -> Result<u64, Error> {
+        let limit = self.provider.max_tokens();
+        if let Some(tokens) = tokens {
+            check_max_context(tokens, limit, self.context_tokens_reservation)?;
+        }
+        self.storage
+            .set_max_context(self.id, tokens)
+            .map_err(|e| Error::StorageError(format!("保存上下文长度失败。{e}")))?;
+        let tokens = tokens.unwrap_or(limit);
+        self.max_context.store(tokens, Ordering::Relaxed);
+        Ok(tokens)
+    }
+
+    /// 已配置的人设名称，按名称排序
+    pub fn persona_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.personas.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    /// 发送给AI的系统消息。用户设定了回复语言时，追加相应要求。
+    pub fn system_prompt(&self, guest: &core::Guest) -> String {
+        let prompt = self.prompt();
+        match self.storage.get_language(guest) {
+            Ok(Some(lang)) => format!("{prompt}\nAlways reply in {lang}."),
+            Ok(None) => prompt,
             Err(e) => {
-                return Err(Box::new(Error::StorageError(format!(
-                    "获取会话记录失败。{e}"
-                ))))
+                tracing::warn!("获取用户{}的语言偏好失败：{e}", guest.name);
+                prompt
+            }
+        }
+    }
+
+    /// 以给定的历史消息为上下文获取AI回复。给定chunks时以流式方式获取。
+    /// 历史消息以系统消息开头时沿用该消息，否则使用默认提示词。超出长度限制的早期消息按照上下文策略处理。
+    /// 概括早期消息时，summary中已有的摘要无需重新生成，仅将新舍弃的消息并入其中。
+    async fn complete(
+        &self,
+        history: &[Message],
+        message: &str,
+        settings: &ConversationSettings,
+        summary: &mut ContextSummary,
+        chunks: Option<&UnboundedSender<String>>,
+    ) -> Result<Completion, Error> {
+        let (system_msg, history) = match history.split_first() {
+            Some((first, rest)) if first.role == Role::System.to_string() => (first.clone(), rest),
+            _ => {
+                tracing::warn!("System message not found, default used.");
+                let default = Message {
+                    role: Role::System.to_string(),
+                    content: self.prompt(),
+                };
+                (default, history)
             }
-            Ok(c) => c,
         };
-        tracing::debug!("Got conversation with {} messages", db_conv.len());
 
-        // 即将发送给AI的会话
-        let mut oai_conv: Vec<Message> = Vec::new();
+        // 长度限制内最早可保留的历史消息位置。注意会话超长问题。
+        // 以供应商的计数方式逐条计算，消息之外的固定开销只计一次
+        let overhead = self.provider.count_tokens(&Conversation::default());
+        let count = |message: &Message| {
+            self.provider
+                .count_tokens(&Conversation {
+                    messages: vec![message.clone()],
+                    temperature: None,
+                    max_tokens: None,
+                })
+                .saturating_sub(overhead)
+        };
+        let mut messages = Vec::with_capacity(history.len() + 1);
+        messages.push(system_msg.clone());
+        messages.extend_from_slice(history);
+        let kept = Conversation::trim_to_tokens(
+            &messages,
+            self.max_context(),
+            self.context_tokens_reservation + overhead,
+            count,
+        );
+        let prompt_tokens = overhead + kept.iter().map(count).sum::<u64>();
+        let mut start = history.len() + 1 - kept.len();
+        if start > 0 {
+            tracing::warn!("Conversation cut at index {}", start - 1);
+        }
 
-        // 追加用户消息
-        let user_msg = Message {
+        // 即将发送给AI的会话
+        let mut oai_conv: Vec<Message> = vec![system_msg];
+        let mut summary_usage = Completion::default();
+        match self.context_strategy {
+            ContextStrategy::DropOldest => {}
+            ContextStrategy::SlidingWindow => {
+                start = start.max(history.len().saturating_sub(self.context_window_messages));
+            }
+            ContextStrategy::SummarizeOldest => {
+                // 摘要与历史消息不符时（如历史消息并非来自当前会话）重新概括
+                if summary.messages > history.len() {
+                    *summary = ContextSummary::default();
+                }
+                if start > summary.messages {
+                    let dropped = &history[summary.messages..start];
+                    summary_usage = self
+                        .summarize(&summary.content, dropped, overhead, &count)
+                        .await?;
+                    *summary = ContextSummary {
+                        content: summary_usage.content.clone(),
+                        messages: start,
+                    };
+                }
+                if summary.messages > 0 {
+                    start = start.max(summary.messages);
+                    oai_conv.push(Message {
+                        role: Role::System.to_string(),
+                        content: format!(
+                            "Summary of the earlier conversation: {}",
+                            summary.content
+                        ),
+                    });
+                }
+            }
+        }
+        oai_conv.extend_from_slice(&history[start..]);
+        oai_conv.push(Message {
             role: Role::User.to_string(),
             content: message.to_owned(),
+        });
+        tracing::debug!("Total messages to AI: {}", oai_conv.len());
+
+        // 摘要的用量计入本次回复，由用户承担
+        let mut completion = self
+            .request(
+                Conversation {
+                    messages: oai_conv,
+                    temperature: settings.temperature,
+                    max_tokens: self.max_completion_tokens,
+                },
+                prompt_tokens,
+                chunks,
+            )
+            .await?;
+        completion.prompt_tokens += summary_usage.prompt_tokens;
+        completion.completion_tokens += summary_usage.completion_tokens;
+        completion.total_tokens += summary_usage.total_tokens;
+        Ok(completion)
+    }
+
+    // 根据会话的首条用户消息生成标题，所用token计入本次回复。生成失败不影响回复。
+    async fn generate_title(&self, guest: &core::Guest, message: &str, response: &mut Response) {
+        let conversation = Conversation {
+            messages: vec![
+                Message {
+                    role: Role::System.to_string(),
+                    content: TITLE_PROMPT.to_string(),
+                },
+                Message {
+                    role: Role::User.to_string(),
+                    content: message.to_owned(),
+                },
+            ],
+            temperature: None,
+            max_tokens: Some(match self.model_family {
+                ModelFamily::Chat => TITLE_MAX_TOKENS,
+                ModelFamily::Reasoning => REASONING_TITLE_MAX_TOKENS,
+            }),
         };
-        oai_conv.push(user_msg.clone());
-
-        // 填充历史会话。注意会话超长问题。
-        let mut prompt_tokens: usize = 0;
-        for t in db_conv.iter().enumerate().rev() {
-            prompt_tokens += self
-                .token_counter
-                .encode_with_special_tokens(&t.1.content)
-                .len();
-            if prompt_tokens as u64 >= self.provider.max_tokens() - self.context_tokens_reservation
-            {
-                tracing::warn!("Conversation cut at index {}", t.0);
-                break;
-            }
-            oai_conv.push(Message {
-                role: Role::try_from(t.1.message_type)?.to_string(),
-                content: t.1.content.clone(),
-            })
+        let tokens = self.provider.count_tokens(&conversation);
+        let completion = match self.request(conversation, tokens, None).await {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::warn!("为用户{}生成会话标题失败：{e}", guest.name);
+                return;
+            }
+        };
+        response.cost += self.cost(&completion);
+        response.prompt_tokens += completion.prompt_tokens;
+        response.completion_tokens += completion.completion_tokens;
+
+        let title = completion
+            .content
+            .trim()
+            .trim_matches(|c| matches!(c, '"' | '“' | '”' | '《' | '》'));
+        let title = core::truncate_chars(title, TITLE_MAX_CHARS).trim();
+        if title.is_empty() {
+            return;
         }
-        tracing::debug!("Total messages to AI: {}", oai_conv.len());
+        if let Err(e) = self.storage.set_conversation_title(guest, self.id, title) {
+            tracing::warn!("保存用户{}的会话标题失败：{e}", guest.name);
+        }
+    }
 
-        // 填充系统消息
-        if oai_conv
-            .first()
-            .is_some_and(|m| m.role != Role::System.to_string())
-        {
-            oai_conv.push(Message {
-                content: self.prompt.clone(),
-                role: Role::System.to_string(),
-            });
-            tracing::warn!("System message not found, default used.")
+    // 将超出长度限制的早期消息并入已有摘要，交由AI重新概括。
+    // 待概括的消息同样受长度限制，超出时舍弃其中最早的部分。
+    async fn summarize(
+        &self,
+        previous: &str,
+        messages: &[Message],
+        overhead: u64,
+        count: impl Fn(&Message) -> u64,
+    ) -> Result<Completion, Error> {
+        tracing::debug!("Summarize {} earlier messages", messages.len());
+        let system = Message {
+            role: Role::System.to_string(),
+            content: if previous.is_empty() {
+                SUMMARY_PROMPT.to_string()
+            } else {
+                format!("{SUMMARY_PROMPT}\nPrevious summary: {previous}")
+            },
+        };
+        let mut input = Vec::with_capacity(messages.len() + 1);
+        input.push(system.clone());
+        input.extend_from_slice(messages);
+        let max_tokens = self.summary_max_tokens();
+        let kept =
+            Conversation::trim_to_tokens(&input, self.max_context(), overhead + max_tokens, count);
+        let transcript: Vec<String> = kept[1..]
+            .iter()
+            .map(|m| format!("{}: {}", m.role, m.content))
+            .collect();
+        let transcript = transcript.join("\n");
+        let conversation = Conversation {
+            messages: vec![
+                system,
+                Message {
+                    role: Role::User.to_string(),
+                    content: transcript,
+                },
+            ],
+            temperature: None,
+            max_tokens: Some(max_tokens),
+        };
+        let tokens = self.provider.count_tokens(&conversation);
+        self.request(conversation, tokens, None).await
+    }
+
+    // 概括早期消息时回复的最大token数
+    fn summary_max_tokens(&self) -> u64 {
+        match self.model_family {
+            ModelFamily::Chat => SUMMARY_MAX_TOKENS,
+            ModelFamily::Reasoning => REASONING_SUMMARY_MAX_TOKENS,
         }
+    }
 
-        // 恢复正常时序
-        oai_conv.reverse();
+    // 等待速率额度后交由AI处理，并记录供应商的健康状态。给定chunks时以流式方式请求。
+    async fn request(
+        &self,
+        conversation: Conversation,
+        tokens: u64,
+        chunks: Option<&UnboundedSender<String>>,
+    ) -> Result<Completion, Error> {
+        // 等待共享的速率额度
+        if let Some(limiter) = &self.rate_limiter {
+            limiter
+                .acquire(tokens)
+                .await
+                .map_err(|e| Error::ProviderError(e.to_string()))?;
+        }
 
-        // 交由AI处理
-        let ai_response = match self
-            .provider
-            .process(&Conversation { messages: oai_conv })
-            .await
-        {
+        // 等待本助手的并发额度，不影响其他助手
+        let _permit = match &self.concurrency {
+            Some(semaphore) => Some(
+                semaphore
+                    .acquire()
+                    .await
+                    .map_err(|e| Error::ProviderError(e.to_string()))?,
+            ),
+            None => None,
+        };
+
+        let result = match chunks {
+            Some(chunks) => self.provider.stream(&conversation, chunks.clone()).await,
+            None => self.provider.process(&conversation).await,
+        };
+        match result {
+            // 内容过滤并非供应商故障，不影响健康状态
+            Err(provider::Error::ContentFiltered(category)) => {
+                tracing::warn!("[{}] 请求被内容过滤拦截。类别：{category}", self.id);
+                Err(Error::ContentFiltered(category))
+            }
             // 告知用户发生内部错误，避免用户徒劳重试或者等待
             Err(e) => {
-                return Err(Box::new(Error::ProviderError(format!(
-                    "获取AI回复时发生错误。{e}"
-                ))))
+                self.health
+                    .lock()
+                    .expect("Health lock should not be poisoned")
+                    .record_failure(&e.to_string());
+                Err(Error::ProviderError(format!("获取AI回复时发生错误。{e}")))
+            }
+            Ok(c) => {
+                let mut health = self
+                    .health
+                    .lock()
+                    .expect("Health lock should not be poisoned");
+                health.record_success();
+                if c.request_id.is_some() {
+                    health.last_request_id = c.request_id.clone();
+                }
+                tracing::debug!("AI replied. Request id: {:?}", c.request_id);
+                Ok(c)
+            }
+        }
+    }
+
+    /// 获取用户当前活跃会话的消息记录。
+    /// 若会话记录不存在，或最后一条消息早于闲置时限，则创建新会话。
+    /// 会话记录无法读取时返回错误，不开启新会话，以免用户的历史记录被悄然弃用。
+    fn active_conversation(
+        &self,
+        guest: &core::Guest,
+        now: NaiveDateTime,
+    ) -> Result<Vec<DbMessage>, Error> {
+        match self.storage.get_conversation(guest, self.id) {
+            Err(StorageError::NotFound) => {
+                tracing::info!("用户{}尚无会话记录，将为其创建新记录。", guest.name);
+            }
+            Err(e) => return Err(conversation_read_error(guest, e)),
+            Ok(conv) => {
+                let stale = match (self.auto_new_conversation_after, conv.last()) {
+                    (Some(window), Some(last)) => now - last.created_at > window,
+                    _ => false,
+                };
+                if !stale {
+                    return Ok(conv);
+                }
+                // 置顶的会话不会被自动替换
+                match self.storage.is_conversation_pinned(guest, self.id) {
+                    Ok(true) => return Ok(conv),
+                    Ok(false) => {}
+                    Err(e) => tracing::warn!("获取用户{}会话置顶状态失败：{e}", guest.name),
+                }
+                tracing::info!("用户{}的会话闲置超时，自动开启新会话。", guest.name);
             }
-            Ok(r) => r,
         };
-        tracing::debug!("AI replied");
+        self.storage
+            .create_conversation(guest, self.id)
+            .map_err(|e| Error::StorageError(format!("创建会话记录失败。{e}")))?;
+        tracing::info!("已为用户{}创建会话记录。", guest.name);
+        self.storage
+            .get_conversation(guest, self.id)
+            .map_err(|e| Error::StorageError(format!("获取会话记录失败。{e}")))
+    }
 
-        // 记录用户消息，并与当前会话记录关联
-        if let Err(e) = self
-            .storage
-            .append_message(guest, self.id, &user_msg, 0.0, 0, 0)
-        {
-            return Err(Box::new(Error::StorageError(format!("追加消息失败。{e}"))));
+    // 当前会话的累计费用达到上限时开启新会话，并返回告知用户的提示。置顶的会话不受影响。
+    fn roll_over_if_costly(&self, guest: &core::Guest) -> Result<Option<String>, Error> {
+        let Some(ceiling) = self.conversation_cost_ceiling else {
+            return Ok(None);
+        };
+        let Ok(conv) = self.storage.get_conversation(guest, self.id) else {
+            return Ok(None);
+        };
+        let cost: f64 = conv.iter().map(|m| m.cost).sum();
+        if cost < ceiling {
+            return Ok(None);
         }
-        tracing::debug!("User message appended");
+        match self.storage.is_conversation_pinned(guest, self.id) {
+            Ok(true) => return Ok(None),
+            Ok(false) => {}
+            Err(e) => tracing::warn!("获取用户{}会话置顶状态失败：{e}", guest.name),
+        }
+        tracing::info!(
+            "用户{}的会话费用{cost}达到上限，自动开启新会话。",
+            guest.name
+        );
+        self.storage
+            .create_conversation(guest, self.id)
+            .map_err(|e| Error::StorageError(format!("创建会话记录失败。{e}")))?;
+        Ok(Some(format!(
+            "当前会话的累计费用已达到上限{}，已自动开启新会话。",
+            core::format_credit(ceiling)
+        )))
+    }
 
-        // 更新AI回复到会话记录
-        tracing::debug!("Constructing reply message");
-        let ai_reply = Message {
-            role: ai_response.role().to_string(),
-            content: ai_response.content().to_owned(),
+    // 以用户专属的系统消息开头，随后是会话记录
+    fn history(&self, guest: &core::Guest, db_conv: &[DbMessage]) -> Vec<Message> {
+        let mut history: Vec<Message> = Vec::with_capacity(db_conv.len() + 1);
+        history.push(Message {
+            role: Role::System.to_string(),
+            content: self.system_prompt(guest),
+        });
+        history.extend(db_conv.iter().map(Message::from));
+        history
+    }
+
+    // 会话设置随会话持久保存，重启后依然有效
+    fn conversation_settings(&self, guest: &core::Guest) -> ConversationSettings {
+        self.storage
+            .get_conversation_settings(guest, self.id)
+            .unwrap_or_else(|e| {
+                tracing::warn!("获取用户{}的会话设置失败：{e}", guest.name);
+                ConversationSettings::default()
+            })
+    }
+
+    // 早期消息的摘要随会话持久保存，避免每次请求重复概括
+    fn context_summary(&self, guest: &core::Guest) -> ContextSummary {
+        self.storage
+            .get_context_summary(guest, self.id)
+            .unwrap_or_else(|e| {
+                tracing::warn!("获取用户{}的会话摘要失败：{e}", guest.name);
+                ContextSummary::default()
+            })
+    }
+
+    /// 以用户当前会话为上下文获取回复，但不写入会话记录。供管理员模拟用户排查问题。
+    /// 只读取会话记录与摘要：不会因闲置超时开启新会话，用户没有会话时也不会为其创建。
+    /// 费用单独记录在operator名下，计入本助手的费用上限。
+    pub async fn preview(
+        &self,
+        operator: &core::Guest,
+        guest: &core::Guest,
+        message: &str,
+    ) -> Result<Response, Error> {
+        let db_conv = match self.storage.get_conversation(guest, self.id) {
+            Ok(conv) => conv,
+            Err(StorageError::NotFound) => Vec::new(),
+            Err(e) => return Err(Error::StorageError(format!("获取会话记录失败。{e}"))),
         };
-        let cost = self.provider.cost(&ai_response);
-        if let Err(e) = self.storage.append_message(
-            guest,
+        let history = self.history(guest, &db_conv);
+        let settings = self.conversation_settings(guest);
+        // 不写入会话记录，新生成的摘要随之丢弃
+        let mut summary = self.context_summary(guest);
+        let response = self
+            .respond(guest, &history, message, &settings, &mut summary, None)
+            .await?;
+        if let Err(e) = self.storage.record_impersonation_cost(
             self.id,
-            &ai_reply,
-            cost,
-            ai_response.prompt_tokens(),
-            ai_response.completion_tokens(),
+            &operator.name,
+            &guest.name,
+            response.cost,
         ) {
-            return Err(Box::new(Error::StorageError(format!(
-                "添加消息到会话记录失败：{}, {e}",
-                guest.name
-            ))));
+            tracing::error!("记录{}模拟{}的费用失败：{e}", operator.name, guest.name);
+        }
+        Ok(response)
+    }
+
+    /// 根据用户消息获取回复，并更新到用户当前会话。
+    /// 给定chunks时以流式方式获取，每收到一段回复内容即发送给chunks，回复结束后chunks随之关闭。
+    pub async fn converse(
+        &self,
+        guest: &core::Guest,
+        message: &str,
+        chunks: Option<UnboundedSender<String>>,
+    ) -> Result<Response, Error> {
+        let notice = self.roll_over_if_costly(guest)?;
+        let db_conv = self.active_conversation(guest, Utc::now().naive_utc())?;
+        tracing::debug!("Got conversation with {} messages", db_conv.len());
+        let history = self.history(guest, &db_conv);
+        let settings = self.conversation_settings(guest);
+        let mut summary = self.context_summary(guest);
+        let summarized = summary.messages;
+        let mut response = self
+            .respond(
+                guest,
+                &history,
+                message,
+                &settings,
+                &mut summary,
+                chunks.as_ref(),
+            )
+            .await?;
+        // 回复已完整，及时关闭chunks，无需等待生成标题与保存记录
+        drop(chunks);
+        response.notice = notice;
+
+        // 保存更新后的摘要，下次只需概括此后舍弃的消息
+        if summary.messages != summarized {
+            if let Err(e) = self.storage.set_context_summary(guest, self.id, &summary) {
+                tracing::warn!("保存用户{}的会话摘要失败：{e}", guest.name);
+            }
+        }
+
+        // 每段会话仅在首条消息时生成一次标题
+        if self.auto_title && db_conv.is_empty() {
+            self.generate_title(guest, message, &mut response).await;
         }
+
+        // 更新用户消息与AI回复到会话记录
+        let user_msg = Message {
+            role: Role::User.to_string(),
+            content: message.to_owned(),
+        };
+        let ai_reply = Message {
+            role: Role::Assistant.to_string(),
+            content: response.content.clone(),
+        };
+        self.record_exchange(guest, &user_msg, &ai_reply, &response)?;
         tracing::debug!("AI's reply appended");
 
+        Ok(response)
+    }
+
+    /// 为即将发送给用户的回复添加装饰内容。装饰内容不计费，也不计入会话记录。
+    pub fn decorate_reply(&self, question: &str, content: &str) -> String {
+        let mut reply = content.to_owned();
+        if self.quote_question {
+            reply = format!("{}\n\n{reply}", quote(question));
+        }
+        match &self.reply_disclaimer {
+            Some(d) if !d.is_empty() => format!("{reply}\n\n{d}"),
+            _ => reply,
+        }
+    }
+
+    /// 记录一轮问答。
+    /// 本轮请求的prompt token（含历史上下文与用户消息）由触发它的AI回复承担，
+    /// 用户消息本身记为0，以免重复计数。供应商返回的请求ID记录在AI回复的附加信息中。
+    fn record_exchange(
+        &self,
+        guest: &core::Guest,
+        user_msg: &Message,
+        ai_reply: &Message,
+        response: &Response,
+    ) -> Result<(), Error> {
+        self.storage
+            .append_message(guest, self.id, user_msg, 0.0, 0, 0)
+            .map_err(|e| Error::StorageError(format!("追加消息失败。{e}")))?;
+        tracing::debug!("User message appended");
+        let reply_id = self
+            .storage
+            .append_message(
+                guest,
+                self.id,
+                ai_reply,
+                response.cost,
+                response.prompt_tokens,
+                response.completion_tokens,
+            )
+            .map_err(|e| {
+                Error::StorageError(format!("添加消息到会话记录失败：{}, {e}", guest.name))
+            })?;
+        if response.request_id.is_some() {
+            let meta = MessageMeta {
+                request_id: response.request_id.clone(),
+                ..Default::default()
+            };
+            if let Err(e) = self.storage.set_message_meta(reply_id, &meta) {
+                tracing::warn!("保存消息{reply_id}的附加信息失败：{e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// 以给定的历史消息与会话设置获取AI回复，并计算费用。给定chunks时以流式方式获取。
+    async fn respond(
+        &self,
+        guest: &core::Guest,
+        history: &[Message],
+        message: &str,
+        settings: &ConversationSettings,
+        summary: &mut ContextSummary,
+        chunks: Option<&UnboundedSender<String>>,
+    ) -> Result<Response, Error> {
+        tracing::debug!(
+            "Chat for {} with {} context messages",
+            guest.name,
+            history.len()
+        );
+        if let Some(reason) = self.spend_cap_reason(Utc::now().naive_utc()) {
+            return Err(Error::CapExceeded(reason));
+        }
+        let completion = self
+            .complete(history, message, settings, summary, chunks)
+            .await?;
+        if completion.total_tokens != completion.prompt_tokens + completion.completion_tokens {
+            tracing::warn!(
+                "供应商报告的token总数{}与prompt {}、completion {}之和不符",
+                completion.total_tokens,
+                completion.prompt_tokens,
+                completion.completion_tokens
+            );
+        }
+        let cost = self.cost(&completion);
         Ok(Response {
-            content: ai_response.content().to_owned(),
+            content: completion.content,
             cost,
+            prompt_tokens: completion.prompt_tokens,
+            completion_tokens: completion.completion_tokens,
+            request_id: completion.request_id,
+            notice: None,
         })
     }
 
-    /// 查账单
-    fn audit(&self, guest: &core::Guest) -> String {
-        // 获取用户会话记录。若会话记录不存在，则创建新记录。
-        if let Err(e) = self.storage.get_conversation(guest, self.id) {
+    /// 设定用户当前会话的采样温度。None表示恢复助手默认值。
+    pub fn set_temperature(
+        &self,
+        guest: &core::Guest,
+        temperature: Option<f64>,
+    ) -> Result<(), Error> {
+        self.active_conversation(guest, Utc::now().naive_utc())?;
+        let mut settings = self
+            .storage
+            .get_conversation_settings(guest, self.id)
+            .map_err(|e| Error::StorageError(format!("获取会话设置失败。{e}")))?;
+        settings.temperature = temperature;
+        self.storage
+            .set_conversation_settings(guest, self.id, &settings)
+            .map_err(|e| Error::StorageError(format!("保存会话设置失败。{e}")))
+    }
+
+    /// 置顶或取消置顶用户当前会话。置顶的会话不会被清理，也不会因闲置而被替换。
+    pub fn set_pinned(&self, guest: &core::Guest, pinned: bool) -> Result<(), Error> {
+        self.active_conversation(guest, Utc::now().naive_utc())?;
+        self.storage
+            .set_conversation_pinned(guest, self.id, pinned)
+            .map_err(|e| Error::StorageError(format!("设置会话置顶失败。{e}")))
+    }
+
+    /// 归档用户当前会话：将其置顶保留，并开启新会话。
+    /// 配置了归档webhook时，另将会话记录发送至该地址，发送结果不影响归档。
+    pub fn archive(&self, guest: &core::Guest) -> Result<(), Error> {
+        self.set_pinned(guest, true)?;
+        // 开启新会话之前读取待归档的会话记录
+        let archived = match &self.archive_webhook {
+            Some(_) => self
+                .storage
+                .get_conversation(guest, self.id)
+                .map_err(|e| Error::StorageError(format!("获取会话记录失败。{e}")))?,
+            None => Vec::new(),
+        };
+        self.storage
+            .create_conversation(guest, self.id)
+            .map_err(|e| Error::StorageError(format!("创建会话记录失败。{e}")))?;
+        if let Some(url) = &self.archive_webhook {
+            let transcript = Transcript::new(guest, self.id, &archived);
+            tokio::spawn(post_transcript(url.clone(), transcript));
+        }
+        Ok(())
+    }
+
+    /// 本助手是否接受该MsgType的企业微信消息。未配置允许的类型时接受全部消息。
+    pub fn accepts(&self, msg_type: &str) -> bool {
+        if self.allowed_content_types.is_empty() {
+            return true;
+        }
+        ContentType::from_msg_type(msg_type)
+            .is_some_and(|t| self.allowed_content_types.contains(&t))
+    }
+
+    /// 用户当前会话中最近一条AI回复。会话不存在或尚无回复时返回None。
+    pub fn last_reply(&self, guest: &core::Guest) -> Option<String> {
+        let conversation = match self.storage.get_conversation(guest, self.id) {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::warn!("获取用户{}会话记录失败：{e}", guest.name);
+                return None;
+            }
+        };
+        conversation
+            .into_iter()
+            .rev()
+            .find(|m| m.message_type == Role::Assistant.to_id())
+            .map(|m| m.content)
+    }
+
+    /// 清除用户当前会话的设置（目前仅有采样温度），恢复助手默认值。返回清除前的设置。
+    /// 回复语言按用户保存，不受影响。
+    pub fn clear_settings(&self, guest: &core::Guest) -> Result<ConversationSettings, Error> {
+        self.storage
+            .clear_conversation_settings(guest, self.id)
+            .map_err(|e| Error::StorageError(format!("清除会话设置失败。{e}")))
+    }
+
+    /// 检查所用供应商是否可用
+    pub async fn check_provider(&self) -> Result<(), provider::Error> {
+        self.provider.health_check(self.model_family).await
+    }
+
+    /// 本助手的累计费用达到每日或每月上限时，返回告知用户的原因
+    pub fn spend_cap_reason(&self, now: NaiveDateTime) -> Option<String> {
+        let today = now.date().and_hms_opt(0, 0, 0)?;
+        let this_month = now.date().with_day(1)?.and_hms_opt(0, 0, 0)?;
+        let caps = [
+            (self.daily_cap, today, "该助手今日额度已用尽"),
+            (self.monthly_cap, this_month, "该助手本月额度已用尽"),
+        ];
+        for (cap, since, reason) in caps {
+            let Some(cap) = cap else {
+                continue;
+            };
+            match self.storage.assistant_spend(self.id, since) {
+                Ok(spent) if spent >= cap => return Some(reason.to_string()),
+                Ok(_) => {}
+                Err(e) => tracing::warn!("统计助手{}的费用失败：{e}", self.id),
+            }
+        }
+        None
+    }
+
+    /// 回复时是否@提问的用户
+    pub fn mention_sender(&self) -> bool {
+        self.mention_sender
+    }
+
+    /// 菜单按钮对应的操作
+    pub fn menu_action(&self, key: &str) -> Option<&MenuAction> {
+        self.menu.get(key)
+    }
+
+    /// 返回所用供应商的ID、名称与健康状态
+    pub fn provider_health(&self) -> (u64, String, Health) {
+        let health = self
+            .health
+            .lock()
+            .expect("Health lock should not be poisoned")
+            .clone();
+        (self.provider_id, self.provider_name.clone(), health)
+    }
+}
+
+/// 归档时发送至webhook的会话记录
+#[derive(Serialize)]
+struct Transcript {
+    guest: String,
+    assistant_id: u64,
+    archived_at: String,
+    messages: Vec<TranscriptMessage>,
+}
+
+#[derive(Serialize)]
+struct TranscriptMessage {
+    role: String,
+    content: String,
+    created_at: String,
+}
+
+impl Transcript {
+    fn new(guest: &core::Guest, assistant_id: u64, messages: &[DbMessage]) -> Self {
+        const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+        Self {
+            guest: guest.name.clone(),
+            assistant_id,
+            archived_at: Utc::now().format(TIME_FORMAT).to_string(),
+            messages: messages
+                .iter()
+                .map(|m| {
+                    let message = Message::from(m);
+                    TranscriptMessage {
+                        role: message.role,
+                        content: message.content,
+                        created_at: m.created_at.format(TIME_FORMAT).to_string(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+// 将归档的会话记录发送至webhook。失败时仅记录日志。
+async fn post_transcript(url: String, transcript: Transcript) {
+    let result = reqwest::Client::new()
+        .post(&url)
+        .timeout(ARCHIVE_WEBHOOK_TIMEOUT)
+        .json(&transcript)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status());
+    match result {
+        Ok(_) => tracing::debug!("已将{}的归档会话记录发送至{url}", transcript.guest),
+        Err(e) => tracing::warn!("发送{}的归档会话记录失败：{e}", transcript.guest),
+    }
+}
+
+// 助手的初始提示词。管理员曾切换过人设时沿用所选人设，该人设已从配置中移除时使用配置的提示词。
+fn restored_prompt(config: &Config, storage: &StorageAgent) -> String {
+    let name = match storage.get_persona(config.agent_id) {
+        Ok(Some(name)) => name,
+        Ok(None) => return config.prompt.clone(),
+        Err(e) => {
+            tracing::warn!("[{}] 获取已选人设失败：{e}", config.agent_id);
+            return config.prompt.clone();
+        }
+    };
+    match config.personas.get(&name) {
+        Some(prompt) => prompt.clone(),
+        None => {
             tracing::warn!(
-                "获取用户{}会话记录失败：{}。将为此用户创建新记录。",
-                guest.name,
-                e
+                "[{}] 已选人设{name}不在配置中，使用配置的提示词",
+                config.agent_id
             );
-            if let Err(e) = self.storage.create_conversation(guest, self.id) {
-                tracing::error!("新建用户{}会话记录失败。{}", guest.name, e);
-                return format!("内部错误，请稍后再试。{e}");
+            config.prompt.clone()
+        }
+    }
+}
+
+// 上下文长度不可超过供应商上限，且须大于为回复预留的长度
+fn check_max_context(tokens: u64, limit: u64, reservation: u64) -> Result<(), Error> {
+    if tokens > limit {
+        return Err(Error::InvalidArgument(format!(
+            "上下文长度不可超过供应商上限{limit}。"
+        )));
+    }
+    if tokens <= reservation {
+        return Err(Error::InvalidArgument(format!(
+            "上下文长度应大于为回复预留的{reservation}。"
+        )));
+    }
+    Ok(())
+}
+
+// 助手的初始上下文长度。沿用管理员保存的调整结果，该结果与当前配置不符时使用供应商上限。
+fn restored_max_context(config: &Config, limit: u64, storage: &StorageAgent) -> u64 {
+    let tokens = match storage.get_max_context(config.agent_id) {
+        Ok(Some(tokens)) => tokens,
+        Ok(None) => return limit,
+        Err(e) => {
+            tracing::warn!("[{}] 获取已保存的上下文长度失败：{e}", config.agent_id);
+            return limit;
+        }
+    };
+    match check_max_context(tokens, limit, config.context_tokens_reservation) {
+        Ok(()) => tokens,
+        Err(e) => {
+            tracing::warn!("[{}] 已保存的上下文长度{tokens}无效：{e}", config.agent_id);
+            limit
+        }
+    }
+}
+
+// 引用用户的问题。多行内容合并为一行，过长时截断。
+fn quote(question: &str) -> String {
+    let line = question.split_whitespace().collect::<Vec<_>>().join(" ");
+    let truncated = core::truncate_chars(&line, QUOTE_MAX_CHARS);
+    if truncated.len() == line.len() {
+        return format!("> {line}");
+    }
+    format!("> {truncated}…")
+}
+
+// 读取会话记录出错（记录不存在除外）时返回的错误。无法解密的会话不会被新会话替换。
+fn conversation_read_error(guest: &core::Guest, e: StorageError) -> Error {
+    match e {
+        StorageError::Decode(e) => {
+            tracing::error!("用户{}的会话记录无法还原：{e}", guest.name);
+            Error::StorageError(format!(
+                "会话记录无法解密，请联系管理员检查消息加密密钥。{e}"
+            ))
+        }
+        e => Error::StorageError(format!("获取会话记录失败。{e}")),
+    }
+}
+
+impl core::Chat for Assistant {
+    type Message = Message;
+
+    /// 根据用户消息，返回合适的回复
+    async fn chat(
+        &self,
+        guest: &core::Guest,
+        message: &str,
+    ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.converse(guest, message, None).await?)
+    }
+
+    async fn chat_with_context(
+        &self,
+        guest: &core::Guest,
+        history: &[Message],
+        message: &str,
+    ) -> Result<Response, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .respond(
+                guest,
+                history,
+                message,
+                &ConversationSettings::default(),
+                &mut ContextSummary::default(),
+                None,
+            )
+            .await?)
+    }
+
+    /// 查账单
+    fn audit(&self, guest: &core::Guest) -> String {
+        // 获取用户会话记录。仅在会话记录不存在时创建新记录。
+        let conversation = match self.storage.get_conversation(guest, self.id) {
+            Ok(c) => c,
+            Err(StorageError::NotFound) => {
+                tracing::info!("用户{}尚无会话记录，将为其创建新记录。", guest.name);
+                if let Err(e) = self.storage.create_conversation(guest, self.id) {
+                    tracing::error!("新建用户{}会话记录失败。{}", guest.name, e);
+                    return format!("内部错误，请稍后再试。{e}");
+                }
+                tracing::info!("已为用户{}创建会话记录。", guest.name);
+                Vec::new()
             }
-            tracing::info!("已为用户{}创建会话记录。", guest.name);
+            Err(e) => return conversation_read_error(guest, e).to_string(),
         };
-        let conversation = self
-            .storage
-            .get_conversation(guest, self.id)
-            .expect("Conversation should be ready");
 
-        format!(
-            "当前会话长度为 {}。累计消耗prompt token {}个，completion token {}个，费用{:.3}。",
-            conversation.last().unwrap().prompt_tokens
-                + conversation.last().unwrap().completion_tokens,
-            conversation.iter().fold(0, |acc, x| acc + x.prompt_tokens),
+        // 新会话尚无消息，没有可统计的内容
+        if conversation.is_empty() {
+            return "当前会话暂无消息".to_string();
+        }
+
+        // 会话长度即最近一次AI回复所用的token数
+        let length = conversation
+            .iter()
+            .rev()
+            .find(|m| m.message_type == Role::Assistant.to_id())
+            .map_or(0, |m| {
+                i64::from(m.prompt_tokens) + i64::from(m.completion_tokens)
+            });
+        let (prompt_tokens, completion_tokens, cost) =
             conversation
                 .iter()
-                .fold(0, |acc, x| acc + x.completion_tokens),
-            conversation.iter().fold(0.0, |acc, x| acc + x.cost)
-        )
+                .fold((0_i64, 0_i64, 0.0), |(p, c, f), m| {
+                    (
+                        p + i64::from(m.prompt_tokens),
+                        c + i64::from(m.completion_tokens),
+                        f + m.cost,
+                    )
+                });
+
+        // 按照当前价格拆分费用。实际费用与之不符时（如价格调整过），显示二者之比。
+        let prompt_price = self.prompt_token_price();
+        let completion_price = self.completion_token_price();
+        let prompt_cost = prompt_price * prompt_tokens as f64 / 1000.0;
+        let completion_cost = completion_price * completion_tokens as f64 / 1000.0;
+        let mut report = format!(
+            "当前会话长度为 {length}。累计消耗prompt token {prompt_tokens}个，completion token {completion_tokens}个，费用{cost:.3}。\nprompt费用：{prompt_tokens} × {prompt_price}/千token = {prompt_cost:.3}\ncompletion费用：{completion_tokens} × {completion_price}/千token = {completion_cost:.3}"
+        );
+        let list_cost = prompt_cost + completion_cost;
+        if list_cost > 0.0 {
+            report.push_str(&format!("\n计费倍率：{:.2}", cost / list_cost));
+        }
+        report
     }
 
     // 开始全新会话
@@ -246,3 +1310,941 @@ impl core::Chat for Assistant {
         Ok(self.storage.create_conversation(guest, self.id)?)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::provider::mock;
+    use tiktoken_rs::cl100k_base;
+
+    pub fn test_config() -> Config {
+        Config {
+            agent_id: 1000002,
+            name: "test-assistant".to_string(),
+            prompt: "You are a helpful assistant.".to_string(),
+            provider_id: 1,
+            context_tokens_reservation: 1024,
+            ..Default::default()
+        }
+    }
+
+    pub fn test_provider_config() -> ProviderCfg {
+        ProviderCfg::for_test("http://127.0.0.1:9/chat")
+    }
+
+    pub fn test_storage() -> Arc<StorageAgent> {
+        Arc::new(
+            StorageAgent::new(":memory:", "administrator")
+                .expect("Database agent should be initialized"),
+        )
+    }
+
+    #[cfg(feature = "mock-provider")]
+    #[tokio::test]
+    async fn test_mock_provider_feature() {
+        let storage = test_storage();
+        let guest = core::Guest::regular("offline-dev", 1.0);
+        storage.create_user(&guest).unwrap();
+        // 配置中的供应商被替换为模拟供应商
+        let assistant = Assistant::new(&test_config(), &test_provider_config(), storage);
+        let response = assistant.chat(&guest, "Hello").await.unwrap();
+        assert_eq!(core::ChatResponse::content(&response), mock::CANNED_REPLY);
+        assert_eq!(core::ChatResponse::cost(&response), 0.0);
+    }
+
+    #[test]
+    fn test_reply_disclaimer() {
+        let plain = Assistant::new(&test_config(), &test_provider_config(), test_storage());
+        assert_eq!(plain.decorate_reply("问题", "答案"), "答案");
+
+        let config = Config {
+            reply_disclaimer: Some("本回复由AI生成，仅供参考".to_string()),
+            ..test_config()
+        };
+        let assistant = Assistant::new(&config, &test_provider_config(), test_storage());
+        assert_eq!(
+            assistant.decorate_reply("问题", "答案"),
+            "答案\n\n本回复由AI生成，仅供参考"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quote_question() {
+        let storage = test_storage();
+        let guest = core::Guest::regular("busy-chat", 1.0);
+        storage.create_user(&guest).unwrap();
+        let provider = mock::Agent::new("答案", 10, 5);
+        let received = provider.received();
+        let config = Config {
+            quote_question: true,
+            ..test_config()
+        };
+        let assistant = Assistant::with_provider(
+            &config,
+            &test_provider_config(),
+            Box::new(provider),
+            storage.clone(),
+        );
+
+        let question = "这是一个很长的问题，\n需要在引用时截断，以免引用内容喧宾夺主，影响阅读";
+        let response = assistant.chat(&guest, question).await.unwrap();
+        let reply = assistant.decorate_reply(question, core::ChatResponse::content(&response));
+        assert_eq!(
+            reply,
+            "> 这是一个很长的问题， 需要在引用时截断，以免引用内容喧宾夺主…\n\n答案"
+        );
+        assert_eq!(assistant.decorate_reply("你好", "答案"), "> 你好\n\n答案");
+
+        // 引用内容不计入下一次请求的上下文
+        assistant.chat(&guest, "继续").await.unwrap();
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        assert!(sent.messages.iter().all(|m| !m.content.starts_with('>')));
+        assert!(sent.messages.iter().any(|m| m.content == "答案"));
+    }
+
+    #[test]
+    fn test_audit_matches_provider_usage() {
+        let storage = test_storage();
+        let assistant = Assistant::new(&test_config(), &test_provider_config(), storage.clone());
+        let guest = core::Guest::regular("audit-user", 1.0);
+        storage.create_user(&guest).unwrap();
+        storage.create_conversation(&guest, assistant.id).unwrap();
+
+        // 模拟两轮问答，数值取自供应商返回的usage
+        let usages = [(58_u64, 68_u64, 0.1), (150, 40, 0.2)];
+        for (i, (prompt, completion, cost)) in usages.iter().enumerate() {
+            let user_msg = Message {
+                role: Role::User.to_string(),
+                content: format!("问题{i}"),
+            };
+            let ai_reply = Message {
+                role: Role::Assistant.to_string(),
+                content: format!("回答{i}"),
+            };
+            let response = Response {
+                content: ai_reply.content.clone(),
+                cost: *cost,
+                prompt_tokens: *prompt,
+                completion_tokens: *completion,
+                request_id: None,
+                notice: None,
+            };
+            assistant
+                .record_exchange(&guest, &user_msg, &ai_reply, &response)
+                .unwrap();
+        }
+
+        assert!(assistant.audit(&guest).starts_with(
+            "当前会话长度为 190。累计消耗prompt token 208个，completion token 108个，费用0.300。"
+        ));
+    }
+
+    #[test]
+    fn test_auto_new_conversation() {
+        let storage = test_storage();
+        let config = Config {
+            auto_new_conversation_after: Some(3600),
+            ..test_config()
+        };
+        let assistant = Assistant::new(&config, &test_provider_config(), storage.clone());
+        let guest = core::Guest::regular("idle-user", 1.0);
+        storage.create_user(&guest).unwrap();
+        let msg = Message {
+            role: Role::User.to_string(),
+            content: "你好".to_string(),
+        };
+        let now = Utc::now().naive_utc();
+        assert!(assistant
+            .active_conversation(&guest, now)
+            .unwrap()
+            .is_empty());
+        storage
+            .append_message(&guest, assistant.id, &msg, 0.0, 0, 0)
+            .unwrap();
+
+        // 闲置时限内，继续当前会话
+        let later = now + Duration::minutes(30);
+        assert_eq!(
+            assistant.active_conversation(&guest, later).unwrap().len(),
+            1
+        );
+
+        // 超出闲置时限，但会话已置顶，继续当前会话
+        let much_later = now + Duration::hours(2);
+        assistant.set_pinned(&guest, true).unwrap();
+        assert_eq!(
+            assistant
+                .active_conversation(&guest, much_later)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // 超出闲置时限，开启新会话
+        assistant.set_pinned(&guest, false).unwrap();
+        assert!(assistant
+            .active_conversation(&guest, much_later)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_undecodable_conversation_kept() {
+        use base64::Engine;
+        let dir =
+            std::env::temp_dir().join(format!("wecom-gpt-undecodable-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_url = dir.join("db.sqlite").to_str().unwrap().to_string();
+
+        // 以密钥加密保存一条消息
+        let encrypted = crate::storage::Config {
+            content_encryption_key: Some(
+                base64::engine::general_purpose::STANDARD.encode([7u8; 32]),
+            ),
+            ..Default::default()
+        };
+        let storage = StorageAgent::with_config(&db_url, "administrator", &encrypted).unwrap();
+        let guest = core::Guest::regular("robin", 1.0);
+        storage.create_user(&guest).unwrap();
+        storage.create_conversation(&guest, 1000002).unwrap();
+        let msg = Message {
+            role: Role::User.to_string(),
+            content: "你好".to_string(),
+        };
+        storage
+            .append_message(&guest, 1000002, &msg, 0.0, 0, 0)
+            .unwrap();
+        let conversation_id = storage.active_conversation_id("robin", 1000002).unwrap();
+        drop(storage);
+
+        // 未配置密钥时无法解密，报告错误且不开启新会话
+        let storage = Arc::new(StorageAgent::new(&db_url, "administrator").unwrap());
+        let assistant = Assistant::new(&test_config(), &test_provider_config(), storage.clone());
+        let err = assistant
+            .active_conversation(&guest, Utc::now().naive_utc())
+            .unwrap_err();
+        assert!(err.to_string().contains("会话记录无法解密"));
+        assert!(assistant.audit(&guest).contains("会话记录无法解密"));
+        assert_eq!(
+            storage.active_conversation_id("robin", 1000002).unwrap(),
+            conversation_id
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_archive_webhook() {
+        use axum::routing::post;
+        use axum::{Json, Router};
+
+        // 记录webhook收到的内容
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<serde_json::Value>();
+        let app = Router::new().route(
+            "/archive",
+            post(move |Json(body): Json<serde_json::Value>| {
+                let tx = tx.clone();
+                async move {
+                    tx.send(body).unwrap();
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = Config {
+            archive_webhook: Some(format!("http://{addr}/archive")),
+            ..test_config()
+        };
+        let storage = test_storage();
+        let guest = core::Guest::regular("archivist", 1.0);
+        storage.create_user(&guest).unwrap();
+        let assistant = Assistant::with_provider(
+            &config,
+            &test_provider_config(),
+            Box::new(mock::Agent::new("mock reply", 10, 5)),
+            storage,
+        );
+        assistant.chat(&guest, "Hello").await.unwrap();
+        assistant.archive(&guest).unwrap();
+
+        let body = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv())
+            .await
+            .expect("Webhook should be called")
+            .unwrap();
+        assert_eq!(body["guest"], "archivist");
+        assert_eq!(body["assistant_id"], 1000002);
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["role"], "user");
+        assert_eq!(messages[0]["content"], "Hello");
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["content"], "mock reply");
+
+        // 归档后开启新会话
+        assert!(assistant.last_reply(&guest).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_chat_with_context() {
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let storage = test_storage();
+        let assistant = Assistant::with_provider(
+            &test_config(),
+            &test_provider_config(),
+            Box::new(provider),
+            storage.clone(),
+        );
+        let guest = core::Guest::regular("replayer", 1.0);
+        let message = |role: Role, content: &str| Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        };
+        let history = vec![
+            message(Role::System, "You are a pirate."),
+            message(Role::User, "q1"),
+            message(Role::Assistant, "a1"),
+        ];
+
+        let response = assistant
+            .chat_with_context(&guest, &history, "q2")
+            .await
+            .expect("Mock provider should reply");
+        assert_eq!(core::ChatResponse::content(&response), "mock reply");
+        assert_eq!(
+            core::ChatResponse::cost(&response),
+            (10.0 * 0.01 + 5.0 * 0.03) / 1000.0
+        );
+
+        // 会话按原样发送，用户消息位于末尾
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        let mut expected = history.clone();
+        expected.push(message(Role::User, "q2"));
+        assert_eq!(sent.messages, expected);
+
+        // 不涉及会话记录
+        assert!(storage.get_conversation(&guest, assistant.id).is_err());
+    }
+
+    #[test]
+    fn test_audit_new_conversation() {
+        let storage = test_storage();
+        let assistant = Assistant::new(&test_config(), &test_provider_config(), storage.clone());
+        let guest = core::Guest::regular("fresh-user", 1.0);
+        storage.create_user(&guest).unwrap();
+        assistant.new_conversation(&guest).unwrap();
+        assert_eq!(assistant.audit(&guest), "当前会话暂无消息");
+    }
+
+    #[test]
+    fn test_audit_empty_conversation() {
+        let storage = test_storage();
+        let assistant = Assistant::new(&test_config(), &test_provider_config(), storage.clone());
+        let guest = core::Guest::regular("fresh-user", 1.0);
+        storage.create_user(&guest).unwrap();
+        assistant.new_conversation(&guest).unwrap();
+
+        // 尚无AI回复时统计结果为零
+        let question = Message {
+            role: Role::User.to_string(),
+            content: "问题".to_string(),
+        };
+        storage
+            .append_message(&guest, assistant.id, &question, 0.0, 0, 0)
+            .unwrap();
+        assert_eq!(
+            assistant.audit(&guest),
+            "当前会话长度为 0。累计消耗prompt token 0个，completion token 0个，费用0.000。\nprompt费用：0 × 0.01/千token = 0.000\ncompletion费用：0 × 0.03/千token = 0.000"
+        );
+
+        // 写入回复后统计真实用量
+        let reply = Message {
+            role: Role::Assistant.to_string(),
+            content: "回答".to_string(),
+        };
+        storage
+            .append_message(&guest, assistant.id, &reply, 0.05, 12, 30)
+            .unwrap();
+        assert!(assistant.audit(&guest).starts_with(
+            "当前会话长度为 42。累计消耗prompt token 12个，completion token 30个，费用0.050。"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_context_strategy() {
+        let message = |role: Role, content: &str| Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        };
+        let system = message(Role::System, "You are a helpful assistant.");
+        let mut history = vec![system.clone()];
+        for i in 0..4 {
+            history.push(message(Role::User, &format!("question {i}")));
+            history.push(message(Role::Assistant, &format!("answer {i}")));
+        }
+        let guest = core::Guest::regular("long-talker", 1.0);
+
+        // 长度限制恰好容纳系统消息与最近3条消息
+        let counter = cl100k_base().unwrap();
+        let tokens: usize = history[..1]
+            .iter()
+            .chain(&history[history.len() - 3..])
+            .map(|m| counter.encode_with_special_tokens(&m.content).len())
+            .sum();
+        let send = |strategy: ContextStrategy| {
+            let config = Config {
+                context_strategy: strategy,
+                context_window_messages: 2,
+                context_tokens_reservation: 4096 - tokens as u64 - 1,
+                ..test_config()
+            };
+            let provider = mock::Agent::new("mock reply", 10, 5);
+            let received = provider.received();
+            let assistant = Assistant::with_provider(
+                &config,
+                &test_provider_config(),
+                Box::new(provider),
+                test_storage(),
+            );
+            let history = history.clone();
+            let guest = guest.clone();
+            async move {
+                assistant
+                    .chat_with_context(&guest, &history, "question 4")
+                    .await
+                    .expect("Mock provider should reply");
+                let sent = received.lock().unwrap();
+                sent.clone()
+            }
+        };
+        let question = message(Role::User, "question 4");
+
+        let sent = send(ContextStrategy::DropOldest).await;
+        assert_eq!(sent.len(), 1);
+        let mut expected = vec![system.clone()];
+        expected.extend_from_slice(&history[6..]);
+        expected.push(question.clone());
+        assert_eq!(sent[0].messages, expected);
+
+        let sent = send(ContextStrategy::SlidingWindow).await;
+        assert_eq!(sent.len(), 1);
+        let mut expected = vec![system.clone()];
+        expected.extend_from_slice(&history[7..]);
+        expected.push(question.clone());
+        assert_eq!(sent[0].messages, expected);
+
+        // 先概括早期消息，再携带摘要发送
+        let sent = send(ContextStrategy::SummarizeOldest).await;
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].messages[0].content, SUMMARY_PROMPT);
+        assert_eq!(sent[0].max_tokens, Some(SUMMARY_MAX_TOKENS));
+        assert!(sent[0].messages[1].content.contains("question 0"));
+        assert!(!sent[0].messages[1].content.contains("answer 2"));
+        let mut expected = vec![
+            system.clone(),
+            message(
+                Role::System,
+                "Summary of the earlier conversation: mock reply",
+            ),
+        ];
+        expected.extend_from_slice(&history[6..]);
+        expected.push(question);
+        assert_eq!(sent[1].messages, expected);
+    }
+
+    #[tokio::test]
+    async fn test_summary_incremental() {
+        let storage = test_storage();
+        let guest = core::Guest::regular("long-talker", 1.0);
+        storage.create_user(&guest).unwrap();
+
+        // 长度限制恰好容纳系统消息与最近一轮问答
+        let counter = cl100k_base().unwrap();
+        let tokens: usize = ["You are a helpful assistant.", "question 0", "mock reply"]
+            .iter()
+            .map(|m| counter.encode_with_special_tokens(m).len())
+            .sum();
+        let config = Config {
+            context_strategy: ContextStrategy::SummarizeOldest,
+            context_tokens_reservation: 4096 - tokens as u64 - 1,
+            ..test_config()
+        };
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let assistant = Assistant::with_provider(
+            &config,
+            &test_provider_config(),
+            Box::new(provider),
+            storage.clone(),
+        );
+        for i in 0..4 {
+            assistant
+                .chat(&guest, &format!("question {i}"))
+                .await
+                .unwrap();
+        }
+
+        // 第三轮概括首轮问答，第四轮只概括第二轮问答，并沿用已有摘要
+        let sent = received.lock().unwrap();
+        assert_eq!(sent.len(), 6);
+        assert_eq!(sent[2].messages[0].content, SUMMARY_PROMPT);
+        assert!(sent[2].messages[1].content.contains("question 0"));
+        assert!(!sent[2].messages[1].content.contains("question 1"));
+        assert!(sent[4].messages[0]
+            .content
+            .ends_with("Previous summary: mock reply"));
+        assert!(!sent[4].messages[1].content.contains("question 0"));
+        assert!(sent[4].messages[1].content.contains("question 1"));
+        assert_eq!(
+            storage.get_context_summary(&guest, assistant.id).unwrap(),
+            ContextSummary {
+                content: "mock reply".to_string(),
+                messages: 4,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_settings_survive_restart() {
+        let storage = test_storage();
+        let guest = core::Guest::regular("warm-user", 1.0);
+        storage.create_user(&guest).unwrap();
+        let assistant = Assistant::new(&test_config(), &test_provider_config(), storage.clone());
+        assistant.set_temperature(&guest, Some(0.3)).unwrap();
+        drop(assistant);
+
+        // 重新创建助手，模拟服务重启
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let assistant = Assistant::with_provider(
+            &test_config(),
+            &test_provider_config(),
+            Box::new(provider),
+            storage.clone(),
+        );
+        assistant.chat(&guest, "Hello").await.unwrap();
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(sent.temperature, Some(0.3));
+
+        // 恢复默认值
+        assistant.set_temperature(&guest, None).unwrap();
+        assistant.chat(&guest, "Hello again").await.unwrap();
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(sent.temperature, None);
+    }
+
+    #[tokio::test]
+    async fn test_conversation_cost_ceiling() {
+        let storage = test_storage();
+        let guest = core::Guest::regular("runaway-thread", 1.0);
+        storage.create_user(&guest).unwrap();
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        // 每轮问答费用为0.00025
+        let config = Config {
+            conversation_cost_ceiling: Some(0.0004),
+            ..test_config()
+        };
+        let assistant = Assistant::with_provider(
+            &config,
+            &test_provider_config(),
+            Box::new(provider),
+            storage.clone(),
+        );
+
+        let response = assistant.chat(&guest, "q1").await.unwrap();
+        assert_eq!(core::ChatResponse::notice(&response), None);
+        let response = assistant.chat(&guest, "q2").await.unwrap();
+        assert_eq!(core::ChatResponse::notice(&response), None);
+        assert_eq!(received.lock().unwrap().last().unwrap().messages.len(), 4);
+
+        // 累计费用超出上限，下一轮开启新会话
+        let response = assistant.chat(&guest, "q3").await.unwrap();
+        assert!(core::ChatResponse::notice(&response)
+            .unwrap()
+            .contains("已自动开启新会话"));
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(sent.messages.len(), 2);
+        assert_eq!(sent.messages[1].content, "q3");
+        assert_eq!(
+            storage
+                .get_conversation(&guest, assistant.id)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_price_override() {
+        let storage = test_storage();
+        let guest = core::Guest::regular("robin", 1.0);
+        storage.create_user(&guest).unwrap();
+        let standard = Assistant::with_provider(
+            &test_config(),
+            &test_provider_config(),
+            Box::new(mock::Agent::new("mock reply", 10, 5)),
+            storage.clone(),
+        );
+        let premium = Assistant::with_provider(
+            &Config {
+                agent_id: 1000004,
+                prompt_token_price: Some(0.02),
+                completion_token_price: Some(0.06),
+                ..test_config()
+            },
+            &test_provider_config(),
+            Box::new(mock::Agent::new("mock reply", 10, 5)),
+            storage,
+        );
+
+        // 同一供应商，按各自的价格计费
+        let response = standard.chat(&guest, "Hello").await.unwrap();
+        assert!((core::ChatResponse::cost(&response) - 0.00025).abs() < 1e-9);
+        let response = premium.chat(&guest, "Hello").await.unwrap();
+        assert!((core::ChatResponse::cost(&response) - 0.0005).abs() < 1e-9);
+        assert!(premium.estimate_cost("Hello") > standard.estimate_cost("Hello"));
+    }
+
+    #[tokio::test]
+    async fn test_max_completion_tokens() {
+        let storage = test_storage();
+        let guest = core::Guest::regular("thrifty-user", 1.0);
+        storage.create_user(&guest).unwrap();
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let config = Config {
+            max_completion_tokens: Some(256),
+            ..test_config()
+        };
+        let assistant = Assistant::with_provider(
+            &config,
+            &test_provider_config(),
+            Box::new(provider),
+            storage.clone(),
+        );
+        assistant.chat(&guest, "Hello").await.unwrap();
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(serde_json::to_value(&sent).unwrap()["max_tokens"], 256);
+    }
+
+    #[tokio::test]
+    async fn test_auto_title() {
+        let storage = test_storage();
+        let guest = core::Guest::regular("robin", 1.0);
+        storage.create_user(&guest).unwrap();
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let config = Config {
+            auto_title: true,
+            ..test_config()
+        };
+        let assistant = Assistant::with_provider(
+            &config,
+            &test_provider_config(),
+            Box::new(provider),
+            storage.clone(),
+        );
+
+        // 首条消息后生成标题，用量计入本次回复
+        let response = assistant.chat(&guest, "Hello").await.unwrap();
+        assert!((core::ChatResponse::cost(&response) - 0.0005).abs() < 1e-9);
+        let (convs, _) = storage
+            .list_conversations(&guest, assistant.id, 10, 0)
+            .unwrap();
+        assert_eq!(convs[0].title.as_deref(), Some("mock reply"));
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(sent.messages[0].content, TITLE_PROMPT);
+        assert_eq!(sent.messages[1].content, "Hello");
+        assert_eq!(sent.max_tokens, Some(TITLE_MAX_TOKENS));
+
+        // 此后的消息不再生成标题
+        assistant.chat(&guest, "Again").await.unwrap();
+        assert_eq!(received.lock().unwrap().len(), 3);
+
+        // 推理模型留出推理所需的余量
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let assistant = Assistant::with_provider(
+            &Config {
+                agent_id: 1000004,
+                ..config
+            },
+            &ProviderCfg {
+                model_family: ModelFamily::Reasoning,
+                ..test_provider_config()
+            },
+            Box::new(provider),
+            storage.clone(),
+        );
+        assistant.chat(&guest, "Hello").await.unwrap();
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(sent.messages[0].content, TITLE_PROMPT);
+        assert_eq!(sent.max_tokens, Some(REASONING_TITLE_MAX_TOKENS));
+        assert_eq!(assistant.summary_max_tokens(), REASONING_SUMMARY_MAX_TOKENS);
+    }
+
+    #[tokio::test]
+    async fn test_clear_settings() {
+        let storage = test_storage();
+        let guest = core::Guest::regular("reset-user", 1.0);
+        storage.create_user(&guest).unwrap();
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let assistant = Assistant::with_provider(
+            &test_config(),
+            &test_provider_config(),
+            Box::new(provider),
+            storage.clone(),
+        );
+        assert_eq!(
+            assistant.clear_settings(&guest).unwrap(),
+            ConversationSettings::default()
+        );
+
+        assistant.set_temperature(&guest, Some(1.5)).unwrap();
+        let cleared = assistant.clear_settings(&guest).unwrap();
+        assert_eq!(cleared.temperature, Some(1.5));
+
+        // 之后的请求使用助手默认值
+        assistant.chat(&guest, "Hello").await.unwrap();
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(sent.temperature, None);
+    }
+
+    #[test]
+    fn test_audit_cost_breakdown() {
+        let storage = test_storage();
+        let assistant = Assistant::new(&test_config(), &test_provider_config(), storage.clone());
+        let guest = core::Guest::regular("billing-user", 1.0);
+        storage.create_user(&guest).unwrap();
+        storage.create_conversation(&guest, assistant.id).unwrap();
+
+        // 按照配置的价格计费：prompt 0.01，completion 0.03
+        let cost = (0.01 * 1000.0 + 0.03 * 500.0) / 1000.0;
+        let reply = Message {
+            role: Role::Assistant.to_string(),
+            content: "回答".to_string(),
+        };
+        storage
+            .append_message(&guest, assistant.id, &reply, cost, 1000, 500)
+            .unwrap();
+
+        let report = assistant.audit(&guest);
+        assert!(report.contains("prompt费用：1000 × 0.01/千token = 0.010"));
+        assert!(report.contains("completion费用：500 × 0.03/千token = 0.015"));
+        assert!(report.ends_with("计费倍率：1.00"));
+    }
+
+    #[tokio::test]
+    async fn test_daily_spend_cap() {
+        let storage = test_storage();
+        let config = Config {
+            assistant_daily_cap: Some(1.0),
+            ..test_config()
+        };
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let assistant = Assistant::with_provider(
+            &config,
+            &test_provider_config(),
+            Box::new(provider),
+            storage.clone(),
+        );
+        let reply = Message {
+            role: Role::Assistant.to_string(),
+            content: "回答".to_string(),
+        };
+        let mut guests = Vec::new();
+        for name in ["spender-a", "spender-b"] {
+            let guest = core::Guest::regular(name, 10.0);
+            storage.create_user(&guest).unwrap();
+            storage.create_conversation(&guest, assistant.id).unwrap();
+            guests.push(guest);
+        }
+
+        // 接近上限时仍可请求
+        storage
+            .append_message(&guests[0], assistant.id, &reply, 0.5, 0, 0)
+            .unwrap();
+        storage
+            .append_message(&guests[1], assistant.id, &reply, 0.49, 0, 0)
+            .unwrap();
+        assert!(assistant.chat(&guests[1], "Hello").await.is_ok());
+
+        // 全部用户的费用累计达到上限后，请求被拦截
+        storage
+            .append_message(&guests[0], assistant.id, &reply, 0.02, 0, 0)
+            .unwrap();
+        let Err(err) = assistant.chat(&guests[1], "Hello").await else {
+            panic!("Request over the cap should be blocked");
+        };
+        assert_eq!(err.to_string(), "该助手今日额度已用尽");
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_inherit_state() {
+        let build = |requests_per_minute: Option<u32>, max_concurrency: Option<usize>| {
+            let config = Config {
+                requests_per_minute,
+                max_concurrency,
+                ..test_config()
+            };
+            Assistant::with_provider(
+                &config,
+                &test_provider_config(),
+                Box::new(mock::Agent::new("mock reply", 10, 5)),
+                test_storage(),
+            )
+        };
+        let previous = build(Some(10), Some(2));
+        previous.health.lock().unwrap().record_failure("timeout");
+
+        // 配置未变时共用原有状态
+        let mut unchanged = build(Some(10), Some(2));
+        unchanged.inherit_state(&previous);
+        assert_eq!(unchanged.provider_health().2.failure_count, 1);
+        assert!(Arc::ptr_eq(
+            unchanged.rate_limiter.as_ref().unwrap(),
+            previous.rate_limiter.as_ref().unwrap()
+        ));
+        assert!(Arc::ptr_eq(
+            unchanged.concurrency.as_ref().unwrap(),
+            previous.concurrency.as_ref().unwrap()
+        ));
+
+        // 限流与并发配置改变时使用新的状态，健康状态仍随供应商沿用
+        let mut changed = build(Some(20), Some(3));
+        changed.inherit_state(&previous);
+        assert_eq!(changed.provider_health().2.failure_count, 1);
+        assert!(!Arc::ptr_eq(
+            changed.rate_limiter.as_ref().unwrap(),
+            previous.rate_limiter.as_ref().unwrap()
+        ));
+        assert_eq!(changed.concurrency.as_ref().unwrap().available_permits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrency() {
+        let delay = std::time::Duration::from_millis(200);
+        let build = |agent_id: u64, max_concurrency: Option<usize>| {
+            let config = Config {
+                agent_id,
+                max_concurrency,
+                ..test_config()
+            };
+            let provider = mock::Agent::new("mock reply", 10, 5).with_delay(delay);
+            Assistant::with_provider(
+                &config,
+                &test_provider_config(),
+                Box::new(provider),
+                test_storage(),
+            )
+        };
+        let slow = build(1000002, Some(1));
+        let other = build(1000004, Some(1));
+        let guest = core::Guest::regular("busy-user", 1.0);
+        let history = vec![Message {
+            role: Role::System.to_string(),
+            content: "You are a helpful assistant.".to_string(),
+        }];
+
+        // 返回请求完成时距开始的时长
+        async fn timed(
+            assistant: &Assistant,
+            guest: &core::Guest,
+            history: &[Message],
+            started: std::time::Instant,
+        ) -> std::time::Duration {
+            assistant
+                .chat_with_context(guest, history, "Hello")
+                .await
+                .expect("Mock provider should reply");
+            started.elapsed()
+        }
+        let started = std::time::Instant::now();
+        let (first, second, third) = tokio::join!(
+            timed(&slow, &guest, &history, started),
+            timed(&slow, &guest, &history, started),
+            timed(&other, &guest, &history, started)
+        );
+
+        // 同一助手的请求依次进行，另一助手的请求不受影响
+        assert!(first.max(second) >= delay * 2);
+        assert!(third < delay * 2);
+    }
+
+    #[tokio::test]
+    async fn test_request_id_in_meta() {
+        let storage = test_storage();
+        let guest = core::Guest::regular("support-user", 1.0);
+        storage.create_user(&guest).unwrap();
+        let provider = mock::Agent::new("mock reply", 10, 5).with_request_id("req-0042");
+        let assistant = Assistant::with_provider(
+            &test_config(),
+            &test_provider_config(),
+            Box::new(provider),
+            storage.clone(),
+        );
+        assistant.chat(&guest, "Hello").await.unwrap();
+
+        let conversation = storage.get_conversation(&guest, assistant.id).unwrap();
+        let meta = |m: &DbMessage| -> Option<MessageMeta> {
+            serde_json::from_str(m.meta.as_deref()?).ok()
+        };
+        assert_eq!(meta(&conversation[0]), None);
+        assert_eq!(
+            meta(&conversation[1]).and_then(|m| m.request_id).as_deref(),
+            Some("req-0042")
+        );
+        let (_, _, health) = assistant.provider_health();
+        assert_eq!(health.last_request_id.as_deref(), Some("req-0042"));
+    }
+
+    #[test]
+    fn test_prompt_precedence() {
+        let storage = test_storage();
+        let guest = core::Guest::regular("prompt-user", 1.0);
+        storage.create_user(&guest).unwrap();
+        let configured = Assistant::new(
+            &Config {
+                prompt: "You are a poet.".to_string(),
+                ..test_config()
+            },
+            &test_provider_config(),
+            storage.clone(),
+        );
+        let unconfigured = Assistant::new(
+            &Config {
+                prompt: String::new(),
+                ..test_config()
+            },
+            &test_provider_config(),
+            storage.clone(),
+        );
+
+        // 均未设置时使用内置提示词
+        assert_eq!(unconfigured.system_prompt(&guest), DEFAULT_PROMPT);
+
+        // 助手未配置提示词时使用全局默认提示词
+        storage
+            .set_default_prompt(Some("You are a lawyer."))
+            .unwrap();
+        assert_eq!(unconfigured.system_prompt(&guest), "You are a lawyer.");
+        assert_eq!(configured.system_prompt(&guest), "You are a poet.");
+
+        // 用户的语言偏好追加在提示词之后
+        storage.set_language(&guest, Some("English")).unwrap();
+        assert_eq!(
+            unconfigured.system_prompt(&guest),
+            "You are a lawyer.\nAlways reply in English."
+        );
+
+        storage.set_default_prompt(None).unwrap();
+        storage.set_language(&guest, None).unwrap();
+        assert_eq!(unconfigured.system_prompt(&guest), DEFAULT_PROMPT);
+    }
+}
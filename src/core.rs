@@ -4,6 +4,7 @@ use std::error::Error;
 
 /// 消息内容的类型
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
 pub enum ContentType {
     Text,
     Image,
@@ -22,6 +23,76 @@ impl ContentType {
             Self::File => 5,
         }
     }
+
+    /// 按照企业微信消息的MsgType解析内容类型。不支持的类型返回None。
+    pub fn from_msg_type(msg_type: &str) -> Option<Self> {
+        match msg_type {
+            "text" => Some(Self::Text),
+            "image" => Some(Self::Image),
+            "voice" => Some(Self::Audio),
+            "video" => Some(Self::Video),
+            "file" => Some(Self::File),
+            _ => None,
+        }
+    }
+}
+
+/// 管理员指令涉及的权限
+/// 完整管理员(Guest::admin=true)拥有全部权限。
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum Permission {
+    Recharge,
+    ManageAdmins,
+    Broadcast,
+}
+
+impl Permission {
+    /// 权限在权限位中对应的比特
+    pub fn bit(&self) -> i32 {
+        match self {
+            Self::Recharge => 1,
+            Self::ManageAdmins => 1 << 1,
+            Self::Broadcast => 1 << 2,
+        }
+    }
+
+    /// 按照指令中使用的名称解析权限
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "充值" => Some(Self::Recharge),
+            "管理" => Some(Self::ManageAdmins),
+            "广播" => Some(Self::Broadcast),
+            _ => None,
+        }
+    }
+}
+
+/// 展示余额时保留的小数位数
+pub const CREDIT_PRECISION: usize = 3;
+
+/// 按统一精度格式化金额
+pub fn format_credit(credit: f64) -> String {
+    format!("{:.*}", CREDIT_PRECISION, credit)
+}
+
+/// 截取字符串的前max个字符
+pub fn truncate_chars(s: &str, max: usize) -> &str {
+    match s.char_indices().nth(max) {
+        Some((end, _)) => &s[..end],
+        None => s,
+    }
+}
+
+/// 截取字符串，长度不超过max字节。截断位置落在多字节字符中间时前移至该字符之前。
+pub fn truncate_bytes_safe(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
 }
 
 /// 一名用户
@@ -31,6 +102,37 @@ pub struct Guest {
     pub name: String,
     pub credit: f64,
     pub admin: bool,
+    pub permissions: i32,
+}
+
+impl Guest {
+    /// 用户是否拥有指定权限
+    pub fn can(&self, permission: Permission) -> bool {
+        self.admin || self.permissions & permission.bit() != 0
+    }
+
+    /// 用户是否可以使用管理员指令
+    pub fn is_operator(&self) -> bool {
+        self.admin || self.permissions != 0
+    }
+
+    /// 按统一精度格式化的余额，用于所有展示余额的场合
+    pub fn formatted_credit(&self) -> String {
+        format_credit(self.credit)
+    }
+}
+
+#[cfg(test)]
+impl Guest {
+    /// 测试用的普通用户
+    pub fn regular(name: &str, credit: f64) -> Self {
+        Self {
+            name: name.to_owned(),
+            credit,
+            admin: false,
+            permissions: 0,
+        }
+    }
 }
 
 /// 一条响应消息应当具备的行为
@@ -39,10 +141,25 @@ pub trait ChatResponse {
     fn content(&self) -> &str;
     // 本次响应消息的成本
     fn cost(&self) -> f64;
+    // 需要告知用户的附加提示，如自动开启了新会话
+    fn notice(&self) -> Option<&str> {
+        None
+    }
+    // 本次响应计费的prompt token数
+    fn prompt_tokens(&self) -> u64 {
+        0
+    }
+    // 本次响应计费的completion token数
+    fn completion_tokens(&self) -> u64 {
+        0
+    }
 }
 
 /// 提供聊天功能的对象应当具备的行为
 pub trait Chat {
+    // 会话中单条历史消息的类型
+    type Message;
+
     // 根据用户与消息内容做出消息反馈
     async fn chat(
         &self,
@@ -50,9 +167,74 @@ pub trait Chat {
         message: &str,
     ) -> Result<impl ChatResponse, Box<dyn Error + Send + Sync>>;
 
+    // 以调用者提供的历史消息为上下文做出消息反馈。不读写会话记录。
+    async fn chat_with_context(
+        &self,
+        guest: &Guest,
+        history: &[Self::Message],
+        message: &str,
+    ) -> Result<impl ChatResponse, Box<dyn Error + Send + Sync>>;
+
     // 返回用户当前会话的资源消耗
     fn audit(&self, guest: &Guest) -> String;
 
     // 开启新会话
     fn new_conversation(&self, guest: &Guest) -> Result<(), Box<dyn Error + Send + Sync>>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{format_credit, truncate_bytes_safe, truncate_chars, Guest, Permission};
+
+    #[test]
+    fn test_guest_permissions() {
+        let admin = Guest {
+            name: "admin".to_string(),
+            credit: 0.0,
+            admin: true,
+            permissions: 0,
+        };
+        assert!(admin.can(Permission::Recharge));
+        assert!(admin.can(Permission::ManageAdmins));
+        assert!(admin.can(Permission::Broadcast));
+
+        let operator = Guest {
+            name: "finance".to_string(),
+            credit: 0.0,
+            admin: false,
+            permissions: Permission::Recharge.bit(),
+        };
+        assert!(operator.is_operator());
+        assert!(operator.can(Permission::Recharge));
+        assert!(!operator.can(Permission::ManageAdmins));
+
+        let guest = Guest::regular("robin", 0.0);
+        assert!(!guest.is_operator());
+    }
+
+    #[test]
+    fn test_formatted_credit() {
+        let guest = Guest::regular("robin", 0.1 + 0.2);
+        assert_eq!(guest.formatted_credit(), "0.300");
+        assert_eq!(format_credit(-1.0), "-1.000");
+    }
+
+    #[test]
+    fn test_truncate_chinese() {
+        let text = "企业微信助手";
+        assert_eq!(truncate_chars(text, 0), "");
+        assert_eq!(truncate_chars(text, 2), "企业");
+        assert_eq!(truncate_chars(text, 6), text);
+        assert_eq!(truncate_chars(text, 100), text);
+
+        // 每个汉字占3字节，落在字符中间的截断位置前移
+        assert_eq!(truncate_bytes_safe(text, 0), "");
+        assert_eq!(truncate_bytes_safe(text, 1), "");
+        assert_eq!(truncate_bytes_safe(text, 3), "企");
+        assert_eq!(truncate_bytes_safe(text, 4), "企");
+        assert_eq!(truncate_bytes_safe(text, 5), "企");
+        assert_eq!(truncate_bytes_safe(text, 17), "企业微信助");
+        assert_eq!(truncate_bytes_safe(text, 18), text);
+        assert_eq!(truncate_bytes_safe("a企b", 3), "a");
+    }
+}
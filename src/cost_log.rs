@@ -0,0 +1,121 @@
+//! 计费记录。将每轮计费追加写入CSV文件，便于在数据库之外对账。
+use chrono::NaiveDateTime;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::sync::Mutex;
+
+/// CSV文件的表头
+const HEADER: &str = "timestamp,guest,agent_id,prompt_tokens,completion_tokens,cost";
+
+/// 一轮计费
+pub struct CostRecord<'a> {
+    pub timestamp: NaiveDateTime,
+    pub guest: &'a str,
+    pub agent_id: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+/// 只追加的CSV计费记录。写入经过缓冲，需调用flush或在析构时写入文件。
+pub struct CostLog {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl CostLog {
+    /// 以追加方式打开文件。新文件先写入表头。
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut writer = BufWriter::new(file);
+        if empty {
+            writeln!(writer, "{HEADER}")?;
+        }
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// 追加一轮计费。写入失败仅记录日志，不影响用户请求。
+    pub fn record(&self, record: &CostRecord) {
+        let mut writer = self
+            .writer
+            .lock()
+            .expect("Cost log lock should not be poisoned");
+        let written = writeln!(
+            writer,
+            "{},{},{},{},{},{:.6}",
+            record.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            escape(record.guest),
+            record.agent_id,
+            record.prompt_tokens,
+            record.completion_tokens,
+            record.cost
+        );
+        if let Err(e) = written {
+            tracing::error!("写入计费记录失败。{}, {e}", record.guest);
+        }
+    }
+
+    /// 将缓冲的记录写入文件
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer
+            .lock()
+            .expect("Cost log lock should not be poisoned")
+            .flush()
+    }
+}
+
+// 含有逗号、引号或换行的字段以引号包裹，内部引号重复一次
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, CostLog, CostRecord, HEADER};
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("robin"), "robin");
+        assert_eq!(escape("a,b"), "\"a,b\"");
+        assert_eq!(escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_append_only() {
+        let dir = std::env::temp_dir().join(format!("wecom-gpt-cost-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cost.csv");
+        let path = path.to_str().unwrap();
+        let timestamp = NaiveDate::from_ymd_opt(2024, 4, 1)
+            .unwrap()
+            .and_hms_opt(8, 30, 0)
+            .unwrap();
+        let record = CostRecord {
+            timestamp,
+            guest: "robin",
+            agent_id: 1000002,
+            prompt_tokens: 10,
+            completion_tokens: 5,
+            cost: 0.00025,
+        };
+
+        // 重新打开时追加记录，不重复写入表头
+        for _ in 0..2 {
+            let log = CostLog::open(path).unwrap();
+            log.record(&record);
+            log.flush().unwrap();
+        }
+        let content = std::fs::read_to_string(path).unwrap();
+        let row = "2024-04-01 08:30:00,robin,1000002,10,5,0.000250";
+        assert_eq!(content, format!("{HEADER}\n{row}\n{row}\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
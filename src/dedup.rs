@@ -0,0 +1,113 @@
+//! 消息去重。企业微信未及时收到响应时，会重复推送同一条消息。
+use crate::storage::Agent as StorageAgent;
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// 记录有效期内处理过的消息ID。可选择保存在数据库中，以便重启后依然有效。
+pub struct Dedup {
+    seen: Mutex<HashMap<String, Instant>>,
+    ttl: Duration,
+    storage: Option<Arc<StorageAgent>>,
+    last_prune: Mutex<Instant>, // 上次清理数据库记录的时间
+}
+
+impl Dedup {
+    /// 仅在内存中记录
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            seen: Mutex::new(HashMap::new()),
+            ttl,
+            storage: None,
+            last_prune: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// 在数据库中记录
+    pub fn with_storage(ttl: Duration, storage: Arc<StorageAgent>) -> Self {
+        Self {
+            storage: Some(storage),
+            ..Self::new(ttl)
+        }
+    }
+
+    /// 记录消息ID。有效期内已处理过该消息时返回false。
+    pub fn first_seen(&self, msg_id: &str) -> bool {
+        let Some(storage) = &self.storage else {
+            return self.first_seen_in_memory(msg_id);
+        };
+        self.prune(storage);
+        match storage.record_processed_message(msg_id, Utc::now().naive_utc()) {
+            Ok(first) => first,
+            Err(e) => {
+                tracing::warn!("记录消息ID失败，改用内存去重。{e}");
+                self.first_seen_in_memory(msg_id)
+            }
+        }
+    }
+
+    fn first_seen_in_memory(&self, msg_id: &str) -> bool {
+        let mut seen = self.seen.lock().expect("Dedup lock should not be poisoned");
+        seen.retain(|_, at| at.elapsed() <= self.ttl);
+        seen.insert(msg_id.to_owned(), Instant::now()).is_none()
+    }
+
+    // 每个有效期清理一次过期的数据库记录
+    fn prune(&self, storage: &StorageAgent) {
+        let mut last_prune = self
+            .last_prune
+            .lock()
+            .expect("Dedup lock should not be poisoned");
+        if last_prune.elapsed() < self.ttl {
+            return;
+        }
+        *last_prune = Instant::now();
+        let Ok(ttl) = chrono::Duration::from_std(self.ttl) else {
+            return;
+        };
+        match storage.prune_processed_messages(Utc::now().naive_utc() - ttl) {
+            Ok(n) => tracing::debug!("Pruned {n} processed message ids"),
+            Err(e) => tracing::warn!("清理已处理消息记录失败。{e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dedup;
+    use crate::storage::Agent as StorageAgent;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_memory_dedup() {
+        let dedup = Dedup::new(Duration::from_secs(60));
+        assert!(dedup.first_seen("7336741709953816625"));
+        assert!(!dedup.first_seen("7336741709953816625"));
+        assert!(dedup.first_seen("7336741709953816626"));
+
+        // 过期后不再视为重复
+        let dedup = Dedup::new(Duration::ZERO);
+        assert!(dedup.first_seen("7336741709953816625"));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(dedup.first_seen("7336741709953816625"));
+    }
+
+    #[test]
+    fn test_dedup_survives_restart() {
+        let storage = Arc::new(
+            StorageAgent::new(":memory:", "administrator")
+                .expect("Database agent should be initialized"),
+        );
+        let dedup = Dedup::with_storage(Duration::from_secs(600), storage.clone());
+        assert!(dedup.first_seen("7336741709953816625"));
+        assert!(!dedup.first_seen("7336741709953816625"));
+        drop(dedup);
+
+        // 重新创建，模拟服务重启
+        let dedup = Dedup::with_storage(Duration::from_secs(600), storage);
+        assert!(!dedup.first_seen("7336741709953816625"));
+        assert!(dedup.first_seen("7336741709953816626"));
+    }
+}
@@ -1,6 +1,9 @@
 mod accountant;
 mod assistant;
 mod core;
+mod cost_log;
+mod dedup;
+mod pending;
 mod provider;
 mod reception;
 mod storage;
@@ -9,16 +12,25 @@ mod wecom_api;
 use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::routing::get;
-use axum::Router;
+use axum::{Json, Router};
 
+use serde::Serialize;
 use std::sync::Arc;
 use tower_http::trace::TraceLayer;
 
 // 统筹全部逻辑的应用Agent
-use reception::Agent;
-pub use reception::Config;
+use reception::{Agent, ProviderHealth};
+pub use reception::{Config, ConfigBuilder};
 use wecom_api::{CallbackParams, UrlVerifyParams};
 
+// 以编程方式构建Config时所需的配置类型
+pub use accountant::Config as AccountantConfig;
+pub use assistant::{
+    Config as AssistantConfig, ContextStrategy, MenuAction, ProviderCfg as ProviderConfig,
+};
+pub use provider::{Kind as ProviderKind, ModelFamily, ReasoningEffort};
+pub use storage::Config as StorageConfig;
+
 // Shared state used in all routers
 type SharedState = Arc<AppState>;
 
@@ -26,17 +38,52 @@ struct AppState {
     app_agent: Agent,
 }
 
+/// 运行中的应用句柄，用于在不重启服务的情况下重载配置
+#[derive(Clone)]
+pub struct AppHandle(SharedState);
+
+impl AppHandle {
+    /// 重载各应用的助手、加解密与消息模块。新配置有误时保留原配置。
+    pub fn reload_config(&self, config: &Config) -> Result<(), reception::Error> {
+        self.0.app_agent.reload_config(config)
+    }
+
+    /// 将缓冲的计费记录写入文件。应在服务退出前调用。
+    pub fn flush(&self) {
+        self.0.app_agent.flush();
+    }
+}
+
+/// 初始化应用。配置了启动检查时将panic，应改用init_app。
 pub fn app(config: &Config) -> Router {
+    app_with_handle(config).0
+}
+
+/// 初始化应用，同时返回可重载配置的应用句柄。配置了启动检查时将panic，应改用init_app。
+pub fn app_with_handle(config: &Config) -> (Router, AppHandle) {
     // 初始化APP agent。
     let cfg: Config = config.clone();
     let app_agent = match Agent::new(&cfg) {
         Err(e) => panic!("初始化应用错误：{e}"),
         Ok(agent) => agent,
     };
+    let state = Arc::new(AppState { app_agent });
+    (router(state.clone()), AppHandle(state))
+}
 
-    // Init a router with this shared state.
+/// 异步初始化应用，同时返回应用句柄。
+/// 配置了validate_providers_on_start或validate_wecom_on_start时，先检查各供应商与企业微信应用是否可用。
+pub async fn init_app(config: &Config) -> (Router, AppHandle) {
+    let app_agent = match Agent::init(config).await {
+        Err(e) => panic!("初始化应用错误：{e}"),
+        Ok(agent) => agent,
+    };
     let state = Arc::new(AppState { app_agent });
+    (router(state.clone()), AppHandle(state))
+}
 
+// Init a router with this shared state.
+fn router(state: SharedState) -> Router {
     Router::new()
         .route(
             "/agent/:agent_id",
@@ -46,6 +93,8 @@ pub fn app(config: &Config) -> Router {
             "/contact/:agent_id",
             get(server_verification_handler).post(account_creation_handler),
         )
+        .route("/providers/health", get(provider_health_handler))
+        .route("/version", get(version_handler))
         .with_state(state)
         .layer(TraceLayer::new_for_http())
 }
@@ -96,3 +145,111 @@ async fn account_creation_handler(
 
     StatusCode::OK
 }
+
+// 返回各AI供应商的健康状态
+async fn provider_health_handler(State(state): State<SharedState>) -> Json<Vec<ProviderHealth>> {
+    Json(state.app_agent.provider_health())
+}
+
+/// 部署的版本与构建信息
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    git_sha: &'static str,
+    build_timestamp: u64, // 构建时的Unix时间戳（秒）
+}
+
+// 返回当前运行的版本
+async fn version_handler() -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("WECOM_GPT_GIT_SHA"),
+        build_timestamp: env!("WECOM_GPT_BUILD_TIMESTAMP")
+            .parse()
+            .unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{app, app_with_handle, init_app};
+    use crate::reception::tests::{test_config, test_config_with_assistant};
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_app_smoke() {
+        let router = app(&test_config());
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/providers/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_reload_config_keeps_router() {
+        let (router, handle) = app_with_handle(&test_config());
+        let verify = || {
+            Request::builder()
+                .uri("/agent/1000004?msg_signature=invalid&timestamp=1708218294&nonce=1372623149&echostr=echostr")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = router.clone().oneshot(verify()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // 同一Router在重载后即可处理新应用的请求。签名无效，故校验失败
+        handle
+            .reload_config(&test_config_with_assistant(1000004))
+            .unwrap();
+        let response = router.oneshot(verify()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_init_app_handle() {
+        let (router, handle) = init_app(&test_config()).await;
+        handle
+            .reload_config(&test_config_with_assistant(1000004))
+            .unwrap();
+        handle.flush();
+        let response = router
+            .oneshot(
+                Request::builder()
+                    .uri("/providers/health")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let response = app(&test_config())
+            .oneshot(
+                Request::builder()
+                    .uri("/version")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!info["git_sha"].as_str().unwrap().is_empty());
+        assert!(info["build_timestamp"].as_u64().unwrap() > 0);
+    }
+}
@@ -0,0 +1,69 @@
+//! 暂存等待用户确认的内容
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 按用户名暂存待确认的内容。超过有效期的内容视为已放弃。
+pub struct Pending<T> {
+    entries: Mutex<HashMap<String, (T, Instant)>>,
+    ttl: Duration,
+}
+
+impl<T> Pending<T> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// 内容的有效期
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// 暂存内容。该用户先前暂存的内容将被覆盖。
+    pub fn hold(&self, key: &str, value: T) {
+        self.entries
+            .lock()
+            .expect("Pending lock should not be poisoned")
+            .insert(key.to_owned(), (value, Instant::now()));
+    }
+
+    /// 取出暂存的内容。内容不存在或已过期时返回None。
+    pub fn take(&self, key: &str) -> Option<T> {
+        let (value, held_at) = self
+            .entries
+            .lock()
+            .expect("Pending lock should not be poisoned")
+            .remove(key)?;
+        if held_at.elapsed() > self.ttl {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pending;
+    use std::time::Duration;
+
+    #[test]
+    fn test_hold_then_take() {
+        let pending = Pending::new(Duration::from_secs(60));
+        pending.hold("robin", "message".to_string());
+        assert_eq!(pending.take("alice"), None);
+        assert_eq!(pending.take("robin"), Some("message".to_string()));
+        assert_eq!(pending.take("robin"), None);
+    }
+
+    #[test]
+    fn test_expired() {
+        let pending = Pending::new(Duration::from_millis(10));
+        pending.hold("robin", 1);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(pending.take("robin"), None);
+    }
+}
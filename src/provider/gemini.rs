@@ -0,0 +1,342 @@
+//! Google Gemini作为API供应商
+use super::openai::{Conversation, Role};
+use super::{
+    log_payload, request_headers, send_with_retry, BoxFuture, Completion, Config, Error, Provider,
+    Sampling,
+};
+use serde::{Deserialize, Serialize};
+
+// generateContent请求
+// {
+//     "systemInstruction": {"parts": [{"text": "You are a helpful assistant."}]},
+//     "contents": [
+//         {"role": "user", "parts": [{"text": "Hello"}]},
+//         {"role": "model", "parts": [{"text": "Hi there!"}]},
+//         {"role": "user", "parts": [{"text": "How are you?"}]}
+//     ]
+// }
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Request {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<Content>,
+    pub contents: Vec<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation_config: Option<GenerationConfig>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationConfig {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct Content {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub parts: Vec<Part>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Part {
+    #[serde(default)]
+    pub text: String,
+}
+
+impl Content {
+    fn new(role: Option<&str>, text: &str) -> Self {
+        Self {
+            role: role.map(|r| r.to_owned()),
+            parts: vec![Part {
+                text: text.to_owned(),
+            }],
+        }
+    }
+}
+
+impl From<&Conversation> for Request {
+    // 系统消息合并为systemInstruction，其余消息的角色映射为user与model
+    fn from(conversation: &Conversation) -> Self {
+        let mut system: Vec<&str> = Vec::new();
+        let mut contents: Vec<Content> = Vec::new();
+        for message in &conversation.messages {
+            match Role::try_from(message.role.as_str()) {
+                Ok(Role::System) => system.push(&message.content),
+                Ok(Role::Assistant) => contents.push(Content::new(Some("model"), &message.content)),
+                _ => contents.push(Content::new(Some("user"), &message.content)),
+            }
+        }
+        let request = Self {
+            system_instruction: (!system.is_empty())
+                .then(|| Content::new(None, &system.join("\n"))),
+            contents,
+            generation_config: None,
+        };
+        let request = match conversation.temperature {
+            Some(t) => request.with_temperature(t),
+            None => request,
+        };
+        match conversation.max_tokens {
+            Some(n) => request.with_max_tokens(n),
+            None => request,
+        }
+    }
+}
+
+impl Request {
+    /// 设置停止序列。序列为空时不做修改。
+    pub fn with_stop(mut self, stop: &[String]) -> Self {
+        if !stop.is_empty() {
+            self.generation_config
+                .get_or_insert_with(GenerationConfig::default)
+                .stop_sequences = stop.to_vec();
+        }
+        self
+    }
+
+    /// 设置采样温度
+    pub fn with_temperature(mut self, temperature: f64) -> Self {
+        self.generation_config
+            .get_or_insert_with(GenerationConfig::default)
+            .temperature = Some(temperature);
+        self
+    }
+
+    /// 设置默认采样参数。会话中已设定的温度不受影响。
+    pub fn with_sampling(mut self, sampling: &Sampling) -> Self {
+        if sampling.temperature.is_none() && sampling.top_p.is_none() {
+            return self;
+        }
+        let config = self
+            .generation_config
+            .get_or_insert_with(GenerationConfig::default);
+        config.temperature = config.temperature.or(sampling.temperature);
+        config.top_p = sampling.top_p;
+        self
+    }
+
+    /// 设置回复的最大token数
+    pub fn with_max_tokens(mut self, max_tokens: u64) -> Self {
+        self.generation_config
+            .get_or_insert_with(GenerationConfig::default)
+            .max_output_tokens = Some(max_tokens);
+        self
+    }
+}
+
+// generateContent返回结果
+// {
+//     "candidates": [
+//         {
+//             "content": {"parts": [{"text": "I'm fine, thank you."}], "role": "model"},
+//             "finishReason": "STOP",
+//             "index": 0
+//         }
+//     ],
+//     "usageMetadata": {"promptTokenCount": 14, "candidatesTokenCount": 6, "totalTokenCount": 20}
+// }
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Response {
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    #[serde(default)]
+    pub usage_metadata: UsageMetadata,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Candidate {
+    #[serde(default)]
+    pub content: Content,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageMetadata {
+    #[serde(default)]
+    pub prompt_token_count: u64,
+    #[serde(default)]
+    pub candidates_token_count: u64,
+    #[serde(default)]
+    pub total_token_count: u64,
+}
+
+impl Response {
+    /// 首个候选回复的全部文本
+    pub fn text(&self) -> String {
+        match self.candidates.first() {
+            Some(c) => c.content.parts.iter().map(|p| p.text.as_str()).collect(),
+            None => String::new(),
+        }
+    }
+}
+
+impl From<Response> for Completion {
+    fn from(response: Response) -> Self {
+        Self {
+            content: response.text(),
+            prompt_tokens: response.usage_metadata.prompt_token_count,
+            completion_tokens: response.usage_metadata.candidates_token_count,
+            total_tokens: response.usage_metadata.total_token_count,
+            request_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Agent {
+    config: Config,
+    client: reqwest::Client,
+}
+
+impl Agent {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: config.clone(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    // 根据会话内容，返回最新消息。
+    pub async fn request(&self, conversation: &Conversation) -> Result<Response, Error> {
+        tracing::debug!("Ask Gemini for response..");
+        let header = request_headers(&self.config, ("x-goog-api-key", &self.config.api_key))?;
+        let body = Request::from(conversation)
+            .with_stop(&self.config.stop)
+            .with_sampling(&self.config.sampling);
+        log_payload(&self.config, &header, &body);
+        send_with_retry(&self.config, || {
+            self.client
+                .post(&self.config.endpoint)
+                .json(&body)
+                .headers(header.clone())
+        })
+        .await?
+        .error_for_status()
+        .map_err(|e| Error::Failed(format!("AI返回错误消息。{}", e.without_url())))?
+        .json::<Response>()
+        .await
+        .map_err(|e| Error::Failed(format!("解析AI返回失败。{}", e.without_url())))
+    }
+}
+
+impl Provider for Agent {
+    fn process<'a>(
+        &'a self,
+        conversation: &'a Conversation,
+    ) -> BoxFuture<'a, Result<Completion, Error>> {
+        Box::pin(async move { Ok(self.request(conversation).await?.into()) })
+    }
+
+    fn max_tokens(&self) -> u64 {
+        self.config.max_tokens
+    }
+
+    fn prompt_token_price(&self) -> f64 {
+        self.config.prompt_token_price
+    }
+
+    fn completion_token_price(&self) -> f64 {
+        self.config.completion_token_price
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::openai::{Conversation, Message, Role};
+    use super::super::{Completion, Sampling};
+    use super::{Content, Request, Response};
+
+    #[test]
+    fn test_request_from_conversation() {
+        let message = |role: Role, content: &str| Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        };
+        let conversation = Conversation {
+            messages: vec![
+                message(Role::System, "You are a helpful assistant."),
+                message(Role::User, "Hello"),
+                message(Role::Assistant, "Hi there!"),
+                message(Role::User, "How are you?"),
+            ],
+            temperature: None,
+            max_tokens: None,
+        };
+        let request = Request::from(&conversation);
+        assert_eq!(
+            request,
+            Request {
+                system_instruction: Some(Content::new(None, "You are a helpful assistant.")),
+                contents: vec![
+                    Content::new(Some("user"), "Hello"),
+                    Content::new(Some("model"), "Hi there!"),
+                    Content::new(Some("user"), "How are you?"),
+                ],
+                generation_config: None,
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["systemInstruction"]["parts"][0]["text"],
+            "You are a helpful assistant."
+        );
+
+        let request = request.with_temperature(0.5);
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["generationConfig"]["temperature"],
+            0.5
+        );
+
+        // 会话中设定的温度优先于默认采样参数
+        let request = request.with_sampling(&Sampling {
+            temperature: Some(0.9),
+            top_p: Some(0.8),
+        });
+        let generation_config = &serde_json::to_value(&request).unwrap()["generationConfig"];
+        assert_eq!(generation_config["temperature"], 0.5);
+        assert_eq!(generation_config["topP"], 0.8);
+
+        let request = Request::from(&Conversation {
+            max_tokens: Some(256),
+            ..conversation
+        });
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["generationConfig"]["maxOutputTokens"],
+            256
+        );
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let body = r#"{
+            "candidates": [
+                {
+                    "content": {"parts": [{"text": "I'm fine, "}, {"text": "thank you."}], "role": "model"},
+                    "finishReason": "STOP",
+                    "index": 0
+                }
+            ],
+            "usageMetadata": {"promptTokenCount": 14, "candidatesTokenCount": 6, "totalTokenCount": 20}
+        }"#;
+        let response: Response = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            Completion::from(response),
+            Completion {
+                content: "I'm fine, thank you.".to_string(),
+                prompt_tokens: 14,
+                completion_tokens: 6,
+                total_tokens: 20,
+                request_id: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! AI供应商的健康状态记录
+use chrono::Utc;
+use serde::Serialize;
+
+/// 连续失败达到此次数时，视为供应商熔断，服务降级
+pub const CIRCUIT_OPEN_AFTER: u64 = 3;
+
+/// 供应商调用的累计统计
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Health {
+    pub success_count: u64,
+    pub failure_count: u64,
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+    pub last_success_at: Option<i64>,    // Unix时间戳，单位秒
+    pub last_failure_at: Option<i64>,    // Unix时间戳，单位秒
+    pub last_request_id: Option<String>, // 最近一次成功调用的请求ID
+}
+
+impl Health {
+    /// 记录一次成功调用
+    pub fn record_success(&mut self) {
+        self.success_count += 1;
+        self.consecutive_failures = 0;
+        self.last_success_at = Some(Utc::now().timestamp());
+    }
+
+    /// 记录一次失败调用及其错误信息
+    pub fn record_failure(&mut self, error: &str) {
+        self.failure_count += 1;
+        self.consecutive_failures += 1;
+        self.last_error = Some(error.to_owned());
+        self.last_failure_at = Some(Utc::now().timestamp());
+    }
+
+    /// 调用成功率。尚无调用记录时返回None。
+    pub fn success_rate(&self) -> Option<f64> {
+        let total = self.success_count + self.failure_count;
+        if total == 0 {
+            None
+        } else {
+            Some(self.success_count as f64 / total as f64)
+        }
+    }
+
+    /// 供应商是否处于熔断状态
+    pub fn circuit_open(&self) -> bool {
+        self.consecutive_failures >= CIRCUIT_OPEN_AFTER
+    }
+
+    /// 合并另一份记录。多个助手共用同一供应商时使用。
+    pub fn merge(&mut self, other: &Health) {
+        self.success_count += other.success_count;
+        self.failure_count += other.failure_count;
+        self.consecutive_failures = self.consecutive_failures.max(other.consecutive_failures);
+        if other.last_failure_at > self.last_failure_at {
+            self.last_failure_at = other.last_failure_at;
+            self.last_error = other.last_error.clone();
+        }
+        if other.last_success_at > self.last_success_at {
+            self.last_success_at = other.last_success_at;
+            self.last_request_id = other.last_request_id.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Health;
+
+    #[test]
+    fn test_failure_then_success() {
+        let mut health = Health::default();
+        assert_eq!(health.success_rate(), None);
+
+        health.record_failure("timeout");
+        assert_eq!(health.failure_count, 1);
+        assert_eq!(health.consecutive_failures, 1);
+        assert_eq!(health.last_error.as_deref(), Some("timeout"));
+        assert!(health.last_failure_at.is_some());
+        assert!(health.last_success_at.is_none());
+
+        health.record_success();
+        assert_eq!(health.success_count, 1);
+        assert_eq!(health.failure_count, 1);
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.last_error.as_deref(), Some("timeout"));
+        assert!(health.last_success_at.is_some());
+        assert_eq!(health.success_rate(), Some(0.5));
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = Health::default();
+        a.record_success();
+        let mut b = Health::default();
+        b.record_failure("bad gateway");
+
+        a.merge(&b);
+        assert_eq!(a.success_count, 1);
+        assert_eq!(a.failure_count, 1);
+        assert_eq!(a.last_error.as_deref(), Some("bad gateway"));
+    }
+}
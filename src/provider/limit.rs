@@ -0,0 +1,164 @@
+//! 供应商调用的速率限制。超出额度的请求排队等待，而非直接报错。
+use super::Error;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 滑动窗口内的请求数与token数限制
+pub struct RateLimiter {
+    max_requests: Option<u32>,
+    max_tokens: Option<u64>,
+    window: Duration,
+    timeout: Duration,
+    records: Mutex<VecDeque<(Instant, u64)>>, // 窗口内每次请求的时间与token数
+}
+
+impl RateLimiter {
+    pub fn new(
+        max_requests: Option<u32>,
+        max_tokens: Option<u64>,
+        window: Duration,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            max_requests,
+            max_tokens,
+            window,
+            timeout,
+            records: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// 每分钟请求数与token数限制。均未设置时返回None。
+    pub fn per_minute(
+        requests: Option<u32>,
+        tokens: Option<u64>,
+        timeout: Duration,
+    ) -> Option<Self> {
+        if requests.is_none() && tokens.is_none() {
+            return None;
+        }
+        Some(Self::new(
+            requests,
+            tokens,
+            Duration::from_secs(60),
+            timeout,
+        ))
+    }
+
+    /// 两个限制器的限额、窗口与等待时限是否均相同
+    pub fn same_limits(&self, other: &RateLimiter) -> bool {
+        self.max_requests == other.max_requests
+            && self.max_tokens == other.max_tokens
+            && self.window == other.window
+            && self.timeout == other.timeout
+    }
+
+    /// 申请一次请求额度。额度不足时等待，超过等待时限则返回错误。
+    pub async fn acquire(&self, tokens: u64) -> Result<(), Error> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let wait = match self.try_acquire(tokens, Instant::now()) {
+                None => return Ok(()),
+                Some(w) => w,
+            };
+            let now = Instant::now();
+            if now + wait > deadline {
+                return Err(Error::Failed(format!(
+                    "供应商调用超出速率限制，等待超过{}秒。",
+                    self.timeout.as_secs()
+                )));
+            }
+            tracing::debug!("Rate limited, waiting {}ms", wait.as_millis());
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // 额度充足时记录本次请求并返回None，否则返回需要等待的时间
+    fn try_acquire(&self, tokens: u64, now: Instant) -> Option<Duration> {
+        let mut records = self
+            .records
+            .lock()
+            .expect("Rate limiter lock should not be poisoned");
+        while records
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) >= self.window)
+        {
+            records.pop_front();
+        }
+
+        let requests_ok = match self.max_requests {
+            Some(max) => records.len() < max as usize,
+            None => true,
+        };
+        // 单次请求超过token额度时，只要窗口为空即放行，以免永远等待
+        let used: u64 = records.iter().map(|(_, t)| t).sum();
+        let tokens_ok = match self.max_tokens {
+            Some(max) => records.is_empty() || used + tokens <= max,
+            None => true,
+        };
+        if requests_ok && tokens_ok {
+            records.push_back((now, tokens));
+            return None;
+        }
+
+        // 等待最早的记录移出窗口。窗口为空却仍超限时（请求数上限为0），等待一整个窗口。
+        match records.front() {
+            Some((oldest, _)) => Some(self.window.saturating_sub(now.duration_since(*oldest))),
+            None => Some(self.window),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_excess_requests_delayed() {
+        let window = Duration::from_millis(300);
+        let limiter = RateLimiter::new(Some(2), None, window, Duration::from_secs(5));
+        let started = Instant::now();
+        limiter.acquire(10).await.unwrap();
+        limiter.acquire(10).await.unwrap();
+        assert!(started.elapsed() < window);
+
+        // 第三次请求需等待窗口滑过
+        limiter.acquire(10).await.unwrap();
+        assert!(started.elapsed() >= window);
+    }
+
+    #[tokio::test]
+    async fn test_token_budget() {
+        let window = Duration::from_millis(300);
+        let limiter = RateLimiter::new(None, Some(100), window, Duration::from_secs(5));
+        let started = Instant::now();
+        limiter.acquire(60).await.unwrap();
+        limiter.acquire(60).await.unwrap();
+        assert!(started.elapsed() >= window);
+    }
+
+    #[tokio::test]
+    async fn test_wait_timeout() {
+        let limiter = RateLimiter::new(
+            Some(1),
+            None,
+            Duration::from_secs(60),
+            Duration::from_millis(100),
+        );
+        limiter.acquire(1).await.unwrap();
+        assert!(limiter.acquire(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_zero_requests_no_panic() {
+        let limiter = RateLimiter::new(
+            Some(0),
+            None,
+            Duration::from_secs(60),
+            Duration::from_millis(100),
+        );
+        assert!(limiter.acquire(1).await.is_err());
+    }
+}
@@ -0,0 +1,148 @@
+//! 测试与离线开发用的AI供应商。返回固定内容，并记录收到的会话。
+use super::openai::Conversation;
+use super::{BoxFuture, Completion, Error, Provider};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tiktoken_rs::{cl100k_base, CoreBPE};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// 离线开发时模拟供应商的固定回复
+#[cfg(feature = "mock-provider")]
+pub const CANNED_REPLY: &str = "这是模拟供应商的固定回复。";
+
+pub struct Agent {
+    completion: Completion,
+    chunks: Vec<String>,     // 流式返回时依次发送的片段，为空时整段发送
+    error: Option<Error>,    // 设置后每次调用都返回该错误
+    delay: Option<Duration>, // 设置后每次调用都等待该时长再返回
+    token_counter: CoreBPE,
+    received: Arc<Mutex<Vec<Conversation>>>,
+}
+
+impl Agent {
+    pub fn new(content: &str, prompt_tokens: u64, completion_tokens: u64) -> Self {
+        Self {
+            completion: Completion {
+                content: content.to_owned(),
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+                request_id: None,
+            },
+            chunks: Vec::new(),
+            error: None,
+            delay: None,
+            token_counter: cl100k_base().unwrap(),
+            received: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Agent {
+    /// 每次调用都失败的供应商
+    pub fn failing(error: &str) -> Self {
+        Self {
+            error: Some(Error::Failed(error.to_owned())),
+            ..Self::new("", 0, 0)
+        }
+    }
+
+    /// 每次调用都被内容过滤拦截的供应商
+    pub fn content_filtered(category: &str) -> Self {
+        Self {
+            error: Some(Error::ContentFiltered(category.to_owned())),
+            ..Self::new("", 0, 0)
+        }
+    }
+
+    /// 每次调用等待指定时长再返回，模拟较慢的供应商
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// 流式返回时依次发送给定的片段，回复内容为各片段相连
+    pub fn with_chunks(mut self, chunks: &[&str]) -> Self {
+        self.completion.content = chunks.concat();
+        self.chunks = chunks.iter().map(|c| c.to_string()).collect();
+        self
+    }
+
+    /// 每次回复都附带指定的请求ID
+    pub fn with_request_id(mut self, request_id: &str) -> Self {
+        self.completion.request_id = Some(request_id.to_owned());
+        self
+    }
+
+    /// 收到的全部会话。供应商移交给助手后，可借此查看其收到的内容。
+    pub fn received(&self) -> Arc<Mutex<Vec<Conversation>>> {
+        self.received.clone()
+    }
+}
+
+impl Provider for Agent {
+    fn process<'a>(
+        &'a self,
+        conversation: &'a Conversation,
+    ) -> BoxFuture<'a, Result<Completion, Error>> {
+        self.received
+            .lock()
+            .expect("Mock lock should not be poisoned")
+            .push(conversation.clone());
+        let result = match &self.error {
+            Some(e) => Err(e.clone()),
+            None => Ok(self.completion.clone()),
+        };
+        let delay = self.delay;
+        Box::pin(async move {
+            if let Some(d) = delay {
+                tokio::time::sleep(d).await;
+            }
+            result
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        conversation: &'a Conversation,
+        chunks: UnboundedSender<String>,
+    ) -> BoxFuture<'a, Result<Completion, Error>> {
+        Box::pin(async move {
+            let completion = self.process(conversation).await?;
+            if self.chunks.is_empty() {
+                let _ = chunks.send(completion.content.clone());
+            }
+            for chunk in &self.chunks {
+                let _ = chunks.send(chunk.clone());
+                tokio::task::yield_now().await;
+            }
+            Ok(completion)
+        })
+    }
+
+    // 仅计入消息内容，不含格式开销，便于测试中推算
+    fn count_tokens(&self, conversation: &Conversation) -> u64 {
+        conversation
+            .messages
+            .iter()
+            .map(|m| {
+                self.token_counter
+                    .encode_with_special_tokens(&m.content)
+                    .len() as u64
+            })
+            .sum()
+    }
+
+    fn max_tokens(&self) -> u64 {
+        4096
+    }
+
+    fn prompt_token_price(&self) -> f64 {
+        0.01
+    }
+
+    fn completion_token_price(&self) -> f64 {
+        0.03
+    }
+}
@@ -1 +1,526 @@
+//! AI供应商需要遵循的行为协议
+// 启用mock-provider特性时不会构建真实的供应商
+#[cfg_attr(feature = "mock-provider", allow(dead_code))]
+pub mod gemini;
+pub mod health;
+pub mod limit;
+#[cfg(any(test, feature = "mock-provider"))]
+pub mod mock;
+#[cfg_attr(feature = "mock-provider", allow(dead_code))]
 pub mod openai;
+
+use crate::core;
+use chrono::{DateTime, Utc};
+use openai::{Conversation, Message, Role};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER, USER_AGENT};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+
+// Custom Error
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Failed(String),          // 请求失败、供应商返回错误或者无法解析返回内容
+    ContentFiltered(String), // 请求或回复被供应商的内容过滤拦截，附带触发的类别
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Failed(e) => write!(f, "{e}"),
+            Self::ContentFiltered(category) => write!(f, "内容被供应商过滤。类别：{category}"),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+/// 供应商接口的类型
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    #[default]
+    OpenAI, // OpenAI兼容接口，包括Azure OpenAI
+    Gemini, // Google Gemini generateContent接口
+}
+
+/// 模型系列。推理模型不接受max_tokens与采样参数，需改用对应的参数
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelFamily {
+    #[default]
+    Chat, // 常规对话模型，使用max_tokens与采样参数
+    Reasoning, // o系列等推理模型，使用max_completion_tokens与reasoning_effort
+}
+
+/// 推理模型的推理强度
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Low,
+    Medium,
+    High,
+}
+
+// AI供应商服务所需要的参数
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub id: u64,
+    pub name: String,
+    #[serde(default)]
+    pub kind: Kind,
+    pub endpoint: String,
+    pub api_key: String,
+    pub max_tokens: u64,
+    pub prompt_token_price: f64,
+    pub completion_token_price: f64,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32, // 遇到限流（429）时的最多重试次数
+    #[serde(default = "default_max_retry_wait_secs")]
+    pub max_retry_wait_secs: u64, // 单次重试前等待时间的上限
+    #[serde(default)]
+    pub stop: Vec<String>, // 停止序列。AI生成到任一序列时停止输出
+    #[serde(default)]
+    pub user_agent: Option<String>, // 请求使用的User-Agent
+    #[serde(default)]
+    pub headers: HashMap<String, String>, // 附加在每个请求上的固定头部
+    #[serde(default)]
+    pub log_payloads: bool, // 调试用：在日志中记录请求头部与内容
+    #[serde(default)]
+    pub redact_headers: Vec<String>, // 记录日志时隐去的头部。API key总是隐去
+    #[serde(default)]
+    pub redact_patterns: Vec<String>, // 记录日志时隐去的字符串，按原文匹配，不支持正则表达式
+    #[serde(default)]
+    pub sampling: Sampling, // 默认采样参数。会话中设定的温度优先
+    #[serde(default)]
+    pub model_family: ModelFamily, // 决定请求中使用的参数名称，仅适用于OpenAI接口
+    #[serde(default)]
+    pub reasoning_effort: Option<ReasoningEffort>, // 推理模型的推理强度，缺省使用供应商默认值
+}
+
+#[cfg(test)]
+impl Config {
+    /// 测试用的OpenAI兼容供应商，ID为1，价格为每千token 0.01与0.03
+    pub fn for_test(endpoint: &str) -> Self {
+        Self {
+            id: 1,
+            name: "test-provider".to_string(),
+            kind: Kind::OpenAI,
+            endpoint: endpoint.to_string(),
+            api_key: "api-key".to_string(),
+            max_tokens: 4096,
+            prompt_token_price: 0.01,
+            completion_token_price: 0.03,
+            max_retries: 2,
+            max_retry_wait_secs: 10,
+            stop: Vec::new(),
+            user_agent: None,
+            headers: Default::default(),
+            log_payloads: false,
+            redact_headers: Vec::new(),
+            redact_patterns: Vec::new(),
+            sampling: Default::default(),
+            model_family: Default::default(),
+            reasoning_effort: None,
+        }
+    }
+}
+
+/// 采样温度的取值范围
+pub const TEMPERATURE_RANGE: RangeInclusive<f64> = 0.0..=2.0;
+
+/// top_p的取值范围
+pub const TOP_P_RANGE: RangeInclusive<f64> = 0.0..=1.0;
+
+/// 采样参数。未设置的参数使用供应商默认值。
+#[derive(Debug, Clone, Deserialize, PartialEq, Default)]
+pub struct Sampling {
+    #[serde(default)]
+    pub temperature: Option<f64>,
+    #[serde(default)]
+    pub top_p: Option<f64>,
+}
+
+impl Sampling {
+    /// 检查各参数是否在文档规定的范围内
+    pub fn validate(&self) -> Result<(), Error> {
+        let check = |name: &str, value: Option<f64>, range: RangeInclusive<f64>| match value {
+            Some(v) if !range.contains(&v) => Err(Error::Failed(format!(
+                "{name}应在{}到{}之间，当前为{v}。",
+                range.start(),
+                range.end()
+            ))),
+            _ => Ok(()),
+        };
+        check("temperature", self.temperature, TEMPERATURE_RANGE)?;
+        check("top_p", self.top_p, TOP_P_RANGE)
+    }
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_max_retry_wait_secs() -> u64 {
+    30
+}
+
+/// 按照配置的接口类型创建供应商
+#[cfg(not(feature = "mock-provider"))]
+pub fn build(config: &Config) -> Box<dyn Provider> {
+    match config.kind {
+        Kind::OpenAI => Box::new(openai::Agent::new(config)),
+        Kind::Gemini => Box::new(gemini::Agent::new(config)),
+    }
+}
+
+/// 启用mock-provider特性时，全部供应商替换为返回固定内容、不产生费用的模拟供应商
+#[cfg(feature = "mock-provider")]
+pub fn build(config: &Config) -> Box<dyn Provider> {
+    tracing::warn!("已启用mock-provider，供应商{}将返回固定内容", config.name);
+    Box::new(mock::Agent::new(mock::CANNED_REPLY, 0, 0))
+}
+
+/// 按照配置生成请求头部：User-Agent与附加头部，以及给定的认证头部。
+pub fn request_headers(config: &Config, auth: (&'static str, &str)) -> Result<HeaderMap, Error> {
+    let invalid = |e: &dyn fmt::Display| Error::Failed(format!("请求头部配置有误。{e}"));
+    let mut headers = HeaderMap::new();
+    for (name, value) in &config.headers {
+        headers.insert(
+            HeaderName::from_bytes(name.as_bytes()).map_err(|e| invalid(&e))?,
+            HeaderValue::from_str(value).map_err(|e| invalid(&e))?,
+        );
+    }
+    if let Some(agent) = &config.user_agent {
+        headers.insert(
+            USER_AGENT,
+            HeaderValue::from_str(agent).map_err(|e| invalid(&e))?,
+        );
+    }
+    headers.insert(
+        HeaderName::from_static(auth.0),
+        HeaderValue::from_str(auth.1).map_err(|e| invalid(&e))?,
+    );
+    Ok(headers)
+}
+
+const REDACTED: &str = "***";
+
+// 日志中请求内容的最大字节数，超出部分截去
+const PAYLOAD_LOG_MAX_BYTES: usize = 8192;
+
+/// 生成用于日志的请求内容。API key与配置的头部均已隐去。
+/// 请求内容中与redact_patterns某项原文相同的字符串替换为***，如某个具体的手机号，而非全部手机号。
+pub fn payload_log(config: &Config, headers: &HeaderMap, body: &impl Serialize) -> String {
+    let redact = |text: &str| -> String {
+        let mut text = text.to_owned();
+        for pattern in config
+            .redact_patterns
+            .iter()
+            .chain(std::iter::once(&config.api_key))
+            .filter(|p| !p.is_empty())
+        {
+            text = text.replace(pattern.as_str(), REDACTED);
+        }
+        text
+    };
+    let headers: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| {
+            let hidden = config
+                .redact_headers
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name.as_str()));
+            let value = match (hidden, value.to_str()) {
+                (false, Ok(v)) => redact(v),
+                _ => REDACTED.to_owned(),
+            };
+            format!("{name}: {value}")
+        })
+        .collect();
+    let body = redact(&serde_json::to_string(body).unwrap_or_default());
+    let snippet = core::truncate_bytes_safe(&body, PAYLOAD_LOG_MAX_BYTES);
+    let ellipsis = if snippet.len() < body.len() {
+        "…"
+    } else {
+        ""
+    };
+    format!(
+        "headers: [{}], body: {snippet}{ellipsis}",
+        headers.join(", ")
+    )
+}
+
+/// 配置了log_payloads时，在日志中记录隐去敏感信息后的请求
+pub fn log_payload(config: &Config, headers: &HeaderMap, body: &impl Serialize) {
+    if config.log_payloads {
+        tracing::debug!(
+            "Payload to {}: {}",
+            config.name,
+            payload_log(config, headers, body)
+        );
+    }
+}
+
+/// 解析Retry-After头部。其值可以是秒数，也可以是HTTP日期。
+pub fn retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// 发送请求。遇到限流时按照Retry-After的提示等待后重试，等待时间不超过配置的上限。
+/// 未提供Retry-After时等待1秒。
+pub async fn send_with_retry(
+    config: &Config,
+    request: impl Fn() -> reqwest::RequestBuilder,
+) -> Result<reqwest::Response, Error> {
+    let max_wait = Duration::from_secs(config.max_retry_wait_secs);
+    let mut attempt = 0;
+    loop {
+        let response = request()
+            .send()
+            .await
+            .map_err(|e| Error::Failed(format!("发送AI请求失败。{}", e.without_url())))?;
+        if response.status() != StatusCode::TOO_MANY_REQUESTS || attempt >= config.max_retries {
+            return Ok(response);
+        }
+        let wait = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| retry_after(v, Utc::now()))
+            .unwrap_or(Duration::from_secs(1))
+            .min(max_wait);
+        attempt += 1;
+        tracing::warn!(
+            "供应商{}限流，{}毫秒后第{attempt}次重试。",
+            config.name,
+            wait.as_millis()
+        );
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// 供应商返回请求ID时可能使用的头部，按优先顺序排列
+pub const REQUEST_ID_HEADERS: [&str; 2] = ["apim-request-id", "x-request-id"];
+
+/// 从响应头部中获取供应商的请求ID
+pub fn request_id(headers: &HeaderMap) -> Option<String> {
+    REQUEST_ID_HEADERS
+        .iter()
+        .find_map(|name| headers.get(*name)?.to_str().ok())
+        .map(|id| id.to_owned())
+}
+
+/// 估算token数量时，每条消息的格式开销
+const ESTIMATED_TOKENS_PER_MESSAGE: u64 = 4;
+
+/// 推理模型健康检查时回复的最大token数，需留出推理的余量
+const REASONING_HEALTH_CHECK_MAX_TOKENS: u64 = 1024;
+
+/// 按照字符粗略估算文本的token数量：ASCII字符约4个一个token，其余字符各算一个。
+pub fn estimate_tokens(text: &str) -> u64 {
+    let ascii = text.chars().filter(|c| c.is_ascii()).count() as u64;
+    let others = text.chars().count() as u64 - ascii;
+    ascii.div_ceil(4) + others
+}
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// 一次AI回复的内容与用量
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Completion {
+    pub content: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64, // 供应商报告的总数，应等于prompt与completion之和
+    pub request_id: Option<String>, // 供应商返回的请求ID，向供应商提交工单时使用
+}
+
+/// AI供应商应当具备的行为
+pub trait Provider: Send + Sync {
+    /// 根据会话内容，返回AI的回复
+    fn process<'a>(
+        &'a self,
+        conversation: &'a Conversation,
+    ) -> BoxFuture<'a, Result<Completion, Error>>;
+
+    /// 以流式方式返回AI的回复，每收到一段内容即发送给chunks，结束后返回完整的回复与用量。
+    /// 默认在获取完整回复后一次性发送，供应商可提供真正的流式实现。
+    fn stream<'a>(
+        &'a self,
+        conversation: &'a Conversation,
+        chunks: UnboundedSender<String>,
+    ) -> BoxFuture<'a, Result<Completion, Error>> {
+        Box::pin(async move {
+            let completion = self.process(conversation).await?;
+            let _ = chunks.send(completion.content.clone());
+            Ok(completion)
+        })
+    }
+
+    /// Token长度限制
+    fn max_tokens(&self) -> u64;
+
+    /// 每千个prompt token的价格
+    fn prompt_token_price(&self) -> f64;
+
+    /// 每千个completion token的价格
+    fn completion_token_price(&self) -> f64;
+
+    /// 估算会话作为prompt时的token数量。默认按照字符数粗略估算，供应商可提供更准确的实现。
+    fn count_tokens(&self, conversation: &Conversation) -> u64 {
+        conversation
+            .messages
+            .iter()
+            .map(|m| estimate_tokens(&m.content) + ESTIMATED_TOKENS_PER_MESSAGE)
+            .sum()
+    }
+
+    /// 检查供应商是否可用。默认发送一条极短的消息并等待回复，回复限定为1个token。
+    /// 推理模型的推理过程同样计入回复的token数，需放宽上限，以免健康的供应商因回复为空而被判为不可用。
+    fn health_check(&self, family: ModelFamily) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            let conversation = Conversation {
+                messages: vec![Message {
+                    role: Role::User.to_string(),
+                    content: "ping".to_string(),
+                }],
+                temperature: None,
+                max_tokens: Some(match family {
+                    ModelFamily::Chat => 1,
+                    ModelFamily::Reasoning => REASONING_HEALTH_CHECK_MAX_TOKENS,
+                }),
+            };
+            self.process(&conversation).await.map(|_| ())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::openai::{Conversation, Message, Role};
+    use super::{
+        estimate_tokens, payload_log, request_headers, retry_after, Config, ModelFamily, Sampling,
+        REASONING_HEALTH_CHECK_MAX_TOKENS,
+    };
+    use chrono::{TimeZone, Utc};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_health_check_single_token() {
+        use super::{mock, Provider};
+        let provider = mock::Agent::new("pong", 1, 1);
+        let received = provider.received();
+        provider.health_check(ModelFamily::Chat).await.unwrap();
+        provider.health_check(ModelFamily::Reasoning).await.unwrap();
+        let sent = received.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].max_tokens, Some(1));
+        assert_eq!(sent[1].max_tokens, Some(REASONING_HEALTH_CHECK_MAX_TOKENS));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 27, 50).unwrap();
+        assert_eq!(retry_after("2", now), Some(Duration::from_secs(2)));
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+            Some(Duration::from_secs(10))
+        );
+        // 已过去的时间无需等待
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_payload_log_redacted() {
+        let config = Config {
+            api_key: "sk-secret-key".to_string(),
+            headers: [("x-gateway-token".to_string(), "gateway-secret".to_string())].into(),
+            log_payloads: true,
+            redact_headers: vec!["X-Gateway-Token".to_string()],
+            redact_patterns: vec!["13800138000".to_string()],
+            ..Config::for_test("http://127.0.0.1:9/chat")
+        };
+        let headers = request_headers(&config, ("api-key", &config.api_key)).unwrap();
+        let conversation = Conversation {
+            messages: vec![Message {
+                role: Role::User.to_string(),
+                content: "我的手机号是13800138000，key是sk-secret-key".to_string(),
+            }],
+            temperature: None,
+            max_tokens: None,
+        };
+        let logged = payload_log(&config, &headers, &conversation);
+        assert!(!logged.contains("sk-secret-key"), "{logged}");
+        assert!(!logged.contains("gateway-secret"), "{logged}");
+        assert!(!logged.contains("13800138000"), "{logged}");
+        assert!(logged.contains("我的手机号是***"), "{logged}");
+
+        // 过长的内容截断后记录，不会截断多字节字符
+        let long = Conversation {
+            messages: vec![Message {
+                role: Role::User.to_string(),
+                content: "汉".repeat(5000),
+            }],
+            ..Conversation::default()
+        };
+        let logged = payload_log(&config, &headers, &long);
+        assert!(logged.ends_with("汉…"), "{logged}");
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("hello world"), 3);
+        assert_eq!(estimate_tokens("你好"), 2);
+        assert_eq!(estimate_tokens("hi你好"), 3);
+    }
+
+    #[test]
+    fn test_sampling_validation() {
+        assert_eq!(
+            Sampling::default().validate().map_err(|e| e.to_string()),
+            Ok(())
+        );
+        let sampling = Sampling {
+            temperature: Some(0.7),
+            top_p: Some(1.0),
+        };
+        assert!(sampling.validate().is_ok());
+
+        let too_hot = Sampling {
+            temperature: Some(5.0),
+            top_p: None,
+        };
+        assert_eq!(
+            too_hot.validate().unwrap_err().to_string(),
+            "temperature应在0到2之间，当前为5。"
+        );
+        let too_wide = Sampling {
+            temperature: None,
+            top_p: Some(2.0),
+        };
+        assert_eq!(
+            too_wide.validate().unwrap_err().to_string(),
+            "top_p应在0到1之间，当前为2。"
+        );
+    }
+}
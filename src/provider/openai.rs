@@ -1,21 +1,17 @@
 //! OpenAI作为API供应商
+use super::{
+    log_payload, request_headers, request_id, send_with_retry, BoxFuture, Completion, Config,
+    Error, ModelFamily, Provider, ReasoningEffort,
+};
 use crate::storage::model;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::convert::{From, TryFrom};
-use std::fmt;
 use std::string::ToString;
-
-// Custom Error
-#[derive(Debug, Clone)]
-pub struct Error(String);
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
-    }
-}
-impl std::error::Error for Error {}
+use std::sync::Arc;
+use tiktoken_rs::{cl100k_base, CoreBPE};
+use tokio::sync::mpsc::UnboundedSender;
 
 // Chat请求返回结果
 // 示例
@@ -40,18 +36,25 @@ impl std::error::Error for Error {}
 //        }
 //     ]
 // }
+// 不同API版本的响应字段有所增减。未知字段忽略，未用到的字段可缺省，仅用量与回复内容为必需。
 #[derive(Deserialize)]
 pub struct Response {
     #[allow(dead_code)]
+    #[serde(default)]
     id: String,
     #[allow(dead_code)]
+    #[serde(default)]
     object: String,
     #[allow(dead_code)]
+    #[serde(default)]
     created: u64,
     #[allow(dead_code)]
+    #[serde(default)]
     model: String,
     pub usage: Usage,
     pub choices: Vec<Choice>,
+    #[serde(skip)]
+    pub request_id: Option<String>, // 来自响应头部，而非响应内容
 }
 
 impl Response {
@@ -63,14 +66,6 @@ impl Response {
         }
     }
 
-    pub fn role(&self) -> Role {
-        tracing::debug!("Returning message role..");
-        match self.choices.first() {
-            Some(c) => Role::try_from(c.message.role.as_str()).unwrap(),
-            None => Role::System, // Never happens
-        }
-    }
-
     pub fn prompt_tokens(&self) -> u64 {
         tracing::debug!("Returning cost..");
         self.usage.prompt_tokens
@@ -80,22 +75,154 @@ impl Response {
         tracing::debug!("Returning cost..");
         self.usage.completion_tokens
     }
+
+    /// 供应商报告的本次请求总token数，应等于prompt与completion之和
+    pub fn total_tokens(&self) -> u64 {
+        self.usage.total_tokens
+    }
+
+    /// 回复被内容过滤截断时，返回触发的类别
+    pub fn filtered_category(&self) -> Option<String> {
+        let choice = self.choices.first()?;
+        (choice.finish_reason.as_deref() == Some(CONTENT_FILTER))
+            .then(|| filtered_category(&choice.content_filter_results))
+    }
+}
+
+/// 内容过滤时供应商使用的错误代码与结束原因
+const CONTENT_FILTER: &str = "content_filter";
+
+// 请求被内容过滤拦截时，Azure OpenAI返回400及如下内容
+// {
+//     "error":{
+//        "code":"content_filter",
+//        "message":"The response was filtered due to the prompt triggering Azure OpenAI's content management policy.",
+//        "status":400,
+//        "innererror":{
+//           "code":"ResponsibleAIPolicyViolation",
+//           "content_filter_result":{
+//              "hate":{"filtered":false,"severity":"safe"},
+//              "violence":{"filtered":true,"severity":"medium"}
+//           }
+//        }
+//     }
+// }
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorDetail,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetail {
+    #[serde(default)]
+    code: Option<String>,
+    #[serde(default)]
+    innererror: Option<InnerError>,
+}
+
+#[derive(Deserialize)]
+struct InnerError {
+    #[serde(default)]
+    content_filter_result: HashMap<String, FilterResult>,
+}
+
+/// 单个类别的内容过滤结果
+#[derive(Deserialize)]
+pub struct FilterResult {
+    #[serde(default)]
+    filtered: bool,
+}
+
+// 触发过滤的类别，多个类别以逗号分隔。供应商未说明时记为unknown。
+fn filtered_category(results: &HashMap<String, FilterResult>) -> String {
+    let mut categories: Vec<&str> = results
+        .iter()
+        .filter(|(_, r)| r.filtered)
+        .map(|(c, _)| c.as_str())
+        .collect();
+    if categories.is_empty() {
+        return "unknown".to_string();
+    }
+    categories.sort_unstable();
+    categories.join(",")
+}
+
+/// 若错误返回内容表明请求被内容过滤拦截，返回触发的类别
+pub fn content_filter_category(body: &str) -> Option<String> {
+    let response: ErrorResponse = serde_json::from_str(body).ok()?;
+    if response.error.code.as_deref() != Some(CONTENT_FILTER) {
+        return None;
+    }
+    let results = response
+        .error
+        .innererror
+        .map(|e| e.content_filter_result)
+        .unwrap_or_default();
+    Some(filtered_category(&results))
+}
+
+// 流式请求逐段返回的内容，以空行分隔的SSE事件传输
+// 示例
+// data: {"id":"chatcmpl-6v7mkQj980V1yBec6ETrKPRqFjNw9","choices":[{"index":0,"delta":{"content":"Yes"},"finish_reason":null}],"usage":null}
+//
+// data: {"id":"chatcmpl-6v7mkQj980V1yBec6ETrKPRqFjNw9","choices":[],"usage":{"prompt_tokens":58,"completion_tokens":68,"total_tokens":126}}
+//
+// data: [DONE]
+// 请求了include_usage时，用量在最后一个片段中返回，其choices为空。
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    delta: Delta,
+    #[serde(default)]
+    finish_reason: Option<String>,
+    #[serde(default)]
+    content_filter_results: HashMap<String, FilterResult>,
+}
+
+#[derive(Deserialize, Default)]
+struct Delta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+// 解析SSE中的一行。非数据行与结束标记返回None。
+fn stream_event(line: &str) -> Result<Option<StreamChunk>, Error> {
+    let Some(data) = line.trim().strip_prefix("data:") else {
+        return Ok(None);
+    };
+    let data = data.trim();
+    if data == "[DONE]" {
+        return Ok(None);
+    }
+    serde_json::from_str(data)
+        .map(Some)
+        .map_err(|e| Error::Failed(format!("解析AI返回失败。{e}")))
 }
 
 #[derive(Deserialize)]
 pub struct Usage {
     pub prompt_tokens: u64,
     pub completion_tokens: u64,
-    #[allow(dead_code)]
     pub total_tokens: u64,
 }
 
 #[derive(Deserialize)]
 pub struct Choice {
     pub message: Message,
+    #[serde(default)]
+    finish_reason: Option<String>, // 内容过滤时可能为null
+    #[serde(default)]
+    content_filter_results: HashMap<String, FilterResult>,
     #[allow(dead_code)]
-    finish_reason: String,
-    #[allow(dead_code)]
+    #[serde(default)]
     index: u64,
 }
 
@@ -196,27 +323,84 @@ impl From<&model::Message> for Message {
 //     {"role": "user",
 //       "content": "Do other Azure AI services support this too?"}
 //   ]
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, Default)]
 pub struct Conversation {
     pub messages: Vec<Message>, // 注意名字要与Json格式匹配
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>, // 采样温度。None表示使用供应商默认值
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>, // 回复的最大token数。None表示使用供应商默认值
+}
+
+impl Conversation {
+    /// 在token预算（max_tokens - reservation）内保留尽可能多的近期消息。
+    /// 开头的系统消息总是保留，其余消息从最早的开始舍弃，直至总数低于预算。
+    /// `count`返回单条消息的token数。
+    pub fn trim_to_tokens(
+        messages: &[Message],
+        max_tokens: u64,
+        reservation: u64,
+        count: impl Fn(&Message) -> u64,
+    ) -> Vec<Message> {
+        let budget = max_tokens.saturating_sub(reservation);
+        let (system, rest) = match messages.split_first() {
+            Some((first, rest)) if first.role == Role::System.to_string() => (Some(first), rest),
+            _ => (None, messages),
+        };
+        let mut used = system.map_or(0, &count);
+        let mut start = rest.len();
+        for (i, m) in rest.iter().enumerate().rev() {
+            let tokens = count(m);
+            if used + tokens >= budget {
+                break;
+            }
+            used += tokens;
+            start = i;
+        }
+        system.into_iter().chain(&rest[start..]).cloned().collect()
+    }
+}
+
+// 发送给OpenAI的请求内容。未配置停止序列、采样参数或回复长度时不包含相应字段。
+// 推理模型以max_completion_tokens与reasoning_effort取代max_tokens与采样参数。
+#[derive(Serialize)]
+pub struct Request<'a> {
+    pub messages: &'a [Message],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<&'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<ReasoningEffort>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
 }
 
-// AI供应商服务所需要的参数
-#[derive(Debug, Clone, Deserialize)]
-pub struct Config {
-    pub id: u64,
-    pub name: String,
-    pub endpoint: String,
-    pub api_key: String,
-    pub max_tokens: u64,
-    pub prompt_token_price: f64,
-    pub completion_token_price: f64,
+// 流式请求的选项。要求在最后一个片段中返回用量，以便计费。
+#[derive(Serialize)]
+pub struct StreamOptions {
+    pub include_usage: bool,
 }
 
-#[derive(Debug, Clone)]
+/// 按照OpenAI的格式，每条消息在内容之外额外占用的token数
+const TOKENS_PER_MESSAGE: u64 = 3;
+
+/// 按照OpenAI的格式，AI回复开头额外占用的token数
+const TOKENS_PER_REPLY: u64 = 3;
+
+#[derive(Clone)]
 pub struct Agent {
     config: Config,
     client: reqwest::Client,
+    token_counter: Arc<CoreBPE>,
 }
 
 impl Agent {
@@ -224,47 +408,685 @@ impl Agent {
         Self {
             config: config.clone(),
             client: reqwest::Client::new(),
+            token_counter: Arc::new(cl100k_base().expect("cl100k_base should be available")),
         }
     }
 
-    /// Token长度限制
-    pub fn max_tokens(&self) -> u64 {
-        self.config.max_tokens
+    /// 根据会话内容与配置生成请求内容
+    pub fn body<'a>(&'a self, conversation: &'a Conversation) -> Request<'a> {
+        let request = Request {
+            messages: &conversation.messages,
+            stop: (!self.config.stop.is_empty()).then_some(self.config.stop.as_slice()),
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            max_completion_tokens: None,
+            reasoning_effort: None,
+            stream: false,
+            stream_options: None,
+        };
+        match self.config.model_family {
+            ModelFamily::Chat => Request {
+                temperature: conversation
+                    .temperature
+                    .or(self.config.sampling.temperature),
+                top_p: self.config.sampling.top_p,
+                max_tokens: conversation.max_tokens,
+                ..request
+            },
+            ModelFamily::Reasoning => Request {
+                max_completion_tokens: conversation.max_tokens,
+                reasoning_effort: self.config.reasoning_effort,
+                ..request
+            },
+        }
+    }
+
+    // 发送请求，返回状态正常的响应与供应商的请求ID
+    async fn send(&self, body: &Request<'_>) -> Result<(reqwest::Response, Option<String>), Error> {
+        let header = request_headers(&self.config, ("api-key", &self.config.api_key))?;
+        log_payload(&self.config, &header, body);
+        let response = send_with_retry(&self.config, || {
+            self.client
+                .post(&self.config.endpoint)
+                .json(body)
+                .headers(header.clone())
+        })
+        .await?;
+
+        // 出错时附上请求ID，便于向供应商反馈
+        let id = request_id(response.headers());
+        let id_note = id_note(&id);
+        // 内容过滤单独处理，以便告知用户而非当作故障
+        let status = response.status();
+        if status == StatusCode::BAD_REQUEST {
+            let body = response
+                .text()
+                .await
+                .map_err(|e| Error::Failed(format!("读取AI返回失败。{id_note}{e}")))?;
+            if let Some(category) = content_filter_category(&body) {
+                tracing::warn!("请求被供应商内容过滤拦截。{id_note}类别：{category}");
+                return Err(Error::ContentFiltered(category));
+            }
+            // 返回内容可能包含请求原文等细节，只记录在日志中，不展示给用户
+            tracing::warn!("AI返回错误消息。{id_note}{status} {body}");
+            return Err(Error::Failed(format!("AI返回错误消息。{id_note}{status}")));
+        }
+        let response = response
+            .error_for_status()
+            .map_err(|e| Error::Failed(format!("AI返回错误消息。{id_note}{}", e.without_url())))?;
+        Ok((response, id))
     }
 
     // 根据会话内容，返回最新消息。
-    pub async fn process(&self, conversation: &Conversation) -> Result<Response, Error> {
+    pub async fn request(&self, conversation: &Conversation) -> Result<Response, Error> {
         // 交由AI处理
         tracing::debug!("Ask AI for response..");
-        let header = {
-            let mut headers = HeaderMap::new();
-            headers.insert(
-                HeaderName::from_static("api-key"),
-                HeaderValue::from_str(&self.config.api_key).expect("API key should be parsed"),
-            );
-            headers
-        };
-        let response = self
-            .client
-            .post(&self.config.endpoint)
-            .json(conversation)
-            .headers(header)
-            .send()
-            .await
-            .map_err(|e| Error(format!("发送AI请求失败。{}", e.without_url())))?
-            .error_for_status()
-            .map_err(|e| Error(format!("AI返回错误消息。{}", e.without_url())))?
+        let (response, id) = self.send(&self.body(conversation)).await?;
+        let id_note = id_note(&id);
+        let mut response = response
             .json::<Response>()
             .await
-            .map_err(|e| Error(format!("解析AI返回失败。{}", e.without_url())))?;
+            .map_err(|e| Error::Failed(format!("解析AI返回失败。{id_note}{}", e.without_url())))?;
+        if let Some(category) = response.filtered_category() {
+            tracing::warn!("回复被供应商内容过滤截断。{id_note}类别：{category}");
+            return Err(Error::ContentFiltered(category));
+        }
+        response.request_id = id;
 
         Ok(response)
     }
 
-    /// 计算价值消耗
-    pub fn cost(&self, response: &Response) -> f64 {
-        (self.config.prompt_token_price * response.prompt_tokens() as f64
-            + self.config.completion_token_price * response.completion_tokens() as f64)
-            / 1000.0
+    /// 以流式方式请求回复，每收到一段内容即发送给chunks。
+    /// 供应商未在结束时返回用量时，按照本地计数估算，以免漏计费用。
+    pub async fn stream_request(
+        &self,
+        conversation: &Conversation,
+        chunks: &UnboundedSender<String>,
+    ) -> Result<Completion, Error> {
+        tracing::debug!("Ask AI for streamed response..");
+        let body = Request {
+            stream: true,
+            stream_options: Some(StreamOptions {
+                include_usage: true,
+            }),
+            ..self.body(conversation)
+        };
+        let (mut response, id) = self.send(&body).await?;
+        let id_note = id_note(&id);
+        let mut content = String::new();
+        let mut usage: Option<Usage> = None;
+
+        // 片段可能在任意字节处断开，只处理完整的行，以免截断多字节字符
+        let mut buffer: Vec<u8> = Vec::new();
+        while let Some(bytes) = response
+            .chunk()
+            .await
+            .map_err(|e| Error::Failed(format!("读取AI返回失败。{id_note}{}", e.without_url())))?
+        {
+            buffer.extend_from_slice(&bytes);
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let Some(event) = stream_event(&String::from_utf8_lossy(&line))? else {
+                    continue;
+                };
+                if let Some(choice) = event.choices.first() {
+                    if choice.finish_reason.as_deref() == Some(CONTENT_FILTER) {
+                        let category = filtered_category(&choice.content_filter_results);
+                        tracing::warn!("回复被供应商内容过滤截断。{id_note}类别：{category}");
+                        return Err(Error::ContentFiltered(category));
+                    }
+                    if let Some(text) = choice.delta.content.as_deref().filter(|t| !t.is_empty()) {
+                        content.push_str(text);
+                        // 接收方已不再关心进度时，仍需读完回复以便计费
+                        let _ = chunks.send(text.to_owned());
+                    }
+                }
+                if event.usage.is_some() {
+                    usage = event.usage;
+                }
+            }
+        }
+
+        let usage = usage.unwrap_or_else(|| {
+            tracing::warn!("流式回复未包含用量，按本地计数估算。{id_note}");
+            let prompt_tokens = self.count_tokens(conversation);
+            let completion_tokens = self
+                .token_counter
+                .encode_with_special_tokens(&content)
+                .len() as u64;
+            Usage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            }
+        });
+        Ok(Completion {
+            content,
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+            request_id: id,
+        })
+    }
+}
+
+// 错误信息中附带的请求ID
+fn id_note(id: &Option<String>) -> String {
+    id.as_deref()
+        .map(|i| format!("请求ID：{i}。"))
+        .unwrap_or_default()
+}
+
+impl Provider for Agent {
+    fn process<'a>(
+        &'a self,
+        conversation: &'a Conversation,
+    ) -> BoxFuture<'a, Result<Completion, Error>> {
+        Box::pin(async move {
+            let response = self.request(conversation).await?;
+            Ok(Completion {
+                content: response.content().to_owned(),
+                prompt_tokens: response.prompt_tokens(),
+                completion_tokens: response.completion_tokens(),
+                total_tokens: response.total_tokens(),
+                request_id: response.request_id,
+            })
+        })
+    }
+
+    fn stream<'a>(
+        &'a self,
+        conversation: &'a Conversation,
+        chunks: UnboundedSender<String>,
+    ) -> BoxFuture<'a, Result<Completion, Error>> {
+        Box::pin(async move { self.stream_request(conversation, &chunks).await })
+    }
+
+    // 与OpenAI的计数方式一致：每条消息计入角色与内容，另加格式开销
+    fn count_tokens(&self, conversation: &Conversation) -> u64 {
+        let count = |text: &str| self.token_counter.encode_with_special_tokens(text).len() as u64;
+        conversation
+            .messages
+            .iter()
+            .map(|m| TOKENS_PER_MESSAGE + count(&m.role) + count(&m.content))
+            .sum::<u64>()
+            + TOKENS_PER_REPLY
+    }
+
+    fn max_tokens(&self) -> u64 {
+        self.config.max_tokens
+    }
+
+    fn prompt_token_price(&self) -> f64 {
+        self.config.prompt_token_price
+    }
+
+    fn completion_token_price(&self) -> f64 {
+        self.config.completion_token_price
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Config, Error, ModelFamily, Provider, ReasoningEffort, Sampling};
+    use super::{Agent, Conversation, Message, Role};
+    use axum::http::{header, HeaderMap, HeaderName, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::routing::post;
+    use axum::Router;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    const REPLY: &str = r#"{
+        "id": "chatcmpl-test",
+        "object": "chat.completion",
+        "created": 1679072642,
+        "model": "gpt-35-turbo",
+        "usage": {"prompt_tokens": 58, "completion_tokens": 68, "total_tokens": 126},
+        "choices": [
+            {"message": {"role": "assistant", "content": "Yes."}, "finish_reason": "stop", "index": 0}
+        ]
+    }"#;
+
+    // 首次请求返回429与Retry-After，之后正常回复
+    async fn rate_limited_server(hits: Arc<AtomicUsize>) -> String {
+        let app = Router::new().route(
+            "/chat",
+            post(move || {
+                let hits = hits.clone();
+                async move {
+                    if hits.fetch_add(1, Ordering::SeqCst) == 0 {
+                        (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "2")])
+                            .into_response()
+                    } else {
+                        ([(header::CONTENT_TYPE, "application/json")], REPLY).into_response()
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}/chat")
+    }
+
+    // 记录收到的请求头部，并正常回复。回复附带请求ID。
+    async fn recording_server(seen: Arc<Mutex<Option<HeaderMap>>>) -> String {
+        let app = Router::new().route(
+            "/chat",
+            post(move |headers: HeaderMap| {
+                let seen = seen.clone();
+                async move {
+                    *seen.lock().unwrap() = Some(headers);
+                    (
+                        [
+                            (header::CONTENT_TYPE, "application/json"),
+                            (HeaderName::from_static("apim-request-id"), "req-0042"),
+                        ],
+                        REPLY,
+                    )
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}/chat")
+    }
+
+    fn test_conversation() -> Conversation {
+        Conversation {
+            messages: vec![Message {
+                role: Role::User.to_string(),
+                content: "Hello".to_string(),
+            }],
+            temperature: None,
+            max_tokens: None,
+        }
+    }
+
+    #[test]
+    fn test_stop_sequences() {
+        let conversation = test_conversation();
+        let agent = Agent::new(&Config::for_test("http://127.0.0.1:9/chat"));
+        let body = serde_json::to_value(agent.body(&conversation)).unwrap();
+        assert!(body.get("stop").is_none());
+        assert!(body.get("temperature").is_none());
+        assert_eq!(body["messages"][0]["content"], "Hello");
+
+        let agent = Agent::new(&Config {
+            stop: vec!["###".to_string(), "END".to_string()],
+            ..Config::for_test("http://127.0.0.1:9/chat")
+        });
+        let body = serde_json::to_value(agent.body(&conversation)).unwrap();
+        assert_eq!(body["stop"], serde_json::json!(["###", "END"]));
+    }
+
+    #[test]
+    fn test_sampling() {
+        let agent = Agent::new(&Config {
+            sampling: Sampling {
+                temperature: Some(0.2),
+                top_p: Some(0.9),
+            },
+            ..Config::for_test("http://127.0.0.1:9/chat")
+        });
+        let body = serde_json::to_value(agent.body(&test_conversation())).unwrap();
+        assert_eq!(body["temperature"], 0.2);
+        assert_eq!(body["top_p"], 0.9);
+
+        // 会话中设定的温度优先
+        let conversation = Conversation {
+            temperature: Some(1.0),
+            ..test_conversation()
+        };
+        let body = serde_json::to_value(agent.body(&conversation)).unwrap();
+        assert_eq!(body["temperature"], 1.0);
+    }
+
+    #[test]
+    fn test_max_tokens() {
+        let agent = Agent::new(&Config::for_test("http://127.0.0.1:9/chat"));
+        let body = serde_json::to_value(agent.body(&test_conversation())).unwrap();
+        assert!(body.get("max_tokens").is_none());
+
+        let conversation = Conversation {
+            max_tokens: Some(256),
+            ..test_conversation()
+        };
+        let body = serde_json::to_value(agent.body(&conversation)).unwrap();
+        assert_eq!(body["max_tokens"], 256);
+    }
+
+    #[test]
+    fn test_model_family() {
+        let conversation = Conversation {
+            temperature: Some(0.5),
+            max_tokens: Some(256),
+            ..test_conversation()
+        };
+        let sampling = Sampling {
+            temperature: Some(0.2),
+            top_p: Some(0.9),
+        };
+
+        // 常规对话模型
+        let agent = Agent::new(&Config {
+            sampling: sampling.clone(),
+            reasoning_effort: Some(ReasoningEffort::High),
+            ..Config::for_test("http://127.0.0.1:9/chat")
+        });
+        let body = serde_json::to_value(agent.body(&conversation)).unwrap();
+        assert_eq!(body["max_tokens"], 256);
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["top_p"], 0.9);
+        assert!(body.get("max_completion_tokens").is_none());
+        assert!(body.get("reasoning_effort").is_none());
+
+        // 推理模型
+        let agent = Agent::new(&Config {
+            sampling,
+            model_family: ModelFamily::Reasoning,
+            reasoning_effort: Some(ReasoningEffort::High),
+            ..Config::for_test("http://127.0.0.1:9/chat")
+        });
+        let body = serde_json::to_value(agent.body(&conversation)).unwrap();
+        assert_eq!(body["max_completion_tokens"], 256);
+        assert_eq!(body["reasoning_effort"], "high");
+        assert!(body.get("max_tokens").is_none());
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("top_p").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_rate_limit() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let endpoint = rate_limited_server(hits.clone()).await;
+        let agent = Agent::new(&Config::for_test(&endpoint));
+        let conversation = test_conversation();
+
+        let started = Instant::now();
+        let completion = agent
+            .process(&conversation)
+            .await
+            .expect("Retry should succeed");
+        let elapsed = started.elapsed();
+
+        assert_eq!(completion.content, "Yes.");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert!(elapsed >= Duration::from_secs(2), "waited {elapsed:?}");
+        assert!(elapsed < Duration::from_secs(5), "waited {elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn test_custom_headers() {
+        let seen = Arc::new(Mutex::new(None));
+        let endpoint = recording_server(seen.clone()).await;
+        let agent = Agent::new(&Config {
+            user_agent: Some("wecom-gpt/test".to_string()),
+            headers: [("x-gateway-route".to_string(), "azure-east".to_string())].into(),
+            ..Config::for_test(&endpoint)
+        });
+        agent
+            .process(&test_conversation())
+            .await
+            .expect("Request should succeed");
+
+        let headers = seen
+            .lock()
+            .unwrap()
+            .take()
+            .expect("Request should be received");
+        assert_eq!(headers[header::USER_AGENT], "wecom-gpt/test");
+        assert_eq!(headers["x-gateway-route"], "azure-east");
+        assert_eq!(headers["api-key"], "api-key");
+    }
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let seen = Arc::new(Mutex::new(None));
+        let endpoint = recording_server(seen).await;
+        let agent = Agent::new(&Config::for_test(&endpoint));
+        let completion = agent
+            .process(&test_conversation())
+            .await
+            .expect("Request should succeed");
+        assert_eq!(completion.request_id.as_deref(), Some("req-0042"));
+    }
+
+    #[test]
+    fn test_count_tokens() {
+        let agent = Agent::new(&Config::for_test("http://127.0.0.1:9/chat"));
+        let conversation = Conversation {
+            messages: vec![
+                Message {
+                    role: Role::System.to_string(),
+                    content: "You are a helpful assistant.".to_string(),
+                },
+                Message {
+                    role: Role::User.to_string(),
+                    content: "hello world".to_string(),
+                },
+            ],
+            temperature: None,
+            max_tokens: None,
+        };
+        // 每条消息3 + 角色1 + 内容（6与2），回复开头3
+        assert_eq!(agent.count_tokens(&conversation), 19);
+        assert_eq!(agent.count_tokens(&Conversation::default()), 3);
+    }
+
+    fn msg(role: Role, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    // 以字符数作为token数，便于计算
+    fn chars(m: &Message) -> u64 {
+        m.content.chars().count() as u64
+    }
+
+    #[test]
+    fn test_trim_to_tokens() {
+        // 空消息
+        assert!(Conversation::trim_to_tokens(&[], 10, 2, chars).is_empty());
+
+        // 仅有系统消息，即使超出预算也保留
+        let system = msg(Role::System, "0123456789");
+        let kept = Conversation::trim_to_tokens(&[system.clone()], 5, 0, chars);
+        assert_eq!(kept, vec![system.clone()]);
+
+        // 恰好达到预算的消息被舍弃，低于预算的保留
+        let messages = vec![
+            system.clone(),
+            msg(Role::User, "aaaa"),
+            msg(Role::Assistant, "bbbb"),
+            msg(Role::User, "cccc"),
+        ];
+        let kept = Conversation::trim_to_tokens(&messages, 20, 2, chars);
+        assert_eq!(kept, vec![system.clone(), messages[3].clone()]);
+        let kept = Conversation::trim_to_tokens(&messages, 21, 2, chars);
+        assert_eq!(
+            kept,
+            vec![system.clone(), messages[2].clone(), messages[3].clone()]
+        );
+        let kept = Conversation::trim_to_tokens(&messages, 25, 2, chars);
+        assert_eq!(kept, messages);
+
+        // 远超预算时只保留系统消息
+        let kept = Conversation::trim_to_tokens(&messages, 8, 2, chars);
+        assert_eq!(kept, vec![system]);
+
+        // 没有系统消息时从最早的消息开始舍弃
+        let kept = Conversation::trim_to_tokens(&messages[1..], 8, 0, chars);
+        assert_eq!(kept, vec![messages[3].clone()]);
+    }
+
+    #[test]
+    fn test_response_schema_tolerance() {
+        // 新版本API增加的字段被忽略
+        let extra = r#"{
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "created": 1679072642,
+            "model": "gpt-35-turbo",
+            "system_fingerprint": "fp_1234",
+            "prompt_filter_results": [{"prompt_index": 0, "content_filter_results": {}}],
+            "usage": {"prompt_tokens": 58, "completion_tokens": 68, "total_tokens": 126, "completion_tokens_details": {}},
+            "choices": [{
+                "message": {"role": "assistant", "content": "Yes."},
+                "finish_reason": "stop",
+                "index": 0,
+                "logprobs": null,
+                "content_filter_results": {"hate": {"filtered": false}}
+            }]
+        }"#;
+        let response: super::Response = serde_json::from_str(extra).unwrap();
+        assert_eq!(response.content(), "Yes.");
+        assert_eq!(response.total_tokens(), 126);
+
+        // 未用到的字段缺省或为null
+        let missing = r#"{
+            "usage": {"prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7},
+            "choices": [{"message": {"role": "assistant", "content": "No."}, "finish_reason": null}]
+        }"#;
+        let response: super::Response = serde_json::from_str(missing).unwrap();
+        assert_eq!(response.content(), "No.");
+        assert_eq!(response.prompt_tokens(), 5);
+
+        // 必需字段仍须存在
+        let no_usage = r#"{"choices": [{"message": {"role": "assistant", "content": "No."}}]}"#;
+        assert!(serde_json::from_str::<super::Response>(no_usage).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_content_filter() {
+        let body = r#"{
+            "error": {
+                "code": "content_filter",
+                "message": "The response was filtered due to the prompt triggering Azure OpenAI's content management policy.",
+                "status": 400,
+                "innererror": {
+                    "code": "ResponsibleAIPolicyViolation",
+                    "content_filter_result": {
+                        "hate": {"filtered": false, "severity": "safe"},
+                        "violence": {"filtered": true, "severity": "medium"}
+                    }
+                }
+            }
+        }"#;
+        assert_eq!(
+            super::content_filter_category(body).as_deref(),
+            Some("violence")
+        );
+        // 其他错误不视为内容过滤
+        let other = r#"{"error": {"code": "invalid_api_key", "message": "Bad key"}}"#;
+        assert_eq!(super::content_filter_category(other), None);
+
+        // 供应商返回400时得到类型化的错误
+        let app = Router::new().route(
+            "/chat",
+            post(move || async move {
+                (
+                    StatusCode::BAD_REQUEST,
+                    [(header::CONTENT_TYPE, "application/json")],
+                    body,
+                )
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let agent = Agent::new(&Config::for_test(&format!("http://{addr}/chat")));
+        let err = agent.process(&test_conversation()).await.unwrap_err();
+        assert_eq!(err, Error::ContentFiltered("violence".to_string()));
+
+        // 其他400错误只向用户展示状态码，返回内容仅记录在日志中
+        let app = Router::new().route(
+            "/chat",
+            post(move || async move { (StatusCode::BAD_REQUEST, other) }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let agent = Agent::new(&Config::for_test(&format!("http://{addr}/chat")));
+        let err = agent.process(&test_conversation()).await.unwrap_err();
+        assert_eq!(
+            err,
+            Error::Failed("AI返回错误消息。400 Bad Request".to_string())
+        );
+
+        // 回复被截断时同样视为内容过滤
+        let truncated = r#"{
+            "usage": {"prompt_tokens": 5, "completion_tokens": 0, "total_tokens": 5},
+            "choices": [{
+                "message": {"role": "assistant", "content": ""},
+                "finish_reason": "content_filter",
+                "content_filter_results": {"sexual": {"filtered": true}}
+            }]
+        }"#;
+        let response: super::Response = serde_json::from_str(truncated).unwrap();
+        assert_eq!(response.filtered_category().as_deref(), Some("sexual"));
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let events = concat!(
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"role\":\"assistant\",\"content\":\"\"},\"finish_reason\":null}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"你好\"},\"finish_reason\":null}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"delta\":{\"content\":\"，世界\"},\"finish_reason\":\"stop\"}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"prompt_tokens\":9,\"completion_tokens\":4,\"total_tokens\":13}}\n\n",
+            "data: [DONE]\n\n",
+        );
+        let seen: Arc<Mutex<Option<serde_json::Value>>> = Arc::new(Mutex::new(None));
+        let recorded = seen.clone();
+        let app = Router::new().route(
+            "/chat",
+            post(move |axum::Json(body): axum::Json<serde_json::Value>| {
+                let recorded = recorded.clone();
+                async move {
+                    *recorded.lock().unwrap() = Some(body);
+                    (
+                        [
+                            (header::CONTENT_TYPE, "text/event-stream"),
+                            (HeaderName::from_static("x-request-id"), "req-stream"),
+                        ],
+                        events,
+                    )
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let agent = Agent::new(&Config::for_test(&format!("http://{addr}/chat")));
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let completion = agent.stream(&test_conversation(), sender).await.unwrap();
+        assert_eq!(completion.content, "你好，世界");
+        assert_eq!(completion.prompt_tokens, 9);
+        assert_eq!(completion.completion_tokens, 4);
+        assert_eq!(completion.request_id.as_deref(), Some("req-stream"));
+
+        // 空片段不转发
+        let mut chunks = Vec::new();
+        while let Some(chunk) = receiver.recv().await {
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks, ["你好", "，世界"]);
+
+        // 请求中要求流式返回并附带用量
+        let body = seen.lock().unwrap().take().unwrap();
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["stream_options"]["include_usage"], true);
+
+        // 非流式请求不包含流式字段
+        let body = serde_json::to_value(agent.body(&test_conversation())).unwrap();
+        assert!(body.get("stream").is_none());
+        assert!(body.get("stream_options").is_none());
     }
 }
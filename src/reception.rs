@@ -1,12 +1,18 @@
 //! Agent负责用户管理，用户请求预处理与分发，收集AI反馈并返回给用户。
 use axum::extract::Query;
 use axum::http::StatusCode;
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use serde_xml_rs::from_str;
 use std::collections::HashMap;
 use std::env;
 use std::fmt;
-use std::sync::Arc;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::time::Instant;
 
 // 企业微信加解密模块
 use wecom_crypto::Agent as CryptoAgent;
@@ -18,19 +24,39 @@ use wecom_agent::{
 };
 
 // 企业微信服务端业务解析模块
-use super::wecom_api::{AppMessageContent, CallbackParams, CallbackRequestBody, UrlVerifyParams};
+use super::wecom_api::{
+    AccessTokenResponse, AppMessageContent, CallbackParams, CallbackRequestBody,
+    MessageSendResponse, UrlVerifyParams,
+};
 
 // 用户管理模块
 use super::accountant::{Accountant, Config as AccountantCfg, Error as AccountError};
 
 // 人工智能模块
-use super::assistant::{Assistant, Config as AssistantCfg, ProviderCfg};
+use super::assistant::{
+    Assistant, Config as AssistantCfg, Error as AssistantError, MenuAction, ProviderCfg,
+};
+
+// 供应商健康状态
+use super::provider::health::Health;
+use super::provider::{estimate_tokens, TEMPERATURE_RANGE};
+
+// 待确认内容暂存模块
+use super::pending::Pending;
+
+// 消息去重模块
+use super::dedup::Dedup;
+
+// 计费记录模块
+use super::cost_log::{CostLog, CostRecord};
 
 // 存储模块
-use super::storage::Agent as StorageAgent;
+use super::storage::{Agent as StorageAgent, Config as StorageCfg, UserOrder};
 
 // 交互涉及到的核心概念
-use super::core::{Chat, ChatResponse, Guest};
+use super::core::{
+    format_credit, truncate_bytes_safe, truncate_chars, Chat, ChatResponse, Guest, Permission,
+};
 
 #[derive(Debug, Clone)]
 pub struct Error(String);
@@ -49,21 +75,387 @@ pub struct Config {
     assistants: Vec<AssistantCfg>,
     accountant: AccountantCfg,
     storage_path: String,
+    #[serde(default)]
+    storage: StorageCfg,
     admin_account: String,
+    #[serde(default)]
+    confirm_cost_threshold: Option<f64>,
+    #[serde(default = "default_confirm_window_secs")]
+    confirm_window_secs: u64,
+    #[serde(default = "default_languages")]
+    languages: Vec<String>, // 用户可通过#语言选择的回复语言
+    #[serde(default)]
+    low_balance_alert: Option<f64>, // 余额低于此值时在回复末尾提醒。用户可自行设定。
+    #[serde(default)]
+    new_conversation_min_credit: Option<f64>, // 余额须高于此值才能通过#新会话开启新会话
+    #[serde(default = "default_content_filter_reply")]
+    content_filter_reply: String, // 消息被供应商内容过滤拦截时回复用户的内容
+    #[serde(default)]
+    impersonation_charges_user: bool, // 管理员模拟用户时，费用是否由被模拟的用户承担。不扣费时计费记录记在管理员名下
+    #[serde(default)]
+    cost_log_path: Option<String>, // 将每轮计费追加写入此CSV文件，便于对账。缺省不写入
+    #[serde(default)]
+    progressive_send_tokens: Option<u64>, // 流式获取回复，每生成约此数量的token分段发送一次。缺省不分段。分段内容在计费前发送
+    #[serde(default = "default_progressive_send_secs")]
+    progressive_send_secs: u64, // 分段发送时，距上次发送超过此秒数也会发送一次。两次发送至少间隔1秒
+    #[serde(default)]
+    validate_providers_on_start: bool, // 启动时检查各供应商是否可用。需通过init_app初始化
+    #[serde(default)]
+    validate_wecom_on_start: bool, // 启动时检查各应用能否获取企业微信access_token。需通过init_app初始化
+    #[serde(default = "default_dedup_window_secs")]
+    dedup_window_secs: u64, // 此时间内重复推送的消息只处理一次
+    #[serde(default)]
+    persist_dedup: bool, // 在数据库中记录已处理的消息，重启后依然去重
+    #[serde(default = "default_command_aliases")]
+    command_aliases: HashMap<String, String>, // 用户指令的别名，如"#balance"对应"#查余额"
+}
+
+fn default_confirm_window_secs() -> u64 {
+    120
+}
+
+fn default_command_aliases() -> HashMap<String, String> {
+    [
+        ("#help", "#帮助"),
+        ("#balance", "#查余额"),
+        ("#usage", "#查消耗"),
+        ("#stats", "#统计"),
+        ("#new", "#新会话"),
+        ("#reset", "#新会话"),
+        ("#status", "#状态"),
+    ]
+    .into_iter()
+    .map(|(alias, command)| (alias.to_string(), command.to_string()))
+    .collect()
+}
+
+fn default_content_filter_reply() -> String {
+    "抱歉，这条消息涉及的内容无法处理，本次未扣费。请调整措辞后再试。".to_string()
+}
+
+fn default_progressive_send_secs() -> u64 {
+    10
+}
+
+fn default_dedup_window_secs() -> u64 {
+    600
+}
+
+fn default_languages() -> Vec<String> {
+    vec![
+        "中文".to_string(),
+        "English".to_string(),
+        "日本語".to_string(),
+    ]
+}
+
+impl Config {
+    /// 从TOML配置文件读取Config。敏感内容依然填写存放实际内容的环境变量名称。
+    pub fn from_file(path: &str) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error(format!("读取配置文件{path}失败。{e}")))?;
+        Self::from_toml(&text)
+    }
+
+    /// 解析TOML格式的配置内容
+    pub fn from_toml(text: &str) -> Result<Self, Error> {
+        toml::from_str(text).map_err(|e| Error(format!("解析配置文件失败。{e}")))
+    }
 }
 
 // 企业微信服务所需要的参数
 #[derive(Deserialize, Clone)]
 pub struct WecomCfg {
     corp_id: String,
+    #[serde(default = "default_wecom_api_base")]
+    api_base: String, // 企业微信服务端API地址，用于启动检查与广播
 }
 
-/// Agent负责协调用户与AI之间的交互过程
-pub struct Agent {
+fn default_wecom_api_base() -> String {
+    "https://qyapi.weixin.qq.com".to_string()
+}
+
+/// 以编程方式构建Config。
+/// 与配置文件一致，敏感内容（corp_id、token、key、secret、endpoint、api_key以及管理员账户）
+/// 均填写存放实际内容的环境变量名称。
+#[derive(Default)]
+pub struct ConfigBuilder {
+    corp_id: Option<String>,
+    wecom_api_base: Option<String>,
+    providers: Vec<ProviderCfg>,
+    assistants: Vec<AssistantCfg>,
+    accountant: Option<AccountantCfg>,
+    storage_path: Option<String>,
+    storage: StorageCfg,
+    admin_account: Option<String>,
+    confirm_cost_threshold: Option<f64>,
+    confirm_window_secs: Option<u64>,
+    languages: Option<Vec<String>>,
+    low_balance_alert: Option<f64>,
+    new_conversation_min_credit: Option<f64>,
+    content_filter_reply: Option<String>,
+    impersonation_charges_user: bool,
+    cost_log_path: Option<String>,
+    progressive_send_tokens: Option<u64>,
+    progressive_send_secs: Option<u64>,
+    validate_providers_on_start: bool,
+    validate_wecom_on_start: bool,
+    dedup_window_secs: Option<u64>,
+    persist_dedup: bool,
+    command_aliases: Option<HashMap<String, String>>,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 企业微信corp_id所在的环境变量
+    pub fn wecom(mut self, corp_id: &str) -> Self {
+        self.corp_id = Some(corp_id.to_owned());
+        self
+    }
+
+    /// 企业微信服务端API地址，用于启动检查与广播。缺省为https://qyapi.weixin.qq.com
+    pub fn wecom_api_base(mut self, api_base: &str) -> Self {
+        self.wecom_api_base = Some(api_base.to_owned());
+        self
+    }
+
+    /// 添加一个AI供应商
+    pub fn provider(mut self, provider: ProviderCfg) -> Self {
+        self.providers.push(provider);
+        self
+    }
+
+    /// 添加一个智能助手
+    pub fn assistant(mut self, assistant: AssistantCfg) -> Self {
+        self.assistants.push(assistant);
+        self
+    }
+
+    /// 通讯录应用
+    pub fn accountant(mut self, accountant: AccountantCfg) -> Self {
+        self.accountant = Some(accountant);
+        self
+    }
+
+    /// 数据库路径
+    pub fn storage_path(mut self, path: &str) -> Self {
+        self.storage_path = Some(path.to_owned());
+        self
+    }
+
+    /// 数据库的可选配置
+    pub fn storage(mut self, storage: StorageCfg) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// 管理员账户所在的环境变量
+    pub fn admin_account(mut self, admin_account: &str) -> Self {
+        self.admin_account = Some(admin_account.to_owned());
+        self
+    }
+
+    /// 预计费用超过阈值的消息需要用户在有效期内确认
+    pub fn confirm_cost(mut self, threshold: f64, window_secs: u64) -> Self {
+        self.confirm_cost_threshold = Some(threshold);
+        self.confirm_window_secs = Some(window_secs);
+        self
+    }
+
+    /// 用户可选择的回复语言
+    pub fn languages(mut self, languages: &[&str]) -> Self {
+        self.languages = Some(languages.iter().map(|l| l.to_string()).collect());
+        self
+    }
+
+    /// 默认的余额预警阈值
+    pub fn low_balance_alert(mut self, threshold: f64) -> Self {
+        self.low_balance_alert = Some(threshold);
+        self
+    }
+
+    /// 余额须高于此值才能通过#新会话开启新会话，以免频繁开启新会话
+    pub fn new_conversation_min_credit(mut self, credit: f64) -> Self {
+        self.new_conversation_min_credit = Some(credit);
+        self
+    }
+
+    /// 消息被供应商内容过滤拦截时回复用户的内容
+    pub fn content_filter_reply(mut self, reply: &str) -> Self {
+        self.content_filter_reply = Some(reply.to_owned());
+        self
+    }
+
+    /// 管理员模拟用户时，由被模拟的用户承担费用。缺省不扣费，计费记录中记在管理员名下。
+    /// 无论是否扣费，模拟产生的费用均计入助手的费用上限
+    pub fn impersonation_charges_user(mut self) -> Self {
+        self.impersonation_charges_user = true;
+        self
+    }
+
+    /// 将每轮计费追加写入指定的CSV文件
+    pub fn cost_log_path(mut self, path: &str) -> Self {
+        self.cost_log_path = Some(path.to_owned());
+        self
+    }
+
+    /// 以流式方式获取回复，每生成约tokens个token或每隔secs秒，将新生成的内容发送给用户。
+    /// 两次发送至少间隔1秒。分段内容在计费前发送，回复随后失败或被内容过滤拦截时不扣费
+    pub fn progressive_send(mut self, tokens: u64, secs: u64) -> Self {
+        self.progressive_send_tokens = Some(tokens);
+        self.progressive_send_secs = Some(secs);
+        self
+    }
+
+    /// 启动时检查各供应商是否可用，以便尽早发现地址或密钥配置错误。需通过init_app初始化应用
+    pub fn validate_providers_on_start(mut self) -> Self {
+        self.validate_providers_on_start = true;
+        self
+    }
+
+    /// 启动时检查各应用能否获取企业微信access_token，以便尽早发现secret配置错误。需通过init_app初始化应用
+    pub fn validate_wecom_on_start(mut self) -> Self {
+        self.validate_wecom_on_start = true;
+        self
+    }
+
+    /// 消息去重的时间窗口，以及是否在数据库中记录已处理的消息
+    pub fn dedup(mut self, window_secs: u64, persist: bool) -> Self {
+        self.dedup_window_secs = Some(window_secs);
+        self.persist_dedup = persist;
+        self
+    }
+
+    /// 用户指令的别名。别名与指令均以#开头，例如("#balance", "#查余额")。
+    pub fn command_aliases(mut self, aliases: &[(&str, &str)]) -> Self {
+        self.command_aliases = Some(
+            aliases
+                .iter()
+                .map(|(alias, command)| (alias.to_string(), command.to_string()))
+                .collect(),
+        );
+        self
+    }
+
+    /// 校验各项内容并生成Config
+    pub fn build(self) -> Result<Config, Error> {
+        let missing = |name: &str| Error(format!("缺少配置项：{name}"));
+        let corp_id = self.corp_id.ok_or_else(|| missing("wecom"))?;
+        let accountant = self.accountant.ok_or_else(|| missing("accountant"))?;
+        let storage_path = self.storage_path.ok_or_else(|| missing("storage_path"))?;
+        let admin_account = self.admin_account.ok_or_else(|| missing("admin_account"))?;
+
+        // 每个助手都需要有对应的供应商，且应用ID不可重复。
+        for (i, a) in self.assistants.iter().enumerate() {
+            if !self.providers.iter().any(|p| p.id == a.provider_id) {
+                return Err(Error(format!(
+                    "助手{}所需的供应商{}不存在",
+                    a.agent_id, a.provider_id
+                )));
+            }
+            if self.assistants[..i]
+                .iter()
+                .any(|x| x.agent_id == a.agent_id)
+                || a.agent_id == accountant.agent_id
+            {
+                return Err(Error(format!("应用ID重复：{}", a.agent_id)));
+            }
+        }
+
+        Ok(Config {
+            wecom: WecomCfg {
+                corp_id,
+                api_base: self.wecom_api_base.unwrap_or_else(default_wecom_api_base),
+            },
+            providers: self.providers,
+            assistants: self.assistants,
+            accountant,
+            storage_path,
+            storage: self.storage,
+            admin_account,
+            confirm_cost_threshold: self.confirm_cost_threshold,
+            confirm_window_secs: self
+                .confirm_window_secs
+                .unwrap_or_else(default_confirm_window_secs),
+            languages: self.languages.unwrap_or_else(default_languages),
+            low_balance_alert: self.low_balance_alert,
+            new_conversation_min_credit: self.new_conversation_min_credit,
+            content_filter_reply: self
+                .content_filter_reply
+                .unwrap_or_else(default_content_filter_reply),
+            impersonation_charges_user: self.impersonation_charges_user,
+            cost_log_path: self.cost_log_path,
+            progressive_send_tokens: self.progressive_send_tokens,
+            progressive_send_secs: self
+                .progressive_send_secs
+                .unwrap_or_else(default_progressive_send_secs),
+            validate_providers_on_start: self.validate_providers_on_start,
+            validate_wecom_on_start: self.validate_wecom_on_start,
+            dedup_window_secs: self
+                .dedup_window_secs
+                .unwrap_or_else(default_dedup_window_secs),
+            persist_dedup: self.persist_dedup,
+            command_aliases: self.command_aliases.unwrap_or_else(default_command_aliases),
+        })
+    }
+}
+
+/// 处理用户请求的结果。HTTP层无需关心，主要便于测试确认处理路径。
+#[derive(Debug, PartialEq, Clone)]
+pub enum ProcessOutcome {
+    Replied,                    // 已回复用户
+    Blocked { reason: String }, // 拒绝处理，已告知用户原因
+    CommandHandled,             // 已处理指令消息
+    Ignored { reason: String }, // 未做处理，如重复消息
+    Error(String),              // 处理出错
+}
+
+/// 单个AI供应商的健康状态
+#[derive(Serialize)]
+pub struct ProviderHealth {
+    pub id: u64,
+    pub name: String,
+    #[serde(flatten)]
+    pub health: Health,
+}
+
+// 流式回复分段发送的时机。距上次发送不少于min_interval，且达到其余任一条件时发送一次。
+struct ProgressiveSend {
+    every_tokens: u64, // 按字符估算的token数
+    every: Duration,
+    min_interval: Duration,
+}
+
+// 各应用的功能模块。重载配置时整体替换。
+struct Apps {
     assistants: HashMap<u64, Assistant>,      // 负责AI功能
     crypto_agents: HashMap<u64, CryptoAgent>, // 负责企业微信消息加解密
     messengers: HashMap<u64, WecomAgent>,     // 负责消息传递
+    wecom_apis: HashMap<u64, WecomApi>,       // 需要完整返回结果时直接调用企业微信API
+}
+
+/// Agent负责协调用户与AI之间的交互过程
+pub struct Agent {
+    apps: RwLock<Arc<Apps>>,                  // 各应用的功能模块，重载时整体替换
+    storage: Arc<StorageAgent>,               // 供重载时新建助手
     accountant: Accountant,                   // 负责账户管理
+    maintenance: AtomicBool,                  // 维护模式下仅响应指令消息
+    confirm_cost_threshold: Option<f64>,      // 预计费用超过此值的消息需要确认
+    pending_chats: Pending<String>,           // 等待用户确认的消息
+    pending_actions: Pending<AdminAction>,    // 等待管理员确认的操作
+    languages: Vec<String>,                   // 可选的回复语言
+    low_balance_alert: Option<f64>,           // 默认的余额预警阈值
+    new_conversation_min_credit: Option<f64>, // 开启新会话所需的最低余额
+    content_filter_reply: String,             // 内容被过滤时回复用户的内容
+    impersonation_charges_user: bool,         // 模拟用户时是否向该用户扣费
+    cost_log: Option<CostLog>,                // 计费记录的CSV文件
+    progressive: Option<ProgressiveSend>,     // 分段发送流式回复的时机
+    unknown_agent_requests: AtomicU64,        // 发往未配置agent_id的请求数
+    dedup: Dedup,                             // 过滤企业微信重复推送的消息
+    command_aliases: HashMap<String, String>, // 用户指令的别名
 }
 
 // 转换环境变量解析错误
@@ -71,50 +463,89 @@ fn to_local_err(name: &str) -> Error {
     Error(format!("找不到环境变量{name}"))
 }
 
+// 按照配置建立各应用的助手、加解密与消息模块
+fn build_apps(config: &Config, storage: &Arc<StorageAgent>) -> Result<Apps, Error> {
+    let mut crypto_agents: HashMap<u64, CryptoAgent> = HashMap::new();
+    let mut assistants: HashMap<u64, Assistant> = HashMap::new();
+    let mut messengers: HashMap<u64, WecomAgent> = HashMap::new();
+    let mut wecom_apis: HashMap<u64, WecomApi> = HashMap::new();
+
+    for assis_cfg in &config.assistants {
+        // 每分钟0次请求意味着永远无法请求供应商
+        if assis_cfg.requests_per_minute == Some(0) {
+            return Err(Error(format!(
+                "应用{}的requests_per_minute须大于0。",
+                assis_cfg.agent_id
+            )));
+        }
+        let mut a_cfg = assis_cfg.clone();
+        // 加解密模块
+        a_cfg.token = env::var(&assis_cfg.token).map_err(|_| to_local_err(&assis_cfg.token))?;
+        a_cfg.key = env::var(&assis_cfg.key).map_err(|_| to_local_err(&assis_cfg.key))?;
+        crypto_agents.insert(a_cfg.agent_id, CryptoAgent::new(&a_cfg.token, &a_cfg.key));
+
+        // 消息发送模块
+        let corp_id =
+            env::var(&config.wecom.corp_id).map_err(|_| to_local_err(&config.wecom.corp_id))?;
+        a_cfg.secret = env::var(&a_cfg.secret).map_err(|_| to_local_err(&a_cfg.secret))?;
+        messengers.insert(a_cfg.agent_id, WecomAgent::new(&corp_id, &a_cfg.secret));
+        wecom_apis.insert(
+            a_cfg.agent_id,
+            WecomApi::new(&config.wecom.api_base, &corp_id, &a_cfg.secret),
+        );
+
+        // 匹配的AI是哪一个
+        for provider_cfg in &config.providers {
+            if provider_cfg.id == assis_cfg.provider_id {
+                provider_cfg
+                    .sampling
+                    .validate()
+                    .map_err(|e| Error(format!("供应商{}的采样参数有误。{e}", provider_cfg.id)))?;
+                let mut p_cfg = provider_cfg.clone();
+                p_cfg.endpoint =
+                    env::var(&p_cfg.endpoint).map_err(|_| to_local_err(&p_cfg.endpoint))?;
+                p_cfg.api_key =
+                    env::var(&p_cfg.api_key).map_err(|_| to_local_err(&p_cfg.api_key))?;
+                assistants.insert(
+                    a_cfg.agent_id,
+                    Assistant::new(&a_cfg, &p_cfg, storage.clone()),
+                );
+            }
+        }
+    }
+
+    Ok(Apps {
+        assistants,
+        crypto_agents,
+        messengers,
+        wecom_apis,
+    })
+}
+
 impl Agent {
-    /// 新建一个应用Agent
+    /// 新建一个应用Agent。配置了启动检查时无法在此同步完成检查，应改用Agent::init。
     pub fn new(config: &Config) -> Result<Self, Error> {
+        if config.validate_providers_on_start || config.validate_wecom_on_start {
+            return Err(Error(
+                "配置了启动检查（validate_providers_on_start或validate_wecom_on_start），请使用init_app初始化应用。"
+                    .to_string(),
+            ));
+        }
+        Self::build(config)
+    }
+
+    // 新建应用Agent，不做启动检查
+    fn build(config: &Config) -> Result<Self, Error> {
         // 初始化存储模块
         let admin_name =
             env::var(&config.admin_account).map_err(|_| to_local_err(&config.admin_account))?;
         let storage = Arc::new(
-            StorageAgent::new(&config.storage_path, admin_name.as_str())
+            StorageAgent::with_config(&config.storage_path, admin_name.as_str(), &config.storage)
                 .map_err(|e| Error(format!("数据库初始化失败。{e}")))?,
         );
 
         // 初始化Assistant、加解密与消息模块
-        let mut crypto_agents: HashMap<u64, CryptoAgent> = HashMap::new();
-        let mut assistants: HashMap<u64, Assistant> = HashMap::new();
-        let mut messengers: HashMap<u64, WecomAgent> = HashMap::new();
-
-        for assis_cfg in &config.assistants {
-            let mut a_cfg = assis_cfg.clone();
-            // 加解密模块
-            a_cfg.token = env::var(&assis_cfg.token).map_err(|_| to_local_err(&assis_cfg.token))?;
-            a_cfg.key = env::var(&assis_cfg.key).map_err(|_| to_local_err(&assis_cfg.key))?;
-            crypto_agents.insert(a_cfg.agent_id, CryptoAgent::new(&a_cfg.token, &a_cfg.key));
-
-            // 消息发送模块
-            let corp_id =
-                env::var(&config.wecom.corp_id).map_err(|_| to_local_err(&config.wecom.corp_id))?;
-            a_cfg.secret = env::var(&a_cfg.secret).map_err(|_| to_local_err(&a_cfg.secret))?;
-            messengers.insert(a_cfg.agent_id, WecomAgent::new(&corp_id, &a_cfg.secret));
-
-            // 匹配的AI是哪一个
-            for provider_cfg in &config.providers {
-                if provider_cfg.id == assis_cfg.provider_id {
-                    let mut p_cfg = provider_cfg.clone();
-                    p_cfg.endpoint =
-                        env::var(&p_cfg.endpoint).map_err(|_| to_local_err(&p_cfg.endpoint))?;
-                    p_cfg.api_key =
-                        env::var(&p_cfg.api_key).map_err(|_| to_local_err(&p_cfg.api_key))?;
-                    assistants.insert(
-                        a_cfg.agent_id,
-                        Assistant::new(&a_cfg, &p_cfg, storage.clone()),
-                    );
-                }
-            }
-        }
+        let apps = build_apps(config, &storage)?;
 
         // 账户管理模块
         let mut acct_cfg = config.accountant.clone();
@@ -122,50 +553,174 @@ impl Agent {
         acct_cfg.key = env::var(&acct_cfg.key).map_err(|_| to_local_err(&acct_cfg.key))?;
         let accountant = Accountant::new(storage.clone(), &acct_cfg);
 
+        // 计费记录
+        let cost_log = match &config.cost_log_path {
+            None => None,
+            Some(path) => Some(
+                CostLog::open(path)
+                    .map_err(|e| Error(format!("打开计费记录文件{path}失败。{e}")))?,
+            ),
+        };
+
+        // 消息去重模块
+        let dedup_window = Duration::from_secs(config.dedup_window_secs);
+        let dedup = if config.persist_dedup {
+            Dedup::with_storage(dedup_window, storage.clone())
+        } else {
+            Dedup::new(dedup_window)
+        };
+
         Ok(Self {
-            assistants,
-            crypto_agents,
-            messengers,
+            apps: RwLock::new(Arc::new(apps)),
+            storage,
             accountant,
+            maintenance: AtomicBool::new(false),
+            confirm_cost_threshold: config.confirm_cost_threshold,
+            pending_chats: Pending::new(Duration::from_secs(config.confirm_window_secs)),
+            pending_actions: Pending::new(Duration::from_secs(config.confirm_window_secs)),
+            languages: config.languages.clone(),
+            low_balance_alert: config.low_balance_alert,
+            new_conversation_min_credit: config.new_conversation_min_credit,
+            content_filter_reply: config.content_filter_reply.clone(),
+            impersonation_charges_user: config.impersonation_charges_user,
+            cost_log,
+            progressive: config
+                .progressive_send_tokens
+                .map(|tokens| ProgressiveSend {
+                    every_tokens: tokens,
+                    every: Duration::from_secs(config.progressive_send_secs),
+                    min_interval: PROGRESS_MIN_INTERVAL,
+                }),
+            unknown_agent_requests: AtomicU64::new(0),
+            dedup,
+            command_aliases: config.command_aliases.clone(),
         })
     }
 
+    // 当前版本的应用模块
+    fn apps(&self) -> Arc<Apps> {
+        self.apps
+            .read()
+            .expect("Apps lock should not be poisoned")
+            .clone()
+    }
+
+    // 测试中替换或移除应用模块
+    #[cfg(test)]
+    fn apps_mut(&mut self) -> &mut Apps {
+        let apps = self
+            .apps
+            .get_mut()
+            .expect("Apps lock should not be poisoned");
+        Arc::get_mut(apps).expect("Apps should not be shared")
+    }
+
+    /// 按照新配置重建各应用的助手、加解密与消息模块，并整体替换。
+    /// 处理中的请求继续使用原有模块直至完成。新配置有误时保留原配置。
+    /// 重建的助手沿用原助手的健康、限流与并发状态，详见Assistant::inherit_state。
+    /// 数据库与账户管理等其余配置项仍需重启后生效。
+    pub fn reload_config(&self, config: &Config) -> Result<(), Error> {
+        let mut apps = build_apps(config, &self.storage)?;
+        let accountant_id = self.accountant.agent_id();
+        if apps.crypto_agents.contains_key(&accountant_id) {
+            return Err(Error(format!("应用ID重复：{accountant_id}")));
+        }
+        let previous = self.apps();
+        for (id, assistant) in apps.assistants.iter_mut() {
+            if let Some(old) = previous.assistants.get(id) {
+                assistant.inherit_state(old);
+            }
+        }
+        let count = apps.crypto_agents.len();
+        *self.apps.write().expect("Apps lock should not be poisoned") = Arc::new(apps);
+        tracing::warn!("配置已重载，当前共有{count}个应用。");
+        Ok(())
+    }
+
+    /// 新建一个应用Agent。配置要求时，检查各供应商与企业微信应用是否可用。
+    pub async fn init(config: &Config) -> Result<Self, Error> {
+        let agent = Self::build(config)?;
+        if config.validate_providers_on_start {
+            agent.validate_providers().await?;
+        }
+        if config.validate_wecom_on_start {
+            validate_wecom(config).await?;
+        }
+        Ok(agent)
+    }
+
+    /// 检查各AI供应商是否可用。多个助手共用同一供应商时只检查一次。
+    pub async fn validate_providers(&self) -> Result<(), Error> {
+        let mut checked: Vec<u64> = Vec::new();
+        let apps = self.apps();
+        for assistant in apps.assistants.values() {
+            let (id, name, _) = assistant.provider_health();
+            if checked.contains(&id) {
+                continue;
+            }
+            assistant
+                .check_provider()
+                .await
+                .map_err(|e| Error(format!("供应商{name}（ID {id}）不可用。{e}")))?;
+            tracing::info!("供应商{name}检查通过");
+            checked.push(id);
+        }
+        Ok(())
+    }
+
+    /// 汇总各AI供应商的健康状态。多个助手共用同一供应商时，其记录将被合并。
+    pub fn provider_health(&self) -> Vec<ProviderHealth> {
+        let mut records: Vec<ProviderHealth> = Vec::new();
+        for assistant in self.apps().assistants.values() {
+            let (id, name, health) = assistant.provider_health();
+            match records.iter_mut().find(|r| r.id == id) {
+                Some(r) => r.health.merge(&health),
+                None => records.push(ProviderHealth { id, name, health }),
+            }
+        }
+        records.sort_by_key(|r| r.id);
+        records
+    }
+
+    // 记录一次发往未配置agent_id的请求
+    fn record_unknown_agent(&self, agent_id: u64) {
+        let count = self.unknown_agent_requests.fetch_add(1, Ordering::Relaxed) + 1;
+        tracing::warn!("[{agent_id}] 未配置该应用，忽略请求。累计{count}次。");
+    }
+
     /// 配合企业微信，验证服务器地址的有效性。
     pub fn verify_url(
         &self,
         agent_id: u64,
         params: Query<UrlVerifyParams>,
     ) -> Result<String, StatusCode> {
-        // 验证的是通讯录组件吗？
-        if agent_id == self.accountant.agent_id() {
-            return self.accountant.verify_url(&params).map_err(|e| {
-                tracing::error!("校验URL失败。{e}");
-                StatusCode::BAD_REQUEST
-            });
-        }
-
-        // 验证对象是哪个Assistant？
-        let Some(crypto_agent) = self.crypto_agents.get(&agent_id) else {
-            tracing::error!("无法获得加解密对象。agent_id: {agent_id}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        // 验证对象是通讯录组件，还是哪个Assistant？
+        let apps = self.apps();
+        let is_contact = agent_id == self.accountant.agent_id();
+        let crypto_agent = if is_contact {
+            self.accountant.crypto_agent()
+        } else {
+            let Some(c) = apps.crypto_agents.get(&agent_id) else {
+                self.record_unknown_agent(agent_id);
+                return Err(StatusCode::NOT_FOUND);
+            };
+            c
         };
 
-        // Is this request safe?
-        if crypto_agent.generate_signature(vec![&params.timestamp, &params.nonce, &params.echostr])
-            != params.msg_signature
-        {
-            tracing::error!("校验签名失败");
-            return Err(StatusCode::BAD_REQUEST);
-        }
-
-        // Give the server what it expects.
-        Ok(crypto_agent
-            .decrypt(&params.echostr)
-            .map_err(|e| {
-                tracing::error!("解密消息失败。{e}");
-                StatusCode::INTERNAL_SERVER_ERROR
-            })?
-            .text)
+        // 记录完整的输入，以便排查token或key配置错误。
+        verify_echo(crypto_agent, &params).map_err(|e| {
+            tracing::error!(
+                "[{agent_id}] 校验URL失败。{e} timestamp: {}, nonce: {}",
+                params.timestamp,
+                params.nonce
+            );
+            // 通讯录组件的任何校验失败均返回400，与助手区分签名与解密错误不同
+            if is_contact {
+                StatusCode::BAD_REQUEST
+            } else {
+                e.status_code()
+            }
+        })
     }
 
     /// 处理用户发来的请求
@@ -175,22 +730,25 @@ impl Agent {
         agent_id: u64,
         params: Query<CallbackParams>,
         body: String,
-    ) {
+    ) -> ProcessOutcome {
+        // 谁可以校验此请求？
+        let apps = self.apps();
+        let Some(crypto_agent) = apps.crypto_agents.get(&agent_id) else {
+            self.record_unknown_agent(agent_id);
+            return ProcessOutcome::Ignored {
+                reason: format!("未配置的应用：{agent_id}"),
+            };
+        };
+
         // 获取请求Body结构体
         let body: CallbackRequestBody = match from_str(&body) {
             Err(e) => {
                 tracing::error!("[{agent_id}] 解析Body出错。终止当前操作。{e}");
-                return;
+                return ProcessOutcome::Error(format!("解析Body出错。{e}"));
             }
             Ok(b) => b,
         };
 
-        // 谁可以校验此请求？
-        let Some(crypto_agent) = self.crypto_agents.get(&agent_id) else {
-            tracing::error!("[{agent_id}] 加解密代理不存在。终止当前操作。");
-            return;
-        };
-
         // 消息被篡改？
         if crypto_agent.generate_signature(vec![
             &params.timestamp,
@@ -199,89 +757,191 @@ impl Agent {
         ]) != params.msg_signature
         {
             tracing::error!("[{agent_id}] 签名校验失败。数据可能被篡改。终止当前操作。");
-            return;
+            return ProcessOutcome::Error("签名校验失败。".to_string());
         }
 
         // 加密的内容是什么？
         let decrypt_result = match crypto_agent.decrypt(&body.encrypted_str) {
             Err(e) => {
                 tracing::error!("[{agent_id}] 解密用户数据失败。终止当前操作。{e}");
-                return;
+                return ProcessOutcome::Error(format!("解密用户数据失败。{e}"));
             }
             Ok(x) => x,
         };
         let msg_content = match from_str::<AppMessageContent>(&decrypt_result.text) {
             Err(e) => {
                 tracing::error!("[{agent_id}] 解析xml失败。终止当前操作。{e}");
-                return;
+                return ProcessOutcome::Error(format!("解析xml失败。{e}"));
             }
             Ok(x) => x,
         };
         tracing::debug!("User message parsed");
+        self.process_message(agent_id, msg_content).await
+    }
+
+    // 处理解密后的用户消息。回复的发送失败仅记录在日志中，不影响处理结果。
+    async fn process_message(
+        &self,
+        agent_id: u64,
+        mut msg_content: AppMessageContent,
+    ) -> ProcessOutcome {
+        // 企业微信重复推送的消息？事件没有MsgId，不做去重。
+        if !msg_content.msg_id.is_empty() && !self.dedup.first_seen(&msg_content.msg_id) {
+            tracing::info!("[{agent_id}] 忽略重复消息：{}", msg_content.msg_id);
+            return ProcessOutcome::Ignored {
+                reason: format!("重复消息：{}", msg_content.msg_id),
+            };
+        }
+
+        // 菜单点击事件转换为预设的回复，或者视为用户发来的消息
+        if msg_content.msg_type == "event" {
+            match self.menu_action(agent_id, &msg_content) {
+                None => {
+                    tracing::debug!("[{agent_id}] 忽略事件：{:?}", msg_content.event);
+                    return ProcessOutcome::Ignored {
+                        reason: format!("事件：{:?}", msg_content.event),
+                    };
+                }
+                Some(MenuAction::Reply(text)) => {
+                    self.log_n_reply(&text, &msg_content).await;
+                    return ProcessOutcome::Replied;
+                }
+                Some(MenuAction::Prompt(prompt)) => {
+                    msg_content.content = prompt;
+                    msg_content.msg_type = "text".to_string();
+                }
+            }
+        }
+
+        // 助手是否接受此类消息？
+        if let Some(reason) = self.content_type_block_reason(agent_id, &msg_content) {
+            self.log_n_reply(&reason, &msg_content).await;
+            return ProcessOutcome::Blocked { reason };
+        }
 
         // 首先验证消息发送者。若用户不存在，则尝试创建该用户。若用户逾期，则返回具体金额。
         let guest_name: &str = msg_content.from_user_name.as_str();
-        let overdue: f64 = match self.accountant.verify_guest(guest_name) {
+        let overdue: Option<f64> = match self.accountant.verify_guest(guest_name) {
             Err(AccountError::Internal(e)) => {
                 tracing::error!("[{agent_id}] 验证用户失败。终止当前操作。{e}");
-                return;
+                return ProcessOutcome::Error(format!("验证用户失败。{e}"));
             }
-            Err(AccountError::Overdue(credit)) => credit,
+            Err(AccountError::Overdue(credit)) => Some(credit),
             Err(AccountError::NotFound) => {
                 tracing::warn!("[{agent_id}] 用户不存在。将注册用户：{guest_name}");
                 let new_guest = Guest {
                     name: guest_name.to_owned(),
                     credit: 0.0,
                     admin: false,
+                    permissions: 0,
                 };
-                if let Err(e) = self.accountant.register(&new_guest) {
-                    tracing::error!("[{agent_id}] 注册用户失败。终止当前操作。{e}");
-                    return;
+                match self.accountant.register_if_absent(&new_guest) {
+                    Err(e) => {
+                        tracing::error!("[{agent_id}] 注册用户失败。终止当前操作。{e}");
+                        return ProcessOutcome::Error(format!("注册用户失败。{e}"));
+                    }
+                    Ok(true) => tracing::info!("[{agent_id}] 注册用户成功：{guest_name}"),
+                    // 通讯录事件等其他请求抢先完成了注册，沿用已有账户
+                    Ok(false) => tracing::info!("[{agent_id}] 用户已由其他请求注册：{guest_name}"),
                 }
-                tracing::info!("[{agent_id}] 注册用户成功：{guest_name}");
-                0.0
+                None
             }
-            Ok(_) => 0.0,
+            Ok(_) => None,
         };
         let Ok(guest) = self.accountant.get_guest(guest_name) else {
             tracing::error!("[{agent_id}] 获取用户失败。终止当前操作。");
-            return;
+            return ProcessOutcome::Error("获取用户失败。".to_string());
         };
 
+        // 用户确认了先前暂存的高消耗消息？
+        let mut message = msg_content.content.clone();
+        let confirmed = message.trim() == "#确认";
+        if confirmed {
+            let Some(held) = self.pending_chats.take(&guest.name) else {
+                self.log_n_reply("没有待确认的消息，或确认已超时。", &msg_content)
+                    .await;
+                return ProcessOutcome::CommandHandled;
+            };
+            message = held;
+        }
+
         // 是指令消息吗？指令消息需要无条件响应。
         // 管理员指令来自管理员(Guest::admin=true)，并且匹配管理员指令格式：$$指令内容$$
         // 用户指令来自普通用户(Guest::admin=false)，并且匹配用户指令格式：#指令内容
         // 所有的指令操作均需要保留日志。
-        let msg_str = msg_content.content.as_str();
-        if (msg_str.trim().starts_with("$$") && msg_str.trim().ends_with("$$"))
-            || msg_str.starts_with('#')
+        let msg_str = message.as_str();
+        if !confirmed
+            && ((msg_str.trim().starts_with("$$") && msg_str.trim().ends_with("$$"))
+                || msg_str.starts_with('#'))
         {
             tracing::debug!("[{agent_id}] Got instruct message, going to handle it..");
-            let sys_msg = self.handle_instruction_msg(&guest, agent_id, &msg_content.content);
+            let sys_msg = self
+                .handle_instruction_msg(&guest, agent_id, &msg_content.content)
+                .await;
             self.log_n_reply(&sys_msg, &msg_content).await;
-            return;
+            return ProcessOutcome::CommandHandled;
         }
 
         // 用户是否可以使用本服务？
-        if overdue < 0.0 {
-            self.log_n_reply(&format!("账户余额不足。当前余额{overdue:.3}"), &msg_content)
-                .await;
-            return;
+        if let Some(reason) = self.chat_block_reason(overdue) {
+            self.log_n_reply(&reason, &msg_content).await;
+            return ProcessOutcome::Blocked { reason };
         }
 
         // 谁来处理常规用户消息？
-        let Some(assistant) = self.assistants.get(&agent_id) else {
+        let apps = self.apps();
+        let Some(assistant) = apps.assistants.get(&agent_id) else {
             tracing::error!("[{agent_id}] 助手不存在。终止当前操作。");
-            return;
+            return ProcessOutcome::Error(format!("助手不存在：{agent_id}"));
+        };
+
+        // 预计消耗过高的消息需要用户确认
+        if !confirmed {
+            if let Some(notice) = self.hold_if_expensive(&guest, assistant, &message) {
+                self.log_n_reply(&notice, &msg_content).await;
+                return ProcessOutcome::Blocked { reason: notice };
+            }
+        }
+
+        // 开启分段发送时以流式方式获取回复，生成过程中将已有内容分段发送给用户
+        let (result, (_, relayed)) = match &self.progressive {
+            None => (
+                assistant.converse(&guest, &message, None).await,
+                (0, String::new()),
+            ),
+            Some(progressive) => {
+                let (chunks, receiver) = mpsc::unbounded_channel();
+                tokio::join!(
+                    assistant.converse(&guest, &message, Some(chunks)),
+                    self.relay_progress(progressive, receiver, &msg_content)
+                )
+            }
         };
-        let reply_msg = match assistant.chat(&guest, &msg_content.content).await {
+        let reply_msg = match result {
+            // 内容过滤不属于故障，告知用户后结束，不扣费
+            // 助手的费用上限由全部用户共享，达到上限时告知用户，不扣费
+            Err(AssistantError::CapExceeded(reason)) => {
+                self.log_n_reply(&reason, &msg_content).await;
+                return ProcessOutcome::Blocked { reason };
+            }
+            Err(AssistantError::ContentFiltered(category)) => {
+                tracing::info!(
+                    "[{agent_id}] 用户{}的消息被内容过滤拦截。类别：{category}",
+                    guest.name
+                );
+                self.log_n_reply(&self.content_filter_reply, &msg_content)
+                    .await;
+                return ProcessOutcome::Blocked {
+                    reason: self.content_filter_reply.clone(),
+                };
+            }
             Err(e) => {
                 self.log_n_reply(
                     format!("获取AI回复失败。请稍后尝试，或者联系管理员处理。{e}").as_str(),
                     &msg_content,
                 )
                 .await;
-                return;
+                return ProcessOutcome::Error(format!("获取AI回复失败。{e}"));
             }
             Ok(m) => m,
         };
@@ -294,114 +954,818 @@ impl Agent {
                 "[{agent_id}] 更新用户账户失败。终止当前操作。{}, {e}",
                 guest.name
             );
-            return;
+            return ProcessOutcome::Error(format!("更新用户账户失败。{e}"));
         }
         tracing::debug!(
             "[{agent_id}] User {} charged {}",
             guest.name,
             reply_msg.cost()
         );
+        self.record_cost(&guest, agent_id, &reply_msg);
 
-        // 回复给用户
-        let content = WecomText::new(reply_msg.content().to_owned());
-        if let Err(e) = self.reply(content, &msg_content).await {
-            tracing::error!("[{agent_id}] 回复用户消息失败。{e}");
+        // 回复给用户。余额偏低时附带提醒。已分段发送的内容不再重复发送。
+        let content = reply_msg.content();
+        let rest = content.strip_prefix(relayed.as_str()).unwrap_or(content);
+        let mut reply = assistant.decorate_reply(&message, rest);
+        if let Some(notice) = reply_msg.notice() {
+            reply = format!("{notice}\n\n{reply}");
+        }
+        if let Some(notice) = self.low_balance_notice(&guest, guest_to_update.credit) {
+            reply = format!("{reply}\n\n{notice}");
+        }
+        let texts = match relayed.is_empty() {
+            true => vec![self.reply_text(&msg_content, &reply)],
+            false => self.closing_texts(&msg_content, &reply),
+        };
+        for text in texts {
+            if let Err(e) = self.reply(WecomText::new(text), &msg_content).await {
+                tracing::error!("[{agent_id}] 回复用户消息失败。{e}");
+            }
         }
+        ProcessOutcome::Replied
     }
 
-    // 向用户回复一条消息。消息内容content需要满足WecomMessage。
-    async fn reply<T>(&self, content: T, msg_content: &AppMessageContent) -> Result<(), Error>
-    where
-        T: Serialize + WecomMessage,
-    {
-        let agent_id = msg_content
-            .agent_id
-            .parse::<u64>()
-            .map_err(|e| Error(format!("解析agent_id出错。{e}")))?;
-        let msg = WecomMsgBuilder::default()
-            .to_users(vec![&msg_content.from_user_name])
-            .from_agent(agent_id as usize)
-            .build(content)
-            .map_err(|e| Error(format!("构建微信消息时出错。{e}")))?;
+    // 将流式回复的进度分段发送给用户，返回发送的消息数与已发送的内容。
+    // 每次只发送上次发送之后新生成的内容，超出单条消息的长度上限时拆分为多条。
+    // 分段内容在计费之前发送：回复随后失败或被内容过滤拦截时，用户已看到部分内容，但不扣费。
+    // 其余内容由调用者在计费后另行发送。
+    async fn relay_progress(
+        &self,
+        progressive: &ProgressiveSend,
+        mut chunks: UnboundedReceiver<String>,
+        msg_content: &AppMessageContent,
+    ) -> (usize, String) {
+        let mut relayed = String::new();
+        let mut sent = 0;
 
-        // 发送该消息
-        tracing::debug!("Sending message to {} ...", msg_content.from_user_name);
-        let Some(messenger) = self.messengers.get(&agent_id) else {
-            return Err(Error(format!("找不到可用的消息代理。 {agent_id}")));
-        };
-        let response = messenger
-            .send(msg)
-            .await
-            .map_err(|e| Error(format!("调用发送消息API失败。{e}")))?;
+        // 提及用户与进度标记同样占用单条消息的长度。余量容不下一个字符时放弃分段发送。
+        let overhead = self.reply_text(msg_content, PROGRESS_PENDING_MARK).len();
+        let budget = WECOM_TEXT_MAX_BYTES.saturating_sub(overhead);
+        if budget < MAX_CHAR_BYTES {
+            tracing::warn!(
+                "提及{}后单条消息已无余量，本次回复不分段发送。",
+                msg_content.from_user_name
+            );
+            while chunks.recv().await.is_some() {}
+            return (sent, relayed);
+        }
 
-        // 发送成功，但是服务器返回错误。
-        if response.is_error() {
-            return Err(Error(format!(
-                "发送消息后收到异常信息。 {}, {}",
-                response.error_code(),
-                response.error_msg()
-            )));
+        let mut unsent = String::new();
+        let mut last_sent = Instant::now();
+        while let Some(chunk) = chunks.recv().await {
+            unsent.push_str(&chunk);
+            let elapsed = last_sent.elapsed();
+            if elapsed < progressive.min_interval
+                || (estimate_tokens(&unsent) < progressive.every_tokens
+                    && elapsed < progressive.every)
+            {
+                continue;
+            }
+            for piece in split_bytes(&unsent, budget) {
+                let progress = format!("{piece}{PROGRESS_PENDING_MARK}");
+                let content = WecomText::new(self.reply_text(msg_content, &progress));
+                if let Err(e) = self.reply(content, msg_content).await {
+                    tracing::error!("分段发送回复失败。{e}");
+                }
+                sent += 1;
+            }
+            relayed.push_str(&unsent);
+            unsent.clear();
+            last_sent = Instant::now();
         }
-        Ok(())
+        (sent, relayed)
     }
 
-    // 回复消息。并将消息内容记录在日志中。主要用在系统指令消息处理中。
-    async fn log_n_reply(&self, msg: &str, msg_content: &AppMessageContent) {
-        tracing::info!(msg);
-        let content = WecomText::new(msg.to_owned());
-        if let Err(e) = self.reply(content, msg_content).await {
-            tracing::error!("发送系统消息时出错。{e}");
+    // 分段发送后的收尾消息。按单条消息的长度上限拆分，最后一条附带完毕标记。
+    fn closing_texts(&self, msg_content: &AppMessageContent, reply: &str) -> Vec<String> {
+        let overhead = self.reply_text(msg_content, PROGRESS_COMPLETE_MARK).len();
+        let budget = WECOM_TEXT_MAX_BYTES.saturating_sub(overhead);
+        let mut pieces: Vec<String> = split_bytes(reply, budget)
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+        match pieces.last_mut() {
+            Some(last) => last.push_str(PROGRESS_COMPLETE_MARK),
+            None => pieces.push(PROGRESS_COMPLETE_MARK.trim_start().to_owned()),
         }
+        pieces
+            .iter()
+            .map(|p| self.reply_text(msg_content, p))
+            .collect()
     }
 
-    // 处理指令消息
-    // 管理员指令内容："用户名 操作名 操作内容"。例如"小白 充值 3.5"。
-    // 常规用户指令内容："查余额"、"查消耗"、"新会话"
-    fn handle_instruction_msg(
-        &self,
-        guest: &Guest,
-        assistant_id: u64,
-        instruction: &str,
-    ) -> String {
-        // 指令角色？
-        if guest.admin && instruction.starts_with('$') {
-            let msg = instruction.trim_matches('$');
-            let args: Vec<&str> = msg.split(' ').collect();
+    // 菜单点击事件对应的操作。非点击事件或未配置的按钮返回None。
+    fn menu_action(&self, agent_id: u64, msg_content: &AppMessageContent) -> Option<MenuAction> {
+        if msg_content.event.as_deref() != Some("click") {
+            return None;
+        }
+        let key = msg_content.event_key.as_deref()?;
+        let apps = self.apps();
+        let action = apps.assistants.get(&agent_id)?.menu_action(key);
+        if action.is_none() {
+            tracing::warn!("[{agent_id}] 未配置的菜单按钮：{key}");
+        }
+        action.cloned()
+    }
 
-            // 指令内容时什么，及如何回复？
-            match args[..] {
-                ["help"] => "当前支持指令：\n查用户：查询全部用户\n用户名 充值 金额：为用户账户充值指定金额\n用户名 管理员 true/false：设定某用户的管理员角色\n用户名 删除：删除指定用户"
+    // 消息类型不被助手接受时的回复内容。返回None表示可以继续处理。
+    fn content_type_block_reason(
+        &self,
+        agent_id: u64,
+        msg_content: &AppMessageContent,
+    ) -> Option<String> {
+        let apps = self.apps();
+        let assistant = apps.assistants.get(&agent_id)?;
+        (!assistant.accepts(&msg_content.msg_type)).then(|| "该助手不支持此类消息".to_string())
+    }
+
+    // 常规聊天消息被拒绝的原因。返回None表示可以继续处理。
+    // overdue为账户已降至透支下限时的余额，由Accountant::verify_guest判定。
+    fn chat_block_reason(&self, overdue: Option<f64>) -> Option<String> {
+        if self.maintenance.load(Ordering::Relaxed) {
+            return Some("系统维护中，请稍后再试".to_string());
+        }
+        if let Some(credit) = overdue {
+            return Some(format!("账户余额不足。当前余额{}", format_credit(credit)));
+        }
+        None
+    }
+
+    // 余额低于预警阈值时的提醒内容。用户自定义的阈值优先于全局配置。
+    // 处于透支额度内时总是提醒。
+    fn low_balance_notice(&self, guest: &Guest, credit: f64) -> Option<String> {
+        let grace = self.accountant.grace_credit();
+        if credit < 0.0 && credit > grace {
+            return Some(format!(
+                "余额提醒：当前余额{}，已进入透支额度。余额降至{}及以下时将暂停服务，请及时充值。",
+                format_credit(credit),
+                format_credit(grace)
+            ));
+        }
+        let custom = self.accountant.alert_threshold(guest).unwrap_or_else(|e| {
+            tracing::warn!("获取用户{}的预警阈值失败：{e}", guest.name);
+            None
+        });
+        let threshold = custom.or(self.low_balance_alert)?;
+        (credit < threshold).then(|| {
+            format!(
+                "余额提醒：当前余额{}，已低于{}。",
+                format_credit(credit),
+                format_credit(threshold)
+            )
+        })
+    }
+
+    // 助手及其供应商的简要健康状态
+    fn service_status(&self, assistant: &Assistant) -> String {
+        let (_, name, health) = assistant.provider_health();
+        let circuit = if health.circuit_open() {
+            format!(
+                "降级（供应商{name}连续失败{}次）",
+                health.consecutive_failures
+            )
+        } else {
+            "正常".to_string()
+        };
+        let rate = match health.success_rate() {
+            Some(r) => format!("{:.1}%", r * 100.0),
+            None => "暂无记录".to_string(),
+        };
+        let maintenance = if self.maintenance.load(Ordering::Relaxed) {
+            "开启"
+        } else {
+            "关闭"
+        };
+        let mut status =
+            format!("服务状态：{circuit}\n调用成功率：{rate}\n维护模式：{maintenance}");
+        if let Some(id) = &health.last_request_id {
+            status.push_str(&format!("\n最近请求ID：{id}"));
+        }
+        status
+    }
+
+    // 设定用户的余额预警阈值
+    fn set_alert_threshold(&self, guest: &Guest, value: &str) -> String {
+        let threshold = match value {
+            "默认" => None,
+            v => match v.parse::<f64>() {
+                Ok(t) if t >= 0.0 => Some(t),
+                _ => return "预警阈值应为非负数字，或“默认”。".to_string(),
+            },
+        };
+        match self.accountant.set_alert_threshold(guest, threshold) {
+            Err(e) => format!("设置预警阈值失败。{e}"),
+            Ok(_) => match threshold {
+                Some(t) => format!("设置成功。{}的余额低于{t}时将收到提醒。", guest.name),
+                None => format!("已恢复{}的默认预警阈值。", guest.name),
+            },
+        }
+    }
+
+    // 若消息的预计费用超过阈值，则暂存该消息并返回需要发送给用户的确认提示。
+    fn hold_if_expensive(
+        &self,
+        guest: &Guest,
+        assistant: &Assistant,
+        message: &str,
+    ) -> Option<String> {
+        let threshold = self.confirm_cost_threshold?;
+        let estimate = assistant.estimate_cost(message);
+        if estimate <= threshold {
+            return None;
+        }
+        self.pending_chats.hold(&guest.name, message.to_owned());
+        Some(format!(
+            "本条消息预计花费{estimate:.3}，超过{threshold:.3}。如需继续，请在{}秒内回复 #确认",
+            self.pending_chats.ttl().as_secs()
+        ))
+    }
+
+    // 回复给用户的文本。助手配置了提及发送者时，在开头@该用户。
+    fn reply_text(&self, msg_content: &AppMessageContent, text: &str) -> String {
+        let apps = self.apps();
+        let mention = msg_content
+            .agent_id
+            .parse::<u64>()
+            .ok()
+            .and_then(|id| apps.assistants.get(&id))
+            .is_some_and(|a| a.mention_sender());
+        if mention {
+            format!("<@{}> {text}", msg_content.from_user_name)
+        } else {
+            text.to_owned()
+        }
+    }
+
+    // 向用户回复一条消息。消息内容content需要满足WecomMessage。
+    async fn reply<T>(&self, content: T, msg_content: &AppMessageContent) -> Result<(), Error>
+    where
+        T: Serialize + WecomMessage,
+    {
+        let agent_id = msg_content
+            .agent_id
+            .parse::<u64>()
+            .map_err(|e| Error(format!("解析agent_id出错。{e}")))?;
+        tracing::debug!("Sending message to {} ...", msg_content.from_user_name);
+        self.send_to(agent_id, vec![&msg_content.from_user_name], content)
+            .await
+    }
+
+    // 通过指定应用向一组用户发送同一条消息。用户数量不可超过MAX_RECIPIENTS_PER_SEND。
+    async fn send_to<T>(&self, agent_id: u64, users: Vec<&str>, content: T) -> Result<(), Error>
+    where
+        T: Serialize + WecomMessage,
+    {
+        let msg = WecomMsgBuilder::default()
+            .to_users(users)
+            .from_agent(agent_id as usize)
+            .build(content)
+            .map_err(|e| Error(format!("构建微信消息时出错。{e}")))?;
+
+        // 发送该消息
+        let apps = self.apps();
+        let Some(messenger) = apps.messengers.get(&agent_id) else {
+            return Err(Error(format!("找不到可用的消息代理。 {agent_id}")));
+        };
+        let response = messenger
+            .send(msg)
+            .await
+            .map_err(|e| Error(format!("调用发送消息API失败。{e}")))?;
+
+        // 发送成功，但是服务器返回错误。
+        if response.is_error() {
+            return Err(Error(format!(
+                "发送消息后收到异常信息。 {}, {}",
+                response.error_code(),
+                response.error_msg()
+            )));
+        }
+        Ok(())
+    }
+
+    // 执行经管理员确认的操作
+    fn execute_admin_action(&self, guest: &Guest, action: AdminAction) -> String {
+        tracing::warn!("{}确认执行：{action:?}", guest.name);
+        match action {
+            AdminAction::Prune { days } => {
+                let before = Utc::now().naive_utc() - chrono::Duration::days(days as i64);
+                match self.accountant.prune_conversations(before) {
+                    Err(e) => format!("清理数据失败。{e}"),
+                    Ok(n) => format!("已清理{n}个{days}天前的非活跃会话。"),
+                }
+            }
+        }
+    }
+
+    // 设定用户偏好的回复语言
+    fn set_language(&self, guest: &Guest, language: &str) -> String {
+        if language.is_empty() {
+            return format!(
+                "可选语言：{}。恢复默认请发送：#语言 默认",
+                self.languages.join("、")
+            );
+        }
+        let choice = match language {
+            "默认" => None,
+            l => match self.languages.iter().find(|x| x.eq_ignore_ascii_case(l)) {
+                Some(x) => Some(x.as_str()),
+                None => return format!("暂不支持该语言。可选语言：{}", self.languages.join("、")),
+            },
+        };
+        match self.accountant.set_language(guest, choice) {
+            Err(e) => format!("设置语言失败。{e}"),
+            Ok(_) => match choice {
+                Some(l) => format!("设置成功。AI将使用{l}回复。"),
+                None => "已恢复默认语言。".to_string(),
+            },
+        }
+    }
+
+    // 用户自己的账户信息
+    fn whoami(&self, guest: &Guest) -> String {
+        let guest = match self.accountant.get_guest(&guest.name) {
+            Ok(g) => g,
+            Err(e) => return format!("获取账户信息失败。{e}"),
+        };
+        let role = if guest.admin {
+            "管理员"
+        } else if guest.is_operator() {
+            "部分管理权限"
+        } else {
+            "普通用户"
+        };
+        format!(
+            "用户名：{}\n余额：{}\n身份：{role}",
+            guest.name,
+            guest.formatted_credit()
+        )
+    }
+
+    // 设定用户当前会话的采样温度
+    fn set_temperature(&self, guest: &Guest, assistant: &Assistant, value: &str) -> String {
+        let temperature = match value {
+            "默认" => None,
+            v => match v.parse::<f64>() {
+                Ok(t) if TEMPERATURE_RANGE.contains(&t) => Some(t),
+                _ => return "温度应为0到2之间的数字，或“默认”。".to_string(),
+            },
+        };
+        match assistant.set_temperature(guest, temperature) {
+            Err(e) => format!("设置温度失败。{e}"),
+            Ok(_) => match temperature {
+                Some(t) => format!("设置成功。当前会话的温度为{t}。"),
+                None => "已恢复默认温度。".to_string(),
+            },
+        }
+    }
+
+    // 清除用户当前会话的设置（目前仅有温度），并列出被清除的项目
+    fn clear_settings(&self, guest: &Guest, assistant: &Assistant) -> String {
+        let cleared = match assistant.clear_settings(guest) {
+            Err(e) => return format!("清除设置失败。{e}"),
+            Ok(s) => s,
+        };
+        let mut items: Vec<String> = Vec::new();
+        if let Some(t) = cleared.temperature {
+            items.push(format!("温度{t}"));
+        }
+        if items.is_empty() {
+            "当前会话没有自定义设置。".to_string()
+        } else {
+            format!("已恢复默认设置。清除的项目：{}", items.join("、"))
+        }
+    }
+
+    // 按照指定顺序分页列出用户
+    fn list_guests(&self, order: &str, page: &str) -> String {
+        let order = match order {
+            "名称" => UserOrder::Name,
+            "余额" => UserOrder::Credit,
+            "注册时间" => UserOrder::CreatedAt,
+            _ => return "排序方式应为：名称、余额或注册时间".to_string(),
+        };
+        let Ok(page) = page.parse::<i64>() else {
+            return "页码解析出错".to_string();
+        };
+        if page < 1 {
+            return "页码应从1开始".to_string();
+        }
+        let Some(offset) = (page - 1).checked_mul(GUESTS_PER_PAGE) else {
+            return "页码超出范围".to_string();
+        };
+        let Ok((guests, total)) = self
+            .accountant
+            .get_guests_page(order, GUESTS_PER_PAGE, offset)
+        else {
+            return "无法从数据库中获得用户".to_string();
+        };
+        let mut msg = format!("共{total}名用户，第{page}页：\n");
+        for g in &guests {
+            msg.push_str(format!("{} {} {}\n", g.name, g.formatted_credit(), g.admin).as_str());
+        }
+        msg.trim().to_owned()
+    }
+
+    // 分页列出用户与当前助手的会话
+    fn list_conversations(&self, guest: &Guest, assistant_id: u64, page: &str) -> String {
+        let page = match page {
+            "" => 1,
+            p => match p.parse::<i64>() {
+                Ok(n) if n >= 1 => n,
+                _ => return "页码应为从1开始的整数".to_string(),
+            },
+        };
+        let Some(offset) = (page - 1).checked_mul(CONVERSATIONS_PER_PAGE) else {
+            return "页码超出范围".to_string();
+        };
+        let (convs, total) = match self.accountant.list_conversations(
+            guest,
+            assistant_id,
+            CONVERSATIONS_PER_PAGE,
+            offset,
+        ) {
+            Ok(r) => r,
+            Err(e) => return format!("获取会话列表失败。{e}"),
+        };
+        if total == 0 {
+            return "暂无会话记录。".to_string();
+        }
+        let pages = (total as u64).div_ceil(CONVERSATIONS_PER_PAGE as u64);
+        let mut msg = format!("共{total}段会话，第{page}/{pages}页：\n");
+        for c in &convs {
+            msg.push_str(&format!(
+                "[{}]{}{}{} {}条消息，最后更新于{}\n",
+                c.id,
+                if c.active { "（当前）" } else { "" },
+                if c.pinned { "（置顶）" } else { "" },
+                c.title
+                    .as_deref()
+                    .map(|t| format!(" {t}"))
+                    .unwrap_or_default(),
+                c.messages,
+                c.updated_at.format("%Y-%m-%d %H:%M")
+            ));
+        }
+        msg.trim().to_owned()
+    }
+
+    // 记录用户对当前会话中上一条AI回复的反馈
+    fn add_feedback(
+        &self,
+        guest: &Guest,
+        assistant: &Assistant,
+        assistant_id: u64,
+        content: &str,
+    ) -> String {
+        if content.is_empty() {
+            return "反馈内容不可为空。用法：#反馈 内容".to_string();
+        }
+        let Some(reply) = assistant.last_reply(guest) else {
+            return "当前会话中没有可反馈的回复。".to_string();
+        };
+        let reply = truncate_chars(&reply, FEEDBACK_REPLY_CHARS);
+        match self
+            .accountant
+            .add_feedback(guest, assistant_id, content, reply)
+        {
+            Err(e) => format!("提交反馈失败。{e}"),
+            Ok(_) => "感谢反馈，管理员将尽快查看。".to_string(),
+        }
+    }
+
+    // 以指定用户的身份与会话上下文获取AI回复，回复给管理员。
+    // 不写入该用户的会话记录；按配置决定是否向该用户扣费。
+    async fn impersonate(
+        &self,
+        operator: &Guest,
+        assistant_id: u64,
+        name: &str,
+        content: &str,
+    ) -> String {
+        if content.is_empty() {
+            return "消息内容不可为空。用法：$$模拟 用户名 消息$$".to_string();
+        }
+        let Ok(target) = self.accountant.get_guest(name) else {
+            return format!("用户不存在：{name}");
+        };
+        let apps = self.apps();
+        let Some(assistant) = apps.assistants.get(&assistant_id) else {
+            tracing::error!("助手不存在。终止当前操作。agent_id: {assistant_id}");
+            return "内部错误，请稍后再试。".to_string();
+        };
+        tracing::warn!("{}模拟用户{name}发送消息", operator.name);
+        let response = match assistant.preview(operator, &target, content).await {
+            Err(e) => return format!("获取AI回复失败。{e}"),
+            Ok(r) => r,
+        };
+        let cost = response.cost();
+        let billing = if self.impersonation_charges_user {
+            let mut charged = target.clone();
+            charged.credit -= cost;
+            if let Err(e) = self.accountant.update_guest(&charged) {
+                return format!("更新用户账户失败。{e}");
+            }
+            self.record_cost(&target, assistant_id, &response);
+            format!("已由{name}承担")
+        } else {
+            // 费用仍计入计费记录，由操作者承担
+            self.record_cost(operator, assistant_id, &response);
+            format!("未向{name}扣费")
+        };
+        format!(
+            "以{name}的身份获取的回复：\n{}\n\n费用{}，{billing}。",
+            response.content(),
+            format_credit(cost)
+        )
+    }
+
+    // 配置了计费记录时，追加一轮计费
+    fn record_cost(&self, guest: &Guest, agent_id: u64, response: &impl ChatResponse) {
+        if let Some(log) = &self.cost_log {
+            log.record(&CostRecord {
+                timestamp: Utc::now().naive_utc(),
+                guest: &guest.name,
+                agent_id,
+                prompt_tokens: response.prompt_tokens(),
+                completion_tokens: response.completion_tokens(),
+                cost: response.cost(),
+            });
+        }
+    }
+
+    /// 将缓冲的计费记录写入文件
+    pub fn flush(&self) {
+        if let Some(log) = &self.cost_log {
+            if let Err(e) = log.flush() {
+                tracing::error!("写入计费记录文件失败。{e}");
+            }
+        }
+    }
+
+    // 由新到旧分页列出用户反馈
+    fn list_feedback(&self, page: &str) -> String {
+        let page = match page.parse::<i64>() {
+            Ok(n) if n >= 1 => n,
+            _ => return "页码应为从1开始的整数".to_string(),
+        };
+        let Some(offset) = (page - 1).checked_mul(FEEDBACK_PER_PAGE) else {
+            return "页码超出范围".to_string();
+        };
+        let (entries, total) = match self.accountant.recent_feedback(FEEDBACK_PER_PAGE, offset) {
+            Ok(r) => r,
+            Err(e) => return format!("获取反馈列表失败。{e}"),
+        };
+        if total == 0 {
+            return "暂无用户反馈。".to_string();
+        }
+        let pages = (total as u64).div_ceil(FEEDBACK_PER_PAGE as u64);
+        let mut msg = format!("共{total}条反馈，第{page}/{pages}页：\n");
+        for f in &entries {
+            msg.push_str(&format!(
+                "[{}] {}（应用{}）：{}\n  回复：{}\n",
+                f.created_at.format("%Y-%m-%d %H:%M"),
+                f.guest_name,
+                f.assistant_id,
+                f.content,
+                f.reply
+            ));
+        }
+        msg.trim().to_owned()
+    }
+
+    // 向全部用户广播一条消息。接收人按照单次发送上限分批发送。
+    async fn broadcast(&self, agent_id: u64, content: &str) -> String {
+        let guests = match self.accountant.get_guests() {
+            Ok(g) => g,
+            Err(e) => return format!("无法从数据库中获得用户。{e}"),
+        };
+        let names: Vec<String> = guests.into_iter().map(|g| g.name).collect();
+        let apps = self.apps();
+        let api = apps.wecom_apis.get(&agent_id);
+        let (delivered, failed) = send_in_batches(&names, |batch| async move {
+            let Some(api) = api else {
+                return Err(Error(format!("找不到可用的消息代理。 {agent_id}")));
+            };
+            api.send_text(agent_id, &batch, content).await
+        })
+        .await;
+        if failed.is_empty() {
+            format!("广播完成。共发送给{delivered}名用户。")
+        } else {
+            format!(
+                "广播完成。成功{delivered}名，失败{}名：{}",
+                failed.len(),
+                failed.join(",")
+            )
+        }
+    }
+
+    // 回复消息。并将消息内容记录在日志中。主要用在系统指令消息处理中。
+    async fn log_n_reply(&self, msg: &str, msg_content: &AppMessageContent) {
+        tracing::info!(msg);
+        let content = WecomText::new(self.reply_text(msg_content, msg));
+        if let Err(e) = self.reply(content, msg_content).await {
+            tracing::error!("发送系统消息时出错。{e}");
+        }
+    }
+
+    // 处理指令消息
+    // 管理员指令内容："用户名 操作名 操作内容"。例如"小白 充值 3.5"。
+    // 常规用户指令内容："查余额"、"查消耗"、"新会话"
+    async fn handle_instruction_msg(
+        &self,
+        guest: &Guest,
+        assistant_id: u64,
+        instruction: &str,
+    ) -> String {
+        // 指令角色？
+        if guest.is_operator() && instruction.starts_with('$') {
+            let msg = instruction.trim_matches('$');
+            let args: Vec<&str> = msg.split(' ').collect();
+            let command = AdminCommand::parse(&args);
+
+            // 操作者有权执行此指令吗？
+            if let Some(permission) = command.permission() {
+                if !guest.can(permission) {
+                    tracing::warn!("{}无权执行指令：{msg}", guest.name);
+                    return "权限不足。".to_string();
+                }
+            }
+
+            // 指令内容时什么，及如何回复？
+            match command {
+                AdminCommand::Help => "当前支持指令：\n查用户 [名称/余额/注册时间] [页码]：分页查询用户\n用户名 充值 金额：为用户账户充值指定金额\n撤销充值 用户名：撤销该用户最近一笔充值\n用户名 管理员 true/false：设定某用户的管理员角色\n用户名 权限 充值,管理,广播/无：设定某用户的部分管理权限\n用户名 预警 金额/默认：设定某用户的余额预警阈值\n用户名 删除：删除指定用户\n合并 旧用户 新用户：将旧用户的会话与余额并入新用户\n广播 内容：向全部用户发送消息\n维护 开启/关闭：切换维护模式，维护期间暂停AI对话\n清理 天数：删除早于指定天数的非活跃会话，需再次确认\n迁移状态：显示已应用的数据库迁移\n用户数：显示用户总数\n重置全部会话：结束全部用户在本应用中的当前会话\n全局提示词 内容/无：设定未配置提示词的助手所用的提示词\n查全局提示词：显示全局提示词\n人设 名称/默认：切换本应用助手的预设提示词，重启后依然有效\n应用ID 上下文 长度/默认：调整指定应用助手的上下文长度，重启后依然有效\n反馈列表 [页码]：由新到旧列出用户反馈\n模拟 用户名 消息：以该用户的会话为上下文获取AI回复，不写入其会话记录"
                     .to_string(),
-                ["查用户"] => {
-                    let Ok(guests) = self.accountant.get_guests() else {
-                        return "无法从数据库中获得用户".to_string();
+                AdminCommand::ListGuests { order, page } => self.list_guests(order, page),
+                AdminCommand::ListFeedback { page } => self.list_feedback(page),
+                AdminCommand::GuestCount => match self.accountant.guest_count() {
+                    Err(e) => format!("统计用户数量出错：{e}"),
+                    Ok(n) => format!("当前共有{n}名用户。"),
+                },
+                AdminCommand::ResetAllConversations => {
+                    match self.accountant.reset_all_conversations(assistant_id) {
+                        Err(e) => format!("重置会话出错：{e}"),
+                        Ok(n) => {
+                            tracing::warn!("{}重置了应用{assistant_id}的全部会话", guest.name);
+                            format!("已结束{n}个当前会话。用户的下一条消息将开启新会话。")
+                        }
+                    }
+                }
+                AdminCommand::MigrationStatus => match self.accountant.migration_status() {
+                    Err(e) => format!("查询迁移状态出错：{e}"),
+                    Ok(v) => format!("已应用{}个数据库迁移：\n{}", v.len(), v.join("\n")),
+                },
+                AdminCommand::Prune { days } => {
+                    let Ok(days) = days.parse::<u32>() else {
+                        return "天数应为非负整数。".to_string();
+                    };
+                    self.pending_actions
+                        .hold(&guest.name, AdminAction::Prune { days });
+                    format!(
+                        "确认清理{days}天前数据？请在{}秒内回复 $$确认$$",
+                        self.pending_actions.ttl().as_secs()
+                    )
+                }
+                AdminCommand::Confirm => match self.pending_actions.take(&guest.name) {
+                    None => "没有待确认的操作，或操作已过期。".to_string(),
+                    Some(action) => self.execute_admin_action(guest, action),
+                },
+                AdminCommand::Maintenance { value } => {
+                    let on = match value {
+                        "开启" => true,
+                        "关闭" => false,
+                        _ => return "维护模式应为：开启或关闭".to_string(),
                     };
-                    let mut msg = String::new();
-                    for g in &guests {
-                        msg.push_str(format!("{} {} {}\n", g.name, g.credit, g.admin).as_str());
+                    self.maintenance.store(on, Ordering::Relaxed);
+                    tracing::warn!("{}将维护模式设为：{value}", guest.name);
+                    format!("维护模式已{value}。")
+                }
+                AdminCommand::Broadcast => {
+                    let content = msg.trim_start_matches("广播").trim();
+                    if content.is_empty() {
+                        return "广播内容不可为空。".to_string();
+                    }
+                    self.broadcast(assistant_id, content).await
+                }
+                AdminCommand::SetDefaultPrompt => {
+                    let prompt = match msg.trim_start_matches("全局提示词").trim() {
+                        "" => return "提示词不可为空。清除请发送：$$全局提示词 无$$".to_string(),
+                        "无" => None,
+                        p => Some(p),
+                    };
+                    match self.accountant.set_default_prompt(prompt) {
+                        Err(e) => format!("设置全局提示词出错：{e}"),
+                        Ok(_) => {
+                            tracing::warn!("{}将全局提示词设为：{prompt:?}", guest.name);
+                            match prompt {
+                                Some(_) => "全局提示词设置成功。".to_string(),
+                                None => "已清除全局提示词。".to_string(),
+                            }
+                        }
+                    }
+                }
+                AdminCommand::DefaultPrompt => match self.accountant.default_prompt() {
+                    Err(e) => format!("获取全局提示词出错：{e}"),
+                    Ok(Some(p)) => format!("当前全局提示词：{p}"),
+                    Ok(None) => "尚未设置全局提示词。".to_string(),
+                },
+                AdminCommand::Impersonate { name } => {
+                    let content = msg.splitn(3, ' ').nth(2).unwrap_or_default().trim();
+                    self.impersonate(guest, assistant_id, name, content).await
+                }
+                AdminCommand::Persona { name } => {
+                    let apps = self.apps();
+                    let Some(assistant) = apps.assistants.get(&assistant_id) else {
+                        tracing::error!("助手不存在。终止当前操作。agent_id: {assistant_id}");
+                        return "内部错误，请稍后再试。".to_string();
+                    };
+                    // "默认"表示恢复配置的提示词，优先于同名人设
+                    let persona = (name != "默认").then_some(name);
+                    match assistant.switch_persona(persona) {
+                        Err(e) => return format!("切换人设出错：{e}"),
+                        Ok(true) => {}
+                        Ok(false) => {
+                            let names = assistant.persona_names();
+                            if names.is_empty() {
+                                return "本应用尚未配置人设。".to_string();
+                            }
+                            return format!("未知人设：{name}。可选人设：{}", names.join("、"));
+                        }
+                    }
+                    tracing::warn!("{}将应用{assistant_id}的人设切换为：{name}", guest.name);
+                    if persona.is_none() {
+                        return "已恢复默认提示词。".to_string();
+                    }
+                    format!("已切换至人设：{name}。")
+                }
+                AdminCommand::Merge { old_name, new_name } => {
+                    match self.accountant.merge_guests(old_name, new_name) {
+                        Err(e) => format!("合并用户出错：{e}"),
+                        Ok(merged) => {
+                            tracing::warn!("{}将用户{old_name}合并至{new_name}", guest.name);
+                            format!(
+                                "合并成功。{}当前余额：{}",
+                                merged.name,
+                                merged.formatted_credit()
+                            )
+                        }
+                    }
+                }
+                AdminCommand::MaxContext { id, value } => {
+                    let Ok(id) = id.parse::<u64>() else {
+                        return "应用ID应为整数。".to_string();
+                    };
+                    let apps = self.apps();
+                    let Some(assistant) = apps.assistants.get(&id) else {
+                        return format!("应用{id}不存在。");
+                    };
+                    // "默认"表示恢复为供应商上限
+                    let tokens = match value {
+                        "默认" => None,
+                        v => match v.parse::<u64>() {
+                            Ok(tokens) => Some(tokens),
+                            Err(_) => return "上下文长度应为正整数或“默认”。".to_string(),
+                        },
+                    };
+                    match assistant.set_max_context(tokens) {
+                        Err(e) => format!("设置上下文长度出错：{e}"),
+                        Ok(tokens) => {
+                            tracing::warn!("{}将应用{id}的上下文长度设为{tokens}", guest.name);
+                            format!("应用{id}的上下文长度已设为{tokens}。")
+                        }
                     }
-                    msg.trim().to_owned()
                 }
-                [username, "充值", value] => {
+                AdminCommand::Recharge { username, value } => {
                     let Ok(v) = value.parse::<f64>() else {
                         return "用户余额解析出错".to_string();
                     };
-                    // 获取待操作的用户
+                    // 充值并记入流水。用户不存在时一并报错
+                    match self.accountant.recharge(username, v, &guest.name) {
+                        Err(e) => format!("更新用户{username}余额出错。{e}"),
+                        Ok(user) => format!("更新成功。当前余额：{}", user.formatted_credit()),
+                    }
+                }
+                AdminCommand::ReverseRecharge { username } => {
+                    match self.accountant.reverse_last_recharge(username, &guest.name) {
+                        Err(e) => format!("撤销{username}的充值出错。{e}"),
+                        Ok((user, amount)) => {
+                            tracing::warn!("{}撤销了{username}的充值{amount}", guest.name);
+                            format!(
+                                "已撤销充值{}。当前余额：{}",
+                                format_credit(amount),
+                                user.formatted_credit()
+                            )
+                        }
+                    }
+                }
+                AdminCommand::AlertThreshold { username, value } => {
                     let user = match self.accountant.get_guest(username) {
                         Ok(u) => u,
-                        Err(e) => return format!("无法找到用户{}。{}", args[0], e),
-                    };
-                    // 更新用户
-                    let user_to_update = Guest {
-                        credit: user.credit + v,
-                        ..user
+                        Err(e) => return format!("无法找到用户。{e}"),
                     };
-                    match self.accountant.update_guest(&user_to_update) {
-                        Err(e) => format!("更新用户{}余额出错。{e}", args[0]),
-                        Ok(_) => format!("更新成功。当前余额：{}", user_to_update.credit),
-                    }
+                    self.set_alert_threshold(&user, value)
                 }
-                [username, "管理员", value] => {
+                AdminCommand::SetAdmin { username, value } => {
                     let Ok(v) = value.parse::<bool>() else {
                         return "管理员属性解析出错。".to_string();
                     };
@@ -425,7 +1789,33 @@ impl Agent {
                         ),
                     }
                 }
-                [username, "删除"] => {
+                AdminCommand::SetPermissions { username, value } => {
+                    // 解析权限列表
+                    let mut permissions = 0;
+                    if value != "无" {
+                        for name in value.split(',') {
+                            let Some(p) = Permission::from_name(name) else {
+                                return format!("无法识别的权限：{name}");
+                            };
+                            permissions |= p.bit();
+                        }
+                    }
+                    // 获取待操作的用户
+                    let user = match self.accountant.get_guest(username) {
+                        Ok(u) => u,
+                        Err(e) => return format!("无法找到用户。{e}"),
+                    };
+                    // 更新用户
+                    let user_to_update = Guest {
+                        permissions,
+                        ..user
+                    };
+                    match self.accountant.update_guest(&user_to_update) {
+                        Err(e) => format!("更新用户权限出错：{e}"),
+                        Ok(_) => format!("更新成功。{}的权限已设为：{value}", user_to_update.name),
+                    }
+                }
+                AdminCommand::Remove { username } => {
                     // 获取待操作的用户
                     let user = match self.accountant.get_guest(username) {
                         Ok(u) => u,
@@ -437,28 +1827,91 @@ impl Agent {
                         Ok(n) => format!("删除{n}条用户记录。"),
                     }
                 }
-                _ => "未知指令".to_string(),
+                AdminCommand::Unknown => "未知指令".to_string(),
             }
         } else {
             // 常规账户指令
-            let Some(assistant) = self.assistants.get(&assistant_id) else {
+            let apps = self.apps();
+            let Some(assistant) = apps.assistants.get(&assistant_id) else {
                 tracing::error!("助手不存在。终止当前操作。agent_id: {assistant_id}");
                 return "内部错误，请稍后再试。".to_string();
             };
-            match instruction {
-                "#帮助" => "#查余额：显示当前账户余额。\n#查消耗：显示当前会话的资源消耗。\n#新会话：开启全新会话。AI将忘记先前会话的全部内容。"
+            let instruction = self.resolve_alias(instruction);
+            match instruction.as_str() {
+                "#帮助" => "#谁：显示当前识别到的账户信息。\n#查余额：显示当前账户余额。\n#查消耗：显示当前会话的资源消耗。\n#统计：显示全部会话的累计消耗。\n#新会话：开启全新会话。AI将忘记先前会话的全部内容。\n#语言 [语言/默认]：设定AI回复所用的语言。\n#预警 金额/默认：余额低于该金额时提醒。\n#会话列表 [页码]：列出在本应用中的全部会话。\n#置顶：置顶当前会话，使其不被清理或自动替换。\n#取消置顶：取消当前会话的置顶。\n#归档：置顶保留当前会话，并开启新会话。\n#重发：重新发送上一条AI回复，不重复计费。\n#温度 数值/默认：设定当前会话的采样温度，取值0到2。\n#清除设置：清除当前会话的温度设置，恢复默认。\n#状态：显示AI服务的健康状态。\n#反馈 内容：对上一条AI回复提出反馈。"
                     .to_string(),
-                "#查余额" => format!("当前余额：{:.3}", guest.credit),
+                "#谁" => self.whoami(guest),
+                "#查余额" => format!("当前余额：{}", guest.formatted_credit()),
                 "#查消耗" => assistant.audit(guest),
+                "#状态" => self.service_status(assistant),
+                "#统计" => match self.accountant.usage(guest) {
+                    Err(e) => format!("统计用量失败。{e}"),
+                    Ok(u) => format!(
+                        "累计消息{}条，消耗prompt token {}个，completion token {}个，费用{:.3}。",
+                        u.messages, u.prompt_tokens, u.completion_tokens, u.cost
+                    ),
+                },
+                "#新会话" if self
+                    .new_conversation_min_credit
+                    .is_some_and(|min| guest.credit <= min) =>
+                {
+                    format!(
+                        "开启新会话需要账户余额高于{}，当前余额：{}。请充值后再试，或继续当前会话。",
+                        format_credit(self.new_conversation_min_credit.unwrap_or_default()),
+                        guest.formatted_credit()
+                    )
+                }
                 "#新会话" => match assistant.new_conversation(guest) {
                     Err(e) => format!("为{}新建会话记录失败。{}", guest.name, e),
                     Ok(_) => "新会话创建成功。您可以开始对话了。".to_string(),
                 },
+                "#置顶" => match assistant.set_pinned(guest, true) {
+                    Err(e) => format!("置顶会话失败。{e}"),
+                    Ok(_) => "已置顶当前会话。该会话不会被清理或自动替换。".to_string(),
+                },
+                "#取消置顶" => match assistant.set_pinned(guest, false) {
+                    Err(e) => format!("取消置顶失败。{e}"),
+                    Ok(_) => "已取消当前会话的置顶。".to_string(),
+                },
+                "#归档" => match assistant.archive(guest) {
+                    Err(e) => format!("归档会话失败。{e}"),
+                    Ok(_) => "已归档当前会话，并开启新会话。".to_string(),
+                },
+                "#重发" => assistant
+                    .last_reply(guest)
+                    .unwrap_or_else(|| "当前会话中没有可重发的回复。".to_string()),
+                s if s.starts_with("#会话列表") => {
+                    self.list_conversations(guest, assistant_id, s["#会话列表".len()..].trim())
+                }
+                s if s.starts_with("#预警") => {
+                    self.set_alert_threshold(guest, s["#预警".len()..].trim())
+                }
+                s if s.starts_with("#语言") => self.set_language(guest, s["#语言".len()..].trim()),
+                s if s.starts_with("#反馈") => {
+                    self.add_feedback(guest, assistant, assistant_id, s["#反馈".len()..].trim())
+                }
+                "#清除设置" => self.clear_settings(guest, assistant),
+                s if s.starts_with("#温度") => {
+                    self.set_temperature(guest, assistant, s["#温度".len()..].trim())
+                }
                 &_ => "抱歉，暂不支持当前指令。".to_string(),
             }
         }
     }
 
+    // 将指令开头的别名替换为对应的指令，其余参数保持不变
+    fn resolve_alias(&self, instruction: &str) -> String {
+        let (head, rest) = match instruction.split_once(char::is_whitespace) {
+            Some((head, rest)) => (head, Some(rest)),
+            None => (instruction, None),
+        };
+        match (self.command_aliases.get(head), rest) {
+            (Some(command), Some(rest)) => format!("{command} {rest}"),
+            (Some(command), None) => command.clone(),
+            (None, _) => instruction.to_owned(),
+        }
+    }
+
     /// 处理通讯录更新事件
     pub async fn handle_account_creation(&self, params: Query<CallbackParams>, body: String) {
         match self.accountant.handle_user_creation_event(params, body) {
@@ -467,3 +1920,2036 @@ impl Agent {
         };
     }
 }
+
+// 逐个检查各应用能否获取企业微信access_token。失败时指明是哪个应用。
+async fn validate_wecom(config: &Config) -> Result<(), Error> {
+    let corp_id =
+        env::var(&config.wecom.corp_id).map_err(|_| to_local_err(&config.wecom.corp_id))?;
+    for assistant in &config.assistants {
+        let agent_id = assistant.agent_id;
+        let secret = env::var(&assistant.secret).map_err(|_| to_local_err(&assistant.secret))?;
+        WecomApi::new(&config.wecom.api_base, &corp_id, &secret)
+            .access_token()
+            .await
+            .map_err(|e| Error(format!("应用{agent_id}获取access_token失败。{e}")))?;
+        tracing::info!("应用{agent_id}获取access_token成功");
+    }
+    Ok(())
+}
+
+// 直接调用企业微信服务端API。用于需要完整返回结果的场合，如获知哪些用户无法送达。
+struct WecomApi {
+    api_base: String,
+    corp_id: String,
+    secret: String,
+    client: reqwest::Client,
+}
+
+impl WecomApi {
+    fn new(api_base: &str, corp_id: &str, secret: &str) -> Self {
+        Self {
+            api_base: api_base.to_owned(),
+            corp_id: corp_id.to_owned(),
+            secret: secret.to_owned(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    // 获取access_token
+    async fn access_token(&self) -> Result<String, Error> {
+        let response = self
+            .client
+            .get(format!("{}/cgi-bin/gettoken", self.api_base))
+            .query(&[
+                ("corpid", self.corp_id.as_str()),
+                ("corpsecret", self.secret.as_str()),
+            ])
+            .send()
+            .await
+            .map_err(|e| Error(e.without_url().to_string()))?
+            .json::<AccessTokenResponse>()
+            .await
+            .map_err(|e| Error(e.without_url().to_string()))?;
+        if response.errcode != 0 {
+            return Err(Error(format!(
+                "错误码{}：{}",
+                response.errcode, response.errmsg
+            )));
+        }
+        if response.access_token.is_empty() {
+            return Err(Error("返回结果中没有access_token".to_string()));
+        }
+        tracing::debug!("access_token有效期{}秒", response.expires_in);
+        Ok(response.access_token)
+    }
+
+    // 向一批用户发送文本消息，返回企业微信报告无法送达的用户
+    async fn send_text(
+        &self,
+        agent_id: u64,
+        users: &[&str],
+        content: &str,
+    ) -> Result<Vec<String>, Error> {
+        let token = self
+            .access_token()
+            .await
+            .map_err(|e| Error(format!("获取access_token失败。{e}")))?;
+        let body = serde_json::json!({
+            "touser": users.join("|"),
+            "msgtype": "text",
+            "agentid": agent_id,
+            "text": { "content": content },
+        });
+        let response = self
+            .client
+            .post(format!("{}/cgi-bin/message/send", self.api_base))
+            .query(&[("access_token", token.as_str())])
+            .json(&body)
+            .send()
+            .await
+            .map_err(|e| Error(format!("调用发送消息API失败。{}", e.without_url())))?
+            .json::<MessageSendResponse>()
+            .await
+            .map_err(|e| Error(format!("解析发送结果失败。{}", e.without_url())))?;
+        if response.errcode != 0 {
+            return Err(Error(format!(
+                "发送消息后收到异常信息。 {}, {}",
+                response.errcode, response.errmsg
+            )));
+        }
+        Ok(response.invalid_users())
+    }
+}
+
+// 日志中签名保留的字符数，足以比对又不完整暴露
+const SIGNATURE_LOG_CHARS: usize = 8;
+
+// URL验证失败的原因
+#[derive(Debug, PartialEq)]
+enum VerifyError {
+    SignatureMismatch { computed: String, received: String },
+    DecryptFailed(String),
+    EmptyEcho, // 解密成功但内容为空
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SignatureMismatch { computed, received } => write!(
+                f,
+                "签名不匹配。请检查token配置。计算值：{}…，收到：{}…",
+                truncate_chars(computed, SIGNATURE_LOG_CHARS),
+                truncate_chars(received, SIGNATURE_LOG_CHARS)
+            ),
+            Self::DecryptFailed(e) => write!(f, "解密echostr失败。请检查key配置。{e}"),
+            Self::EmptyEcho => write!(f, "解密后的echostr为空。请检查key配置。"),
+        }
+    }
+}
+
+impl VerifyError {
+    // 返回给企业微信服务器的状态码
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::SignatureMismatch { .. } => StatusCode::BAD_REQUEST,
+            Self::DecryptFailed(_) | Self::EmptyEcho => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// 校验签名，并返回解密后的echostr。
+fn verify_echo(
+    crypto_agent: &CryptoAgent,
+    params: &UrlVerifyParams,
+) -> Result<String, VerifyError> {
+    // Is this request safe? 签名须按timestamp、nonce、echostr的顺序计算
+    let computed =
+        crypto_agent.generate_signature(vec![&params.timestamp, &params.nonce, &params.echostr]);
+    if computed != params.msg_signature {
+        return Err(VerifyError::SignatureMismatch {
+            computed,
+            received: params.msg_signature.clone(),
+        });
+    }
+
+    // Give the server what it expects.
+    let echo = crypto_agent
+        .decrypt(&params.echostr)
+        .map_err(|e| VerifyError::DecryptFailed(e.to_string()))?
+        .text;
+    tracing::debug!("解密后的echostr长度：{}", echo.len());
+    if echo.is_empty() {
+        return Err(VerifyError::EmptyEcho);
+    }
+    Ok(echo)
+}
+
+/// 查用户指令每页显示的用户数
+const GUESTS_PER_PAGE: i64 = 20;
+
+/// 会话列表每页显示的会话数量
+const CONVERSATIONS_PER_PAGE: i64 = 10;
+
+/// 反馈列表每页显示的反馈数量
+const FEEDBACK_PER_PAGE: i64 = 10;
+
+/// 反馈中保存的AI回复片段的最大字符数
+const FEEDBACK_REPLY_CHARS: usize = 50;
+
+/// 分段发送的回复末尾附带的标记
+const PROGRESS_PENDING_MARK: &str = "\n\n（回复生成中…）";
+
+/// 分段发送后，完整回复末尾附带的标记
+const PROGRESS_COMPLETE_MARK: &str = "\n\n（回复完毕）";
+
+/// 两次分段发送之间的最短间隔
+const PROGRESS_MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 企业微信单条文本消息的最大字节数
+const WECOM_TEXT_MAX_BYTES: usize = 2048;
+
+/// 单个UTF-8字符的最大字节数
+const MAX_CHAR_BYTES: usize = 4;
+
+// 将文本按字节上限拆分为多段，不截断字符。上限容不下一个字符时不再拆分。
+fn split_bytes(text: &str, max: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let piece = match truncate_bytes_safe(rest, max) {
+            "" => rest,
+            p => p,
+        };
+        pieces.push(piece);
+        rest = &rest[piece.len()..];
+    }
+    pieces
+}
+
+/// 企业微信单次发送消息允许的最大接收人数
+const MAX_RECIPIENTS_PER_SEND: usize = 1000;
+
+// 将接收人按照单次发送上限分组
+fn batch_recipients(names: &[String], size: usize) -> Vec<Vec<&str>> {
+    names
+        .chunks(size)
+        .map(|c| c.iter().map(|n| n.as_str()).collect())
+        .collect()
+}
+
+// 按照单次发送上限分批发送，返回成功送达的人数与发送失败的用户。单批失败不影响其余批次。
+// send返回本批中企业微信无法送达的用户（invaliduser）。
+async fn send_in_batches<'a, F, Fut>(names: &'a [String], mut send: F) -> (usize, Vec<&'a str>)
+where
+    F: FnMut(Vec<&'a str>) -> Fut,
+    Fut: Future<Output = Result<Vec<String>, Error>>,
+{
+    let mut delivered: usize = 0;
+    let mut failed: Vec<&str> = Vec::new();
+    for batch in batch_recipients(names, MAX_RECIPIENTS_PER_SEND) {
+        match send(batch.clone()).await {
+            Ok(invalid) => {
+                let (rejected, accepted): (Vec<&str>, Vec<&str>) = batch
+                    .into_iter()
+                    .partition(|n| invalid.iter().any(|i| i == n));
+                if !rejected.is_empty() {
+                    tracing::warn!("广播消息未能送达：{}", rejected.join(","));
+                }
+                delivered += accepted.len();
+                failed.extend(rejected);
+            }
+            Err(e) => {
+                tracing::error!("广播消息失败。{e}");
+                failed.extend(batch);
+            }
+        }
+    }
+    (delivered, failed)
+}
+
+/// 需要管理员二次确认后才执行的操作
+#[derive(Debug, Clone, PartialEq)]
+enum AdminAction {
+    Prune { days: u32 }, // 清理指定天数前的非活跃会话
+}
+
+/// 解析后的管理员指令
+#[derive(Debug, PartialEq)]
+enum AdminCommand<'a> {
+    Help,
+    ListGuests {
+        order: &'a str,
+        page: &'a str,
+    },
+    ListFeedback {
+        page: &'a str,
+    },
+    GuestCount,
+    ResetAllConversations,
+    MigrationStatus,
+    Prune {
+        days: &'a str,
+    },
+    Confirm,
+    Maintenance {
+        value: &'a str,
+    },
+    Broadcast,
+    SetDefaultPrompt,
+    DefaultPrompt,
+    Impersonate {
+        name: &'a str,
+    },
+    Persona {
+        name: &'a str,
+    },
+    Merge {
+        old_name: &'a str,
+        new_name: &'a str,
+    },
+    MaxContext {
+        id: &'a str,
+        value: &'a str,
+    },
+    Recharge {
+        username: &'a str,
+        value: &'a str,
+    },
+    ReverseRecharge {
+        username: &'a str,
+    },
+    AlertThreshold {
+        username: &'a str,
+        value: &'a str,
+    },
+    SetAdmin {
+        username: &'a str,
+        value: &'a str,
+    },
+    SetPermissions {
+        username: &'a str,
+        value: &'a str,
+    },
+    Remove {
+        username: &'a str,
+    },
+    Unknown,
+}
+
+impl<'a> AdminCommand<'a> {
+    // 解析管理员指令。权限与执行均以解析结果为准。
+    // 以第二个参数为操作名的指令（如"用户名 删除"）优先匹配，因此首个参数可以是任意名称，
+    // 包括"广播"、"人设"这样的指令名。
+    fn parse(args: &[&'a str]) -> Self {
+        match *args {
+            [id, "上下文", value] => Self::MaxContext { id, value },
+            [username, "充值", value] => Self::Recharge { username, value },
+            [username, "预警", value] => Self::AlertThreshold { username, value },
+            [username, "管理员", value] => Self::SetAdmin { username, value },
+            [username, "权限", value] => Self::SetPermissions { username, value },
+            [username, "删除"] => Self::Remove { username },
+            ["help"] => Self::Help,
+            ["查用户"] => Self::ListGuests {
+                order: "名称",
+                page: "1",
+            },
+            ["查用户", order] => Self::ListGuests { order, page: "1" },
+            ["查用户", order, page] => Self::ListGuests { order, page },
+            ["反馈列表"] => Self::ListFeedback { page: "1" },
+            ["反馈列表", page] => Self::ListFeedback { page },
+            ["用户数"] => Self::GuestCount,
+            ["重置全部会话"] => Self::ResetAllConversations,
+            ["迁移状态"] => Self::MigrationStatus,
+            ["清理", days] => Self::Prune { days },
+            ["确认"] => Self::Confirm,
+            ["维护", value] => Self::Maintenance { value },
+            ["广播", ..] => Self::Broadcast,
+            ["全局提示词", ..] => Self::SetDefaultPrompt,
+            ["查全局提示词"] => Self::DefaultPrompt,
+            ["模拟", name, _, ..] => Self::Impersonate { name },
+            ["人设", name] => Self::Persona { name },
+            ["合并", old_name, new_name] => Self::Merge { old_name, new_name },
+            ["撤销充值", username] => Self::ReverseRecharge { username },
+            _ => Self::Unknown,
+        }
+    }
+
+    // 执行此指令所需的权限。返回None表示任何管理员均可执行。
+    fn permission(&self) -> Option<Permission> {
+        match self {
+            Self::Broadcast => Some(Permission::Broadcast),
+            Self::Recharge { .. } | Self::AlertThreshold { .. } | Self::ReverseRecharge { .. } => {
+                Some(Permission::Recharge)
+            }
+            Self::Merge { .. }
+            | Self::SetAdmin { .. }
+            | Self::SetPermissions { .. }
+            | Self::Remove { .. }
+            | Self::Maintenance { .. }
+            | Self::Prune { .. }
+            | Self::ResetAllConversations
+            | Self::SetDefaultPrompt
+            | Self::Impersonate { .. }
+            | Self::Persona { .. }
+            | Self::MaxContext { .. }
+            | Self::MigrationStatus => Some(Permission::ManageAdmins),
+            Self::Help
+            | Self::ListGuests { .. }
+            | Self::ListFeedback { .. }
+            | Self::GuestCount
+            | Self::Confirm
+            | Self::DefaultPrompt
+            | Self::Unknown => None,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::provider::health::CIRCUIT_OPEN_AFTER;
+    use crate::provider::mock;
+    use crate::provider::Provider;
+
+    pub const TEST_AGENT_ID: u64 = 1000002;
+    pub const TEST_ADMIN: &str = "administrator";
+
+    // 测试用配置。与生产环境一致，敏感内容均通过环境变量间接提供。
+    pub fn test_config() -> Config {
+        env::set_var("WECOM_GPT_TEST_CORP_ID", "ww0000000000000000");
+        env::set_var("WECOM_GPT_TEST_TOKEN", "QDG6eK");
+        env::set_var(
+            "WECOM_GPT_TEST_KEY",
+            "jWmYm7qr5nMoAUwZRjGtBxmz3KA1tkAj3ykkR6q2B2A",
+        );
+        env::set_var("WECOM_GPT_TEST_SECRET", "secret");
+        env::set_var("WECOM_GPT_TEST_ENDPOINT", "http://127.0.0.1:9/chat");
+        env::set_var("WECOM_GPT_TEST_API_KEY", "api-key");
+        env::set_var("WECOM_GPT_TEST_ADMIN", TEST_ADMIN);
+
+        ConfigBuilder::new()
+            .wecom("WECOM_GPT_TEST_CORP_ID")
+            // 供应商的地址与密钥同样从环境变量读取
+            .provider(ProviderCfg {
+                api_key: "WECOM_GPT_TEST_API_KEY".to_string(),
+                ..ProviderCfg::for_test("WECOM_GPT_TEST_ENDPOINT")
+            })
+            .assistant(test_assistant_config(TEST_AGENT_ID))
+            .accountant(AccountantCfg {
+                agent_id: 1000003,
+                token: "WECOM_GPT_TEST_TOKEN".to_string(),
+                key: "WECOM_GPT_TEST_KEY".to_string(),
+                onboarding_credit: 0.0,
+                grace_credit: 0.0,
+            })
+            .storage_path(":memory:")
+            .admin_account("WECOM_GPT_TEST_ADMIN")
+            .build()
+            .expect("Test config should be valid")
+    }
+
+    // 测试用的助手配置
+    pub fn test_assistant_config(agent_id: u64) -> AssistantCfg {
+        AssistantCfg {
+            agent_id,
+            name: "test-assistant".to_string(),
+            token: "WECOM_GPT_TEST_TOKEN".to_string(),
+            key: "WECOM_GPT_TEST_KEY".to_string(),
+            secret: "WECOM_GPT_TEST_SECRET".to_string(),
+            prompt: "You are a helpful assistant.".to_string(),
+            provider_id: 1,
+            context_tokens_reservation: 1024,
+            ..Default::default()
+        }
+    }
+
+    // 在测试用配置的基础上增加一个助手
+    pub fn test_config_with_assistant(agent_id: u64) -> Config {
+        let mut config = test_config();
+        config.assistants.push(test_assistant_config(agent_id));
+        config
+    }
+
+    pub fn test_agent() -> Agent {
+        Agent::new(&test_config()).expect("Test agent should be initialized")
+    }
+
+    // 以模拟供应商替换配置中的助手，并且不实际发送消息。助手与接待共用存储。
+    pub fn install_mock(agent: &mut Agent, config: &AssistantCfg, provider: mock::Agent) {
+        let assistant = Assistant::with_provider(
+            config,
+            &crate::assistant::tests::test_provider_config(),
+            Box::new(provider),
+            agent.storage.clone(),
+        );
+        let apps = agent.apps_mut();
+        apps.assistants.insert(config.agent_id, assistant);
+        apps.messengers.clear();
+    }
+
+    // 使用模拟供应商的测试接待
+    pub fn agent_with_mock(provider: mock::Agent) -> Agent {
+        let mut agent = test_agent();
+        install_mock(&mut agent, &test_assistant_config(TEST_AGENT_ID), provider);
+        agent
+    }
+
+    // 发往测试应用的文本消息。每条消息的MsgId不同，不会被当作重复推送。
+    pub fn text_message(from: &str, content: &str) -> AppMessageContent {
+        static MSG_ID: AtomicU64 = AtomicU64::new(1);
+        let msg_id = MSG_ID.fetch_add(1, Ordering::Relaxed);
+        from_str::<AppMessageContent>(&format!(
+            "<xml><ToUserName><![CDATA[ww637951f75e40d82b]]></ToUserName><FromUserName><![CDATA[{from}]]></FromUserName><CreateTime>1708218294</CreateTime><MsgType><![CDATA[text]]></MsgType><Content><![CDATA[{content}]]></Content><MsgId>{msg_id}</MsgId><AgentID>{TEST_AGENT_ID}</AgentID></xml>"
+        ))
+        .expect("Message should be parsed")
+    }
+
+    // 注册一名普通用户并返回
+    pub fn register_guest(agent: &Agent, name: &str, credit: f64) -> Guest {
+        let guest = Guest::regular(name, credit);
+        agent
+            .accountant
+            .register_if_absent(&guest)
+            .expect("Guest registration should succeed");
+        guest
+    }
+
+    #[tokio::test]
+    async fn test_recharge_only_operator() {
+        let agent = test_agent();
+        register_guest(&agent, "robin", 0.0);
+        let operator = Guest {
+            permissions: Permission::Recharge.bit(),
+            ..register_guest(&agent, "finance", 0.0)
+        };
+        agent.accountant.update_guest(&operator).unwrap();
+
+        // 不可设定管理员
+        let reply = agent
+            .handle_instruction_msg(&operator, TEST_AGENT_ID, "$$robin 管理员 true$$")
+            .await;
+        assert_eq!(reply, "权限不足。");
+        assert!(!agent.accountant.get_guest("robin").unwrap().admin);
+
+        // 可以充值
+        agent
+            .handle_instruction_msg(&operator, TEST_AGENT_ID, "$$robin 充值 1.5$$")
+            .await;
+        assert_eq!(agent.accountant.get_guest("robin").unwrap().credit, 1.5);
+    }
+
+    #[tokio::test]
+    async fn test_whoami() {
+        let agent = test_agent();
+        let guest = register_guest(&agent, "robin", 2.5);
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#谁")
+            .await;
+        assert_eq!(reply, "用户名：robin\n余额：2.500\n身份：普通用户");
+
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "#谁")
+            .await;
+        assert!(reply.contains(TEST_ADMIN));
+        assert!(reply.ends_with("身份：管理员"));
+    }
+
+    #[tokio::test]
+    async fn test_new_conversation_min_credit() {
+        let mut config = test_config();
+        config.new_conversation_min_credit = Some(0.0);
+        let agent = Agent::new(&config).expect("Test agent should be initialized");
+
+        let broke = register_guest(&agent, "robin", 0.0);
+        let reply = agent
+            .handle_instruction_msg(&broke, TEST_AGENT_ID, "#新会话")
+            .await;
+        assert_eq!(
+            reply,
+            "开启新会话需要账户余额高于0.000，当前余额：0.000。请充值后再试，或继续当前会话。"
+        );
+
+        let funded = register_guest(&agent, "batman", 1.0);
+        let reply = agent
+            .handle_instruction_msg(&funded, TEST_AGENT_ID, "#新会话")
+            .await;
+        assert_eq!(reply, "新会话创建成功。您可以开始对话了。");
+    }
+
+    #[tokio::test]
+    async fn test_reverse_recharge() {
+        let agent = test_agent();
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        register_guest(&agent, "robin", 1.0);
+
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$robin 充值 10$$")
+            .await;
+        assert_eq!(reply, "更新成功。当前余额：11.000");
+
+        // 撤销后恢复充值前的余额
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$撤销充值 robin$$")
+            .await;
+        assert_eq!(reply, "已撤销充值10.000。当前余额：1.000");
+        assert_eq!(agent.accountant.get_guest("robin").unwrap().credit, 1.0);
+
+        // 最近一笔流水已是冲正记录
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$撤销充值 robin$$")
+            .await;
+        assert!(reply.contains("最近一笔余额流水不是充值"), "{reply}");
+        assert_eq!(agent.accountant.get_guest("robin").unwrap().credit, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_consistent_credit_format() {
+        let agent = test_agent();
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        let guest = register_guest(&agent, "robin", 0.1);
+
+        // 充值后的余额存在浮点误差，各处展示应一致
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$robin 充值 0.2$$")
+            .await;
+        assert_eq!(reply, "更新成功。当前余额：0.300");
+
+        let guest = agent.accountant.get_guest(&guest.name).unwrap();
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#查余额")
+            .await;
+        assert_eq!(reply, "当前余额：0.300");
+
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$查用户$$")
+            .await;
+        assert!(reply.contains("robin 0.300 false"));
+    }
+
+    #[tokio::test]
+    async fn test_grant_permissions() {
+        let agent = test_agent();
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        register_guest(&agent, "finance", 0.0);
+
+        agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$finance 权限 充值,广播$$")
+            .await;
+        let operator = agent.accountant.get_guest("finance").unwrap();
+        assert!(operator.can(Permission::Recharge));
+        assert!(operator.can(Permission::Broadcast));
+        assert!(!operator.can(Permission::ManageAdmins));
+    }
+
+    #[test]
+    fn test_batch_recipients() {
+        let names: Vec<String> = (0..1500).map(|i| format!("user{i}")).collect();
+        let batches = batch_recipients(&names, MAX_RECIPIENTS_PER_SEND);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].len(), 1000);
+        assert_eq!(batches[1].len(), 500);
+        assert_eq!(batches[1][0], "user1000");
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_in_batches() {
+        // 每批单独发送，某一批失败时其余批次照常发送
+        let names: Vec<String> = (0..2500).map(|i| format!("user{i}")).collect();
+        let mut sizes = Vec::new();
+        let (delivered, failed) = send_in_batches(&names, |batch| {
+            sizes.push(batch.len());
+            let batch_no = sizes.len();
+            async move {
+                match batch_no {
+                    2 => Err(Error("发送失败".to_string())),
+                    3 => Ok(vec!["user2001".to_string()]),
+                    _ => Ok(Vec::new()),
+                }
+            }
+        })
+        .await;
+        assert_eq!(sizes, [1000, 1000, 500]);
+        assert_eq!(delivered, 1499);
+        assert_eq!(failed.len(), 1001);
+        assert_eq!(failed[0], "user1000");
+        assert_eq!(failed[1000], "user2001");
+
+        // 无法发送时，回复中列出失败的用户
+        let mut agent = test_agent();
+        register_guest(&agent, "robin", 0.0);
+        agent.apps_mut().wecom_apis.clear();
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$广播 系统将于今晚升级$$")
+            .await;
+        assert!(reply.starts_with("广播完成。成功0名，失败2名："), "{reply}");
+        assert!(reply.contains("robin"), "{reply}");
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_invalid_users() {
+        use axum::routing::{get, post};
+        use axum::Router;
+
+        // 模拟企业微信接口，robin无法送达
+        let app = Router::new()
+            .route(
+                "/cgi-bin/gettoken",
+                get(|| async {
+                    r#"{"errcode":0,"errmsg":"ok","access_token":"token","expires_in":7200}"#
+                }),
+            )
+            .route(
+                "/cgi-bin/message/send",
+                post(|| async { r#"{"errcode":0,"errmsg":"ok","invaliduser":"robin"}"# }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut config = test_config();
+        config.wecom.api_base = format!("http://{addr}");
+        let agent = Agent::new(&config).expect("Test agent should be initialized");
+        register_guest(&agent, "robin", 0.0);
+        register_guest(&agent, "alice", 0.0);
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$广播 系统将于今晚升级$$")
+            .await;
+        assert_eq!(reply, "广播完成。成功2名，失败1名：robin");
+    }
+
+    #[tokio::test]
+    async fn test_list_guests_page_range() {
+        let agent = test_agent();
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$查用户 名称 1$$")
+            .await;
+        assert!(reply.starts_with("共1名用户，第1页："), "{reply}");
+        let reply = agent
+            .handle_instruction_msg(
+                &admin,
+                TEST_AGENT_ID,
+                &format!("$$查用户 名称 {}$$", i64::MAX),
+            )
+            .await;
+        assert_eq!(reply, "页码超出范围");
+    }
+
+    #[tokio::test]
+    async fn test_list_conversations_page_range() {
+        let agent = test_agent();
+        let guest = register_guest(&agent, "robin", 1.0);
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#会话列表 0")
+            .await;
+        assert_eq!(reply, "页码应为从1开始的整数");
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, &format!("#会话列表 {}", i64::MAX))
+            .await;
+        assert_eq!(reply, "页码超出范围");
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_requires_permission() {
+        let agent = test_agent();
+        let guest = Guest {
+            permissions: Permission::Recharge.bit(),
+            ..register_guest(&agent, "finance", 0.0)
+        };
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "$$广播 系统将于今晚升级$$")
+            .await;
+        assert_eq!(reply, "权限不足。");
+
+        // 对名为"广播"的用户执行的操作不视为广播
+        let broadcaster = Guest {
+            permissions: Permission::Broadcast.bit(),
+            ..guest
+        };
+        let reply = agent
+            .handle_instruction_msg(&broadcaster, TEST_AGENT_ID, "$$广播 删除$$")
+            .await;
+        assert_eq!(reply, "权限不足。");
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$广播 删除$$")
+            .await;
+        assert!(reply.starts_with("无法找到用户。"), "{reply}");
+    }
+
+    #[tokio::test]
+    async fn test_permission_follows_dispatch() {
+        let agent = test_agent();
+        let operator = Guest {
+            permissions: Permission::Recharge.bit(),
+            ..register_guest(&agent, "finance", 0.0)
+        };
+        agent.accountant.update_guest(&operator).unwrap();
+
+        // 按充值权限放行的指令只能执行充值，不能借此改写全局提示词
+        let reply = agent
+            .handle_instruction_msg(&operator, TEST_AGENT_ID, "$$全局提示词 充值 x$$")
+            .await;
+        assert_eq!(reply, "用户余额解析出错");
+        assert_eq!(agent.accountant.default_prompt().unwrap(), None);
+
+        // 也不能借此模拟用户对话
+        let reply = agent
+            .handle_instruction_msg(&operator, TEST_AGENT_ID, "$$模拟 充值 x$$")
+            .await;
+        assert_eq!(reply, "用户余额解析出错");
+    }
+
+    #[tokio::test]
+    async fn test_command_named_user() {
+        let agent = test_agent();
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+
+        // 与指令同名的用户同样可以作为操作对象
+        for name in ["清理", "人设", "维护", "全局提示词", "模拟"] {
+            register_guest(&agent, name, 0.0);
+            let reply = agent
+                .handle_instruction_msg(&admin, TEST_AGENT_ID, &format!("$${name} 充值 1$$"))
+                .await;
+            assert!(reply.starts_with("更新成功。"), "{name}: {reply}");
+            let reply = agent
+                .handle_instruction_msg(&admin, TEST_AGENT_ID, &format!("$${name} 删除$$"))
+                .await;
+            assert_eq!(reply, "删除1条用户记录。", "{name}");
+            assert!(agent.accountant.get_guest(name).is_err());
+        }
+        assert_eq!(agent.accountant.default_prompt().unwrap(), None);
+    }
+
+    // 构造URL验证请求参数。签名由测试用的加解密对象生成。
+    fn verify_params(agent: &Agent, echostr: &str, valid_signature: bool) -> UrlVerifyParams {
+        let timestamp = "1708218294".to_string();
+        let nonce = "1372623149".to_string();
+        let echostr = echostr.to_string();
+        let msg_signature = if valid_signature {
+            agent.apps().crypto_agents[&TEST_AGENT_ID]
+                .generate_signature(vec![&timestamp, &nonce, &echostr])
+        } else {
+            "invalid".to_string()
+        };
+        UrlVerifyParams {
+            msg_signature,
+            timestamp,
+            nonce,
+            echostr,
+        }
+    }
+
+    // 以测试用的key加密echostr
+    fn encrypt_echo(agent: &Agent, text: &str) -> String {
+        agent.apps().crypto_agents[&TEST_AGENT_ID].encrypt(&wecom_crypto::Source {
+            text: text.to_string(),
+            receive_id: "ww0000000000000000".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_verify_url() {
+        let agent = test_agent();
+        let params = verify_params(&agent, &encrypt_echo(&agent, "1616140317555161061"), true);
+        assert_eq!(
+            agent.verify_url(TEST_AGENT_ID, Query(params)),
+            Ok("1616140317555161061".to_string())
+        );
+    }
+
+    #[test]
+    fn test_verify_url_empty_echo() {
+        let agent = test_agent();
+        let params = verify_params(&agent, &encrypt_echo(&agent, ""), true);
+        assert_eq!(
+            verify_echo(&agent.apps().crypto_agents[&TEST_AGENT_ID], &params),
+            Err(VerifyError::EmptyEcho)
+        );
+        assert_eq!(
+            agent.verify_url(TEST_AGENT_ID, Query(params)),
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        );
+    }
+
+    #[test]
+    fn test_verify_url_signature_mismatch() {
+        let agent = test_agent();
+        let params = verify_params(&agent, "echostr", false);
+        assert_eq!(
+            verify_echo(&agent.apps().crypto_agents[&TEST_AGENT_ID], &params),
+            Err(VerifyError::SignatureMismatch {
+                computed: agent.apps().crypto_agents[&TEST_AGENT_ID].generate_signature(vec![
+                    &params.timestamp,
+                    &params.nonce,
+                    &params.echostr
+                ]),
+                received: "invalid".to_string(),
+            })
+        );
+        assert_eq!(
+            agent.verify_url(TEST_AGENT_ID, Query(params)),
+            Err(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_agent_id() {
+        let agent = test_agent();
+        let unknown = 404;
+        let params = verify_params(&agent, "echostr", true);
+        assert_eq!(
+            agent.verify_url(unknown, Query(params)),
+            Err(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(agent.unknown_agent_requests.load(Ordering::Relaxed), 1);
+
+        // 消息直接丢弃，不尝试解析
+        let params = CallbackParams {
+            msg_signature: "signature".to_string(),
+            nonce: "nonce".to_string(),
+            timestamp: "1700000000".to_string(),
+        };
+        let outcome = agent
+            .handle_user_request(unknown, Query(params), "<xml></xml>".to_string())
+            .await;
+        assert_eq!(
+            outcome,
+            ProcessOutcome::Ignored {
+                reason: "未配置的应用：404".to_string()
+            }
+        );
+        assert_eq!(agent.unknown_agent_requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reload_config() {
+        let agent = agent_with_mock(mock::Agent::failing("timeout"));
+        let guest = register_guest(&agent, "robin", 1.0);
+        let apps = agent.apps();
+        let assistant = &apps.assistants[&TEST_AGENT_ID];
+        assert!(assistant.chat(&guest, "Hello").await.is_err());
+        assistant.set_max_context(Some(2048)).unwrap();
+        drop(apps);
+        let failures = agent.provider_health()[0].health.failure_count;
+        assert!(failures > 0);
+
+        let new_id = 1000004;
+        let params = || verify_params(&agent, "echostr", false);
+        assert_eq!(
+            agent.verify_url(new_id, Query(params())),
+            Err(StatusCode::NOT_FOUND)
+        );
+
+        // 新增的应用在重载后即可处理请求，原有应用不受影响
+        agent
+            .reload_config(&test_config_with_assistant(new_id))
+            .unwrap();
+        assert_eq!(
+            agent.verify_url(new_id, Query(params())),
+            Err(StatusCode::BAD_REQUEST)
+        );
+        assert!(agent.apps().assistants.contains_key(&TEST_AGENT_ID));
+
+        // 有误的配置被拒绝，保留原配置
+        let mut invalid = test_config();
+        invalid.providers[0].sampling.temperature = Some(5.0);
+        assert!(agent.reload_config(&invalid).is_err());
+        assert!(agent
+            .reload_config(&test_config_with_assistant(1000003))
+            .is_err());
+        assert!(agent.apps().assistants.contains_key(&new_id));
+
+        // 重建的助手沿用原有的供应商健康状态与上下文长度
+        agent.reload_config(&test_config()).unwrap();
+        let health = agent.provider_health();
+        assert_eq!(health.len(), 1);
+        assert_eq!(health[0].health.failure_count, failures);
+        assert_eq!(agent.apps().assistants[&TEST_AGENT_ID].max_context(), 2048);
+    }
+
+    #[test]
+    fn test_verify_url_decrypt_failure() {
+        let agent = test_agent();
+        let params = verify_params(&agent, "bm90IGEgdmFsaWQgY2lwaGVydGV4dA==", true);
+        assert!(matches!(
+            verify_echo(&agent.apps().crypto_agents[&TEST_AGENT_ID], &params),
+            Err(VerifyError::DecryptFailed(_))
+        ));
+        assert_eq!(
+            agent.verify_url(TEST_AGENT_ID, Query(params)),
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        );
+
+        // 通讯录组件解密失败时仍返回400
+        let params = verify_params(&agent, "bm90IGEgdmFsaWQgY2lwaGVydGV4dA==", true);
+        assert_eq!(
+            agent.verify_url(agent.accountant.agent_id(), Query(params)),
+            Err(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[test]
+    fn test_config_builder() {
+        let config = test_config();
+        assert!(Agent::new(&config).is_ok());
+        assert_eq!(config.wecom.api_base, "https://qyapi.weixin.qq.com");
+
+        // 自定义企业微信API地址
+        let custom = ConfigBuilder::new()
+            .wecom("WECOM_GPT_TEST_CORP_ID")
+            .wecom_api_base("http://127.0.0.1:8080")
+            .provider(ProviderCfg::for_test("WECOM_GPT_TEST_ENDPOINT"))
+            .assistant(test_assistant_config(TEST_AGENT_ID))
+            .accountant(config.accountant.clone())
+            .storage_path(":memory:")
+            .admin_account("WECOM_GPT_TEST_ADMIN")
+            .build()
+            .unwrap();
+        assert_eq!(custom.wecom.api_base, "http://127.0.0.1:8080");
+
+        // 缺少必要配置项
+        assert!(ConfigBuilder::new()
+            .wecom("WECOM_GPT_TEST_CORP_ID")
+            .build()
+            .is_err());
+
+        // 助手对应的供应商不存在
+        let mut orphan = test_assistant_config(1000004);
+        orphan.provider_id = 2;
+        let result = ConfigBuilder::new()
+            .wecom("WECOM_GPT_TEST_CORP_ID")
+            .assistant(orphan)
+            .accountant(config.accountant.clone())
+            .storage_path(":memory:")
+            .admin_account("WECOM_GPT_TEST_ADMIN")
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_maintenance_mode() {
+        let agent = test_agent();
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        assert_eq!(agent.chat_block_reason(None), None);
+
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$维护 开启$$")
+            .await;
+        assert_eq!(reply, "维护模式已开启。");
+        assert_eq!(
+            agent.chat_block_reason(None),
+            Some("系统维护中，请稍后再试".to_string())
+        );
+
+        // 管理员指令依然有效
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$查用户$$")
+            .await;
+        assert!(reply.contains(TEST_ADMIN));
+
+        agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$维护 关闭$$")
+            .await;
+        assert_eq!(agent.chat_block_reason(None), None);
+    }
+
+    #[tokio::test]
+    async fn test_expensive_message_held() {
+        let mut config = test_config();
+        config.confirm_cost_threshold = Some(0.001);
+        let mut agent = Agent::new(&config).expect("Test agent should be initialized");
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        install_mock(&mut agent, &test_assistant_config(TEST_AGENT_ID), provider);
+        register_guest(&agent, "robin", 10.0);
+
+        // 短消息不需要确认
+        let outcome = agent
+            .process_message(TEST_AGENT_ID, text_message("robin", "你好"))
+            .await;
+        assert_eq!(outcome, ProcessOutcome::Replied);
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        // 长消息被暂存，不请求供应商
+        let long_message = "请详细解释这段代码的含义。".repeat(50);
+        let outcome = agent
+            .process_message(TEST_AGENT_ID, text_message("robin", &long_message))
+            .await;
+        let ProcessOutcome::Blocked { reason } = outcome else {
+            panic!("Expensive message should be held");
+        };
+        assert!(reason.contains("#确认"), "{reason}");
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        // 确认后发送暂存的消息
+        let outcome = agent
+            .process_message(TEST_AGENT_ID, text_message("robin", "#确认"))
+            .await;
+        assert_eq!(outcome, ProcessOutcome::Replied);
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(sent.messages.last().unwrap().content, long_message);
+        assert_eq!(received.lock().unwrap().len(), 2);
+
+        // 暂存的消息仅可确认一次
+        let outcome = agent
+            .process_message(TEST_AGENT_ID, text_message("robin", "#确认"))
+            .await;
+        assert_eq!(outcome, ProcessOutcome::CommandHandled);
+        assert_eq!(received.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reply_language() {
+        let agent = test_agent();
+        let guest = register_guest(&agent, "robin", 1.0);
+        let apps = agent.apps();
+        let assistant = apps.assistants.get(&TEST_AGENT_ID).unwrap();
+        assert!(!assistant.system_prompt(&guest).contains("Always reply in"));
+
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#语言 Klingon")
+            .await;
+        assert!(reply.starts_with("暂不支持该语言"));
+
+        agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#语言 english")
+            .await;
+        assert!(assistant
+            .system_prompt(&guest)
+            .ends_with("\nAlways reply in English."));
+
+        agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#语言 默认")
+            .await;
+        assert!(!assistant.system_prompt(&guest).contains("Always reply in"));
+    }
+
+    #[tokio::test]
+    async fn test_custom_alert_threshold() {
+        let mut config = test_config();
+        config.low_balance_alert = Some(1.0);
+        let agent = Agent::new(&config).expect("Test agent should be initialized");
+        let cautious = register_guest(&agent, "cautious", 3.0);
+        let relaxed = register_guest(&agent, "relaxed", 3.0);
+
+        agent
+            .handle_instruction_msg(&cautious, TEST_AGENT_ID, "#预警 5")
+            .await;
+
+        // 自定义阈值生效，而全局阈值不会触发提醒
+        assert_eq!(
+            agent.low_balance_notice(&cautious, 3.0),
+            Some("余额提醒：当前余额3.000，已低于5.000。".to_string())
+        );
+        assert_eq!(agent.low_balance_notice(&relaxed, 3.0), None);
+        assert!(agent.low_balance_notice(&relaxed, 0.5).is_some());
+
+        // 管理员可为用户恢复默认
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$cautious 预警 默认$$")
+            .await;
+        assert_eq!(agent.low_balance_notice(&cautious, 3.0), None);
+    }
+
+    #[tokio::test]
+    async fn test_prune_requires_confirmation() {
+        let agent = test_agent();
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        let guest = register_guest(&agent, "robin", 1.0);
+        let apps = agent.apps();
+        let assistant = &apps.assistants[&TEST_AGENT_ID];
+        assistant.new_conversation(&guest).unwrap();
+        assistant.new_conversation(&guest).unwrap();
+
+        // 未确认时不执行
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$清理 0$$")
+            .await;
+        assert!(reply.starts_with("确认清理0天前数据？"));
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$确认$$")
+            .await;
+        assert_eq!(reply, "已清理1个0天前的非活跃会话。");
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$确认$$")
+            .await;
+        assert_eq!(reply, "没有待确认的操作，或操作已过期。");
+
+        // 过期后放弃操作
+        let mut config = test_config();
+        config.confirm_window_secs = 0;
+        let agent = Agent::new(&config).expect("Test agent should be initialized");
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$清理 30$$")
+            .await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$确认$$")
+            .await;
+        assert_eq!(reply, "没有待确认的操作，或操作已过期。");
+    }
+
+    #[tokio::test]
+    async fn test_service_status() {
+        let agent = test_agent();
+        let guest = register_guest(&agent, "robin", 1.0);
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#状态")
+            .await;
+        assert_eq!(
+            reply,
+            "服务状态：正常\n调用成功率：暂无记录\n维护模式：关闭"
+        );
+
+        // 测试供应商地址不可达，连续失败后熔断
+        let apps = agent.apps();
+        let assistant = &apps.assistants[&TEST_AGENT_ID];
+        for _ in 0..CIRCUIT_OPEN_AFTER {
+            assert!(assistant.chat(&guest, "Hello").await.is_err());
+        }
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#状态")
+            .await;
+        assert!(reply.starts_with("服务状态：降级"));
+        assert!(reply.contains("调用成功率：0.0%"));
+    }
+
+    #[tokio::test]
+    async fn test_validate_providers_on_start() {
+        // 默认不检查，测试供应商地址不可达也能启动
+        assert!(Agent::init(&test_config()).await.is_ok());
+
+        let mut config = test_config();
+        config.validate_providers_on_start = true;
+        // 同步初始化无法完成检查，直接报错而非忽略
+        let err = Agent::new(&config)
+            .err()
+            .expect("New should refuse to skip validation");
+        assert!(err.to_string().contains("请使用init_app初始化应用"));
+        let err = Agent::init(&config).await.err().expect("Init should fail");
+        assert!(err
+            .to_string()
+            .contains("供应商test-provider（ID 1）不可用"));
+
+        // 供应商检查失败时，错误信息指明是哪个供应商
+        let agent = agent_with_mock(mock::Agent::failing("invalid api key"));
+        let err = agent.validate_providers().await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "供应商test-provider（ID 1）不可用。invalid api key"
+        );
+    }
+
+    #[test]
+    fn test_config_from_toml() {
+        let text = r#"
+            storage_path = "/var/lib/wecom-gpt/db.sqlite"
+            admin_account = "WECOM_GPT_TEST_ADMIN"
+            low_balance_alert = 1.0
+
+            [wecom]
+            corp_id = "WECOM_GPT_TEST_CORP_ID"
+
+            [[providers]]
+            id = 1
+            name = "test-provider"
+            endpoint = "WECOM_GPT_TEST_ENDPOINT"
+            api_key = "WECOM_GPT_TEST_API_KEY"
+            max_tokens = 4096
+            prompt_token_price = 0.01
+            completion_token_price = 0.03
+
+            [[assistants]]
+            agent_id = 1000002
+            name = "test-assistant"
+            token = "WECOM_GPT_TEST_TOKEN"
+            key = "WECOM_GPT_TEST_KEY"
+            secret = "WECOM_GPT_TEST_SECRET"
+            prompt = "You are a helpful assistant."
+            provider_id = 1
+            context_tokens_reservation = 1024
+
+            [accountant]
+            agent_id = 1000003
+            token = "WECOM_GPT_TEST_TOKEN"
+            key = "WECOM_GPT_TEST_KEY"
+        "#;
+        let config = Config::from_toml(text).expect("Sample config should be parsed");
+        assert_eq!(config.providers[0].max_tokens, 4096);
+        assert_eq!(config.assistants[0].agent_id, TEST_AGENT_ID);
+        assert_eq!(config.storage_path, "/var/lib/wecom-gpt/db.sqlite");
+        assert_eq!(config.low_balance_alert, Some(1.0));
+        assert_eq!(config.dedup_window_secs, 600);
+
+        // 环境变量中的内容可用于初始化
+        test_config();
+        let config = Config {
+            storage_path: ":memory:".to_string(),
+            ..config
+        };
+        assert!(Agent::new(&config).is_ok());
+
+        assert!(Config::from_toml("storage_path = 1").is_err());
+    }
+
+    #[test]
+    fn test_zero_requests_per_minute_rejected() {
+        let mut config = test_config();
+        config.assistants[0].requests_per_minute = Some(0);
+        let Err(e) = Agent::new(&config) else {
+            panic!("Zero requests per minute should be rejected");
+        };
+        assert_eq!(
+            e.to_string(),
+            format!("应用{TEST_AGENT_ID}的requests_per_minute须大于0。")
+        );
+    }
+
+    #[test]
+    fn test_invalid_sampling_rejected() {
+        let mut config = test_config();
+        config.providers[0].sampling.temperature = Some(5.0);
+        let Err(e) = Agent::new(&config) else {
+            panic!("Out-of-range temperature should be rejected");
+        };
+        assert_eq!(
+            e.to_string(),
+            "供应商1的采样参数有误。temperature应在0到2之间，当前为5。"
+        );
+    }
+
+    #[test]
+    fn test_content_type_allowed() {
+        let mut config = test_config();
+        config.assistants[0].allowed_content_types = vec![crate::core::ContentType::Text];
+        let agent = Agent::new(&config).expect("Test agent should be initialized");
+        let message = |msg_type: &str| {
+            from_str::<AppMessageContent>(&format!(
+                "<xml><ToUserName><![CDATA[ww637951f75e40d82b]]></ToUserName><FromUserName><![CDATA[robin]]></FromUserName><CreateTime>1708218294</CreateTime><MsgType><![CDATA[{msg_type}]]></MsgType><PicUrl><![CDATA[https://example.com/a.jpg]]></PicUrl><MediaId><![CDATA[media-id]]></MediaId><MsgId>7336741709953816625</MsgId><AgentID>1000002</AgentID></xml>"
+            ))
+            .expect("Message should be parsed")
+        };
+
+        // 仅接受文本的助手拒绝图片
+        assert_eq!(
+            agent.content_type_block_reason(TEST_AGENT_ID, &message("image")),
+            Some("该助手不支持此类消息".to_string())
+        );
+        assert_eq!(
+            agent.content_type_block_reason(TEST_AGENT_ID, &message("text")),
+            None
+        );
+
+        // 未配置时接受全部类型
+        let agent = test_agent();
+        assert_eq!(
+            agent.content_type_block_reason(TEST_AGENT_ID, &message("image")),
+            None
+        );
+        assert_eq!(
+            agent.content_type_block_reason(TEST_AGENT_ID, &message("location")),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_menu_click() {
+        let mut agent = test_agent();
+        let guest = register_guest(&agent, "robin", 1.0);
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let mut config = test_assistant_config(TEST_AGENT_ID);
+        config.menu.insert(
+            "weather".to_string(),
+            MenuAction::Prompt("今天天气如何？".to_string()),
+        );
+        config.menu.insert(
+            "about".to_string(),
+            MenuAction::Reply("关于本应用".to_string()),
+        );
+        install_mock(&mut agent, &config, provider);
+
+        let click = |key: &str| {
+            from_str::<AppMessageContent>(&format!(
+                "<xml><ToUserName><![CDATA[ww637951f75e40d82b]]></ToUserName><FromUserName><![CDATA[robin]]></FromUserName><CreateTime>1708218294</CreateTime><MsgType><![CDATA[event]]></MsgType><Event><![CDATA[click]]></Event><EventKey><![CDATA[{key}]]></EventKey><AgentID>1000002</AgentID></xml>"
+            ))
+            .expect("Click event should be parsed")
+        };
+        assert_eq!(
+            agent.menu_action(TEST_AGENT_ID, &click("about")),
+            Some(MenuAction::Reply("关于本应用".to_string()))
+        );
+        assert_eq!(agent.menu_action(TEST_AGENT_ID, &click("unknown")), None);
+
+        // 预设消息交由助手处理
+        let Some(MenuAction::Prompt(prompt)) = agent.menu_action(TEST_AGENT_ID, &click("weather"))
+        else {
+            panic!("Weather button should map to a prompt");
+        };
+        let apps = agent.apps();
+        let assistant = &apps.assistants[&TEST_AGENT_ID];
+        let reply = assistant.chat(&guest, &prompt).await.unwrap();
+        assert_eq!(reply.content(), "mock reply");
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(sent.messages.last().unwrap().content, "今天天气如何？");
+    }
+
+    #[tokio::test]
+    async fn test_process_outcome() {
+        let agent = agent_with_mock(mock::Agent::new("mock reply", 10, 5));
+        register_guest(&agent, "robin", 1.0);
+        register_guest(&agent, "debtor", -1.0);
+
+        // 常规对话
+        let outcome = agent
+            .process_message(TEST_AGENT_ID, text_message("robin", "Hello"))
+            .await;
+        assert_eq!(outcome, ProcessOutcome::Replied);
+        assert!(agent.accountant.get_guest("robin").unwrap().credit < 1.0);
+
+        // 欠费用户
+        let outcome = agent
+            .process_message(TEST_AGENT_ID, text_message("debtor", "Hello"))
+            .await;
+        assert_eq!(
+            outcome,
+            ProcessOutcome::Blocked {
+                reason: "账户余额不足。当前余额-1.000".to_string()
+            }
+        );
+
+        // 指令消息
+        let command = text_message("robin", "#查余额");
+        let msg_id = command.msg_id.clone();
+        let outcome = agent.process_message(TEST_AGENT_ID, command).await;
+        assert_eq!(outcome, ProcessOutcome::CommandHandled);
+
+        // 重复推送的消息
+        let duplicate = AppMessageContent {
+            msg_id: msg_id.clone(),
+            ..text_message("robin", "#查余额")
+        };
+        let outcome = agent.process_message(TEST_AGENT_ID, duplicate).await;
+        assert_eq!(
+            outcome,
+            ProcessOutcome::Ignored {
+                reason: format!("重复消息：{msg_id}")
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resend_last_reply() {
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let agent = agent_with_mock(provider);
+        let guest = register_guest(&agent, "robin", 1.0);
+
+        // 尚无回复
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#重发")
+            .await;
+        assert_eq!(reply, "当前会话中没有可重发的回复。");
+
+        agent.apps().assistants[&TEST_AGENT_ID]
+            .chat(&guest, "Hello")
+            .await
+            .unwrap();
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        // 重发不调用供应商，也不扣费
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#重发")
+            .await;
+        assert_eq!(reply, "mock reply");
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(agent.accountant.get_guest("robin").unwrap().credit, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_list_feedback() {
+        let agent = agent_with_mock(mock::Agent::new("mock reply", 10, 5));
+        let guest = register_guest(&agent, "robin", 1.0);
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$反馈列表$$")
+            .await;
+        assert_eq!(reply, "暂无用户反馈。");
+
+        // 尚无回复时无法反馈
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#反馈 答非所问")
+            .await;
+        assert_eq!(reply, "当前会话中没有可反馈的回复。");
+
+        agent.apps().assistants[&TEST_AGENT_ID]
+            .chat(&guest, "Hello")
+            .await
+            .unwrap();
+        for content in ["答非所问", "回复太短"] {
+            let reply = agent
+                .handle_instruction_msg(&guest, TEST_AGENT_ID, &format!("#反馈 {content}"))
+                .await;
+            assert_eq!(reply, "感谢反馈，管理员将尽快查看。");
+        }
+
+        // 由新到旧列出
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$反馈列表$$")
+            .await;
+        let lines: Vec<&str> = reply.lines().collect();
+        assert_eq!(lines[0], "共2条反馈，第1/1页：");
+        assert!(lines[1].ends_with("robin（应用1000002）：回复太短"));
+        assert_eq!(lines[2], "  回复：mock reply");
+        assert!(lines[3].ends_with("robin（应用1000002）：答非所问"));
+
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$反馈列表 0$$")
+            .await;
+        assert_eq!(reply, "页码应为从1开始的整数");
+        let command = format!("$$反馈列表 {}$$", i64::MAX);
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, &command)
+            .await;
+        assert_eq!(reply, "页码超出范围");
+    }
+
+    #[tokio::test]
+    async fn test_impersonate() {
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let agent = agent_with_mock(provider);
+        let guest = register_guest(&agent, "robin", 1.0);
+        agent.apps().assistants[&TEST_AGENT_ID]
+            .chat(&guest, "Hello")
+            .await
+            .unwrap();
+
+        // 以robin的会话为上下文，回复给管理员
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$模拟 robin 再说一遍$$")
+            .await;
+        assert!(reply.starts_with("以robin的身份获取的回复：\nmock reply"));
+        assert!(reply.ends_with("未向robin扣费。"));
+        let sent = received.lock().unwrap().last().cloned().unwrap();
+        let contents: Vec<&str> = sent.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents[1..], ["Hello", "mock reply", "再说一遍"]);
+
+        // 不写入会话记录，也不扣费
+        assert_eq!(
+            agent
+                .storage
+                .get_conversation(&guest, TEST_AGENT_ID)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(agent.accountant.get_guest("robin").unwrap().credit, 1.0);
+
+        // 费用单独记录，计入助手的费用上限
+        let since = Utc::now().naive_utc() - chrono::Duration::hours(1);
+        let spent = agent.storage.assistant_spend(TEST_AGENT_ID, since).unwrap();
+        let conversed: f64 = agent
+            .storage
+            .get_conversation(&guest, TEST_AGENT_ID)
+            .unwrap()
+            .iter()
+            .map(|m| m.cost)
+            .sum();
+        assert!(spent > conversed, "{spent} {conversed}");
+
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$模拟 nobody 你好$$")
+            .await;
+        assert_eq!(reply, "用户不存在：nobody");
+
+        // 模拟没有会话的用户时不为其创建会话
+        let quiet = register_guest(&agent, "quiet", 1.0);
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$模拟 quiet 你好$$")
+            .await;
+        assert!(reply.starts_with("以quiet的身份获取的回复："));
+        assert!(matches!(
+            agent.storage.get_conversation(&quiet, TEST_AGENT_ID),
+            Err(crate::storage::Error::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cost_log() {
+        let dir =
+            std::env::temp_dir().join(format!("wecom-gpt-reception-cost-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cost.csv");
+        let mut config = test_config();
+        config.cost_log_path = Some(path.to_str().unwrap().to_string());
+        let mut agent = Agent::new(&config).expect("Test agent should be initialized");
+        let guest = register_guest(&agent, "robin", 1.0);
+        install_mock(
+            &mut agent,
+            &test_assistant_config(TEST_AGENT_ID),
+            mock::Agent::new("mock reply", 10, 5),
+        );
+        let message = text_message("robin", "Hello");
+        let outcome = agent.process_message(TEST_AGENT_ID, message).await;
+        assert_eq!(outcome, ProcessOutcome::Replied);
+        agent.flush();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(
+            lines[0],
+            "timestamp,guest,agent_id,prompt_tokens,completion_tokens,cost"
+        );
+        assert_eq!(lines.len(), 2);
+        let fields: Vec<&str> = lines[1].split(',').collect();
+        assert!(chrono::NaiveDateTime::parse_from_str(fields[0], "%Y-%m-%d %H:%M:%S").is_ok());
+        assert_eq!(fields[1..], ["robin", "1000002", "10", "5", "0.000250"]);
+
+        // 模拟用户且不向其扣费时，费用记在操作者名下
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$模拟 robin 你好$$")
+            .await;
+        agent.flush();
+        let content = std::fs::read_to_string(&path).unwrap();
+        let last = content.lines().last().unwrap();
+        assert_eq!(last.split(',').nth(1), Some(TEST_ADMIN));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_switch_persona() {
+        let mut agent = test_agent();
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        let guest = register_guest(&agent, "robin", 1.0);
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let config = AssistantCfg {
+            personas: HashMap::from([
+                ("严谨模式".to_string(), "You are rigorous.".to_string()),
+                ("轻松模式".to_string(), "You are casual.".to_string()),
+            ]),
+            ..test_assistant_config(TEST_AGENT_ID)
+        };
+        install_mock(&mut agent, &config, provider);
+
+        // 未配置的人设被拒绝，提示词保持不变
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$人设 幽默模式$$")
+            .await;
+        assert_eq!(reply, "未知人设：幽默模式。可选人设：严谨模式、轻松模式");
+        assert_eq!(
+            agent.apps().assistants[&TEST_AGENT_ID].prompt(),
+            "You are a helpful assistant."
+        );
+
+        // 普通用户无权切换
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "$$人设 严谨模式$$")
+            .await;
+        assert_ne!(reply, "已切换至人设：严谨模式。");
+
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$人设 严谨模式$$")
+            .await;
+        assert_eq!(reply, "已切换至人设：严谨模式。");
+
+        // 下一次请求使用所选人设的提示词
+        agent.apps().assistants[&TEST_AGENT_ID]
+            .chat(&guest, "Hello")
+            .await
+            .unwrap();
+        let sent = received.lock().unwrap();
+        assert_eq!(sent[0].messages[0].content, "You are rigorous.");
+        drop(sent);
+
+        // 所选人设在重新创建助手后依然有效
+        install_mock(&mut agent, &config, mock::Agent::new("mock reply", 10, 5));
+        assert_eq!(
+            agent.apps().assistants[&TEST_AGENT_ID].prompt(),
+            "You are rigorous."
+        );
+
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, "$$人设 默认$$")
+            .await;
+        assert_eq!(reply, "已恢复默认提示词。");
+        assert_eq!(
+            agent.apps().assistants[&TEST_AGENT_ID].prompt(),
+            "You are a helpful assistant."
+        );
+        install_mock(&mut agent, &config, mock::Agent::new("mock reply", 10, 5));
+        assert_eq!(
+            agent.apps().assistants[&TEST_AGENT_ID].prompt(),
+            "You are a helpful assistant."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_adjust_max_context() {
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        let mut agent = agent_with_mock(provider);
+        let admin = agent.accountant.get_guest(TEST_ADMIN).unwrap();
+        let guest = register_guest(&agent, "robin", 1.0);
+        let apps = agent.apps();
+        let assistant = &apps.assistants[&TEST_AGENT_ID];
+        for _ in 0..3 {
+            assistant.chat(&guest, "Hello").await.unwrap();
+        }
+        // 系统消息、两轮历史与本条消息
+        assert_eq!(received.lock().unwrap()[2].messages.len(), 6);
+
+        // 超出供应商上限或不大于预留长度时拒绝
+        let command = format!("$${TEST_AGENT_ID} 上下文 8000$$");
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, &command)
+            .await;
+        assert_eq!(
+            reply,
+            "设置上下文长度出错：上下文长度不可超过供应商上限4096。"
+        );
+        let command = format!("$${TEST_AGENT_ID} 上下文 1024$$");
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, &command)
+            .await;
+        assert_eq!(
+            reply,
+            "设置上下文长度出错：上下文长度应大于为回复预留的1024。"
+        );
+        assert_eq!(agent.apps().assistants[&TEST_AGENT_ID].max_context(), 4096);
+
+        // 预留1024后仅余6个token，恰好容纳系统消息，历史全部舍弃
+        let command = format!("$${TEST_AGENT_ID} 上下文 1030$$");
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, &command)
+            .await;
+        assert_eq!(
+            reply,
+            format!("应用{TEST_AGENT_ID}的上下文长度已设为1030。")
+        );
+        agent.apps().assistants[&TEST_AGENT_ID]
+            .chat(&guest, "Hello")
+            .await
+            .unwrap();
+        let sent = received.lock().unwrap();
+        assert_eq!(sent[3].messages.len(), 2);
+        assert_eq!(sent[3].messages[0].content, "You are a helpful assistant.");
+        drop(sent);
+        drop(apps);
+
+        // 调整结果在重新创建助手后依然有效
+        let config = test_assistant_config(TEST_AGENT_ID);
+        install_mock(&mut agent, &config, mock::Agent::new("mock reply", 10, 5));
+        assert_eq!(agent.apps().assistants[&TEST_AGENT_ID].max_context(), 1030);
+
+        let command = format!("$${TEST_AGENT_ID} 上下文 默认$$");
+        let reply = agent
+            .handle_instruction_msg(&admin, TEST_AGENT_ID, &command)
+            .await;
+        assert_eq!(
+            reply,
+            format!("应用{TEST_AGENT_ID}的上下文长度已设为4096。")
+        );
+        install_mock(&mut agent, &config, mock::Agent::new("mock reply", 10, 5));
+        assert_eq!(agent.apps().assistants[&TEST_AGENT_ID].max_context(), 4096);
+    }
+
+    #[tokio::test]
+    async fn test_validate_wecom_on_start() {
+        use axum::routing::get;
+        use axum::Router;
+
+        // 模拟企业微信接口，secret错误时返回错误码
+        let app = Router::new().route(
+            "/cgi-bin/gettoken",
+            get(|| async { r#"{"errcode":40001,"errmsg":"invalid credential"}"# }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut config = test_config();
+        config.wecom.api_base = format!("http://{addr}");
+        config.validate_wecom_on_start = true;
+        let err = Agent::init(&config).await.err().expect("Init should fail");
+        assert_eq!(
+            err.to_string(),
+            format!("应用{TEST_AGENT_ID}获取access_token失败。错误码40001：invalid credential")
+        );
+    }
+
+    #[test]
+    fn test_reply_mention() {
+        let message = text_message("robin", "你好");
+        let agent = test_agent();
+        assert_eq!(agent.reply_text(&message, "答案"), "答案");
+
+        let mut assistant_cfg = test_assistant_config(TEST_AGENT_ID);
+        assistant_cfg.mention_sender = true;
+        let mut config = test_config();
+        config.assistants = vec![assistant_cfg];
+        let agent = Agent::new(&config).expect("Test agent should be initialized");
+        assert_eq!(agent.reply_text(&message, "答案"), "<@robin> 答案");
+    }
+
+    #[tokio::test]
+    async fn test_command_aliases() {
+        let agent = test_agent();
+        let guest = register_guest(&agent, "robin", 2.5);
+        let expected = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#查余额")
+            .await;
+        assert_eq!(expected, "当前余额：2.500");
+        let reply = agent
+            .handle_instruction_msg(&guest, TEST_AGENT_ID, "#balance")
+            .await;
+        assert_eq!(reply, expected);
+
+        // 别名之后的参数保持不变
+        assert_eq!(agent.resolve_alias("#usage"), "#查消耗");
+        assert_eq!(agent.resolve_alias("#help me"), "#帮助 me");
+        assert_eq!(agent.resolve_alias("#balances"), "#balances");
+    }
+
+    #[test]
+    fn test_onboarding_credit() {
+        let mut config = test_config();
+        config.accountant.onboarding_credit = 5.0;
+        let agent = Agent::new(&config).expect("Test agent should be initialized");
+        agent.accountant.register_contact("robin").unwrap();
+        let guest = agent.accountant.get_guest("robin").unwrap();
+        assert_eq!(guest.credit, 5.0);
+
+        // 已存在的用户不会重复获得额度
+        agent
+            .accountant
+            .update_guest(&Guest {
+                credit: 1.0,
+                ..guest
+            })
+            .unwrap();
+        agent.accountant.register_contact("robin").unwrap();
+        assert_eq!(agent.accountant.get_guest("robin").unwrap().credit, 1.0);
+    }
+
+    // 以通讯录应用的key加密的新增成员事件
+    fn contact_event(agent: &Agent, user_id: &str) -> (CallbackParams, String) {
+        let crypto = agent.accountant.crypto_agent();
+        let encrypted = crypto.encrypt(&wecom_crypto::Source {
+            text: format!("<xml><UserID><![CDATA[{user_id}]]></UserID></xml>"),
+            receive_id: "ww0000000000000000".to_string(),
+        });
+        let timestamp = "1708218294".to_string();
+        let nonce = "1372623149".to_string();
+        let msg_signature = crypto.generate_signature(vec![&timestamp, &nonce, &encrypted]);
+        let body = format!(
+            "<xml><ToUserName><![CDATA[ww0000000000000000]]></ToUserName><AgentID><![CDATA[1000003]]></AgentID><Encrypt><![CDATA[{encrypted}]]></Encrypt></xml>"
+        );
+        let params = CallbackParams {
+            msg_signature,
+            nonce,
+            timestamp,
+        };
+        (params, body)
+    }
+
+    #[test]
+    fn test_contact_event_registration() {
+        let agent = test_agent();
+        let count = agent.accountant.guest_count().unwrap();
+        let (params, body) = contact_event(&agent, "alice");
+        assert!(agent
+            .accountant
+            .handle_user_creation_event(Query(params), body)
+            .is_ok());
+        assert_eq!(agent.accountant.guest_count().unwrap(), count + 1);
+
+        // 用户发送消息时已自动注册，通讯录事件视为成功，不重复创建，也不改动余额
+        register_guest(&agent, "robin", 2.0);
+        let (params, body) = contact_event(&agent, "robin");
+        assert!(agent
+            .accountant
+            .handle_user_creation_event(Query(params), body)
+            .is_ok());
+        assert_eq!(agent.accountant.guest_count().unwrap(), count + 2);
+        assert_eq!(agent.accountant.get_guest("robin").unwrap().credit, 2.0);
+    }
+
+    #[tokio::test]
+    async fn test_grace_credit() {
+        let mut config = test_config();
+        config.accountant.grace_credit = -1.0;
+        let mut agent = Agent::new(&config).expect("Test agent should be initialized");
+        agent.apps_mut().messengers.clear();
+
+        // 透支额度内可以继续使用，并收到提醒，提醒中指明暂停服务的下限
+        let guest = register_guest(&agent, "robin", -0.5);
+        assert!(agent.accountant.verify_guest("robin").is_ok());
+        let notice = agent.low_balance_notice(&guest, guest.credit).unwrap();
+        assert!(notice.contains("已进入透支额度"), "{notice}");
+        assert!(
+            notice.contains("余额降至-1.000及以下时将暂停服务"),
+            "{notice}"
+        );
+
+        // 恰好等于透支下限时即暂停服务，与提醒内容一致
+        register_guest(&agent, "bob", -1.0);
+        let outcome = agent
+            .process_message(TEST_AGENT_ID, text_message("bob", "Hello"))
+            .await;
+        assert_eq!(
+            outcome,
+            ProcessOutcome::Blocked {
+                reason: "账户余额不足。当前余额-1.000".to_string()
+            }
+        );
+
+        // 低于透支下限时暂停服务
+        register_guest(&agent, "alice", -1.5);
+        let Err(AccountError::Overdue(credit)) = agent.accountant.verify_guest("alice") else {
+            panic!("Guest below the grace floor should be overdue");
+        };
+        assert_eq!(
+            agent.chat_block_reason(Some(credit)),
+            Some("账户余额不足。当前余额-1.500".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spend_cap_blocks_message() {
+        let mut config = test_config();
+        config.assistants[0].assistant_daily_cap = Some(0.0);
+        let mut agent = Agent::new(&config).expect("Test agent should be initialized");
+        let provider = mock::Agent::new("mock reply", 10, 5);
+        let received = provider.received();
+        install_mock(&mut agent, &config.assistants[0], provider);
+        register_guest(&agent, "robin", 1.0);
+
+        let outcome = agent
+            .process_message(TEST_AGENT_ID, text_message("robin", "Hello"))
+            .await;
+        assert_eq!(
+            outcome,
+            ProcessOutcome::Blocked {
+                reason: "该助手今日额度已用尽".to_string()
+            }
+        );
+        assert!(received.lock().unwrap().is_empty());
+        assert_eq!(agent.accountant.get_guest("robin").unwrap().credit, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_content_filtered_not_billed() {
+        let mut config = test_config();
+        config.content_filter_reply = "内容无法处理。".to_string();
+        let mut agent = Agent::new(&config).expect("Test agent should be initialized");
+        let guest = register_guest(&agent, "robin", 1.0);
+        install_mock(
+            &mut agent,
+            &test_assistant_config(TEST_AGENT_ID),
+            mock::Agent::content_filtered("violence"),
+        );
+        let message = text_message("robin", "Hello");
+
+        let outcome = agent.process_message(TEST_AGENT_ID, message).await;
+        assert_eq!(
+            outcome,
+            ProcessOutcome::Blocked {
+                reason: "内容无法处理。".to_string()
+            }
+        );
+        assert_eq!(agent.accountant.get_guest("robin").unwrap().credit, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_progressive_send() {
+        let mut config = test_config();
+        config.progressive_send_tokens = Some(2);
+        config.progressive_send_secs = 0;
+        let mut agent = Agent::new(&config).expect("Test agent should be initialized");
+        let guest = register_guest(&agent, "robin", 1.0);
+        agent.apps_mut().messengers.clear();
+        let message = text_message("robin", "Hello");
+        let chunks = ["企业", "微信", "助手", "流式", "回复"];
+
+        // 间隔不受限时，每生成约4个token分段发送一次，最后剩余的片段随完整回复发送
+        let provider = mock::Agent::new("", 10, 5).with_chunks(&chunks);
+        let conversation = crate::provider::openai::Conversation::default();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let progressive = ProgressiveSend {
+            every_tokens: 4,
+            every: Duration::from_secs(60),
+            min_interval: Duration::ZERO,
+        };
+        let (completion, (sent, relayed)) = tokio::join!(
+            provider.stream(&conversation, sender),
+            agent.relay_progress(&progressive, receiver, &message)
+        );
+        let content = completion.unwrap().content;
+        assert_eq!(content, "企业微信助手流式回复");
+        assert_eq!(sent, 2);
+        assert!(
+            !relayed.is_empty() && content.starts_with(&relayed),
+            "{relayed}"
+        );
+
+        // 超出单条消息长度上限的内容拆分发送
+        let long = "a".repeat(3000);
+        let provider = mock::Agent::new("", 10, 5).with_chunks(&[long.as_str(), "b"]);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (_, (sent, relayed)) = tokio::join!(
+            provider.stream(&conversation, sender),
+            agent.relay_progress(&progressive, receiver, &message)
+        );
+        assert_eq!(sent, 2);
+        assert_eq!(relayed, long);
+
+        // 收尾消息同样拆分，完毕标记位于最后一条
+        let texts = agent.closing_texts(&message, &long);
+        assert_eq!(texts.len(), 2);
+        assert!(texts.iter().all(|t| t.len() <= WECOM_TEXT_MAX_BYTES));
+        assert!(texts[1].ends_with(PROGRESS_COMPLETE_MARK));
+        assert!(!texts[0].ends_with(PROGRESS_COMPLETE_MARK));
+        assert_eq!(agent.closing_texts(&message, ""), vec!["（回复完毕）"]);
+        assert_eq!(split_bytes("企业微信", 7), vec!["企业", "微信"]);
+        assert_eq!(split_bytes("企业", 2), vec!["企业"]);
+
+        // 配置的间隔为0时，仍受最短间隔限制
+        let provider = mock::Agent::new("", 10, 5).with_chunks(&chunks);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let progressive = agent.progressive.as_ref().unwrap();
+        let (_, (sent, relayed)) = tokio::join!(
+            provider.stream(&conversation, sender),
+            agent.relay_progress(progressive, receiver, &message)
+        );
+        assert_eq!(sent, 0);
+        assert!(relayed.is_empty());
+
+        // 经由助手流式获取回复时，照常计费并记录完整的回复
+        install_mock(
+            &mut agent,
+            &test_assistant_config(TEST_AGENT_ID),
+            mock::Agent::new("", 10, 5).with_chunks(&chunks),
+        );
+        let outcome = agent.process_message(TEST_AGENT_ID, message).await;
+        assert_eq!(outcome, ProcessOutcome::Replied);
+        let credit = agent.accountant.get_guest("robin").unwrap().credit;
+        assert!((credit - (1.0 - 0.00025)).abs() < 1e-9, "{credit}");
+        assert_eq!(
+            agent.apps().assistants[&TEST_AGENT_ID]
+                .last_reply(&guest)
+                .as_deref(),
+            Some("企业微信助手流式回复")
+        );
+    }
+}
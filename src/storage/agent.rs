@@ -1,10 +1,18 @@
-use chrono::Utc;
+use chrono::{NaiveDateTime, Utc};
 use std::fmt;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
+use aes_gcm::aead::{Aead, AeadCore, OsRng};
+use aes_gcm::{Aes256Gcm, Key, KeyInit, Nonce};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use diesel::prelude::*;
-use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool, PoolError, PooledConnection};
 use diesel::sqlite::SqliteConnection;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use serde::{Deserialize, Serialize};
 
 use super::{model, schema};
 use crate::core;
@@ -12,105 +20,775 @@ use crate::provider::openai;
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 
+// 余额流水的类型
+const LEDGER_RECHARGE: &str = "recharge";
+const LEDGER_REVERSAL: &str = "reversal";
+
+type SqlitePool = Pool<ConnectionManager<SqliteConnection>>;
+
 #[derive(Debug, Clone)]
 pub enum Error {
     NotFound,
+    AlreadyExists,
     Database(String),
     Connection(String),
+    Decode(String),     // 已保存的消息内容无法解密或解压，多为消息加密密钥有误
+    Unwritable(String), // 数据库文件本身不可写，如只读、磁盘已满或I/O错误
 }
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let err_msg = match self {
             Self::NotFound => "Item not found",
+            Self::AlreadyExists => "Item already exists",
             Self::Database(msg) => msg,
             Self::Connection(msg) => msg,
+            Self::Decode(msg) => msg,
+            Self::Unwritable(msg) => msg,
         };
         write!(f, "{}", err_msg)
     }
 }
 impl std::error::Error for Error {}
 
-pub struct Agent {
-    connections: Pool<ConnectionManager<SqliteConnection>>,
+/// 存储模块的可选配置项
+#[derive(Deserialize, Clone, Debug)]
+pub struct Config {
+    /// 连接池最大连接数
+    #[serde(default = "default_max_connections")]
+    pub max_connections: u32,
+    /// 连接池保持的最少空闲连接数。缺省时与最大连接数相同。
+    #[serde(default)]
+    pub min_idle: Option<u32>,
+    /// 启动时打开数据库的最多尝试次数
+    #[serde(default = "default_connect_attempts")]
+    pub connect_attempts: u32,
+    /// 首次重试前的等待时间（毫秒），此后每次加倍
+    #[serde(default = "default_connect_backoff_ms")]
+    pub connect_backoff_ms: u64,
+    /// 备用数据库路径。主数据库不可写（如磁盘已满、权限不足）时，改用该数据库继续服务。
+    /// 切换时写入`<备用数据库路径>.switched`记录，重启后继续使用备用数据库；删除该记录即恢复使用主数据库
+    #[serde(default)]
+    pub failover_path: Option<String>,
+    /// 等待其他连接释放数据库锁的最长时间（毫秒）
+    #[serde(default = "default_busy_timeout_ms")]
+    pub busy_timeout_ms: u64,
+    /// 保存的消息内容的最大字符数，超出部分截去。不影响发送给AI的内容。缺省不限制
+    #[serde(default)]
+    pub max_content_chars: Option<usize>,
+    /// 以zstd压缩保存消息内容，读取时自动解压。缺省不压缩
+    #[serde(default)]
+    pub compress_content: bool,
+    /// 数据库加密密钥。设置后每个连接均以SQLCipher的`PRAGMA key`打开数据库，需启用sqlcipher特性
+    #[serde(default)]
+    pub db_encryption_key: Option<String>,
+    /// 消息内容的加密密钥，为base64编码的32字节AES-256密钥。设置后以AES-GCM加密保存消息内容，
+    /// 读取时自动解密。缺省不加密。
+    /// 仅加密消息内容：消息的语义向量、会话标题与上下文摘要均由消息内容生成，但仍以明文保存。
+    /// 需要一并保护时请使用db_encryption_key加密整个数据库
+    #[serde(default)]
+    pub content_encryption_key: Option<String>,
 }
 
-impl Agent {
-    /// 初始化数据库
-    pub fn new(database_url: &str, admin: &str) -> Result<Self, Error> {
-        // Init a db pool
-        let manager = ConnectionManager::<SqliteConnection>::new(database_url);
-        let connections = Pool::builder()
-            .build(manager)
-            .map_err(|e| Error::Database(e.to_string()))?;
+fn default_max_connections() -> u32 {
+    10
+}
 
-        // 初始化数据库结构
-        {
-            let conn = &mut connections
-                .get()
-                .map_err(|e| Error::Connection(e.to_string()))?;
-            conn.run_pending_migrations(MIGRATIONS)
-                .map_err(|e| Error::Database(e.to_string()))?;
+fn default_connect_attempts() -> u32 {
+    5
+}
+
+fn default_connect_backoff_ms() -> u64 {
+    200
+}
+
+fn default_busy_timeout_ms() -> u64 {
+    5000
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_connections: default_max_connections(),
+            min_idle: None,
+            connect_attempts: default_connect_attempts(),
+            connect_backoff_ms: default_connect_backoff_ms(),
+            failover_path: None,
+            busy_timeout_ms: default_busy_timeout_ms(),
+            max_content_chars: None,
+            compress_content: false,
+            db_encryption_key: None,
+            content_encryption_key: None,
         }
+    }
+}
 
-        // 数据库默认内容需要初始化？
-        let db_initialized: bool = {
-            let conn = &mut connections
-                .get()
-                .map_err(|e| Error::Connection(e.to_string()))?;
-            match schema::db_init_status::table
-                .find(1)
-                .first::<model::DbStatus>(conn)
-            {
-                Ok(o) => {
-                    tracing::info!("当前数据库初始化于{}", o.initialized_at);
-                    true
-                }
-                Err(e) => {
-                    tracing::warn!("数据库尚未初始化（{e}）。将初始化数据库。");
-                    false
-                }
+// 尝试打开数据库，失败时按指数退避重试。
+// 适用于数据库所在卷稍晚才挂载的部署环境。
+fn wait_for_database(database_url: &str, config: &Config) -> Result<(), Error> {
+    let attempts = config.connect_attempts.max(1);
+    let mut backoff = Duration::from_millis(config.connect_backoff_ms);
+    for attempt in 1..=attempts {
+        match SqliteConnection::establish(database_url) {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt == attempts => {
+                return Err(Error::Connection(format!(
+                    "尝试{attempts}次后仍无法打开数据库{database_url}。{e}"
+                )))
             }
+            Err(e) => {
+                tracing::warn!(
+                    "第{attempt}次打开数据库失败：{e}。{}毫秒后重试。",
+                    backoff.as_millis()
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 打开数据库并建立连接池，同时完成迁移与默认内容的初始化
+fn open_pool(database_url: &str, admin: &str, config: &Config) -> Result<SqlitePool, Error> {
+    // 未启用sqlcipher特性时PRAGMA key不起作用，数据将以明文保存
+    if config.db_encryption_key.is_some() && !cfg!(feature = "sqlcipher") {
+        return Err(Error::Connection(
+            "配置了数据库加密密钥，但程序未启用sqlcipher特性。".to_string(),
+        ));
+    }
+
+    // 确认数据库可用后再建立连接池
+    wait_for_database(database_url, config)?;
+
+    // Init a db pool
+    let manager = ConnectionManager::<SqliteConnection>::new(database_url);
+    let connections = Pool::builder()
+        .max_size(config.max_connections)
+        .min_idle(config.min_idle)
+        .connection_customizer(Box::new(ConnectionSetup {
+            busy_timeout_ms: config.busy_timeout_ms,
+            encryption_key: config.db_encryption_key.clone(),
+        }))
+        .build(manager)
+        .map_err(|e| Error::Database(e.to_string()))?;
+
+    // 初始化数据库结构与默认内容。多个实例共用同一数据库时，排他事务保证同一时间仅有一个实例执行，
+    // 其余实例等待其完成后发现已无需执行。
+    let conn = &mut connections
+        .get()
+        .map_err(|e| Error::Connection(e.to_string()))?;
+    conn.exclusive_transaction(|conn| initialize(conn, admin))
+        .map_err(|e| Error::Database(e.to_string()))?;
+
+    Ok(connections)
+}
+
+// 执行数据库迁移，并在首次使用时填充默认内容
+fn initialize(
+    conn: &mut SqliteConnection,
+    admin: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    conn.run_pending_migrations(MIGRATIONS)?;
+
+    // 数据库默认内容需要初始化？
+    match schema::db_init_status::table
+        .find(1)
+        .first::<model::DbStatus>(conn)
+    {
+        Ok(o) => {
+            tracing::info!("当前数据库初始化于{}", o.initialized_at);
+            return Ok(());
+        }
+        Err(e) => tracing::warn!("数据库尚未初始化（{e}）。将初始化数据库。"),
+    }
+    let timestamp = Utc::now().naive_utc();
+
+    // 填充默认的管理员用户
+    {
+        use schema::guests;
+        diesel::insert_into(guests::table)
+            .values((
+                guests::id.eq(1),
+                guests::name.eq(admin),
+                guests::credit.eq(0.0),
+                guests::created_at.eq(timestamp),
+                guests::updated_at.eq(timestamp),
+                guests::admin.eq(true),
+            ))
+            .execute(conn)
+            .map_err(|e| format!("创建管理员账户出错。{e}"))?;
+    }
+
+    // 填充数据库初始化日期
+    {
+        use schema::db_init_status::dsl::*;
+        diesel::insert_into(db_init_status)
+            .values(initialized_at.eq(timestamp))
+            .execute(conn)?;
+    }
+    tracing::info!("数据库初始化完成。");
+    Ok(())
+}
+
+// 每个连接的初始设置
+// - 配置了密钥时解密数据库。PRAGMA key须先于其他语句执行。
+// - 设置等待锁的时长，以免其他连接或实例写入时立即报错。
+struct ConnectionSetup {
+    busy_timeout_ms: u64,
+    encryption_key: Option<String>,
+}
+
+impl fmt::Debug for ConnectionSetup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionSetup")
+            .field("busy_timeout_ms", &self.busy_timeout_ms)
+            .field("encrypted", &self.encryption_key.is_some())
+            .finish()
+    }
+}
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for ConnectionSetup {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        if let Some(key) = &self.encryption_key {
+            diesel::sql_query(format!("PRAGMA key = '{}'", key.replace('\'', "''")))
+                .execute(conn)
+                .map_err(diesel::r2d2::Error::QueryError)?;
+        }
+        diesel::sql_query(format!("PRAGMA busy_timeout = {}", self.busy_timeout_ms))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+// 助手设置不存在时插入空行，以便各项设置分别更新而互不覆盖
+fn ensure_assistant_settings(conn: &mut SqliteConnection, id: u64) -> QueryResult<()> {
+    use schema::assistant_settings::dsl;
+    diesel::insert_or_ignore_into(dsl::assistant_settings)
+        .values((
+            dsl::assistant_id.eq(id as i32),
+            dsl::updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(conn)
+        .map(|_| ())
+}
+
+// 将查询错误转换为本模块的错误。数据库文件本身不可写时单独标记，以便切换至备用数据库。
+fn query_error(e: diesel::result::Error) -> Error {
+    match &e {
+        diesel::result::Error::DatabaseError(kind, info) if is_unwritable(kind, info.message()) => {
+            Error::Unwritable(e.to_string())
+        }
+        _ => Error::Database(e.to_string()),
+    }
+}
+
+// 错误是否源于数据库文件本身不可写，如只读、磁盘已满或I/O错误。
+// diesel仅为约束冲突等错误区分类型，其余SQLite错误均为Unknown，只保留SQLite的错误描述。
+// 这些结果码的描述是固定的，因此与sqlite3_errstr给出的描述逐一比对，而不是匹配其中的字词。
+fn is_unwritable(kind: &diesel::result::DatabaseErrorKind, message: &str) -> bool {
+    use libsqlite3_sys::{SQLITE_CANTOPEN, SQLITE_FULL, SQLITE_IOERR, SQLITE_READONLY};
+    if matches!(kind, diesel::result::DatabaseErrorKind::ReadOnlyTransaction) {
+        return true;
+    }
+    [SQLITE_READONLY, SQLITE_FULL, SQLITE_IOERR, SQLITE_CANTOPEN]
+        .into_iter()
+        .any(|code| sqlite_errstr(code) == message)
+}
+
+// SQLite结果码对应的英文描述
+fn sqlite_errstr(code: std::os::raw::c_int) -> &'static str {
+    // sqlite3_errstr返回静态字符串，对任意结果码均不为空
+    let description = unsafe { std::ffi::CStr::from_ptr(libsqlite3_sys::sqlite3_errstr(code)) };
+    description.to_str().unwrap_or_default()
+}
+
+// 切换记录的路径。该文件存在表示已切换至备用数据库，重启后直接使用备用数据库。
+fn failover_marker(path: &str) -> String {
+    format!("{path}.switched")
+}
+
+// 备用数据库。首次切换时才建立连接池。
+struct Failover {
+    path: String,
+    admin: String,
+    config: Config,
+    pool: Mutex<Option<SqlitePool>>,
+    switching: Mutex<()>, // 保证同一时间仅有一个线程执行切换，切换期间不影响其他线程读写主数据库
+}
+
+impl Failover {
+    // 是否已切换至备用数据库
+    fn switched(&self) -> bool {
+        self.pool
+            .lock()
+            .expect("Failover lock should not be poisoned")
+            .is_some()
+    }
+}
+
+// 数据库连接池。切换至备用数据库后，全部读写均使用备用数据库。
+struct Connections {
+    primary: SqlitePool,
+    failover: Option<Failover>,
+}
+
+impl Connections {
+    fn get(&self) -> Result<PooledConnection<ConnectionManager<SqliteConnection>>, PoolError> {
+        let secondary = self.failover.as_ref().and_then(|f| {
+            f.pool
+                .lock()
+                .expect("Failover lock should not be poisoned")
+                .clone()
+        });
+        match secondary {
+            Some(pool) => pool.get(),
+            None => self.primary.get(),
+        }
+    }
+
+    // 切换至备用数据库。先将主数据库的当前内容复制过去，替换已有的备用数据库；
+    // 复制失败时沿用已有的备用数据库，没有时从空白开始。切换后写入切换记录，重启后依然使用备用数据库。
+    // 已切换或切换成功时返回true。
+    fn fail_over(&self, reason: &str) -> bool {
+        let Some(failover) = &self.failover else {
+            return false;
         };
-        if !db_initialized {
-            let timestamp = Utc::now().naive_utc();
-            // 填充默认的管理员用户
-            {
-                use schema::guests;
-                let conn = &mut connections
-                    .get()
-                    .map_err(|e| Error::Connection(e.to_string()))?;
-                diesel::insert_into(guests::table)
-                    .values((
-                        guests::id.eq(1),
-                        guests::name.eq(admin),
-                        guests::credit.eq(0.0),
-                        guests::created_at.eq(timestamp),
-                        guests::updated_at.eq(timestamp),
-                        guests::admin.eq(true),
-                    ))
-                    .execute(conn)
-                    .map_err(|e| Error::Database(format!("创建管理员账户出错。{e}")))?;
+        if failover.switched() {
+            return true;
+        }
+        // 复制与建立连接池耗时较长，期间其他线程仍可读写主数据库，只有切换本身需要短暂持有连接池的锁
+        let _switching = failover
+            .switching
+            .lock()
+            .expect("Failover lock should not be poisoned");
+        if failover.switched() {
+            return true;
+        }
+        tracing::error!(
+            "主数据库不可写，切换至备用数据库{}。{reason}",
+            failover.path
+        );
+        if let Err(e) = self.copy_primary(&failover.path) {
+            tracing::error!("复制主数据库内容失败，备用数据库将沿用已有内容或从空白开始。{e}");
+        }
+        // 主数据库已不可用，打开备用数据库时不再等待重试
+        let config = Config {
+            connect_attempts: 1,
+            ..failover.config.clone()
+        };
+        let pool = match open_pool(&failover.path, &failover.admin, &config) {
+            Ok(p) => p,
+            Err(e) => {
+                tracing::error!("打开备用数据库失败。{e}");
+                return false;
             }
+        };
+        let marker = failover_marker(&failover.path);
+        let record = format!("{} {reason}\n", Utc::now().naive_utc());
+        if let Err(e) = std::fs::write(&marker, record) {
+            tracing::error!("写入切换记录{marker}失败，重启后将重新使用主数据库。{e}");
+        }
+        *failover
+            .pool
+            .lock()
+            .expect("Failover lock should not be poisoned") = Some(pool);
+        true
+    }
 
-            // 填充数据库初始化日期
-            {
-                use schema::db_init_status::dsl::*;
-                let conn = &mut connections
-                    .get()
-                    .map_err(|e| Error::Connection(e.to_string()))?;
-                diesel::insert_into(db_init_status)
-                    .values(initialized_at.eq(timestamp))
-                    .execute(conn)
-                    .map_err(|e| Error::Database(e.to_string()))?;
+    // 将主数据库的当前内容复制为备用数据库。先写入临时文件，完成后再替换，以免留下不完整的备用数据库。
+    fn copy_primary(&self, path: &str) -> Result<(), String> {
+        let staging = format!("{path}.copying");
+        let _ = std::fs::remove_file(&staging);
+        let mut conn = self.primary.get().map_err(|e| e.to_string())?;
+        diesel::sql_query(format!("VACUUM INTO '{}'", staging.replace('\'', "''")))
+            .execute(&mut conn)
+            .map_err(|e| e.to_string())?;
+        std::fs::rename(&staging, path).map_err(|e| e.to_string())
+    }
+}
+
+/// 用户列表的排序方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UserOrder {
+    Name,      // 按用户名升序
+    Credit,    // 按余额降序
+    CreatedAt, // 按注册时间升序
+}
+
+/// 用户在全部会话中的累计用量
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Usage {
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub cost: f64,
+    pub messages: i64,
+}
+
+/// 一段会话的概要
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConversationSummary {
+    pub id: i32,
+    pub active: bool,
+    pub pinned: bool,
+    pub title: Option<String>,
+    pub messages: i64,
+    pub updated_at: NaiveDateTime, // 最后一条消息的时间。无消息时为会话的更新时间
+}
+
+/// 一条用户反馈
+#[derive(Debug, PartialEq, Clone)]
+pub struct FeedbackEntry {
+    pub guest_name: String,
+    pub assistant_id: u64,
+    pub content: String, // 反馈内容
+    pub reply: String,   // 被反馈的AI回复片段
+    pub created_at: NaiveDateTime,
+}
+
+/// 会话的个性化设置，目前仅有采样温度。未设置的项沿用助手默认值。
+/// 回复语言等偏好按用户保存，不属于会话设置。
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct ConversationSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>, // 采样温度
+}
+
+/// 超出长度限制的早期消息的摘要。messages为摘要涵盖的消息数，自会话的首条消息起算。
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ContextSummary {
+    pub content: String,
+    pub messages: usize,
+}
+
+/// 消息的附加信息
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+pub struct MessageMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>, // 供应商返回的请求ID
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool, // 保存的内容是否因超长而被截断
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool, // 保存的内容是否经过压缩
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>, // 内容经过加密时所用的nonce，base64编码
+}
+
+// 压缩后的内容以base64编码保存在文本列中
+fn compress(content: &str) -> Result<String, Error> {
+    let bytes = zstd::encode_all(content.as_bytes(), 0)
+        .map_err(|e| Error::Database(format!("压缩消息内容失败。{e}")))?;
+    Ok(BASE64.encode(bytes))
+}
+
+fn decompress(content: &str) -> Result<String, Error> {
+    let bytes = BASE64
+        .decode(content)
+        .map_err(|e| Error::Decode(format!("消息内容编码有误。{e}")))?;
+    let bytes = zstd::decode_all(bytes.as_slice())
+        .map_err(|e| Error::Decode(format!("解压消息内容失败。{e}")))?;
+    String::from_utf8(bytes).map_err(|e| Error::Decode(format!("消息内容编码有误。{e}")))
+}
+
+// 解析base64编码的AES-256密钥
+fn content_cipher(key: &str) -> Result<Aes256Gcm, Error> {
+    let bytes = BASE64
+        .decode(key)
+        .map_err(|e| Error::Database(format!("消息加密密钥编码有误。{e}")))?;
+    if bytes.len() != 32 {
+        return Err(Error::Database(format!(
+            "消息加密密钥应为32字节，实际为{}字节。",
+            bytes.len()
+        )));
+    }
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)))
+}
+
+// 加密后的内容以base64编码保存在文本列中，nonce另行保存在附加信息中
+fn encrypt(cipher: &Aes256Gcm, content: &str) -> Result<(String, String), Error> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let bytes = cipher
+        .encrypt(&nonce, content.as_bytes())
+        .map_err(|e| Error::Database(format!("加密消息内容失败。{e}")))?;
+    Ok((BASE64.encode(bytes), BASE64.encode(nonce)))
+}
+
+fn decrypt(cipher: &Aes256Gcm, content: &str, nonce: &str) -> Result<String, Error> {
+    let decode = |s: &str| {
+        BASE64
+            .decode(s)
+            .map_err(|e| Error::Decode(format!("消息内容编码有误。{e}")))
+    };
+    let nonce = decode(nonce)?;
+    if nonce.len() != 12 {
+        return Err(Error::Decode("消息加密nonce长度有误。".to_string()));
+    }
+    let bytes = cipher
+        .decrypt(Nonce::from_slice(&nonce), decode(content)?.as_slice())
+        .map_err(|e| Error::Decode(format!("解密消息内容失败。{e}")))?;
+    String::from_utf8(bytes).map_err(|e| Error::Decode(format!("消息内容编码有误。{e}")))
+}
+
+// 按附加信息的标注，依次解密、解压，还原为原始内容
+fn decode_message(
+    mut message: model::Message,
+    cipher: Option<&Aes256Gcm>,
+) -> Result<model::Message, Error> {
+    let meta = message
+        .meta
+        .as_deref()
+        .and_then(|m| serde_json::from_str::<MessageMeta>(m).ok())
+        .unwrap_or_default();
+    if let Some(nonce) = &meta.nonce {
+        let Some(cipher) = cipher else {
+            return Err(Error::Decode(format!(
+                "消息{}已加密，但未配置消息加密密钥。",
+                message.id
+            )));
+        };
+        message.content = decrypt(cipher, &message.content, nonce)?;
+    }
+    if meta.compressed {
+        message.content = decompress(&message.content)?;
+    }
+    Ok(message)
+}
+
+// 向量以小端序f32数组的形式存储
+fn encode_embedding(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn decode_embedding(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect()
+}
+
+// 余弦相似度。维度不一致或存在零向量时返回None。
+fn cosine_similarity(a: &[f32], b: &[f32]) -> Option<f32> {
+    if a.len() != b.len() || a.is_empty() {
+        return None;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denominator = norm(a) * norm(b);
+    if denominator > 0.0 {
+        Some(dot / denominator)
+    } else {
+        None
+    }
+}
+
+pub struct Agent {
+    connections: Connections,
+    max_content_chars: Option<usize>,
+    compress_content: bool,
+    cipher: Option<Aes256Gcm>, // 加密消息内容
+}
+
+impl Agent {
+    /// 使用默认配置初始化数据库
+    pub fn new(database_url: &str, admin: &str) -> Result<Self, Error> {
+        Self::with_config(database_url, admin, &Config::default())
+    }
+
+    /// 初始化数据库。配置了备用数据库时，主数据库不可写后改用备用数据库。
+    pub fn with_config(database_url: &str, admin: &str, config: &Config) -> Result<Self, Error> {
+        // r2d2在最大连接数为0或最少空闲连接数超过最大连接数时直接panic，需事先检查
+        if config.max_connections == 0 {
+            return Err(Error::Connection("连接池最大连接数须大于0。".to_string()));
+        }
+        if let Some(min_idle) = config.min_idle.filter(|m| *m > config.max_connections) {
+            return Err(Error::Connection(format!(
+                "连接池最少空闲连接数{min_idle}不可超过最大连接数{}。",
+                config.max_connections
+            )));
+        }
+        let cipher = config
+            .content_encryption_key
+            .as_deref()
+            .map(content_cipher)
+            .transpose()?;
+        // 曾切换至备用数据库时，备用数据库的内容较新，直接使用备用数据库
+        if let Some(path) = config.failover_path.as_deref() {
+            let marker = failover_marker(path);
+            if std::path::Path::new(&marker).exists() {
+                tracing::warn!(
+                    "此前已切换至备用数据库{path}，继续使用备用数据库。恢复主数据库后请删除{marker}。"
+                );
+                let config = Config {
+                    failover_path: None,
+                    ..config.clone()
+                };
+                return Self::with_config(path, admin, &config);
             }
-            tracing::info!("数据库初始化完成。");
         }
+        let primary = open_pool(database_url, admin, config)?;
+        let failover = config.failover_path.as_ref().map(|path| Failover {
+            path: path.clone(),
+            admin: admin.to_owned(),
+            config: config.clone(),
+            pool: Mutex::new(None),
+            switching: Mutex::new(()),
+        });
+        Ok(Self {
+            connections: Connections { primary, failover },
+            max_content_chars: config.max_content_chars,
+            compress_content: config.compress_content,
+            cipher,
+        })
+    }
+
+    // 执行写操作。主数据库不可写且配置了备用数据库时，切换至备用数据库后重试一次。
+    fn with_failover<T>(&self, op: impl Fn() -> Result<T, Error>) -> Result<T, Error> {
+        match op() {
+            Err(Error::Unwritable(msg)) if self.connections.fail_over(&msg) => op(),
+            result => result,
+        }
+    }
+
+    /// 已应用的数据库迁移版本，按版本升序排列
+    pub fn migration_status(&self) -> Result<Vec<String>, Error> {
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let mut versions: Vec<String> = conn
+            .applied_migrations()
+            .map_err(|e| Error::Database(e.to_string()))?
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        versions.sort();
+        Ok(versions)
+    }
+
+    /// 全局默认提示词。助手未配置提示词时使用。
+    pub fn get_default_prompt(&self) -> Result<Option<String>, Error> {
+        use schema::global_settings::dsl::*;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let prompt: Option<Option<String>> = global_settings
+            .find(1)
+            .select(default_prompt)
+            .first(conn)
+            .optional()
+            .map_err(|e| Error::Database(e.to_string()))?;
+        Ok(prompt.flatten())
+    }
+
+    /// 设置全局默认提示词。None表示清除。
+    pub fn set_default_prompt(&self, prompt: Option<&str>) -> Result<(), Error> {
+        self.with_failover(|| self.set_default_prompt_once(prompt))
+    }
+
+    fn set_default_prompt_once(&self, prompt: Option<&str>) -> Result<(), Error> {
+        use schema::global_settings::dsl::*;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        diesel::replace_into(global_settings)
+            .values((
+                id.eq(1),
+                default_prompt.eq(prompt),
+                updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// 助手在运行时选择的人设。None表示使用配置的提示词。
+    pub fn get_persona(&self, assistant_id: u64) -> Result<Option<String>, Error> {
+        use schema::assistant_settings::dsl;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let selected: Option<Option<String>> = dsl::assistant_settings
+            .find(assistant_id as i32)
+            .select(dsl::persona)
+            .first(conn)
+            .optional()
+            .map_err(|e| Error::Database(e.to_string()))?;
+        Ok(selected.flatten())
+    }
+
+    /// 保存助手选择的人设，重启后依然有效。None表示恢复使用配置的提示词。
+    pub fn set_persona(&self, assistant_id: u64, name: Option<&str>) -> Result<(), Error> {
+        self.with_failover(|| self.set_persona_once(assistant_id, name))
+    }
+
+    fn set_persona_once(&self, assistant_id: u64, name: Option<&str>) -> Result<(), Error> {
+        use schema::assistant_settings::dsl;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            ensure_assistant_settings(conn, assistant_id)?;
+            diesel::update(dsl::assistant_settings.find(assistant_id as i32))
+                .set((
+                    dsl::persona.eq(name),
+                    dsl::updated_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn)
+                .map(|_| ())
+        })
+        .map_err(query_error)
+    }
+
+    /// 管理员为助手调整的上下文长度。None表示未调整。
+    pub fn get_max_context(&self, assistant_id: u64) -> Result<Option<u64>, Error> {
+        use schema::assistant_settings::dsl;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let tokens: Option<Option<i32>> = dsl::assistant_settings
+            .find(assistant_id as i32)
+            .select(dsl::max_context)
+            .first(conn)
+            .optional()
+            .map_err(|e| Error::Database(e.to_string()))?;
+        Ok(tokens.flatten().map(|t| t as u64))
+    }
+
+    /// 保存助手的上下文长度，重启后依然有效。None表示恢复使用供应商上限。
+    pub fn set_max_context(&self, assistant_id: u64, tokens: Option<u64>) -> Result<(), Error> {
+        self.with_failover(|| self.set_max_context_once(assistant_id, tokens))
+    }
 
-        Ok(Self { connections })
+    fn set_max_context_once(&self, assistant_id: u64, tokens: Option<u64>) -> Result<(), Error> {
+        use schema::assistant_settings::dsl;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            ensure_assistant_settings(conn, assistant_id)?;
+            diesel::update(dsl::assistant_settings.find(assistant_id as i32))
+                .set((
+                    dsl::max_context.eq(tokens.map(|t| t as i32)),
+                    dsl::updated_at.eq(Utc::now().naive_utc()),
+                ))
+                .execute(conn)
+                .map(|_| ())
+        })
+        .map_err(query_error)
     }
 
     /// 注册新用户
     pub fn create_user(&self, guest: &core::Guest) -> Result<(), Error> {
+        self.with_failover(|| self.create_user_once(guest))
+    }
+
+    fn create_user_once(&self, guest: &core::Guest) -> Result<(), Error> {
         use self::schema::guests::dsl::*;
 
         // 插入该数据
@@ -125,35 +803,85 @@ impl Agent {
             created_at: timestamp,
             updated_at: timestamp,
             admin: guest.admin,
+            permissions: guest.permissions,
         };
 
-        // 返回结果
+        // 返回结果。用户名重复时单独报告，便于调用者视为已注册
         let _ = diesel::insert_into(guests)
             .values(&new_guest)
             .execute(conn)
-            .map_err(|e| Error::Database(e.to_string()))?;
+            .map_err(|e| match e {
+                diesel::result::Error::DatabaseError(
+                    diesel::result::DatabaseErrorKind::UniqueViolation,
+                    _,
+                ) => Error::AlreadyExists,
+                e => query_error(e),
+            })?;
         Ok(())
     }
 
-    /// 获取全部用户
-    pub fn get_users(&self) -> Result<Vec<core::Guest>, Error> {
+    /// 用户是否存在。仅查询计数，不读取用户记录。
+    pub fn guest_exists(&self, unique_guest_name: &str) -> Result<bool, Error> {
         use self::schema::guests::dsl::*;
+        use diesel::dsl::exists;
         let conn = &mut self
             .connections
             .get()
             .map_err(|e| Error::Connection(e.to_string()))?;
-        let db_users: Vec<model::Guest> = guests
-            .load(conn)
+        diesel::select(exists(guests.filter(name.eq(unique_guest_name))))
+            .get_result(conn)
+            .map_err(|e| Error::Database(e.to_string()))
+    }
+
+    /// 用户总数
+    pub fn get_guest_count(&self) -> Result<i64, Error> {
+        use self::schema::guests::dsl::*;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        guests
+            .count()
+            .get_result(conn)
+            .map_err(|e| Error::Database(e.to_string()))
+    }
+
+    /// 分页获取用户。结果按照指定方式排序，并附带用户总数。
+    pub fn get_users(
+        &self,
+        order: UserOrder,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<core::Guest>, i64), Error> {
+        use self::schema::guests::dsl::*;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let total: i64 = guests
+            .count()
+            .get_result(conn)
             .map_err(|e| Error::Database(e.to_string()))?;
+        let query = guests
+            .select(model::Guest::as_select())
+            .limit(limit)
+            .offset(offset);
+        let db_users: Vec<model::Guest> = match order {
+            UserOrder::Name => query.order((name.asc(), id.asc())).load(conn),
+            UserOrder::Credit => query.order((credit.desc(), id.asc())).load(conn),
+            UserOrder::CreatedAt => query.order((created_at.asc(), id.asc())).load(conn),
+        }
+        .map_err(|e| Error::Database(e.to_string()))?;
         let users = db_users
             .iter()
             .map(|u| core::Guest {
                 name: u.name.clone(),
                 credit: u.credit,
                 admin: u.admin,
+                permissions: u.permissions,
             })
             .collect();
-        Ok(users)
+        Ok((users, total))
     }
 
     /// 按照用户名获取用户
@@ -172,11 +900,16 @@ impl Agent {
             name: user.name,
             credit: user.credit,
             admin: user.admin,
+            permissions: user.permissions,
         })
     }
 
     // 更新用户
     pub fn update_user(&self, guest: &core::Guest) -> Result<(), Error> {
+        self.with_failover(|| self.update_user_once(guest))
+    }
+
+    fn update_user_once(&self, guest: &core::Guest) -> Result<(), Error> {
         use self::schema::guests::dsl::*;
         let conn = &mut self
             .connections
@@ -187,34 +920,336 @@ impl Agent {
                 credit.eq(guest.credit),
                 updated_at.eq(Utc::now().naive_utc()),
                 admin.eq(guest.admin),
+                permissions.eq(guest.permissions),
             ))
             .execute(conn)
-            .map_err(|e| Error::Database(e.to_string()))?;
+            .map_err(query_error)?;
         Ok(())
     }
 
-    // 删除用户
-    pub fn remove_user(&self, guest: &core::Guest) -> Result<u64, Error> {
+    // 设置用户偏好的回复语言。None表示清除偏好。
+    pub fn set_language(&self, guest: &core::Guest, lang: Option<&str>) -> Result<(), Error> {
+        self.with_failover(|| self.set_language_once(guest, lang))
+    }
+
+    fn set_language_once(&self, guest: &core::Guest, lang: Option<&str>) -> Result<(), Error> {
         use self::schema::guests::dsl::*;
         let conn = &mut self
             .connections
             .get()
             .map_err(|e| Error::Connection(e.to_string()))?;
-        let rows_deleted = diesel::delete(guests.filter(name.eq(&guest.name)))
+        let rows = diesel::update(guests.filter(name.eq(&guest.name)))
+            .set((language.eq(lang), updated_at.eq(Utc::now().naive_utc())))
             .execute(conn)
-            .map_err(|e| Error::Database(e.to_string()))?;
-        Ok(rows_deleted as u64)
+            .map_err(query_error)?;
+        if rows == 0 {
+            return Err(Error::NotFound);
+        }
+        Ok(())
     }
 
-    // 新建一条会话记录作为当前活跃会话记录。
-    // 此操作会将之前活跃会话记录标记为非活跃。
-    pub fn create_conversation(&self, guest: &core::Guest, assistant_id: u64) -> Result<(), Error> {
-        use schema::conversations;
-        let timestamp = Utc::now().naive_utc();
-
-        // Find the user
-        let user: model::Guest = {
-            use self::schema::guests::dsl::*;
+    // 获取用户偏好的回复语言
+    pub fn get_language(&self, guest: &core::Guest) -> Result<Option<String>, Error> {
+        use self::schema::guests::dsl::*;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        guests
+            .filter(name.eq(&guest.name))
+            .select(language)
+            .first(conn)
+            .map_err(|_| Error::NotFound)
+    }
+
+    // 设置用户的余额预警阈值。None表示使用全局配置。
+    pub fn set_alert_threshold(
+        &self,
+        guest: &core::Guest,
+        threshold: Option<f64>,
+    ) -> Result<(), Error> {
+        self.with_failover(|| self.set_alert_threshold_once(guest, threshold))
+    }
+
+    fn set_alert_threshold_once(
+        &self,
+        guest: &core::Guest,
+        threshold: Option<f64>,
+    ) -> Result<(), Error> {
+        use self::schema::guests::dsl::*;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let rows = diesel::update(guests.filter(name.eq(&guest.name)))
+            .set((
+                alert_threshold.eq(threshold),
+                updated_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)
+            .map_err(query_error)?;
+        if rows == 0 {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    // 获取用户的余额预警阈值
+    pub fn get_alert_threshold(&self, guest: &core::Guest) -> Result<Option<f64>, Error> {
+        use self::schema::guests::dsl::*;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        guests
+            .filter(name.eq(&guest.name))
+            .select(alert_threshold)
+            .first(conn)
+            .map_err(|_| Error::NotFound)
+    }
+
+    // 删除用户
+    pub fn remove_user(&self, guest: &core::Guest) -> Result<u64, Error> {
+        self.with_failover(|| self.remove_user_once(guest))
+    }
+
+    fn remove_user_once(&self, guest: &core::Guest) -> Result<u64, Error> {
+        use schema::{credit_ledger, feedback, guests};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        // 余额流水与反馈随用户一并删除，以免遗留的记录被当作其他用户的记录，如撤销他人的充值
+        let rows_deleted = conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                let Some(user_id) = guests::table
+                    .filter(guests::name.eq(&guest.name))
+                    .select(guests::id)
+                    .first::<i32>(conn)
+                    .optional()?
+                else {
+                    return Ok(0);
+                };
+                diesel::delete(credit_ledger::table.filter(credit_ledger::guest_id.eq(user_id)))
+                    .execute(conn)?;
+                diesel::delete(feedback::table.filter(feedback::guest_id.eq(user_id)))
+                    .execute(conn)?;
+                diesel::delete(guests::table.find(user_id)).execute(conn)
+            })
+            .map_err(query_error)?;
+        Ok(rows_deleted as u64)
+    }
+
+    // 合并用户：将旧用户的会话记录转移至新用户，余额相加，随后删除旧用户。
+    // 全部操作在同一事务中完成。若双方在同一助手下均有活跃会话，保留新用户的活跃会话。
+    pub fn merge_guests(&self, old_name: &str, new_name: &str) -> Result<core::Guest, Error> {
+        self.with_failover(|| self.merge_guests_once(old_name, new_name))
+    }
+
+    fn merge_guests_once(&self, old_name: &str, new_name: &str) -> Result<core::Guest, Error> {
+        use schema::{conversations, credit_ledger, feedback, guests};
+        if old_name == new_name {
+            return Err(Error::Database("不能将用户合并到自身".to_string()));
+        }
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let merged = conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                let old: model::Guest = guests::table
+                    .filter(guests::name.eq(old_name))
+                    .select(model::Guest::as_select())
+                    .first(conn)?;
+                let new: model::Guest = guests::table
+                    .filter(guests::name.eq(new_name))
+                    .select(model::Guest::as_select())
+                    .first(conn)?;
+                let timestamp = Utc::now().naive_utc();
+
+                // 新用户已有活跃会话的助手，旧用户的对应会话转为非活跃
+                let active_assistants: Vec<i32> = conversations::table
+                    .filter(conversations::guest_id.eq(new.id))
+                    .filter(conversations::active.eq(true))
+                    .select(conversations::assistant_id)
+                    .load(conn)?;
+                diesel::update(
+                    conversations::table
+                        .filter(conversations::guest_id.eq(old.id))
+                        .filter(conversations::assistant_id.eq_any(active_assistants)),
+                )
+                .set((
+                    conversations::active.eq(false),
+                    conversations::updated_at.eq(timestamp),
+                ))
+                .execute(conn)?;
+
+                // 转移会话记录。消息经由会话关联，随之转移。
+                diesel::update(conversations::table.filter(conversations::guest_id.eq(old.id)))
+                    .set(conversations::guest_id.eq(new.id))
+                    .execute(conn)?;
+                diesel::update(credit_ledger::table.filter(credit_ledger::guest_id.eq(old.id)))
+                    .set(credit_ledger::guest_id.eq(new.id))
+                    .execute(conn)?;
+                diesel::update(feedback::table.filter(feedback::guest_id.eq(old.id)))
+                    .set(feedback::guest_id.eq(new.id))
+                    .execute(conn)?;
+
+                // 合并余额并删除旧用户
+                let credit = old.credit + new.credit;
+                diesel::update(guests::table.filter(guests::id.eq(new.id)))
+                    .set((guests::credit.eq(credit), guests::updated_at.eq(timestamp)))
+                    .execute(conn)?;
+                diesel::delete(guests::table.filter(guests::id.eq(old.id))).execute(conn)?;
+
+                Ok(core::Guest {
+                    name: new.name,
+                    credit,
+                    admin: new.admin,
+                    permissions: new.permissions,
+                })
+            })
+            .map_err(|e| match e {
+                diesel::result::Error::NotFound => Error::NotFound,
+                e => query_error(e),
+            })?;
+        Ok(merged)
+    }
+
+    // 为用户充值，并在同一事务中记入余额流水。返回充值后的用户。
+    pub fn recharge(
+        &self,
+        guest_name: &str,
+        amount: f64,
+        operator: &str,
+    ) -> Result<core::Guest, Error> {
+        self.with_failover(|| self.recharge_once(guest_name, amount, operator))
+    }
+
+    fn recharge_once(
+        &self,
+        guest_name: &str,
+        amount: f64,
+        operator: &str,
+    ) -> Result<core::Guest, Error> {
+        use schema::{credit_ledger, guests};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let user: model::Guest = guests::table
+                .filter(guests::name.eq(guest_name))
+                .select(model::Guest::as_select())
+                .first(conn)?;
+            let timestamp = Utc::now().naive_utc();
+            let credit = user.credit + amount;
+            diesel::update(guests::table.filter(guests::id.eq(user.id)))
+                .set((guests::credit.eq(credit), guests::updated_at.eq(timestamp)))
+                .execute(conn)?;
+            diesel::insert_into(credit_ledger::table)
+                .values(&model::NewLedgerEntry {
+                    guest_id: user.id,
+                    amount,
+                    kind: LEDGER_RECHARGE,
+                    operator,
+                    created_at: timestamp,
+                })
+                .execute(conn)?;
+            Ok(core::Guest {
+                name: user.name,
+                credit,
+                admin: user.admin,
+                permissions: user.permissions,
+            })
+        })
+        .map_err(|e| match e {
+            diesel::result::Error::NotFound => Error::NotFound,
+            e => query_error(e),
+        })
+    }
+
+    // 撤销用户最近一笔余额流水。该流水须为充值，撤销时扣回充值金额并记入一笔冲正流水。
+    // 返回撤销后的用户与扣回的金额。
+    pub fn reverse_last_recharge(
+        &self,
+        guest_name: &str,
+        operator: &str,
+    ) -> Result<(core::Guest, f64), Error> {
+        self.with_failover(|| self.reverse_last_recharge_once(guest_name, operator))
+    }
+
+    fn reverse_last_recharge_once(
+        &self,
+        guest_name: &str,
+        operator: &str,
+    ) -> Result<(core::Guest, f64), Error> {
+        use schema::{credit_ledger, guests};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let reversed = conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                let user: model::Guest = guests::table
+                    .filter(guests::name.eq(guest_name))
+                    .select(model::Guest::as_select())
+                    .first(conn)?;
+                let last: Option<model::LedgerEntry> = credit_ledger::table
+                    .filter(credit_ledger::guest_id.eq(user.id))
+                    .order(credit_ledger::id.desc())
+                    .select(model::LedgerEntry::as_select())
+                    .first(conn)
+                    .optional()?;
+                let Some(entry) = last.filter(|e| e.kind == LEDGER_RECHARGE) else {
+                    return Ok(None);
+                };
+                let timestamp = Utc::now().naive_utc();
+                let credit = user.credit - entry.amount;
+                diesel::update(guests::table.filter(guests::id.eq(user.id)))
+                    .set((guests::credit.eq(credit), guests::updated_at.eq(timestamp)))
+                    .execute(conn)?;
+                diesel::insert_into(credit_ledger::table)
+                    .values(&model::NewLedgerEntry {
+                        guest_id: user.id,
+                        amount: -entry.amount,
+                        kind: LEDGER_REVERSAL,
+                        operator,
+                        created_at: timestamp,
+                    })
+                    .execute(conn)?;
+                let guest = core::Guest {
+                    name: user.name,
+                    credit,
+                    admin: user.admin,
+                    permissions: user.permissions,
+                };
+                Ok(Some((guest, entry.amount)))
+            })
+            .map_err(|e| match e {
+                diesel::result::Error::NotFound => Error::NotFound,
+                e => query_error(e),
+            })?;
+        reversed.ok_or_else(|| Error::Database("最近一笔余额流水不是充值，无法撤销。".to_string()))
+    }
+
+    // 新建一条会话记录作为当前活跃会话记录。
+    // 此操作会将之前活跃会话记录标记为非活跃。
+    pub fn create_conversation(&self, guest: &core::Guest, assistant_id: u64) -> Result<(), Error> {
+        self.with_failover(|| self.create_conversation_once(guest, assistant_id))
+    }
+
+    fn create_conversation_once(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+    ) -> Result<(), Error> {
+        use schema::conversations;
+        let timestamp = Utc::now().naive_utc();
+
+        // Find the user
+        let user: model::Guest = {
+            use self::schema::guests::dsl::*;
             let conn = &mut self
                 .connections
                 .get()
@@ -241,7 +1276,7 @@ impl Agent {
                     conversations::updated_at.eq(timestamp),
                 ))
                 .execute(conn)
-                .map_err(|e| Error::Database(e.to_string()))?;
+                .map_err(query_error)?;
         }
 
         // Insert new one
@@ -260,307 +1295,2010 @@ impl Agent {
             diesel::insert_into(conversations::table)
                 .values(&new_conv)
                 .execute(conn)
-                .map_err(|e| Error::Database(e.to_string()))?;
+                .map_err(query_error)?;
         }
         Ok(())
     }
 
-    /// 获取用户当前活跃的会话记录
-    pub fn get_conversation(
+    /// 记录已处理的消息ID。该ID先前已记录时返回false。
+    pub fn record_processed_message(
+        &self,
+        message_id: &str,
+        at: NaiveDateTime,
+    ) -> Result<bool, Error> {
+        self.with_failover(|| self.record_processed_message_once(message_id, at))
+    }
+
+    fn record_processed_message_once(
+        &self,
+        message_id: &str,
+        at: NaiveDateTime,
+    ) -> Result<bool, Error> {
+        use schema::processed_messages::dsl::*;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let rows = diesel::insert_or_ignore_into(processed_messages)
+            .values((msg_id.eq(message_id), processed_at.eq(at)))
+            .execute(conn)
+            .map_err(query_error)?;
+        Ok(rows == 1)
+    }
+
+    /// 删除早于指定时间的已处理消息记录，返回删除的数量
+    pub fn prune_processed_messages(&self, before: NaiveDateTime) -> Result<usize, Error> {
+        self.with_failover(|| self.prune_processed_messages_once(before))
+    }
+
+    fn prune_processed_messages_once(&self, before: NaiveDateTime) -> Result<usize, Error> {
+        use schema::processed_messages::dsl::*;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        diesel::delete(processed_messages.filter(processed_at.lt(before)))
+            .execute(conn)
+            .map_err(query_error)
+    }
+
+    // 删除早于指定时间的非活跃会话及其消息，返回删除的会话数量。
+    // 活跃会话与置顶会话不受影响。
+    pub fn prune_conversations(&self, before: NaiveDateTime) -> Result<usize, Error> {
+        self.with_failover(|| self.prune_conversations_once(before))
+    }
+
+    fn prune_conversations_once(&self, before: NaiveDateTime) -> Result<usize, Error> {
+        use schema::{conversations, messages};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let stale = conversations::table
+                .filter(conversations::active.eq(false))
+                .filter(conversations::pinned.eq(false))
+                .filter(conversations::updated_at.lt(before));
+            diesel::delete(
+                messages::table
+                    .filter(messages::conversation_id.eq_any(stale.select(conversations::id))),
+            )
+            .execute(conn)?;
+            diesel::delete(stale).execute(conn)
+        })
+        .map_err(query_error)
+    }
+
+    // 将全部用户与指定助手的活跃会话标记为非活跃，返回受影响的会话数量。
+    // 用户发送下一条消息时将开启新会话。
+    pub fn reset_all_conversations(&self, assistant_id: u64) -> Result<usize, Error> {
+        self.with_failover(|| self.reset_all_conversations_once(assistant_id))
+    }
+
+    fn reset_all_conversations_once(&self, assistant_id: u64) -> Result<usize, Error> {
+        use schema::conversations;
+        let timestamp = Utc::now().naive_utc();
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::update(
+                conversations::table
+                    .filter(conversations::active.eq(true))
+                    .filter(conversations::assistant_id.eq(assistant_id as i32)),
+            )
+            .set((
+                conversations::active.eq(false),
+                conversations::updated_at.eq(timestamp),
+            ))
+            .execute(conn)
+        })
+        .map_err(query_error)
+    }
+
+    /// 分页列出用户与指定助手的会话，新近的会话在前。同时返回会话总数。
+    pub fn list_conversations(
         &self,
         guest: &core::Guest,
         assistant_id: u64,
-    ) -> Result<Vec<model::Message>, Error> {
-        // Find the user
-        let user: model::Guest = {
-            use self::schema::guests::dsl::*;
-            let conn = &mut self
-                .connections
-                .get()
-                .map_err(|e| Error::Connection(e.to_string()))?;
-            guests
-                .filter(name.eq(&guest.name))
-                .select(model::Guest::as_select())
-                .first(conn)
-                .map_err(|e| Error::Database(e.to_string()))?
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<ConversationSummary>, i64), Error> {
+        use diesel::dsl::{count_star, max};
+        use schema::{conversations, guests, messages};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let user: model::Guest = guests::table
+            .filter(guests::name.eq(&guest.name))
+            .select(model::Guest::as_select())
+            .first(conn)
+            .map_err(|_| Error::NotFound)?;
+
+        let user_convs = || {
+            model::Conversation::belonging_to(&user)
+                .filter(conversations::assistant_id.eq(assistant_id as i32))
+        };
+        let total: i64 = user_convs()
+            .count()
+            .get_result(conn)
+            .map_err(|e| Error::Database(e.to_string()))?;
+        let convs: Vec<model::Conversation> = user_convs()
+            .order_by(conversations::id.desc())
+            .limit(limit)
+            .offset(offset)
+            .select(model::Conversation::as_select())
+            .load(conn)
+            .map_err(|e| Error::Database(e.to_string()))?;
+
+        // 各会话的消息数与最后一条消息的时间
+        let ids: Vec<i32> = convs.iter().map(|c| c.id).collect();
+        let stats: Vec<(i32, i64, Option<NaiveDateTime>)> = messages::table
+            .filter(messages::conversation_id.eq_any(ids))
+            .group_by(messages::conversation_id)
+            .select((
+                messages::conversation_id,
+                count_star(),
+                max(messages::created_at),
+            ))
+            .load(conn)
+            .map_err(|e| Error::Database(e.to_string()))?;
+
+        let summaries = convs
+            .into_iter()
+            .map(|c| {
+                let stat = stats.iter().find(|s| s.0 == c.id);
+                ConversationSummary {
+                    id: c.id,
+                    active: c.active,
+                    pinned: c.pinned,
+                    title: c.title,
+                    messages: stat.map_or(0, |s| s.1),
+                    updated_at: stat.and_then(|s| s.2).unwrap_or(c.updated_at),
+                }
+            })
+            .collect();
+        Ok((summaries, total))
+    }
+
+    /// 记录用户对某条AI回复的反馈
+    pub fn add_feedback(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        content: &str,
+        reply: &str,
+    ) -> Result<(), Error> {
+        self.with_failover(|| self.add_feedback_once(guest, assistant_id, content, reply))
+    }
+
+    fn add_feedback_once(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        content: &str,
+        reply: &str,
+    ) -> Result<(), Error> {
+        use schema::{feedback, guests};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let guest_id: i32 = guests::table
+            .filter(guests::name.eq(&guest.name))
+            .select(guests::id)
+            .first(conn)
+            .map_err(|_| Error::NotFound)?;
+        diesel::insert_into(feedback::table)
+            .values(&model::NewFeedback {
+                guest_id,
+                assistant_id: assistant_id as i32,
+                content,
+                reply,
+                created_at: Utc::now().naive_utc(),
+            })
+            .execute(conn)
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// 按时间由新到旧分页列出用户反馈，同时返回反馈总数
+    pub fn recent_feedback(
+        &self,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<FeedbackEntry>, i64), Error> {
+        use schema::{feedback, guests};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        // 与列表使用相同的连接条件，总数不含已删除用户的反馈
+        let total: i64 = feedback::table
+            .inner_join(guests::table)
+            .count()
+            .get_result(conn)
+            .map_err(|e| Error::Database(e.to_string()))?;
+        let rows: Vec<(String, i32, String, String, NaiveDateTime)> = feedback::table
+            .inner_join(guests::table)
+            .order_by((feedback::created_at.desc(), feedback::id.desc()))
+            .limit(limit)
+            .offset(offset)
+            .select((
+                guests::name,
+                feedback::assistant_id,
+                feedback::content,
+                feedback::reply,
+                feedback::created_at,
+            ))
+            .load(conn)
+            .map_err(|e| Error::Database(e.to_string()))?;
+        let entries = rows
+            .into_iter()
+            .map(
+                |(guest_name, assistant_id, content, reply, created_at)| FeedbackEntry {
+                    guest_name,
+                    assistant_id: assistant_id as u64,
+                    content,
+                    reply,
+                    created_at,
+                },
+            )
+            .collect();
+        Ok((entries, total))
+    }
+
+    /// 获取用户当前活跃会话的设置。尚无活跃会话或未曾设置时返回默认值。
+    pub fn get_conversation_settings(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+    ) -> Result<ConversationSettings, Error> {
+        use schema::{conversations, guests};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let raw: Option<Option<String>> = conversations::table
+            .inner_join(guests::table)
+            .filter(guests::name.eq(&guest.name))
+            .filter(conversations::active.eq(true))
+            .filter(conversations::assistant_id.eq(assistant_id as i32))
+            .select(conversations::settings)
+            .first(conn)
+            .optional()
+            .map_err(|e| Error::Database(e.to_string()))?;
+        match raw.flatten() {
+            None => Ok(ConversationSettings::default()),
+            Some(s) => serde_json::from_str(&s)
+                .map_err(|e| Error::Database(format!("会话设置格式有误。{e}"))),
+        }
+    }
+
+    /// 保存用户当前活跃会话的设置。设置为默认值时清空记录。
+    pub fn set_conversation_settings(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        settings: &ConversationSettings,
+    ) -> Result<(), Error> {
+        self.with_failover(|| self.set_conversation_settings_once(guest, assistant_id, settings))
+    }
+
+    fn set_conversation_settings_once(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        settings: &ConversationSettings,
+    ) -> Result<(), Error> {
+        use schema::{conversations, guests};
+        let value = if *settings == ConversationSettings::default() {
+            None
+        } else {
+            Some(serde_json::to_string(settings).map_err(|e| Error::Database(e.to_string()))?)
         };
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let owner = guests::table
+            .filter(guests::name.eq(&guest.name))
+            .select(guests::id);
+        let rows = diesel::update(
+            conversations::table
+                .filter(conversations::guest_id.eq_any(owner))
+                .filter(conversations::active.eq(true))
+                .filter(conversations::assistant_id.eq(assistant_id as i32)),
+        )
+        .set(conversations::settings.eq(value))
+        .execute(conn)
+        .map_err(query_error)?;
+        if rows == 0 {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    /// 获取用户当前活跃会话的早期消息摘要。尚无摘要时返回空摘要。
+    pub fn get_context_summary(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+    ) -> Result<ContextSummary, Error> {
+        use schema::{conversations, guests};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let raw: Option<(Option<String>, i32)> = conversations::table
+            .inner_join(guests::table)
+            .filter(guests::name.eq(&guest.name))
+            .filter(conversations::active.eq(true))
+            .filter(conversations::assistant_id.eq(assistant_id as i32))
+            .select((conversations::summary, conversations::summarized_messages))
+            .first(conn)
+            .optional()
+            .map_err(|e| Error::Database(e.to_string()))?;
+        match raw {
+            Some((Some(content), messages)) => Ok(ContextSummary {
+                content,
+                messages: messages.max(0) as usize,
+            }),
+            _ => Ok(ContextSummary::default()),
+        }
+    }
+
+    /// 保存用户当前活跃会话的早期消息摘要
+    pub fn set_context_summary(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        summary: &ContextSummary,
+    ) -> Result<(), Error> {
+        self.with_failover(|| self.set_context_summary_once(guest, assistant_id, summary))
+    }
+
+    fn set_context_summary_once(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        summary: &ContextSummary,
+    ) -> Result<(), Error> {
+        use schema::conversations;
+        let conversation_id = self.active_conversation_id(&guest.name, assistant_id)?;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        diesel::update(conversations::table.find(conversation_id))
+            .set((
+                conversations::summary.eq(&summary.content),
+                conversations::summarized_messages.eq(summary.messages as i32),
+            ))
+            .execute(conn)
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// 清除用户当前活跃会话的全部设置，返回清除前的设置
+    pub fn clear_conversation_settings(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+    ) -> Result<ConversationSettings, Error> {
+        let previous = self.get_conversation_settings(guest, assistant_id)?;
+        if previous != ConversationSettings::default() {
+            self.set_conversation_settings(guest, assistant_id, &ConversationSettings::default())?;
+        }
+        Ok(previous)
+    }
+
+    /// 置顶或取消置顶用户当前活跃会话
+    pub fn set_conversation_pinned(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        pinned: bool,
+    ) -> Result<(), Error> {
+        self.with_failover(|| self.set_conversation_pinned_once(guest, assistant_id, pinned))
+    }
+
+    fn set_conversation_pinned_once(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        pinned: bool,
+    ) -> Result<(), Error> {
+        use schema::{conversations, guests};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let owner = guests::table
+            .filter(guests::name.eq(&guest.name))
+            .select(guests::id);
+        let rows = diesel::update(
+            conversations::table
+                .filter(conversations::guest_id.eq_any(owner))
+                .filter(conversations::active.eq(true))
+                .filter(conversations::assistant_id.eq(assistant_id as i32)),
+        )
+        .set(conversations::pinned.eq(pinned))
+        .execute(conn)
+        .map_err(query_error)?;
+        if rows == 0 {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    /// 设定用户当前活跃会话的标题
+    pub fn set_conversation_title(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        title: &str,
+    ) -> Result<(), Error> {
+        self.with_failover(|| self.set_conversation_title_once(guest, assistant_id, title))
+    }
+
+    fn set_conversation_title_once(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        title: &str,
+    ) -> Result<(), Error> {
+        use schema::conversations;
+        let conversation_id = self.active_conversation_id(&guest.name, assistant_id)?;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        diesel::update(conversations::table.find(conversation_id))
+            .set(conversations::title.eq(title))
+            .execute(conn)
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// 用户当前活跃会话是否已置顶。尚无活跃会话时返回false。
+    pub fn is_conversation_pinned(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+    ) -> Result<bool, Error> {
+        use schema::{conversations, guests};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let pinned: Option<bool> = conversations::table
+            .inner_join(guests::table)
+            .filter(guests::name.eq(&guest.name))
+            .filter(conversations::active.eq(true))
+            .filter(conversations::assistant_id.eq(assistant_id as i32))
+            .select(conversations::pinned)
+            .first(conn)
+            .optional()
+            .map_err(|e| Error::Database(e.to_string()))?;
+        Ok(pinned.unwrap_or(false))
+    }
+
+    /// 保存消息的向量表示
+    pub fn set_embedding(&self, message_id: i32, vector: &[f32]) -> Result<(), Error> {
+        self.with_failover(|| self.set_embedding_once(message_id, vector))
+    }
+
+    fn set_embedding_once(&self, message_id: i32, vector: &[f32]) -> Result<(), Error> {
+        use schema::messages;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let rows = diesel::update(messages::table.find(message_id))
+            .set(messages::embedding.eq(encode_embedding(vector)))
+            .execute(conn)
+            .map_err(query_error)?;
+        if rows == 0 {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    /// 在用户的全部历史消息中，按余弦相似度查找与给定向量最接近的消息
+    pub fn nearest_messages(
+        &self,
+        guest: &core::Guest,
+        vector: &[f32],
+        limit: usize,
+    ) -> Result<Vec<(model::Message, f32)>, Error> {
+        use schema::{conversations, guests, messages};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let candidates: Vec<model::Message> = messages::table
+            .inner_join(conversations::table.inner_join(guests::table))
+            .filter(guests::name.eq(&guest.name))
+            .filter(messages::embedding.is_not_null())
+            .select(model::Message::as_select())
+            .load(conn)
+            .map_err(|e| Error::Database(e.to_string()))?;
+        let candidates = candidates
+            .into_iter()
+            .map(|m| decode_message(m, self.cipher.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut scored: Vec<(model::Message, f32)> = candidates
+            .into_iter()
+            .filter_map(|m| {
+                let score = cosine_similarity(vector, &decode_embedding(m.embedding.as_deref()?))?;
+                Some((m, score))
+            })
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.truncate(limit);
+        Ok(scored)
+    }
+
+    /// 用户在指定助手下当前活跃会话的ID。用户不存在或没有活跃会话时返回NotFound。
+    pub fn active_conversation_id(
+        &self,
+        guest_name: &str,
+        assistant_id: u64,
+    ) -> Result<i32, Error> {
+        use schema::{conversations, guests};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        conversations::table
+            .inner_join(guests::table)
+            .filter(guests::name.eq(guest_name))
+            .filter(conversations::active.eq(true))
+            .filter(conversations::assistant_id.eq(assistant_id as i32))
+            .select(conversations::id)
+            .first(conn)
+            .optional()
+            .map_err(|e| Error::Database(e.to_string()))?
+            .ok_or(Error::NotFound)
+    }
+
+    /// 获取用户当前活跃的会话记录
+    pub fn get_conversation(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+    ) -> Result<Vec<model::Message>, Error> {
+        use schema::messages;
+        let conversation_id = self.active_conversation_id(&guest.name, assistant_id)?;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        // 同一时刻写入的消息以自增ID区分先后
+        let messages: Vec<model::Message> = messages::table
+            .filter(messages::conversation_id.eq(conversation_id))
+            .order_by((messages::created_at.asc(), messages::id.asc()))
+            .select(model::Message::as_select())
+            .load(conn)
+            .map_err(|e| Error::Database(e.to_string()))?;
+        messages
+            .into_iter()
+            .map(|m| decode_message(m, self.cipher.as_ref()))
+            .collect()
+    }
+
+    // 将新的消息添加到用户当前会话内容结尾，返回新消息的ID
+    pub fn append_message(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        message: &openai::Message,
+        cost: f64,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) -> Result<i32, Error> {
+        self.with_failover(|| {
+            self.append_message_once(
+                guest,
+                assistant_id,
+                message,
+                cost,
+                prompt_tokens,
+                completion_tokens,
+            )
+        })
+    }
+
+    fn append_message_once(
+        &self,
+        guest: &core::Guest,
+        assistant_id: u64,
+        message: &openai::Message,
+        cost: f64,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) -> Result<i32, Error> {
+        let conversation_id = self.active_conversation_id(&guest.name, assistant_id)?;
+
+        // 超长的内容截断后保存，配置压缩时压缩后保存，并在附加信息中注明
+        let mut meta = MessageMeta::default();
+        let mut content = message.content.clone();
+        if let Some(max) = self.max_content_chars {
+            let truncated = core::truncate_chars(&content, max);
+            if truncated.len() < content.len() {
+                content = truncated.to_owned();
+                meta.truncated = true;
+            }
+        }
+        if self.compress_content {
+            content = compress(&content)?;
+            meta.compressed = true;
+        }
+        if let Some(cipher) = &self.cipher {
+            let (encrypted, nonce) = encrypt(cipher, &content)?;
+            content = encrypted;
+            meta.nonce = Some(nonce);
+        }
+        let meta = if meta == MessageMeta::default() {
+            None
+        } else {
+            Some(serde_json::to_string(&meta).map_err(|e| Error::Database(e.to_string()))?)
+        };
+
+        // 新增消息记录
+        let timestamp = Utc::now().naive_utc();
+        let new_msg = model::NewMessage {
+            conversation_id,
+            created_at: timestamp,
+            content,
+            cost,
+            message_type: openai::Role::try_from(message.role.as_str())
+                .unwrap()
+                .to_id(),
+            content_type: core::ContentType::Text.to_id(), // Static for now
+            prompt_tokens: prompt_tokens as i32,
+            completion_tokens: completion_tokens as i32,
+            meta,
+        };
+        use schema::messages;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        diesel::insert_into(messages::table)
+            .values(&new_msg)
+            .returning(messages::id)
+            .get_result(conn)
+            .map_err(query_error)
+    }
+
+    /// 保存消息的附加信息，与已有的附加信息合并
+    pub fn set_message_meta(&self, message_id: i32, meta: &MessageMeta) -> Result<(), Error> {
+        self.with_failover(|| self.set_message_meta_once(message_id, meta))
+    }
+
+    fn set_message_meta_once(&self, message_id: i32, meta: &MessageMeta) -> Result<(), Error> {
+        use schema::messages;
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let current: Option<String> = messages::table
+            .find(message_id)
+            .select(messages::meta)
+            .first(conn)
+            .map_err(|_| Error::NotFound)?;
+        let mut merged: MessageMeta = current
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+        if meta.request_id.is_some() {
+            merged.request_id = meta.request_id.clone();
+        }
+        merged.truncated |= meta.truncated;
+        let value = serde_json::to_string(&merged).map_err(|e| Error::Database(e.to_string()))?;
+        diesel::update(messages::table.find(message_id))
+            .set(messages::meta.eq(value))
+            .execute(conn)
+            .map_err(query_error)?;
+        Ok(())
+    }
+
+    /// 统计用户在全部会话中的累计用量
+    pub fn lifetime_usage(&self, guest: &core::Guest) -> Result<Usage, Error> {
+        use diesel::dsl::{count_star, sum};
+        use schema::{conversations, guests, messages};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let (prompt_tokens, completion_tokens, cost, count): (
+            Option<i64>,
+            Option<i64>,
+            Option<f64>,
+            i64,
+        ) = messages::table
+            .inner_join(conversations::table.inner_join(guests::table))
+            .filter(guests::name.eq(&guest.name))
+            .select((
+                sum(messages::prompt_tokens),
+                sum(messages::completion_tokens),
+                sum(messages::cost),
+                count_star(),
+            ))
+            .first(conn)
+            .map_err(|e| Error::Database(e.to_string()))?;
+        Ok(Usage {
+            prompt_tokens: prompt_tokens.unwrap_or(0),
+            completion_tokens: completion_tokens.unwrap_or(0),
+            cost: cost.unwrap_or(0.0),
+            messages: count,
+        })
+    }
+
+    /// 统计助手自指定时间起的累计费用，包括全部用户会话与管理员模拟用户产生的费用
+    pub fn assistant_spend(&self, assistant_id: u64, since: NaiveDateTime) -> Result<f64, Error> {
+        use diesel::dsl::sum;
+        use schema::{conversations, impersonation_costs, messages};
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        let conversed: Option<f64> = messages::table
+            .inner_join(conversations::table)
+            .filter(conversations::assistant_id.eq(assistant_id as i32))
+            .filter(messages::created_at.ge(since))
+            .select(sum(messages::cost))
+            .first(conn)
+            .map_err(|e| Error::Database(e.to_string()))?;
+        let impersonated: Option<f64> = impersonation_costs::table
+            .filter(impersonation_costs::assistant_id.eq(assistant_id as i32))
+            .filter(impersonation_costs::created_at.ge(since))
+            .select(sum(impersonation_costs::cost))
+            .first(conn)
+            .map_err(|e| Error::Database(e.to_string()))?;
+        Ok(conversed.unwrap_or(0.0) + impersonated.unwrap_or(0.0))
+    }
+
+    /// 记录一次管理员模拟用户产生的费用
+    pub fn record_impersonation_cost(
+        &self,
+        assistant_id: u64,
+        operator: &str,
+        guest: &str,
+        cost: f64,
+    ) -> Result<(), Error> {
+        self.with_failover(|| {
+            self.record_impersonation_cost_once(assistant_id, operator, guest, cost)
+        })
+    }
+
+    fn record_impersonation_cost_once(
+        &self,
+        assistant_id: u64,
+        operator: &str,
+        guest: &str,
+        cost: f64,
+    ) -> Result<(), Error> {
+        let conn = &mut self
+            .connections
+            .get()
+            .map_err(|e| Error::Connection(e.to_string()))?;
+        diesel::insert_into(schema::impersonation_costs::table)
+            .values(&model::NewImpersonationCost {
+                assistant_id: assistant_id as i32,
+                operator,
+                guest,
+                cost,
+                created_at: Utc::now().naive_utc(),
+            })
+            .execute(conn)
+            .map_err(query_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Agent, Config, Usage, UserOrder};
+
+    // 测试默认ADMIN初始化
+    #[test]
+    fn test_init_user() {
+        // 初始化
+        let agent = Agent::new(":memory:", "administrator").expect("Agent init can not fail");
+        assert_eq!(agent.get_user("administrator").unwrap().admin, true);
+    }
+
+    // 测试用户存在性检查与用户计数
+    #[test]
+    fn test_guest_exists_and_count() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        assert!(agent.guest_exists("administrator").unwrap());
+        assert!(!agent.guest_exists("robin").unwrap());
+        assert_eq!(agent.get_guest_count().unwrap(), 1);
+
+        for name in ["robin", "batman"] {
+            let guest = core::Guest::regular(name, 0.0);
+            agent.create_user(&guest).unwrap();
+        }
+        assert!(agent.guest_exists("robin").unwrap());
+        assert_eq!(agent.get_guest_count().unwrap(), 3);
+    }
+
+    // 测试自定义连接池大小
+    #[test]
+    fn test_pool_size() {
+        let config = Config {
+            max_connections: 3,
+            min_idle: Some(1),
+            ..Config::default()
+        };
+        let agent = Agent::with_config(":memory:", "administrator", &config)
+            .expect("Agent with custom pool should be initialized");
+        assert_eq!(agent.connections.max_size(), 3);
+
+        // 同时持有全部连接
+        let conns: Vec<_> = (0..3)
+            .map(|_| {
+                agent
+                    .connections
+                    .get()
+                    .expect("Checkout within the limit should succeed")
+            })
+            .collect();
+        assert_eq!(conns.len(), 3);
+    }
+
+    // 测试无效的连接池大小
+    #[test]
+    fn test_invalid_pool_size() {
+        let empty = Config {
+            max_connections: 0,
+            ..Config::default()
+        };
+        assert!(matches!(
+            Agent::with_config(":memory:", "administrator", &empty),
+            Err(super::Error::Connection(_))
+        ));
+        let too_idle = Config {
+            max_connections: 2,
+            min_idle: Some(3),
+            ..Config::default()
+        };
+        assert!(matches!(
+            Agent::with_config(":memory:", "administrator", &too_idle),
+            Err(super::Error::Connection(_))
+        ));
+    }
+
+    #[test]
+    fn test_user_create() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+
+        // Register new users
+        let guest = core::Guest {
+            name: "yinguobing".to_string(),
+            credit: 1.2,
+            admin: true,
+            permissions: 0,
+        };
+        agent
+            .create_user(&guest)
+            .expect("User registration should succeed");
+
+        // Fetch the users
+        let registered_user = agent
+            .get_user("yinguobing")
+            .expect("Existing user should be got without any error");
+
+        assert_eq!(guest, registered_user);
+    }
+
+    #[test]
+    fn test_user_get_all() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "yinguobing").expect("Database agent should be initialized");
+
+        // Register new users
+        let guest = core::Guest {
+            name: "robin".to_string(),
+            credit: 1.2,
+            admin: true,
+            permissions: 0,
+        };
+        agent
+            .create_user(&guest)
+            .expect("User registration should succeed");
+
+        let admin = core::Guest {
+            name: "yinguobing".to_string(),
+            credit: 0.0,
+            admin: true,
+            permissions: 0,
+        };
+
+        // Fetch the users
+        let (registered_users, total) = agent
+            .get_users(UserOrder::CreatedAt, 10, 0)
+            .expect("All existing user should be got without any error");
+
+        assert_eq!(vec![admin, guest], registered_users);
+        assert_eq!(total, 2);
+    }
+
+    #[test]
+    fn test_user_order_and_page() {
+        use super::core;
+        let agent = Agent::new(":memory:", "carol").expect("Database agent should be initialized");
+        for (n, c) in [("bob", 3.0), ("alice", 1.0), ("dave", 2.0)] {
+            let guest = core::Guest::regular(n, c);
+            agent
+                .create_user(&guest)
+                .expect("User registration should succeed");
+        }
+        let names = |order: UserOrder, limit: i64, offset: i64| -> (Vec<String>, i64) {
+            let (users, total) = agent.get_users(order, limit, offset).unwrap();
+            (users.into_iter().map(|u| u.name).collect(), total)
+        };
+
+        assert_eq!(
+            names(UserOrder::Name, 10, 0),
+            (
+                vec!["alice".into(), "bob".into(), "carol".into(), "dave".into()],
+                4
+            )
+        );
+        assert_eq!(
+            names(UserOrder::Credit, 10, 0),
+            (
+                vec!["bob".into(), "dave".into(), "alice".into(), "carol".into()],
+                4
+            )
+        );
+        assert_eq!(
+            names(UserOrder::CreatedAt, 10, 0),
+            (
+                vec!["carol".into(), "bob".into(), "alice".into(), "dave".into()],
+                4
+            )
+        );
+
+        // 分页
+        assert_eq!(
+            names(UserOrder::Name, 2, 0),
+            (vec!["alice".into(), "bob".into()], 4)
+        );
+        assert_eq!(
+            names(UserOrder::Name, 2, 2),
+            (vec!["carol".into(), "dave".into()], 4)
+        );
+        assert_eq!(names(UserOrder::Name, 2, 4), (vec![], 4));
+    }
+
+    #[test]
+    fn test_user_duplicate_register() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+
+        // Register new users
+        let guest = core::Guest {
+            name: "yinguobing".to_string(),
+            credit: 1.2,
+            admin: true,
+            permissions: 0,
+        };
+        agent
+            .create_user(&guest)
+            .expect("User registration should succeed");
+        assert!(matches!(
+            agent.create_user(&guest),
+            Err(super::Error::AlreadyExists)
+        ));
+    }
+
+    #[test]
+    fn test_user_invalid_get() {
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        // Fetch an invalid user
+        assert!(agent.get_user("NotExisted").is_err());
+    }
+
+    #[test]
+    fn test_user_update() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let mut guest = core::Guest {
+            name: "yinguobing".to_string(),
+            credit: 1.2,
+            admin: true,
+            permissions: 0,
+        };
+        agent
+            .create_user(&guest)
+            .expect("User registration should succeed");
+        guest.credit = 2.2;
+        agent
+            .update_user(&guest)
+            .expect("User update should succeed");
+        let user = agent.get_user(&guest.name).unwrap();
+        assert_eq!(guest, user);
+    }
+
+    #[test]
+    fn test_user_delete() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let mut guest = core::Guest {
+            name: "yinguobing".to_string(),
+            credit: 1.2,
+            admin: true,
+            permissions: 0,
+        };
+        assert_eq!(agent.remove_user(&guest).unwrap(), 0);
+        guest.name = "administrator".to_string();
+        let n = agent
+            .remove_user(&guest)
+            .expect("This user remove should not fail");
+        assert_eq!(n, 1);
+    }
+
+    // 测试会话记录
+    #[test]
+    fn test_conversation() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+
+        let guest = core::Guest {
+            name: "yinguobing".to_string(),
+            credit: 1.2,
+            admin: true,
+            permissions: 0,
+        };
+        agent
+            .create_user(&guest)
+            .expect("User registration should succeed");
+        let assistant_id = 10003;
+
+        // Create
+        agent
+            .create_conversation(&guest, assistant_id)
+            .expect("1st Conversation should be created without error");
+        let msg1 = super::openai::Message {
+            content: "message a".to_string(),
+            role: super::openai::Role::User.to_string(),
+        };
+        agent
+            .append_message(&guest, assistant_id, &msg1, 0.18, 0, 0)
+            .expect("Conversation should be updated without error");
+
+        agent
+            .create_conversation(&guest, assistant_id)
+            .expect("Conversation should be created without error");
+        let msg2 = super::openai::Message {
+            content: "message b".to_string(),
+            role: super::openai::Role::Assistant.to_string(),
+        };
+        agent
+            .append_message(&guest, assistant_id, &msg2, 0.81, 2, 5)
+            .expect("Conversation should be updated without error");
+
+        // Get active conversation
+        let active_conv = agent
+            .get_conversation(&guest, assistant_id)
+            .expect("Active conversation should always be ready");
+
+        assert_eq!(
+            super::openai::Message::from(active_conv.first().unwrap()),
+            msg2
+        );
+    }
+
+    #[test]
+    fn test_active_conversation_id() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let guest = core::Guest::regular("robin", 1.0);
+        agent.create_user(&guest).unwrap();
+
+        // 尚无活跃会话，或用户不存在
+        assert!(matches!(
+            agent.active_conversation_id("robin", 10003),
+            Err(super::Error::NotFound)
+        ));
+        assert!(matches!(
+            agent.active_conversation_id("nobody", 10003),
+            Err(super::Error::NotFound)
+        ));
+
+        // 新会话取代旧会话，其他助手的会话互不影响
+        agent.create_conversation(&guest, 10003).unwrap();
+        let first = agent.active_conversation_id("robin", 10003).unwrap();
+        agent.create_conversation(&guest, 10003).unwrap();
+        let second = agent.active_conversation_id("robin", 10003).unwrap();
+        assert_ne!(first, second);
+        agent.create_conversation(&guest, 10004).unwrap();
+        assert_eq!(
+            agent.active_conversation_id("robin", 10003).unwrap(),
+            second
+        );
+
+        // 消息写入活跃会话
+        let message = super::openai::Message {
+            content: "hello".to_string(),
+            role: super::openai::Role::User.to_string(),
+        };
+        agent
+            .append_message(&guest, 10003, &message, 0.0, 0, 0)
+            .unwrap();
+        let messages = agent.get_conversation(&guest, 10003).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].conversation_id, second);
+    }
+
+    #[test]
+    fn test_context_summary() {
+        use super::{core, ContextSummary};
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let guest = core::Guest::regular("robin", 1.0);
+        agent.create_user(&guest).unwrap();
+        agent.create_conversation(&guest, 10003).unwrap();
+        assert_eq!(
+            agent.get_context_summary(&guest, 10003).unwrap(),
+            ContextSummary::default()
+        );
+
+        let summary = ContextSummary {
+            content: "用户询问了天气".to_string(),
+            messages: 4,
+        };
+        agent.set_context_summary(&guest, 10003, &summary).unwrap();
+        assert_eq!(agent.get_context_summary(&guest, 10003).unwrap(), summary);
+
+        // 新会话不沿用旧会话的摘要
+        agent.create_conversation(&guest, 10003).unwrap();
+        assert_eq!(
+            agent.get_context_summary(&guest, 10003).unwrap(),
+            ContextSummary::default()
+        );
+    }
+
+    #[test]
+    fn test_persona() {
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        assert_eq!(agent.get_persona(10003).unwrap(), None);
+        agent.set_persona(10003, Some("严谨模式")).unwrap();
+        agent.set_persona(10003, Some("轻松模式")).unwrap();
+        assert_eq!(
+            agent.get_persona(10003).unwrap().as_deref(),
+            Some("轻松模式")
+        );
+        // 各助手的设置互不影响
+        assert_eq!(agent.get_persona(10004).unwrap(), None);
+        agent.set_persona(10003, None).unwrap();
+        assert_eq!(agent.get_persona(10003).unwrap(), None);
+    }
+
+    #[test]
+    fn test_assistant_spend_includes_impersonation() {
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let since = chrono::Utc::now().naive_utc() - chrono::Duration::hours(1);
+        let guest = crate::core::Guest::regular("robin", 1.0);
+        agent.create_user(&guest).unwrap();
+        agent.create_conversation(&guest, 10003).unwrap();
+        let msg = super::openai::Message {
+            role: "assistant".to_string(),
+            content: "你好".to_string(),
+        };
+        agent
+            .append_message(&guest, 10003, &msg, 0.5, 10, 5)
+            .unwrap();
+        agent
+            .record_impersonation_cost(10003, "administrator", "robin", 0.25)
+            .unwrap();
+        agent
+            .record_impersonation_cost(10004, "administrator", "robin", 1.0)
+            .unwrap();
+        assert_eq!(agent.assistant_spend(10003, since).unwrap(), 0.75);
+        assert_eq!(agent.assistant_spend(10004, since).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_persisted_max_context() {
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        assert_eq!(agent.get_max_context(10003).unwrap(), None);
+        agent.set_persona(10003, Some("严谨模式")).unwrap();
+        agent.set_max_context(10003, Some(2048)).unwrap();
+        assert_eq!(agent.get_max_context(10003).unwrap(), Some(2048));
+        // 分别保存的设置互不覆盖
+        assert_eq!(
+            agent.get_persona(10003).unwrap().as_deref(),
+            Some("严谨模式")
+        );
+        agent.set_persona(10003, None).unwrap();
+        assert_eq!(agent.get_max_context(10003).unwrap(), Some(2048));
+        agent.set_max_context(10003, None).unwrap();
+        assert_eq!(agent.get_max_context(10003).unwrap(), None);
+    }
+
+    #[test]
+    fn test_recent_feedback() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let guest = core::Guest::regular("robin", 1.0);
+        agent.create_user(&guest).unwrap();
+        assert_eq!(agent.recent_feedback(10, 0).unwrap(), (Vec::new(), 0));
+
+        for i in 1..=3 {
+            agent
+                .add_feedback(&guest, 10003, &format!("反馈{i}"), &format!("回复{i}"))
+                .unwrap();
+        }
+        let (entries, total) = agent.recent_feedback(2, 0).unwrap();
+        assert_eq!(total, 3);
+        let contents: Vec<&str> = entries.iter().map(|e| e.content.as_str()).collect();
+        assert_eq!(contents, ["反馈3", "反馈2"]);
+        assert_eq!(entries[0].guest_name, "robin");
+        assert_eq!(entries[0].reply, "回复3");
+        let (entries, _) = agent.recent_feedback(2, 2).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].content, "反馈1");
+
+        // 未注册的用户无法反馈
+        let stranger = core::Guest {
+            name: "stranger".to_string(),
+            ..guest.clone()
+        };
+        assert!(matches!(
+            agent.add_feedback(&stranger, 10003, "反馈", "回复"),
+            Err(super::Error::NotFound)
+        ));
+
+        // 删除用户后，其反馈不再计入
+        agent.remove_user(&guest).unwrap();
+        assert_eq!(agent.recent_feedback(10, 0).unwrap(), (Vec::new(), 0));
+    }
+
+    // 测试跨会话的累计用量
+    #[test]
+    fn test_lifetime_usage() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let guest = core::Guest::regular("yinguobing", 1.2);
+        agent
+            .create_user(&guest)
+            .expect("User registration should succeed");
+        assert_eq!(agent.lifetime_usage(&guest).unwrap(), Usage::default());
+
+        let msg = super::openai::Message {
+            content: "message".to_string(),
+            role: super::openai::Role::Assistant.to_string(),
+        };
+        for assistant_id in [10003, 10004] {
+            agent
+                .create_conversation(&guest, assistant_id)
+                .expect("Conversation should be created without error");
+            agent
+                .append_message(&guest, assistant_id, &msg, 0.5, 10, 20)
+                .expect("Conversation should be updated without error");
+        }
+        agent
+            .create_conversation(&guest, 10003)
+            .expect("Conversation should be created without error");
+        agent
+            .append_message(&guest, 10003, &msg, 0.25, 1, 2)
+            .expect("Conversation should be updated without error");
+
+        let usage = agent.lifetime_usage(&guest).unwrap();
+        assert_eq!(
+            usage,
+            Usage {
+                prompt_tokens: 21,
+                completion_tokens: 42,
+                cost: 1.25,
+                messages: 3,
+            }
+        );
+    }
+
+    // 测试用户合并
+    #[test]
+    fn test_merge_guests() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let old = core::Guest::regular("old-id", 1.5);
+        let new = core::Guest::regular("new-id", 2.0);
+        let msg = super::openai::Message {
+            content: "message".to_string(),
+            role: super::openai::Role::Assistant.to_string(),
+        };
+        for guest in [&old, &new] {
+            agent
+                .create_user(guest)
+                .expect("User registration should succeed");
+            agent
+                .create_conversation(guest, 10003)
+                .expect("Conversation should be created without error");
+            agent
+                .append_message(guest, 10003, &msg, 0.5, 10, 20)
+                .expect("Conversation should be updated without error");
+        }
+        agent
+            .create_conversation(&old, 10004)
+            .expect("Conversation should be created without error");
+        agent
+            .append_message(&old, 10004, &msg, 0.25, 1, 2)
+            .expect("Conversation should be updated without error");
+
+        let merged = agent
+            .merge_guests("old-id", "new-id")
+            .expect("Merge should succeed");
+        assert_eq!(merged.credit, 3.5);
+        assert_eq!(agent.get_user("new-id").unwrap().credit, 3.5);
+        assert!(agent.get_user("old-id").is_err());
+
+        // 新用户拥有双方全部历史
+        assert_eq!(
+            agent.lifetime_usage(&merged).unwrap(),
+            Usage {
+                prompt_tokens: 21,
+                completion_tokens: 42,
+                cost: 1.25,
+                messages: 3,
+            }
+        );
+        // 同一助手下保留新用户原有的活跃会话，旧用户独有的活跃会话随之转移
+        assert_eq!(agent.get_conversation(&merged, 10003).unwrap().len(), 1);
+        assert_eq!(agent.get_conversation(&merged, 10004).unwrap().len(), 1);
+
+        assert!(agent.merge_guests("new-id", "new-id").is_err());
+        assert!(matches!(
+            agent.merge_guests("missing", "new-id"),
+            Err(super::Error::NotFound)
+        ));
+    }
+
+    // 测试启动时数据库路径稍晚可用
+    #[test]
+    fn test_connect_retry() {
+        let dir = std::env::temp_dir().join(format!("wecom-gpt-retry-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let db_path = dir.join("db.sqlite");
+        let db_url = db_path.to_str().unwrap().to_string();
+        let config = Config {
+            connect_attempts: 6,
+            connect_backoff_ms: 50,
+            ..Config::default()
+        };
+
+        // 目录始终不存在时，给出明确错误
+        let err = Agent::with_config(
+            &db_url,
+            "administrator",
+            &Config {
+                connect_attempts: 2,
+                ..config.clone()
+            },
+        )
+        .err()
+        .expect("Missing directory should fail");
+        assert!(err.to_string().contains("尝试2次后仍无法打开数据库"));
+
+        // 目录稍后出现时，重试成功
+        let mount = {
+            let dir = dir.clone();
+            std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(120));
+                std::fs::create_dir_all(dir).unwrap();
+            })
+        };
+        let agent = Agent::with_config(&db_url, "administrator", &config);
+        mount.join().unwrap();
+        assert!(agent.is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // 测试主数据库不可写时切换至备用数据库
+    #[test]
+    fn test_failover_to_secondary() {
+        use super::core;
+        let dir = std::env::temp_dir().join(format!("wecom-gpt-failover-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let primary = dir.join("primary.sqlite");
+        let secondary = dir.join("secondary.sqlite");
+        let guest = core::Guest::regular("yinguobing", 1.2);
+        {
+            let agent = Agent::new(primary.to_str().unwrap(), "administrator")
+                .expect("Database agent should be initialized");
+            agent
+                .create_user(&guest)
+                .expect("User registration should succeed");
+            // 早先遗留的备用数据库，切换时应被主数据库的当前内容替换
+            let stale = Agent::new(secondary.to_str().unwrap(), "administrator").unwrap();
+            stale
+                .create_user(&core::Guest::regular("stale-user", 0.0))
+                .unwrap();
+        }
+
+        // 以只读方式打开主数据库，模拟写入失败
+        let config = Config {
+            failover_path: Some(secondary.to_str().unwrap().to_string()),
+            ..Config::default()
+        };
+        let primary_url = format!("file:{}?mode=ro", primary.display());
+        let agent = Agent::with_config(&primary_url, "administrator", &config)
+            .expect("Read-only primary should still open");
+        assert_eq!(agent.get_user(&guest.name).unwrap().credit, 1.2);
+        let marker = super::failover_marker(secondary.to_str().unwrap());
+        assert!(!std::path::Path::new(&marker).exists());
+
+        let msg = super::openai::Message {
+            content: "message".to_string(),
+            role: super::openai::Role::User.to_string(),
+        };
+        agent
+            .create_conversation(&guest, 10003)
+            .expect("Write should fail over to the secondary database");
+        agent
+            .append_message(&guest, 10003, &msg, 0.0, 0, 0)
+            .expect("Write should go to the secondary database");
+        assert!(std::path::Path::new(&marker).exists());
+        drop(agent);
+
+        // 重启后依据切换记录直接使用备用数据库
+        let restarted = Agent::with_config(&primary_url, "administrator", &config).unwrap();
+        assert_eq!(restarted.get_conversation(&guest, 10003).unwrap().len(), 1);
+        drop(restarted);
+
+        // 已有用户随主数据库内容复制至备用数据库，新写入的内容仅在备用数据库中
+        let backup = Agent::new(secondary.to_str().unwrap(), "administrator").unwrap();
+        assert_eq!(backup.get_user(&guest.name).unwrap().credit, 1.2);
+        assert_eq!(backup.get_conversation(&guest, 10003).unwrap().len(), 1);
+        assert!(backup.get_user("stale-user").is_err());
+        let original = Agent::new(primary.to_str().unwrap(), "administrator").unwrap();
+        assert!(original.get_conversation(&guest, 10003).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-        // Find the activate conversation
-        let db_conv: model::Conversation = {
-            use schema::conversations;
-            let conn = &mut self
-                .connections
-                .get()
-                .map_err(|e| Error::Connection(e.to_string()))?;
-            model::Conversation::belonging_to(&user)
-                .filter(conversations::active.eq(true))
-                .filter(conversations::assistant_id.eq(assistant_id as i32))
-                .first(conn)
-                .map_err(|e| Error::Database(e.to_string()))?
+    #[test]
+    fn test_is_unwritable() {
+        use diesel::result::DatabaseErrorKind;
+        assert!(super::is_unwritable(
+            &DatabaseErrorKind::Unknown,
+            "attempt to write a readonly database"
+        ));
+        assert!(super::is_unwritable(
+            &DatabaseErrorKind::Unknown,
+            "database or disk is full"
+        ));
+        // 仅包含相关字词的其他错误不视为不可写
+        assert!(!super::is_unwritable(
+            &DatabaseErrorKind::Unknown,
+            "no such table: disk"
+        ));
+        assert!(!super::is_unwritable(
+            &DatabaseErrorKind::UniqueViolation,
+            "UNIQUE constraint failed: guests.name"
+        ));
+    }
+
+    // 测试超长的消息内容截断后保存
+    #[test]
+    fn test_content_truncated() {
+        use super::{core, MessageMeta};
+        let config = Config {
+            max_content_chars: Some(5),
+            ..Config::default()
         };
+        let agent = Agent::with_config(":memory:", "administrator", &config)
+            .expect("Database agent should be initialized");
+        let guest = core::Guest::regular("yinguobing", 1.2);
+        agent.create_user(&guest).unwrap();
+        agent.create_conversation(&guest, 10003).unwrap();
+        for content in ["你好，世界！", "hello"] {
+            let msg = super::openai::Message {
+                content: content.to_string(),
+                role: super::openai::Role::User.to_string(),
+            };
+            agent
+                .append_message(&guest, 10003, &msg, 0.0, 0, 0)
+                .expect("Conversation should be updated without error");
+        }
 
-        // Find all the messages belonging to this conversation
-        let messages: Vec<model::Message> = {
-            let conn = &mut self
-                .connections
-                .get()
-                .map_err(|e| Error::Connection(e.to_string()))?;
-            let mut db_msgs: Vec<model::Message> = model::Message::belonging_to(&db_conv)
-                .select(model::Message::as_select())
-                .load(conn)
-                .map_err(|e| Error::Database(e.to_string()))?;
-            db_msgs.sort_by(|a, b| a.created_at.cmp(&b.created_at));
-            db_msgs
+        let messages = agent.get_conversation(&guest, 10003).unwrap();
+        assert_eq!(messages[0].content, "你好，世界");
+        let meta: MessageMeta = serde_json::from_str(messages[0].meta.as_deref().unwrap()).unwrap();
+        assert!(meta.truncated);
+        assert_eq!(messages[1].content, "hello");
+        assert_eq!(messages[1].meta, None);
+
+        // 追加请求ID时保留截断标记
+        let request = MessageMeta {
+            request_id: Some("req-0042".to_string()),
+            ..Default::default()
         };
-        Ok(messages)
+        agent.set_message_meta(messages[0].id, &request).unwrap();
+        let messages = agent.get_conversation(&guest, 10003).unwrap();
+        let meta: MessageMeta = serde_json::from_str(messages[0].meta.as_deref().unwrap()).unwrap();
+        assert!(meta.truncated);
+        assert_eq!(meta.request_id.as_deref(), Some("req-0042"));
     }
 
-    // 将新的消息添加到用户当前会话内容结尾
-    pub fn append_message(
-        &self,
-        guest: &core::Guest,
-        assistant_id: u64,
-        message: &openai::Message,
-        cost: f64,
-        prompt_tokens: u64,
-        completion_tokens: u64,
-    ) -> Result<(), Error> {
-        // 获取当前用户
-        let user = {
-            use self::schema::guests::dsl::*;
-            let conn = &mut self
-                .connections
-                .get()
-                .map_err(|e| Error::Connection(e.to_string()))?;
-            guests
-                .filter(name.eq(&guest.name))
-                .select(model::Guest::as_select())
-                .first(conn)
-                .map_err(|_| Error::NotFound)?
+    // 测试压缩保存的消息内容读取时还原
+    #[test]
+    fn test_content_compressed() {
+        use super::{core, schema, MessageMeta};
+        use diesel::prelude::*;
+        let config = Config {
+            compress_content: true,
+            ..Config::default()
+        };
+        let agent = Agent::with_config(":memory:", "administrator", &config)
+            .expect("Database agent should be initialized");
+        let guest = core::Guest::regular("yinguobing", 1.2);
+        agent.create_user(&guest).unwrap();
+        agent.create_conversation(&guest, 10003).unwrap();
+        let content = "你好，世界！".repeat(100);
+        let msg = super::openai::Message {
+            content: content.clone(),
+            role: super::openai::Role::User.to_string(),
         };
+        let id = agent
+            .append_message(&guest, 10003, &msg, 0.0, 0, 0)
+            .expect("Conversation should be updated without error");
 
-        // 获取当前活跃会话
-        let db_conv: model::Conversation = {
-            use schema::conversations;
-            let conn = &mut self
-                .connections
-                .get()
-                .map_err(|e| Error::Connection(e.to_string()))?;
-            model::Conversation::belonging_to(&user)
-                .filter(conversations::active.eq(true))
-                .filter(conversations::assistant_id.eq(assistant_id as i32))
+        // 数据库中保存的是压缩后的内容
+        let (stored, meta): (String, Option<String>) = {
+            let conn = &mut agent.connections.get().unwrap();
+            schema::messages::table
+                .find(id)
+                .select((schema::messages::content, schema::messages::meta))
                 .first(conn)
-                .map_err(|_| Error::NotFound)?
+                .unwrap()
         };
+        assert!(stored.len() < content.len());
+        let meta: MessageMeta = serde_json::from_str(meta.as_deref().unwrap()).unwrap();
+        assert!(meta.compressed);
 
-        // 新增消息记录
-        let timestamp = Utc::now().naive_utc();
-        let new_msg = model::NewMessage {
-            conversation_id: db_conv.id,
-            created_at: timestamp,
-            content: message.content.clone(),
-            cost,
-            message_type: openai::Role::try_from(message.role.as_str())
+        let messages = agent.get_conversation(&guest, 10003).unwrap();
+        assert_eq!(messages[0].content, content);
+    }
+
+    #[test]
+    fn test_content_encrypted() {
+        use super::{core, schema, MessageMeta, BASE64};
+        use base64::Engine;
+        use diesel::prelude::*;
+        let config = Config {
+            content_encryption_key: Some(BASE64.encode([7u8; 32])),
+            ..Config::default()
+        };
+        let agent = Agent::with_config(":memory:", "administrator", &config)
+            .expect("Database agent should be initialized");
+        let guest = core::Guest::regular("yinguobing", 1.2);
+        agent.create_user(&guest).unwrap();
+        agent.create_conversation(&guest, 10003).unwrap();
+        let content = "机密内容";
+        let msg = super::openai::Message {
+            content: content.to_string(),
+            role: super::openai::Role::User.to_string(),
+        };
+        let id = agent
+            .append_message(&guest, 10003, &msg, 0.0, 0, 0)
+            .expect("Conversation should be updated without error");
+
+        // 数据库中保存的是密文，nonce保存在附加信息中
+        let (stored, meta): (String, Option<String>) = {
+            let conn = &mut agent.connections.get().unwrap();
+            schema::messages::table
+                .find(id)
+                .select((schema::messages::content, schema::messages::meta))
+                .first(conn)
                 .unwrap()
-                .to_id(),
-            content_type: core::ContentType::Text.to_id(), // Static for now
-            prompt_tokens: prompt_tokens as i32,
-            completion_tokens: completion_tokens as i32,
         };
-        {
-            use schema::messages;
-            let conn = &mut self
-                .connections
-                .get()
-                .map_err(|e| Error::Connection(e.to_string()))?;
-            diesel::insert_into(messages::table)
-                .values(&new_msg)
-                .execute(conn)
-                .map_err(|e| Error::Database(e.to_string()))?;
-        }
-        Ok(())
-    }
-}
+        assert!(!stored.contains(content));
+        let meta: MessageMeta = serde_json::from_str(meta.as_deref().unwrap()).unwrap();
+        assert!(meta.nonce.is_some());
 
-#[cfg(test)]
-mod tests {
-    use super::Agent;
+        let messages = agent.get_conversation(&guest, 10003).unwrap();
+        assert_eq!(messages[0].content, content);
 
-    // 测试默认ADMIN初始化
+        // 密钥有误时报告无法还原，而非一般的数据库错误
+        let mut agent = agent;
+        agent.cipher = Some(super::content_cipher(&BASE64.encode([8u8; 32])).unwrap());
+        assert!(matches!(
+            agent.get_conversation(&guest, 10003),
+            Err(super::Error::Decode(_))
+        ));
+        agent.cipher = None;
+        assert!(matches!(
+            agent.get_conversation(&guest, 10003),
+            Err(super::Error::Decode(_))
+        ));
+
+        // 密钥长度有误时拒绝初始化
+        let config = Config {
+            content_encryption_key: Some(BASE64.encode([7u8; 16])),
+            ..Config::default()
+        };
+        assert!(Agent::with_config(":memory:", "administrator", &config).is_err());
+    }
+
+    // 测试多个实例同时初始化同一数据库
     #[test]
-    fn test_init_user() {
-        // 初始化
-        let agent = Agent::new(":memory:", "administrator").expect("Agent init can not fail");
-        assert_eq!(agent.get_user("administrator").unwrap().admin, true);
+    fn test_concurrent_initialization() {
+        let dir = std::env::temp_dir().join(format!("wecom-gpt-concurrent-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_url = dir.join("db.sqlite").to_str().unwrap().to_string();
+
+        let instances: Vec<_> = (0..2)
+            .map(|_| {
+                let db_url = db_url.clone();
+                std::thread::spawn(move || Agent::new(&db_url, "administrator"))
+            })
+            .collect();
+        for instance in instances {
+            let agent = instance
+                .join()
+                .unwrap()
+                .expect("Every instance should initialize");
+            assert_eq!(agent.get_guest_count().unwrap(), 1);
+        }
+        let agent = Agent::new(&db_url, "administrator").unwrap();
+        let versions = agent.migration_status().unwrap();
+        let mut deduped = versions.clone();
+        deduped.dedup();
+        assert_eq!(versions, deduped);
+        assert!(agent.get_user("administrator").unwrap().admin);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
+    // 测试时间戳相同的消息保持写入顺序
     #[test]
-    fn test_user_create() {
-        use super::core;
+    fn test_conversation_order_with_same_timestamp() {
+        use super::{core, model, schema};
+        use diesel::prelude::*;
         let agent =
             Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
-
-        // Register new users
-        let guest = core::Guest {
-            name: "yinguobing".to_string(),
-            credit: 1.2,
-            admin: true,
-        };
+        let guest = core::Guest::regular("yinguobing", 1.2);
         agent
             .create_user(&guest)
             .expect("User registration should succeed");
+        agent
+            .create_conversation(&guest, 10003)
+            .expect("Conversation should be created without error");
+        let msg = super::openai::Message {
+            content: "first".to_string(),
+            role: super::openai::Role::User.to_string(),
+        };
+        agent
+            .append_message(&guest, 10003, &msg, 0.0, 0, 0)
+            .expect("Conversation should be updated without error");
 
-        // Fetch the users
-        let registered_user = agent
-            .get_user("yinguobing")
-            .expect("Existing user should be got without any error");
+        // 以完全相同的时间戳写入后续消息
+        let first = agent.get_conversation(&guest, 10003).unwrap().remove(0);
+        {
+            let conn = &mut agent.connections.get().unwrap();
+            for content in ["second", "third"] {
+                diesel::insert_into(schema::messages::table)
+                    .values(&model::NewMessage {
+                        conversation_id: first.conversation_id,
+                        created_at: first.created_at,
+                        content: content.to_string(),
+                        cost: 0.0,
+                        message_type: first.message_type,
+                        content_type: first.content_type,
+                        prompt_tokens: 0,
+                        completion_tokens: 0,
+                        meta: None,
+                    })
+                    .execute(conn)
+                    .expect("Message should be inserted");
+            }
+        }
 
-        assert_eq!(guest, registered_user);
+        for _ in 0..3 {
+            let contents: Vec<String> = agent
+                .get_conversation(&guest, 10003)
+                .unwrap()
+                .into_iter()
+                .map(|m| m.content)
+                .collect();
+            assert_eq!(contents, ["first", "second", "third"]);
+        }
     }
 
+    // 测试迁移状态包含全部内嵌迁移
     #[test]
-    fn test_user_get_all() {
-        use super::core;
+    fn test_migration_status() {
+        use diesel::migration::{Migration, MigrationName, MigrationSource};
+        use diesel::sqlite::Sqlite;
         let agent =
-            Agent::new(":memory:", "yinguobing").expect("Database agent should be initialized");
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let mut embedded: Vec<String> = MigrationSource::<Sqlite>::migrations(&super::MIGRATIONS)
+            .unwrap()
+            .iter()
+            .map(|m| m.name().version().to_string())
+            .collect();
+        embedded.sort();
+        assert!(!embedded.is_empty());
+        assert_eq!(agent.migration_status().unwrap(), embedded);
+    }
 
-        // Register new users
-        let guest = core::Guest {
-            name: "robin".to_string(),
-            credit: 1.2,
-            admin: true,
-        };
+    // 测试会话列表
+    #[test]
+    fn test_list_conversations() {
+        use super::core;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let guest = core::Guest::regular("yinguobing", 1.2);
         agent
             .create_user(&guest)
             .expect("User registration should succeed");
-
-        let admin = core::Guest {
-            name: "yinguobing".to_string(),
-            credit: 0.0,
-            admin: true,
+        let msg = super::openai::Message {
+            content: "message".to_string(),
+            role: super::openai::Role::User.to_string(),
         };
+        for count in [1, 0, 2] {
+            agent
+                .create_conversation(&guest, 10003)
+                .expect("Conversation should be created without error");
+            for _ in 0..count {
+                agent
+                    .append_message(&guest, 10003, &msg, 0.0, 0, 0)
+                    .expect("Conversation should be updated without error");
+            }
+        }
+        // 其他助手的会话不在列表中
+        agent
+            .create_conversation(&guest, 10004)
+            .expect("Conversation should be created without error");
 
-        // Fetch the users
-        let registered_users = agent
-            .get_users()
-            .expect("All existing user should be got without any error");
+        let (list, total) = agent.list_conversations(&guest, 10003, 10, 0).unwrap();
+        assert_eq!(total, 3);
+        let counts: Vec<(i64, bool)> = list.iter().map(|c| (c.messages, c.active)).collect();
+        assert_eq!(counts, [(2, true), (0, false), (1, false)]);
 
-        assert_eq!(vec![admin, guest], registered_users);
+        let (page, total) = agent.list_conversations(&guest, 10003, 2, 2).unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(page, list[2..].to_vec());
     }
 
+    // 测试置顶的会话不会被清理
     #[test]
-    fn test_user_duplicate_register() {
+    fn test_pinned_conversation_survives_prune() {
         use super::core;
         let agent =
             Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
-
-        // Register new users
-        let guest = core::Guest {
-            name: "yinguobing".to_string(),
-            credit: 1.2,
-            admin: true,
-        };
+        let guest = core::Guest::regular("yinguobing", 1.2);
         agent
             .create_user(&guest)
             .expect("User registration should succeed");
-        assert!(agent.create_user(&guest).is_err());
+        assert!(!agent.is_conversation_pinned(&guest, 10003).unwrap());
+
+        // 第一段会话置顶，第二段不置顶，第三段为活跃会话
+        agent.create_conversation(&guest, 10003).unwrap();
+        agent.set_conversation_pinned(&guest, 10003, true).unwrap();
+        assert!(agent.is_conversation_pinned(&guest, 10003).unwrap());
+        agent.create_conversation(&guest, 10003).unwrap();
+        assert!(!agent.is_conversation_pinned(&guest, 10003).unwrap());
+        agent.create_conversation(&guest, 10003).unwrap();
+
+        let future = chrono::Utc::now().naive_utc() + chrono::Duration::days(1);
+        assert_eq!(agent.prune_conversations(future).unwrap(), 1);
+        let (list, total) = agent.list_conversations(&guest, 10003, 10, 0).unwrap();
+        assert_eq!(total, 2);
+        let flags: Vec<(bool, bool)> = list.iter().map(|c| (c.active, c.pinned)).collect();
+        assert_eq!(flags, [(true, false), (false, true)]);
     }
 
+    // 测试重置全部会话
     #[test]
-    fn test_user_invalid_get() {
+    fn test_reset_all_conversations() {
+        use super::core;
         let agent =
             Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
-        // Fetch an invalid user
-        assert!(agent.get_user("NotExisted").is_err());
+        let guests: Vec<core::Guest> = ["alice", "bob", "carol"]
+            .iter()
+            .map(|name| core::Guest::regular(name, 1.0))
+            .collect();
+        for guest in &guests {
+            agent
+                .create_user(guest)
+                .expect("User registration should succeed");
+            agent
+                .create_conversation(guest, 10003)
+                .expect("Conversation should be created without error");
+        }
+        // 其他助手的会话不受影响
+        agent
+            .create_conversation(&guests[0], 10004)
+            .expect("Conversation should be created without error");
+
+        assert_eq!(agent.reset_all_conversations(10003).unwrap(), 3);
+        for guest in &guests {
+            assert!(agent.get_conversation(guest, 10003).is_err());
+            let (list, total) = agent.list_conversations(guest, 10003, 10, 0).unwrap();
+            assert_eq!(total, 1);
+            assert!(!list[0].active);
+        }
+        assert!(agent.get_conversation(&guests[0], 10004).is_ok());
+        assert_eq!(agent.reset_all_conversations(10003).unwrap(), 0);
     }
 
+    // 测试按向量查找最接近的消息
     #[test]
-    fn test_user_update() {
+    fn test_nearest_messages() {
         use super::core;
         let agent =
             Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
-        let mut guest = core::Guest {
-            name: "yinguobing".to_string(),
-            credit: 1.2,
-            admin: true,
-        };
+        let guest = core::Guest::regular("yinguobing", 1.2);
         agent
             .create_user(&guest)
             .expect("User registration should succeed");
-        guest.credit = 2.2;
         agent
-            .update_user(&guest)
-            .expect("User update should succeed");
-        let user = agent.get_user(&guest.name).unwrap();
-        assert_eq!(guest, user);
+            .create_conversation(&guest, 10003)
+            .expect("Conversation should be created without error");
+        for content in ["cats", "rockets", "no embedding"] {
+            let msg = super::openai::Message {
+                content: content.to_string(),
+                role: super::openai::Role::User.to_string(),
+            };
+            agent
+                .append_message(&guest, 10003, &msg, 0.0, 0, 0)
+                .expect("Conversation should be updated without error");
+        }
+        let messages = agent.get_conversation(&guest, 10003).unwrap();
+        agent.set_embedding(messages[0].id, &[1.0, 0.0]).unwrap();
+        agent.set_embedding(messages[1].id, &[0.0, 1.0]).unwrap();
+
+        let nearest = agent.nearest_messages(&guest, &[0.9, 0.1], 5).unwrap();
+        assert_eq!(nearest.len(), 2);
+        assert_eq!(nearest[0].0.content, "cats");
+        assert!(nearest[0].1 > nearest[1].1);
+
+        let nearest = agent.nearest_messages(&guest, &[0.1, 0.9], 1).unwrap();
+        assert_eq!(nearest.len(), 1);
+        assert_eq!(nearest[0].0.content, "rockets");
     }
 
+    // 测试加密数据库须以正确密钥打开
+    #[cfg(feature = "sqlcipher")]
     #[test]
-    fn test_user_delete() {
+    fn test_encrypted_database() {
         use super::core;
-        let agent =
-            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
-        let mut guest = core::Guest {
-            name: "yinguobing".to_string(),
-            credit: 1.2,
-            admin: true,
+        let dir = std::env::temp_dir().join(format!("wecom-gpt-encrypted-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db_url = dir.join("db.sqlite").to_str().unwrap().to_string();
+        let config = Config {
+            db_encryption_key: Some("correct horse".to_string()),
+            ..Config::default()
         };
-        assert_eq!(agent.remove_user(&guest).unwrap(), 0);
-        guest.name = "administrator".to_string();
-        let n = agent
-            .remove_user(&guest)
-            .expect("This user remove should not fail");
-        assert_eq!(n, 1);
+
+        let agent = Agent::with_config(&db_url, "administrator", &config).unwrap();
+        let guest = core::Guest::regular("robin", 1.0);
+        agent.create_user(&guest).unwrap();
+        drop(agent);
+
+        // 缺少密钥或密钥错误时无法读取
+        assert!(Agent::new(&db_url, "administrator").is_err());
+        let wrong_key = Config {
+            db_encryption_key: Some("battery staple".to_string()),
+            ..Config::default()
+        };
+        assert!(Agent::with_config(&db_url, "administrator", &wrong_key).is_err());
+
+        let agent = Agent::with_config(&db_url, "administrator", &config).unwrap();
+        assert_eq!(agent.get_user("robin").unwrap().credit, 1.0);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    // 测试会话记录
+    // 测试撤销充值及其流水
     #[test]
-    fn test_conversation() {
-        use super::core;
+    fn test_reverse_last_recharge() {
+        use super::{core, schema};
+        use diesel::prelude::*;
         let agent =
             Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let guest = core::Guest::regular("robin", 1.0);
+        agent.create_user(&guest).unwrap();
 
-        let guest = core::Guest {
-            name: "yinguobing".to_string(),
-            credit: 1.2,
-            admin: true,
-        };
-        agent
-            .create_user(&guest)
-            .expect("User registration should succeed");
-        let assistant_id = 10003;
-
-        // Create
-        agent
-            .create_conversation(&guest, assistant_id)
-            .expect("1st Conversation should be created without error");
-        let msg1 = super::openai::Message {
-            content: "message a".to_string(),
-            role: super::openai::Role::User.to_string(),
-        };
-        agent
-            .append_message(&guest, assistant_id, &msg1, 0.18, 0, 0)
-            .expect("Conversation should be updated without error");
+        // 没有流水时无法撤销
+        assert!(agent
+            .reverse_last_recharge("robin", "administrator")
+            .is_err());
 
-        agent
-            .create_conversation(&guest, assistant_id)
-            .expect("Conversation should be created without error");
-        let msg2 = super::openai::Message {
-            content: "message b".to_string(),
-            role: super::openai::Role::Assistant.to_string(),
-        };
-        agent
-            .append_message(&guest, assistant_id, &msg2, 0.81, 2, 5)
-            .expect("Conversation should be updated without error");
+        let recharged = agent.recharge("robin", 2.5, "administrator").unwrap();
+        assert_eq!(recharged.credit, 3.5);
+        let (reversed, amount) = agent
+            .reverse_last_recharge("robin", "administrator")
+            .unwrap();
+        assert_eq!(amount, 2.5);
+        assert_eq!(reversed.credit, 1.0);
+        assert_eq!(agent.get_user("robin").unwrap().credit, 1.0);
 
-        // Get active conversation
-        let active_conv = agent
-            .get_conversation(&guest, assistant_id)
-            .expect("Active conversation should always be ready");
+        // 最近一笔已是冲正流水，不可重复撤销
+        assert!(agent
+            .reverse_last_recharge("robin", "administrator")
+            .is_err());
+        assert_eq!(agent.get_user("robin").unwrap().credit, 1.0);
+        assert!(matches!(
+            agent.recharge("nobody", 1.0, "administrator"),
+            Err(super::Error::NotFound)
+        ));
 
+        let conn = &mut agent.connections.get().unwrap();
+        let entries: Vec<(f64, String, String)> = schema::credit_ledger::table
+            .order(schema::credit_ledger::id.asc())
+            .select((
+                schema::credit_ledger::amount,
+                schema::credit_ledger::kind,
+                schema::credit_ledger::operator,
+            ))
+            .load(conn)
+            .unwrap();
         assert_eq!(
-            super::openai::Message::from(active_conv.first().unwrap()),
-            msg2
+            entries,
+            vec![
+                (2.5, "recharge".to_string(), "administrator".to_string()),
+                (-2.5, "reversal".to_string(), "administrator".to_string()),
+            ]
         );
     }
+
+    #[test]
+    fn test_remove_user_clears_ledger() {
+        use super::{core, schema};
+        use diesel::prelude::*;
+        let agent =
+            Agent::new(":memory:", "administrator").expect("Database agent should be initialized");
+        let guest = core::Guest::regular("robin", 1.0);
+        agent.create_user(&guest).unwrap();
+        agent.recharge("robin", 2.5, "administrator").unwrap();
+        agent.add_feedback(&guest, 10003, "反馈", "回复").unwrap();
+
+        // 删除用户时一并删除其流水与反馈
+        assert_eq!(agent.remove_user(&guest).unwrap(), 1);
+        {
+            let conn = &mut agent.connections.get().unwrap();
+            let entries: i64 = schema::credit_ledger::table
+                .count()
+                .get_result(conn)
+                .unwrap();
+            assert_eq!(entries, 0);
+            let feedback: i64 = schema::feedback::table.count().get_result(conn).unwrap();
+            assert_eq!(feedback, 0);
+        }
+
+        // 同名的新用户无法撤销旧用户的充值
+        agent.create_user(&guest).unwrap();
+        assert!(agent
+            .reverse_last_recharge("robin", "administrator")
+            .is_err());
+        assert_eq!(agent.get_user("robin").unwrap().credit, 1.0);
+    }
 }
@@ -2,4 +2,7 @@ mod agent;
 pub mod model;
 mod schema;
 
-pub use agent::Agent;
+pub use agent::{
+    Agent, Config, ContextSummary, ConversationSettings, ConversationSummary, Error, FeedbackEntry,
+    MessageMeta, Usage, UserOrder,
+};
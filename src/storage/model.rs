@@ -22,6 +22,9 @@ pub struct Guest {
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub admin: bool,
+    pub permissions: i32,
+    pub language: Option<String>,
+    pub alert_threshold: Option<f64>,
 }
 
 #[derive(Insertable)]
@@ -33,6 +36,7 @@ pub struct NewGuest<'a> {
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub admin: bool,
+    pub permissions: i32,
 }
 
 // 会话记录
@@ -47,6 +51,11 @@ pub struct Conversation {
     pub active: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub settings: Option<String>, // JSON格式的会话设置
+    pub pinned: bool,             // 置顶的会话不会被清理
+    pub title: Option<String>,    // 自动生成的会话标题
+    pub summary: Option<String>,  // 早期消息的摘要
+    pub summarized_messages: i32, // 摘要涵盖的消息数
 }
 
 #[derive(Insertable)]
@@ -75,6 +84,8 @@ pub struct Message {
     pub content_type: i32,
     pub prompt_tokens: i32,
     pub completion_tokens: i32,
+    pub embedding: Option<Vec<u8>>,
+    pub meta: Option<String>, // JSON格式的附加信息
 }
 
 // 用于插入表的新消息
@@ -90,4 +101,54 @@ pub struct NewMessage {
     pub content_type: i32,
     pub prompt_tokens: i32,
     pub completion_tokens: i32,
+    pub meta: Option<String>,
+}
+
+// 余额流水。充值与撤销充值各记一条
+#[derive(Queryable, Selectable, Identifiable, Associations, PartialEq, Debug)]
+#[diesel(table_name = schema::credit_ledger)]
+#[diesel(belongs_to(Guest))]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct LedgerEntry {
+    pub id: i32,
+    pub guest_id: i32,
+    pub amount: f64,      // 余额变动，撤销时为负
+    pub kind: String,     // recharge或reversal
+    pub operator: String, // 执行操作的管理员
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::credit_ledger)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewLedgerEntry<'a> {
+    pub guest_id: i32,
+    pub amount: f64,
+    pub kind: &'a str,
+    pub operator: &'a str,
+    pub created_at: NaiveDateTime,
+}
+
+// 管理员模拟用户产生的费用
+#[derive(Insertable)]
+#[diesel(table_name = schema::impersonation_costs)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewImpersonationCost<'a> {
+    pub assistant_id: i32,
+    pub operator: &'a str, // 执行模拟的管理员
+    pub guest: &'a str,    // 被模拟的用户
+    pub cost: f64,
+    pub created_at: NaiveDateTime,
+}
+
+// 用户对AI回复的反馈
+#[derive(Insertable)]
+#[diesel(table_name = schema::feedback)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct NewFeedback<'a> {
+    pub guest_id: i32,
+    pub assistant_id: i32,
+    pub content: &'a str, // 反馈内容
+    pub reply: &'a str,   // 被反馈的AI回复片段
+    pub created_at: NaiveDateTime,
 }
@@ -1,5 +1,14 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    assistant_settings (assistant_id) {
+        assistant_id -> Integer,
+        persona -> Nullable<Text>,
+        updated_at -> Timestamp,
+        max_context -> Nullable<Integer>,
+    }
+}
+
 diesel::table! {
     conversations (id) {
         id -> Integer,
@@ -8,6 +17,22 @@ diesel::table! {
         active -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        settings -> Nullable<Text>,
+        pinned -> Bool,
+        title -> Nullable<Text>,
+        summary -> Nullable<Text>,
+        summarized_messages -> Integer,
+    }
+}
+
+diesel::table! {
+    credit_ledger (id) {
+        id -> Integer,
+        guest_id -> Integer,
+        amount -> Double,
+        kind -> Text,
+        operator -> Text,
+        created_at -> Timestamp,
     }
 }
 
@@ -18,6 +43,25 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    feedback (id) {
+        id -> Integer,
+        guest_id -> Integer,
+        assistant_id -> Integer,
+        content -> Text,
+        reply -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    global_settings (id) {
+        id -> Integer,
+        default_prompt -> Nullable<Text>,
+        updated_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     guests (id) {
         id -> Integer,
@@ -26,6 +70,20 @@ diesel::table! {
         created_at -> Timestamp,
         updated_at -> Timestamp,
         admin -> Bool,
+        permissions -> Integer,
+        language -> Nullable<Text>,
+        alert_threshold -> Nullable<Double>,
+    }
+}
+
+diesel::table! {
+    impersonation_costs (id) {
+        id -> Integer,
+        assistant_id -> Integer,
+        operator -> Text,
+        guest -> Text,
+        cost -> Double,
+        created_at -> Timestamp,
     }
 }
 
@@ -40,15 +98,32 @@ diesel::table! {
         content_type -> Integer,
         prompt_tokens -> Integer,
         completion_tokens -> Integer,
+        embedding -> Nullable<Binary>,
+        meta -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    processed_messages (msg_id) {
+        msg_id -> Text,
+        processed_at -> Timestamp,
     }
 }
 
 diesel::joinable!(conversations -> guests (guest_id));
+diesel::joinable!(credit_ledger -> guests (guest_id));
+diesel::joinable!(feedback -> guests (guest_id));
 diesel::joinable!(messages -> conversations (conversation_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    assistant_settings,
     conversations,
+    credit_ledger,
     db_init_status,
+    feedback,
+    global_settings,
     guests,
+    impersonation_costs,
     messages,
+    processed_messages,
 );
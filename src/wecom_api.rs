@@ -65,6 +65,17 @@ pub struct CallbackRequestBody {
 //   <MsgId>7336741709953816625</MsgId>
 //   <AgentID>1000002</AgentID>
 // </xml>
+//
+// 菜单点击事件没有Content与MsgId，而是以Event与EventKey说明事件内容
+// <xml>
+//   <ToUserName><![CDATA[ww637951f75e40d82b]]></ToUserName>
+//   <FromUserName><![CDATA[YinGuoBing]]></FromUserName>
+//   <CreateTime>1708218294</CreateTime>
+//   <MsgType><![CDATA[event]]></MsgType>
+//   <Event><![CDATA[click]]></Event>
+//   <EventKey><![CDATA[weather]]></EventKey>
+//   <AgentID>1000002</AgentID>
+// </xml>
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct AppMessageContent {
     #[serde(rename = "ToUserName")]
@@ -75,12 +86,56 @@ pub struct AppMessageContent {
     pub create_time: u64,
     #[serde(rename = "MsgType")]
     pub msg_type: String,
-    #[serde(rename = "Content")]
+    #[serde(rename = "Content", default)]
     pub content: String,
-    #[serde(rename = "MsgId")]
+    #[serde(rename = "MsgId", default)]
     pub msg_id: String,
     #[serde(rename = "AgentID")]
     pub agent_id: String,
+    #[serde(rename = "Event", default)]
+    pub event: Option<String>, // 事件类型，仅事件消息包含
+    #[serde(rename = "EventKey", default)]
+    pub event_key: Option<String>, // 菜单按钮的key，仅菜单事件包含
+}
+
+/// 获取access_token接口的返回结果
+/// | 参数          | 说明
+/// | errcode      | 出错返回码，为0表示成功
+/// | errmsg       | 返回码提示语
+/// | access_token | 获取到的凭证
+/// | expires_in   | 凭证的有效时间（秒）
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct AccessTokenResponse {
+    pub errcode: i64,
+    pub errmsg: String,
+    #[serde(default)]
+    pub access_token: String,
+    #[serde(default)]
+    pub expires_in: u64,
+}
+
+/// 发送应用消息接口的返回结果
+/// | 参数          | 说明
+/// | errcode      | 出错返回码，为0表示成功
+/// | errmsg       | 返回码提示语
+/// | invaliduser  | 不合法的userid，以"|"分隔。部分用户无法送达时，errcode仍可能为0
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct MessageSendResponse {
+    pub errcode: i64,
+    pub errmsg: String,
+    #[serde(default)]
+    pub invaliduser: String,
+}
+
+impl MessageSendResponse {
+    /// 无法送达的用户
+    pub fn invalid_users(&self) -> Vec<String> {
+        self.invaliduser
+            .split('|')
+            .filter(|u| !u.is_empty())
+            .map(str::to_owned)
+            .collect()
+    }
 }
 
 /// 企业微信通讯录更新事件回调结构体